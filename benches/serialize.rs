@@ -0,0 +1,30 @@
+use avif_serialize::Aviffy;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn serialize(c: &mut Criterion) {
+    let color = vec![0x55; 50_000];
+    let alpha = vec![0xAA; 10_000];
+
+    c.bench_function("try_to_vec color+alpha", |b| {
+        let aviffy = Aviffy::new();
+        b.iter(|| aviffy.try_to_vec(black_box(&color), Some(black_box(&alpha)), 1920, 1080, 8).unwrap())
+    });
+
+    c.bench_function("write color+alpha to io::Write", |b| {
+        let aviffy = Aviffy::new();
+        let mut out = Vec::with_capacity(100_000);
+        b.iter(|| {
+            out.clear();
+            aviffy.write(&mut out, black_box(&color), Some(black_box(&alpha)), 1920, 1080, 8).unwrap();
+        })
+    });
+
+    c.bench_function("write_into_vec reused color+alpha", |b| {
+        let aviffy = Aviffy::new();
+        let mut out = Vec::new();
+        b.iter(|| aviffy.write_into_vec(&mut out, black_box(&color), Some(black_box(&alpha)), 1920, 1080, 8).unwrap())
+    });
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
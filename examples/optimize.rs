@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::fs;
 use std::path::Path;
 
@@ -8,12 +9,48 @@ fn main() {
 
     let avif = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
 
-    // Chrome won't like the 0 size (https://crbug.com/1120973)
-    // - put real size in your code.
-    // Firefox doesn't mind it tho.
-    let out = avif_serialize::serialize_to_vec(&avif.primary_item, avif.alpha_item.as_deref(), 0, 0, 8);
+    // Chrome needs the real size (https://crbug.com/1120973), but avif_parse only gives the image data
+    let (width, height, depth_bits) = image_properties(&avif_file).expect("Can't find ispe and av1C of the image");
+
+    // The rest of the av1C is copied from the AV1 data, so it's right for any chroma subsampling
+    let out = avif_serialize::Aviffy::new()
+        .strict_av1c(false)
+        .try_to_vec(&avif.primary_item, avif.alpha_item.as_deref(), width, height, depth_bits)
+        .expect("Can't write the image");
 
     let new_path = Path::new(&path).with_extension("rewrite.avif");
     fs::write(&new_path, out).expect("Can't write new file");
     eprintln!("Written {}", new_path.display());
 }
+
+/// Width and height from the first `ispe`, and bit depth from the first `av1C`, which are normally the primary image's
+fn image_properties(avif_file: &[u8]) -> Option<(u32, u32, u8)> {
+    let meta = child(avif_file, b"meta")?;
+    // meta has version and flags
+    let ipco = child(child(meta.get(4..)?, b"iprp")?, b"ipco")?;
+    let ispe = child(ipco, b"ispe")?;
+    let width = u32::from_be_bytes(ispe.get(4..8)?.try_into().ok()?);
+    let height = u32::from_be_bytes(ispe.get(8..12)?.try_into().ok()?);
+    let flags = *child(ipco, b"av1C")?.get(2)?;
+    let depth_bits = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+        (false, _) => 8,
+        (true, false) => 10,
+        (true, true) => 12,
+    };
+    Some((width, height, depth_bits))
+}
+
+/// Payload of the first box of this type
+fn child<'a>(mut boxes: &'a [u8], typ: &[u8; 4]) -> Option<&'a [u8]> {
+    while boxes.len() >= 8 {
+        let size = u32::from_be_bytes(boxes[..4].try_into().ok()?) as usize;
+        if size < 8 || size > boxes.len() {
+            return None;
+        }
+        if &boxes[4..8] == typ {
+            return Some(&boxes[8..size]);
+        }
+        boxes = &boxes[size..];
+    }
+    None
+}
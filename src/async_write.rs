@@ -0,0 +1,74 @@
+//! Writing to Tokio's `AsyncWrite`, enabled with the `async` Cargo feature.
+
+use crate::{Aviffy, DataOrder, Error};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+impl Aviffy {
+    /// Like [`Aviffy::write`], but for async outputs, such as sockets. It makes the same file.
+    ///
+    /// The boxes are serialized up front, and the color and alpha data is written from the given slices without copying it.
+    /// Like in [`Aviffy::start_write`], options that read the AV1 bitstream can't be used, and [`Aviffy::idat_threshold`] is ignored.
+    /// The output isn't flushed.
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, mut into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        let pending = self.start_write(Vec::new(), color_av1_data.len() as u64, alpha_av1_data.map(|data| data.len() as u64), width, height, depth_bits)?;
+        into_output.write_all(&pending.out).await?;
+        let (first, second) = match (alpha_av1_data, self.data_order) {
+            (Some(alpha), DataOrder::AlphaFirst) => (alpha, Some(color_av1_data)),
+            (Some(alpha), DataOrder::ColorFirst) => (color_av1_data, Some(alpha)),
+            (None, _) => (color_av1_data, None),
+        };
+        into_output.write_all(first).await?;
+        if let Some(second) = second {
+            into_output.write_all(second).await?;
+        }
+        Ok(into_output.write_all(&pending.rest).await?)
+    }
+}
+
+/// Runs a future that never waits, like writing to a `Vec`
+#[cfg(test)]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+
+    struct Noop;
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+    let waker = Arc::new(Noop).into();
+    let mut future = Box::pin(future);
+    match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(out) => out,
+        Poll::Pending => panic!("the output isn't ready"),
+    }
+}
+
+#[test]
+fn same_as_sync() {
+    use crate::{FourCC, MdatGroup};
+
+    let color = [1; 100];
+    let alpha = [2; 50];
+    let configs: [fn(&mut Aviffy) -> &mut Aviffy; 5] = [
+        |a| a,
+        |a| a.data_order(DataOrder::ColorFirst),
+        |a| a.add_alternative(&[3; 10], 4, 4).add_metadata(FourCC(*b"Exif"), "", &[0; 8]).append_box(FourCC(*b"skip"), b"end"),
+        |a| a.separate_mdat(MdatGroup::Metadata, true).add_metadata(FourCC(*b"Exif"), "", &[0; 8]).align_mdat(64),
+        |a| a.premultiplied_alpha(true).depth_map(&[4; 20]),
+    ];
+    for (i, config) in configs.iter().enumerate() {
+        for &with_alpha in &[false, true] {
+            let mut aviffy = Aviffy::new();
+            config(&mut aviffy);
+            let alpha = Some(&alpha[..]).filter(|_| with_alpha);
+            let expected = aviffy.try_to_vec(&color, alpha, 8, 8, 8).unwrap();
+            let mut out = Vec::new();
+            block_on(aviffy.write_async(&mut out, &color, alpha, 8, 8, 8)).unwrap();
+            assert_eq!(expected, out, "config {}, alpha {}", i, with_alpha);
+        }
+    }
+
+    let err = block_on(Aviffy::new().write_async(Vec::new(), &color, None, 8, 8, 9)).unwrap_err();
+    assert!(matches!(err, Error::InvalidDepth(9)), "{:?}", err);
+    assert!(block_on(Aviffy::new().validate_bitstream(true).write_async(Vec::new(), &color, None, 8, 8, 8)).is_err());
+}
@@ -1,19 +1,51 @@
+use crate::constants::{ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
+use crate::Error;
 use crate::writer::Writer;
 use crate::writer::WriterBackend;
 use crate::writer::IO;
+use crate::writer::write_patched_box;
 use arrayvec::ArrayVec;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::io;
-use std::io::Write;
+use std::io::{Seek, Write};
 
 pub trait MpegBox {
     fn len(&self) -> usize;
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error>;
 }
 
-#[derive(Copy, Clone)]
+/// Four-character code identifying a box type, brand, item type or reference type, e.g. `av01`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct FourCC(pub [u8; 4]);
 
+impl FourCC {
+    pub const fn new(code: &[u8; 4]) -> Self {
+        Self(*code)
+    }
+}
+
+impl TryFrom<&str> for FourCC {
+    type Error = Error;
+
+    /// The string must be exactly 4 bytes long, or it's [`Error::InvalidFourCC`]
+    fn try_from(code: &str) -> Result<Self, Self::Error> {
+        let code: [u8; 4] = code.as_bytes().try_into()
+            .map_err(|_| Error::InvalidFourCC(code.to_owned()))?;
+        Ok(Self(code))
+    }
+}
+
+/// Prints the code as ASCII, with other bytes escaped
+impl fmt::Display for FourCC {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.iter().flat_map(|&b| std::ascii::escape_default(b)) {
+            fmt::Write::write_char(f, c as char)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Debug for FourCC {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match std::str::from_utf8(&self.0) {
@@ -26,52 +58,538 @@ impl fmt::Debug for FourCC {
 #[derive(Debug, Clone)]
 pub struct AvifFile<'data> {
     pub ftyp: FtypBox,
-    pub meta: MetaBox,
-    pub mdat: MdatBox<'data>,
+    pub meta: MetaBox<'data>,
+    /// Empty when the data is in `meta`'s `idat` instead. The boxes are written one after another.
+    pub mdat: ArrayVec<MdatBox<'data>, MAX_MDAT>,
+    /// Caller-supplied boxes written at the end of the file
+    pub trailing: ArrayVec<RawBox<'data>, MAX_TRAILING>,
+    /// After the trailing boxes, to make the file a certain size
+    pub end_padding: Option<FreeBox>,
+    /// File offset of `mdat`'s payload will be a multiple of this
+    pub mdat_alignment: u32,
+    /// `mdat` goes right after `ftyp`, and `meta` after it
+    pub mdat_first: bool,
 }
 
-impl AvifFile<'_> {
+/// Pieces of data of all `mdat` boxes, with the headers of the boxes after the first one between them
+const MAX_FILE_CHUNKS: usize = MAX_DATA_CHUNKS + MAX_MDAT - 1;
+
+impl<'data> AvifFile<'data> {
+    fn meta_after_mdat(&self) -> bool {
+        self.mdat_first && !self.mdat.is_empty()
+    }
+
+    /// Boxes before the `free` padding and `mdat`
+    fn len_before_mdat(&self) -> usize {
+        self.ftyp.len() + if self.meta_after_mdat() { 0 } else { self.meta.len() }
+    }
+
+    /// `free` box inserted before `mdat` to align its payload
+    fn padding(&self) -> Option<FreeBox> {
+        let mdat = self.mdat.first()?;
+        let len = padding_len(self.len_before_mdat() + mdat.header_len(), self.mdat_alignment.max(1) as usize);
+        if len == 0 {
+            return None;
+        }
+        Some(FreeBox { len })
+    }
+
     /// Where the primary data starts inside the `mdat` box, for `iloc`'s offset
-    fn mdat_payload_start_offset(&self) -> u32 {
-        (self.ftyp.len() + self.meta.len()
-            + BASIC_BOX_SIZE) as u32 // mdat head
+    fn mdat_payload_start_offset(&self) -> u64 {
+        (self.len_before_mdat()
+            + self.padding().map_or(0, |free| free.len())
+            + self.mdat.first().map_or(0, |mdat| mdat.header_len())) as u64
+    }
+
+    /// For each `mdat`, where its data starts in the data of all of them together, and what `iloc`'s offset of that is.
+    /// `idat` offsets start at its payload.
+    fn data_starts(&self) -> ArrayVec<(usize, u64), MAX_MDAT> {
+        let mut starts = ArrayVec::new();
+        if self.mdat.is_empty() {
+            starts.push((0, 0));
+            return starts;
+        }
+        let (mut pos, mut offset) = (0, self.mdat_payload_start_offset());
+        for (i, mdat) in self.mdat.iter().enumerate() {
+            if i > 0 {
+                offset += mdat.header_len() as u64;
+            }
+            starts.push((pos, offset));
+            pos += mdat.payload_len();
+            offset += mdat.payload_len() as u64;
+        }
+        starts
     }
 
     /// `iloc` is mostly unnecssary, high risk of out-of-buffer accesses in parsers that don't pay attention,
     /// and also awkward to serialize, because its content depends on its own serialized byte size.
     fn fix_iloc_positions(&mut self) {
-        let start_offset = self.mdat_payload_start_offset();
-        for iloc_item in self.meta.iloc.items.iter_mut() {
-            for ex in iloc_item.extents.iter_mut() {
-                let abs = match ex.offset {
-                    IlocOffset::Relative(ref mut n) => {
-                        *n as u32 + start_offset
-                    },
-                    IlocOffset::Absolute(_) => continue,
+        self.choose_iloc_field_size();
+        let starts = self.data_starts();
+        self.meta.iloc.resolve_offsets(&starts);
+    }
+
+    fn choose_iloc_field_size(&mut self) {
+        // ISOBMFF allows only 4 or 8 bytes for offsets and lengths (0 is only for whole-file items), so 4 is already the smallest.
+        // 64-bit fields make `meta` larger, which moves the data further, so it's decided with the smaller `meta` first.
+        // If everything fits with 32-bit fields, that layout is final.
+        if !self.meta.iloc.large {
+            let starts = self.data_starts();
+            self.meta.iloc.large = self.meta.iloc.items.iter().flat_map(|item| &item.extents).any(|ex| {
+                let offset = match ex.offset {
+                    IlocOffset::Relative(n) => absolute_offset(&starts, n),
+                    IlocOffset::Absolute(n) => n,
                 };
-                ex.offset = IlocOffset::Absolute(abs);
+                offset > u32::MAX.into() || ex.len as u64 > u32::MAX.into()
+            });
+        }
+    }
+
+    /// Box headers are small, so they're copied into buffers, and written together with the data using vectored I/O
+    pub fn write<W: Write>(&mut self, out: W) -> io::Result<()> {
+        self.fix_iloc_positions();
+
+        let (head, mdat_headers, tail) = self.serialize_around_data();
+        let mut slices = ArrayVec::<&[u8], { MAX_FILE_CHUNKS + 2 }>::new();
+        slices.push(&head);
+        slices.extend(DataChunk::slices(&self.data_in_order(&mdat_headers)));
+        slices.push(&tail);
+        IO::new(out).extend_from_slices(&slices)
+    }
+
+    /// Same as `write`, but `DataChunk::Streamed` pieces of `mdat` are written by `stream` with their length, in the file order
+    pub fn write_streaming<W: Write>(&mut self, mut out: W, mut stream: impl FnMut(&mut W, usize) -> io::Result<()>) -> io::Result<()> {
+        self.fix_iloc_positions();
+
+        let (head, mdat_headers, tail) = self.serialize_around_data();
+        out.write_all(&head)?;
+        for chunk in self.data_in_order(&mdat_headers) {
+            match chunk {
+                DataChunk::Slice(data) => out.write_all(data)?,
+                DataChunk::Streamed(len) => stream(&mut out, len)?,
+                DataChunk::Placeholder(_) => panic!("placeholders can't be written"),
+            }
+        }
+        out.write_all(&tail)
+    }
+
+    /// Same as `write`, but `meta`'s size is patched in after its contents have been written, and `iloc` after the data,
+    /// instead of computing where everything goes up front. Only the sizes of `iloc`'s fields are decided in advance.
+    pub fn write_seekable<W: Write + Seek>(&mut self, mut out: W) -> io::Result<()> {
+        self.choose_iloc_field_size();
+        let origin = out.stream_position()?;
+        if self.mdat.is_empty() {
+            // offsets in `idat` don't depend on where it is
+            self.meta.iloc.resolve_offsets(&[(0, 0)]);
+        }
+
+        write_box(&mut out, &self.ftyp)?;
+        let iloc_pos = if self.meta_after_mdat() { None } else { Some(self.write_meta_seekable(&mut out)?) };
+        let mut starts = ArrayVec::<(usize, u64), MAX_MDAT>::new();
+        if self.mdat.is_empty() {
+            starts.push((0, 0));
+        }
+        let mut data_len = 0;
+        for (i, mdat) in self.mdat.iter().enumerate() {
+            if i == 0 {
+                let unaligned = (out.stream_position()? - origin) as usize + mdat.header_len();
+                let len = padding_len(unaligned, self.mdat_alignment.max(1) as usize);
+                if len > 0 {
+                    write_box(&mut out, &FreeBox { len })?;
+                }
             }
+            let mut header = Vec::with_capacity(mdat.header_len());
+            let _ = mdat.write_header(&mut Writer::new(&mut header));
+            out.write_all(&header)?;
+            starts.push((data_len, out.stream_position()? - origin));
+            IO::new(&mut out).extend_from_slices(&DataChunk::slices(mdat.data_chunks))?;
+            data_len += mdat.payload_len();
+        }
+        self.meta.iloc.resolve_offsets(&starts);
+
+        match iloc_pos {
+            // it has the same size with the real offsets
+            Some(iloc_pos) => {
+                let end = out.stream_position()?;
+                out.seek(io::SeekFrom::Start(iloc_pos))?;
+                write_box(&mut out, &self.meta.iloc)?;
+                out.seek(io::SeekFrom::Start(end))?;
+            },
+            None => {
+                self.write_meta_seekable(&mut out)?;
+            },
+        }
+        for b in &self.trailing {
+            write_box(&mut out, b)?;
+        }
+        if let Some(free) = self.end_padding {
+            // it may be large, so it's not buffered
+            free.write(&mut Writer::new(&mut IO::new(&mut out)))?;
+        }
+        Ok(())
+    }
+
+    /// Children in the same order as `MetaBox::write`. Returns where `iloc` is, to be overwritten once its offsets are known.
+    fn write_meta_seekable<W: Write + Seek>(&self, out: &mut W) -> io::Result<u64> {
+        let meta = &self.meta;
+        let mut iloc_pos = 0;
+        write_patched_box(out, *b"meta", |out| {
+            out.write_all(&[0; 4])?; // version and flags
+            write_box(out, &meta.hdlr)?;
+            if !meta.dinf.is_empty() {
+                write_box(out, &meta.dinf)?;
+            }
+            write_box(out, &meta.pitm)?;
+            iloc_pos = out.stream_position()?;
+            // The offsets may not be known yet, but any non-zero values have the same size
+            let mut iloc = meta.iloc.clone();
+            iloc.resolve_offsets(&[(0, 1)]);
+            write_box(out, &iloc)?;
+            write_box(out, &meta.iinf)?;
+            if !meta.iref.is_empty() {
+                write_box(out, &meta.iref)?;
+            }
+            write_box(out, &meta.iprp)?;
+            if !meta.grpl.is_empty() {
+                write_box(out, &meta.grpl)?;
+            }
+            if let Some(idat) = &meta.idat {
+                write_box(out, idat)?;
+            }
+            Ok(())
+        })?;
+        Ok(iloc_pos)
+    }
+
+    /// Writes everything before the `DataChunk::Streamed` pieces of `mdat`, which must be next to each other.
+    /// Returns their total length, and the rest of the file that goes after them.
+    pub fn write_until_streamed<W: Write>(&mut self, mut out: W) -> io::Result<(u64, Vec<u8>)> {
+        self.fix_iloc_positions();
+
+        let (head, mdat_headers, tail) = self.serialize_around_data();
+        let chunks = self.data_in_order(&mdat_headers);
+        let is_streamed = |chunk: &DataChunk<'_>| matches!(chunk, DataChunk::Streamed(_));
+        let start = chunks.iter().position(is_streamed).unwrap_or(chunks.len());
+        let end = start + chunks[start..].iter().take_while(|c| is_streamed(c)).count();
+        let mut slices = ArrayVec::<&[u8], { MAX_FILE_CHUNKS + 1 }>::new();
+        slices.push(&head);
+        slices.extend(DataChunk::slices(&chunks[..start]));
+        IO::new(out.by_ref()).extend_from_slices(&slices)?;
+
+        let rest_slices = DataChunk::slices(&chunks[end..]);
+        let mut rest = Vec::with_capacity(rest_slices.iter().map(|s| s.len()).sum::<usize>() + tail.len());
+        for data in rest_slices {
+            rest.extend_from_slice(data);
+        }
+        rest.extend_from_slice(&tail);
+        Ok((chunks[start..end].iter().map(|c| c.len() as u64).sum(), rest))
+    }
+
+    /// Same as `write_streaming`, but the `DataChunk::Streamed` pieces are filled with zeros, to be overwritten later.
+    /// `reserved_ids` are the items that have them, each with one extent.
+    pub fn write_reserved<W: Write + Seek>(&mut self, mut out: W, reserved_ids: &[u16]) -> io::Result<ReservedLayout> {
+        let origin = out.stream_position()?;
+        self.write_streaming(&mut out, |out, len| {
+            io::copy(&mut io::Read::take(io::repeat(0), len as u64), out).map(drop)
+        })?;
+
+        let items = reserved_ids.iter().map(|&id| {
+            let item = self.meta.iloc.items.iter().find(|item| item.id == id).expect("reserved item");
+            match item.extents[..] {
+                [IlocExtent { offset: IlocOffset::Absolute(offset), len }] => ReservedItem { id, offset: origin + item.base_offset + offset, len },
+                _ => unreachable!(),
+            }
+        }).collect::<ArrayVec<_, 2>>();
+        let mut mdat_ends = ArrayVec::new();
+        for (mdat, &(_, payload_offset)) in self.mdat.iter().zip(&self.data_starts()) {
+            let payload_end = origin + payload_offset + mdat.payload_len() as u64;
+            if let Some(item) = items.iter().position(|item| item.offset + item.len as u64 == payload_end) {
+                mdat_ends.push(ReservedMdat { pos: origin + payload_offset - mdat.header_len() as u64, header_len: mdat.header_len(), payload_len: mdat.payload_len(), item });
+            }
+        }
+        // `meta` is first in the tail when it's after `mdat`
+        let meta_start = if self.meta_after_mdat() { self.file_len() - self.tail_len() } else { self.ftyp.len() };
+        let dinf_len = if self.meta.dinf.is_empty() { 0 } else { self.meta.dinf.len() };
+        Ok(ReservedLayout {
+            iloc_pos: origin + (meta_start + FULL_BOX_SIZE + self.meta.hdlr.len() + dinf_len + self.meta.pitm.len()) as u64,
+            iloc: self.meta.iloc.clone(),
+            items,
+            mdat_ends,
+            end: origin + self.file_len() as u64,
+        })
+    }
+
+    /// Everything before the first `mdat`'s payload (including its header), the headers of the other `mdat` boxes, and the boxes after them
+    fn serialize_around_data(&self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut head = Vec::with_capacity(self.mdat_payload_start_offset() as usize);
+        let _ = self.write_header(&mut Writer::new(&mut head));
+        if let Some(free) = self.padding() {
+            let _ = free.write(&mut Writer::new(&mut head));
+        }
+        let mut mdat_headers = Vec::new();
+        for (i, mdat) in self.mdat.iter().enumerate() {
+            let _ = mdat.write_header(&mut Writer::new(if i == 0 { &mut head } else { &mut mdat_headers }));
+        }
+        let mut tail = Vec::with_capacity(self.tail_len());
+        let _ = self.write_tail(&mut Writer::new(&mut tail));
+        (head, mdat_headers, tail)
+    }
+
+    /// Data of all `mdat` boxes in the file order, with the `mdat_headers` of the boxes after the first one between them
+    fn data_in_order<'a>(&'a self, mut mdat_headers: &'a [u8]) -> ArrayVec<DataChunk<'a>, MAX_FILE_CHUNKS> {
+        let mut chunks = ArrayVec::new();
+        for (i, mdat) in self.mdat.iter().enumerate() {
+            if i > 0 {
+                let (header, rest) = mdat_headers.split_at(mdat.header_len());
+                chunks.push(DataChunk::Slice(header));
+                mdat_headers = rest;
+            }
+            chunks.extend(mdat.data_chunks.iter().copied());
+        }
+        chunks
+    }
+
+    /// Same as `write`, but without a temporary buffer for the header, and with at most one allocation
+    pub fn write_to_vec(&mut self, out: &mut Vec<u8>) {
+        self.fix_iloc_positions();
+
+        out.reserve(self.file_len());
+        let mut w = Writer::new(out);
+        let _ = self.write_header(&mut w);
+        let _ = self.write_data(&mut w);
+    }
+
+    /// Size of the file that `write` would make
+    pub fn serialized_len(&mut self) -> usize {
+        self.fix_iloc_positions();
+        self.file_len()
+    }
+
+    /// File offset and length of every extent of the item, like `iloc` after `fix_iloc_positions`
+    pub fn item_extents(&mut self, id: u16) -> ArrayVec<(u64, usize), MAX_EXTENTS> {
+        self.fix_iloc_positions();
+        // `idat` is last in `meta`, which is right after `ftyp` when it has the data
+        let idat_payload_start = self.meta.idat.as_ref().map_or(0, |idat| self.ftyp.len() + self.meta.len() - idat.len() + BASIC_BOX_SIZE);
+        let base = if self.meta.iloc.in_idat { idat_payload_start as u64 } else { 0 };
+        self.meta.iloc.items.iter().filter(|item| item.id == id).flat_map(|item| item.extents.iter().map(move |ex| match ex.offset {
+            IlocOffset::Absolute(offset) => (base + item.base_offset + offset, ex.len),
+            IlocOffset::Relative(_) => unreachable!(),
+        })).collect()
+    }
+
+    fn file_len(&self) -> usize {
+        self.len_before_mdat()
+            + self.padding().map_or(0, |free| free.len())
+            + self.mdat.iter().map(|mdat| mdat.len()).sum::<usize>()
+            + self.tail_len()
+    }
+
+    /// Boxes before `free` and `mdat`
+    fn write_header<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        self.ftyp.write(w)?;
+        if !self.meta_after_mdat() {
+            self.meta.write(w)?;
+        }
+        Ok(())
+    }
+
+    fn write_data<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        if let Some(free) = self.padding() {
+            free.write(w)?;
+        }
+        for mdat in &self.mdat {
+            mdat.write(w)?;
+        }
+        self.write_tail(w)
+    }
+
+    fn tail_len(&self) -> usize {
+        (if self.meta_after_mdat() { self.meta.len() } else { 0 })
+            + self.trailing.iter().map(|b| b.len()).sum::<usize>()
+            + self.end_padding.map_or(0, |free| free.len())
+    }
+
+    /// Boxes after `mdat`
+    fn write_tail<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        if self.meta_after_mdat() {
+            self.meta.write(w)?;
+        }
+        for b in &self.trailing {
+            b.write(w)?;
         }
+        if let Some(free) = self.end_padding {
+            free.write(w)?;
+        }
+        Ok(())
     }
+}
 
-    pub fn write<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+/// A box of the file, as described by [`Aviffy::box_tree`](crate::Aviffy::box_tree)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoxInfo {
+    pub typ: FourCC,
+    /// Size of the whole box, including its header
+    pub len: u64,
+    /// Boxes inside it, for boxes that are containers (e.g. `meta`, `iprp` and `ipco`), in the order they're written
+    pub children: Vec<BoxInfo>,
+}
+
+impl BoxInfo {
+    fn new(typ: [u8; 4], len: usize, children: Vec<BoxInfo>) -> Self {
+        Self { typ: FourCC(typ), len: len as u64, children }
+    }
+
+    /// This box or the first box of this type inside it, depth-first
+    pub fn find(&self, typ: FourCC) -> Option<&BoxInfo> {
+        if self.typ == typ {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(typ))
+    }
+}
+
+impl AvifFile<'_> {
+    /// Types and sizes of the boxes that `write` would write, without serializing them
+    pub fn box_tree(&mut self) -> Vec<BoxInfo> {
         self.fix_iloc_positions();
 
-        let mut tmp = Vec::with_capacity(self.ftyp.len() + self.meta.len());
-        let mut w = Writer::new(&mut tmp);
-        let _ = self.ftyp.write(&mut w);
-        let _ = self.meta.write(&mut w);
-        drop(w);
-        out.write_all(&tmp)?;
-        drop(tmp);
-
-        let mut out = IO(out);
-        let mut w = Writer::new(&mut out);
-        self.mdat.write(&mut w)?;
+        let mut boxes = vec![BoxInfo::new(*b"ftyp", self.ftyp.len(), Vec::new())];
+        if !self.meta_after_mdat() {
+            boxes.push(self.meta.box_tree());
+        }
+        boxes.extend(self.padding().map(|free| BoxInfo::new(*b"free", free.len(), Vec::new())));
+        boxes.extend(self.mdat.iter().map(|mdat| BoxInfo::new(*b"mdat", mdat.len(), Vec::new())));
+        if self.meta_after_mdat() {
+            boxes.push(self.meta.box_tree());
+        }
+        boxes.extend(self.trailing.iter().map(|b| BoxInfo::new(b.typ.0, b.len(), Vec::new())));
+        boxes.extend(self.end_padding.map(|free| BoxInfo::new(*b"free", free.len(), Vec::new())));
+        boxes
+    }
+}
+
+impl MetaBox<'_> {
+    /// Children in the same order as `write`
+    fn box_tree(&self) -> BoxInfo {
+        let leaf = |typ, len| BoxInfo::new(typ, len, Vec::new());
+        let mut children = vec![leaf(*b"hdlr", self.hdlr.len())];
+        if !self.dinf.is_empty() {
+            let urls = self.dinf.urls.iter().map(|url| leaf(*b"url ", FULL_BOX_SIZE + url.len() + 1)).collect();
+            let dref = BoxInfo::new(*b"dref", self.dinf.len() - BASIC_BOX_SIZE, urls);
+            children.push(BoxInfo::new(*b"dinf", self.dinf.len(), vec![dref]));
+        }
+        children.push(leaf(*b"pitm", self.pitm.len()));
+        children.push(leaf(*b"iloc", self.iloc.len()));
+        children.push(BoxInfo::new(*b"iinf", self.iinf.len(), self.iinf.items.iter().map(|infe| leaf(*b"infe", infe.len())).collect()));
+        if !self.iref.is_empty() {
+            children.push(BoxInfo::new(*b"iref", self.iref.len(), self.iref.entries.iter().map(|entry| leaf(entry.typ.0, entry.len())).collect()));
+        }
+        let ipco = &self.iprp.ipco;
+        let ipco = BoxInfo::new(*b"ipco", ipco.len(), ipco.props.iter().map(|prop| leaf(prop.fourcc().0, prop.len())).collect());
+        children.push(BoxInfo::new(*b"iprp", self.iprp.len(), vec![ipco, leaf(*b"ipma", self.iprp.ipma.len())]));
+        if !self.grpl.is_empty() {
+            children.push(BoxInfo::new(*b"grpl", self.grpl.len(), self.grpl.groups.iter().map(|group| leaf(group.typ.0, group.len())).collect()));
+        }
+        children.extend(self.idat.as_ref().map(|idat| leaf(*b"idat", idat.len())));
+        BoxInfo::new(*b"meta", self.len(), children)
+    }
+}
+
+/// Where `AvifFile::write_reserved` has left space for data. Positions are in the output, not relative to the file.
+#[derive(Debug, Clone)]
+pub struct ReservedLayout {
+    iloc_pos: u64,
+    /// With the reserved lengths, which are replaced by `patch`
+    iloc: IlocBox,
+    pub items: ArrayVec<ReservedItem, 2>,
+    /// `mdat` boxes that end with reserved space, so they can be shrunk when it's not all used
+    mdat_ends: ArrayVec<ReservedMdat, MAX_MDAT>,
+    /// Where the file ends
+    pub end: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReservedItem {
+    pub id: u16,
+    pub offset: u64,
+    pub len: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ReservedMdat {
+    pos: u64,
+    header_len: usize,
+    payload_len: usize,
+    /// Index in `ReservedLayout::items` of the space at the end of the payload
+    item: usize,
+}
+
+impl ReservedLayout {
+    /// Changes the lengths of the reserved items in `iloc` to `lens` (in the same order, and not larger),
+    /// and turns unused space at the end of `mdat` into a `free` box. The file keeps its size, and `out` is left at its end.
+    pub fn patch<W: Write + Seek>(&mut self, mut out: W, lens: &[usize]) -> io::Result<()> {
+        for (reserved, &len) in self.items.iter().zip(lens) {
+            for item in self.iloc.items.iter_mut().filter(|item| item.id == reserved.id) {
+                item.extents[0].len = len;
+            }
+        }
+        // the fields have the same size, because the lengths are smaller
+        out.seek(io::SeekFrom::Start(self.iloc_pos))?;
+        write_box(&mut out, &self.iloc)?;
+
+        for mdat in &self.mdat_ends {
+            let reserved = self.items[mdat.item];
+            let unused = reserved.len - lens[mdat.item];
+            // a few bytes can't fit a `free` box, so they stay in `mdat`
+            if unused < BASIC_BOX_SIZE {
+                continue;
+            }
+            let payload = [DataChunk::Placeholder(mdat.payload_len - unused)];
+            let shrunk = MdatBox { data_chunks: &payload, large: mdat.header_len > BASIC_BOX_SIZE };
+            let mut header = Vec::with_capacity(mdat.header_len + 16);
+            let _ = shrunk.write_header(&mut Writer::new(&mut header));
+            out.seek(io::SeekFrom::Start(mdat.pos))?;
+            out.write_all(&header)?;
+
+            header.clear();
+            let _ = FreeBox { len: unused }.write_header(&mut Writer::new(&mut header));
+            out.seek(io::SeekFrom::Start(reserved.offset + lens[mdat.item] as u64))?;
+            out.write_all(&header)?;
+        }
+        out.seek(io::SeekFrom::Start(self.end))?;
         Ok(())
     }
 }
 
+/// Small boxes are serialized to a buffer first, because `Writer` needs their size
+fn write_box<W: Write>(out: &mut W, b: &impl MpegBox) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(b.len());
+    let _ = b.write(&mut Writer::new(&mut buf));
+    out.write_all(&buf)
+}
+
+/// The entry of `AvifFile::data_starts` for the `mdat` with the `n`-th byte of data.
+/// An item's data is never split between `mdat` boxes.
+fn payload_start(starts: &[(usize, u64)], n: usize) -> (usize, u64) {
+    *starts.iter().rev().find(|&&(start, _)| start <= n).unwrap_or(&starts[0])
+}
+
+/// `iloc` offset of the `n`-th byte of data
+fn absolute_offset(starts: &[(usize, u64)], n: usize) -> u64 {
+    let (start, offset) = payload_start(starts, n);
+    offset + (n - start) as u64
+}
+
+/// Length of the `free` box that moves `unaligned` to a multiple of `alignment`, or 0 if it's aligned already
+pub fn padding_len(unaligned: usize, alignment: usize) -> usize {
+    let mut len = (alignment - unaligned % alignment) % alignment;
+    // the free box needs space for its own header
+    while len != 0 && len < BASIC_BOX_SIZE {
+        len += alignment;
+    }
+    len
+}
+
 const BASIC_BOX_SIZE: usize = 8;
 const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
 
@@ -79,7 +597,7 @@ const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
 pub struct FtypBox {
     pub major_brand: FourCC,
     pub minor_version: u32,
-    pub compatible_brands: ArrayVec<FourCC, 2>,
+    pub compatible_brands: ArrayVec<FourCC, 3>,
 }
 
 /// File Type box (chunk)
@@ -106,48 +624,65 @@ impl MpegBox for FtypBox {
 
 /// Metadata box
 #[derive(Debug, Clone)]
-pub struct MetaBox {
+pub struct MetaBox<'data> {
     pub hdlr: HdlrBox,
+    /// Written only if some items are in other files
+    pub dinf: DinfBox<'data>,
     pub iloc: IlocBox,
-    pub iinf: IinfBox,
+    pub iinf: IinfBox<'data>,
     pub pitm: PitmBox,
-    pub iprp: IprpBox,
-    pub iref: ArrayVec<IrefBox, 2>,
+    pub iprp: IprpBox<'data>,
+    pub iref: IrefBox,
+    pub grpl: GrplBox,
+    pub idat: Option<IdatBox<'data>>,
 }
 
-impl MpegBox for MetaBox {
+impl MpegBox for MetaBox<'_> {
     #[inline]
     fn len(&self) -> usize {
         FULL_BOX_SIZE
             + self.hdlr.len()
+            + if self.dinf.is_empty() { 0 } else { self.dinf.len() }
             + self.pitm.len()
             + self.iloc.len()
             + self.iinf.len()
             + self.iprp.len()
-            + self.iref.iter().map(|b| b.len()).sum::<usize>()
+            + if self.iref.is_empty() { 0 } else { self.iref.len() }
+            + if self.grpl.is_empty() { 0 } else { self.grpl.len() }
+            + self.idat.as_ref().map_or(0, |idat| idat.len())
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"meta", 0)?;
         self.hdlr.write(&mut b)?;
+        if !self.dinf.is_empty() {
+            self.dinf.write(&mut b)?;
+        }
         self.pitm.write(&mut b)?;
         self.iloc.write(&mut b)?;
         self.iinf.write(&mut b)?;
-        for iref in &self.iref {
-            iref.write(&mut b)?;
+        if !self.iref.is_empty() {
+            self.iref.write(&mut b)?;
+        }
+        self.iprp.write(&mut b)?;
+        if !self.grpl.is_empty() {
+            self.grpl.write(&mut b)?;
         }
-        self.iprp.write(&mut b)
+        if let Some(idat) = &self.idat {
+            idat.write(&mut b)?;
+        }
+        Ok(())
     }
 }
 
 /// Item Info box
 #[derive(Debug, Clone)]
-pub struct IinfBox {
-    pub items: ArrayVec<InfeBox, 2>,
+pub struct IinfBox<'data> {
+    pub items: ArrayVec<InfeBox<'data>, MAX_ITEMS>,
 }
 
-impl MpegBox for IinfBox {
+impl MpegBox for IinfBox<'_> {
     #[inline]
     fn len(&self) -> usize {
         FULL_BOX_SIZE
@@ -168,13 +703,17 @@ impl MpegBox for IinfBox {
 
 /// Item Info Entry box
 #[derive(Debug, Copy, Clone)]
-pub struct InfeBox {
+pub struct InfeBox<'data> {
     pub id: u16,
     pub typ: FourCC,
     pub name: &'static str,
+    /// MIME type of `mime` items, or the `item_uri_type` of `uri ` items
+    pub content_type: Option<&'data str>,
+    /// Not meant to be displayed on its own
+    pub hidden: bool,
 }
 
-impl MpegBox for InfeBox {
+impl MpegBox for InfeBox<'_> {
     #[inline(always)]
     fn len(&self) -> usize {
         FULL_BOX_SIZE
@@ -182,27 +721,38 @@ impl MpegBox for InfeBox {
         + 2 // item_protection_index
         + 4 // type
         + self.name.as_bytes().len() + 1 // nul-terminated
+        + self.content_type.map_or(0, |content_type| content_type.len() + 1)
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"infe", 2)?;
+        b.full_box_with_flags(*b"infe", 2, if self.hidden { 1 } else { 0 })?;
         b.u16(self.id)?;
         b.u16(0)?;
         b.push(&self.typ.0)?;
         b.push(self.name.as_bytes())?;
-        b.u8(0)
+        b.u8(0)?;
+        if let Some(content_type) = self.content_type {
+            b.push(content_type.as_bytes())?;
+            b.u8(0)?;
+        }
+        Ok(())
     }
 }
 
+/// Handler box, says what kind of data the `meta` box describes
 #[derive(Debug, Clone)]
 pub struct HdlrBox {
+    /// `pict` for images
+    pub handler_type: FourCC,
+    /// Informative only, may be empty
+    pub name: &'static str,
 }
 
 impl MpegBox for HdlrBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 4 + 4 + 13
+        FULL_BOX_SIZE + 4 + 4 + 12 + self.name.len() + 1
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -211,23 +761,62 @@ impl MpegBox for HdlrBox {
         // and it does it the way classic MacOS used to, because Quicktime.
         b.full_box(*b"hdlr", 0)?;
         b.u32(0)?; // old MacOS file type handler
-        b.push(b"pict")?; // MacOS Quicktime subtype
+        b.push(&self.handler_type.0)?; // MacOS Quicktime subtype
         b.u32(0)?; // Firefox 92 wants all 0 here
         b.u32(0)?; // Reserved
         b.u32(0)?; // Reserved
-        b.u8(0)?; // Pascal string for component name
+        b.push(self.name.as_bytes())?;
+        b.u8(0)?; // nul-terminated component name
+        Ok(())
+    }
+}
+
+/// Data information, with a `dref` of other files that items can have their data in
+#[derive(Debug, Clone, Default)]
+pub struct DinfBox<'data> {
+    /// `url ` entries of `dref`. `iloc`'s `data_reference_index` is 1 for the first one, and 0 is this file.
+    pub urls: ArrayVec<&'data str, MAX_METADATA>,
+}
+
+impl DinfBox<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.urls.is_empty()
+    }
+}
+
+impl MpegBox for DinfBox<'_> {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+        + FULL_BOX_SIZE + 4 // dref, entry count
+        + self.urls.iter().map(|url| FULL_BOX_SIZE + url.len() + 1).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"dinf")?;
+        let mut dref = b.new_box(self.len() - BASIC_BOX_SIZE);
+        dref.full_box(*b"dref", 0)?;
+        dref.u32(self.urls.len() as u32)?;
+        for url in &self.urls {
+            let mut entry = dref.new_box(FULL_BOX_SIZE + url.len() + 1);
+            // no flags, because the data isn't in this file
+            entry.full_box(*b"url ", 0)?;
+            entry.push(url.as_bytes())?;
+            entry.u8(0)?;
+        }
         Ok(())
     }
 }
 
 /// Item properties + associations
 #[derive(Debug, Clone)]
-pub struct IprpBox {
-    pub ipco: IpcoBox,
+pub struct IprpBox<'data> {
+    pub ipco: IpcoBox<'data>,
     pub ipma: IpmaBox,
 }
 
-impl MpegBox for IprpBox {
+impl MpegBox for IprpBox<'_> {
     #[inline(always)]
     fn len(&self) -> usize {
         BASIC_BOX_SIZE
@@ -243,21 +832,45 @@ impl MpegBox for IprpBox {
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum IpcoProp {
-    Av1C(Av1CBox),
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcoProp<'data> {
+    Av1C(Av1CBox<'data>),
     Pixi(PixiBox),
     Ispe(IspeBox),
-    AuxC(AuxCBox),
+    AuxC(AuxCBox<'data>),
+    Colr(ColrBox<'data>),
+    Clap(ClapBox),
+    /// Layer selector, the id of the AV1 spatial layer to display
+    Lsel(u16),
 }
 
-impl IpcoProp {
+impl IpcoProp<'_> {
+    pub fn fourcc(&self) -> FourCC {
+        FourCC(match self {
+            Self::Av1C(_) => *b"av1C",
+            Self::Pixi(_) => *b"pixi",
+            Self::Ispe(_) => *b"ispe",
+            Self::AuxC(_) => *b"auxC",
+            Self::Colr(_) => *b"colr",
+            Self::Clap(_) => *b"clap",
+            Self::Lsel(_) => *b"lsel",
+        })
+    }
+
+    /// Whether the property is marked essential in `ipma`, unless overridden.
+    pub fn essential(&self) -> bool {
+        essential_by_default(self.fourcc())
+    }
+
     pub fn len(&self) -> usize {
         match self {
             Self::Av1C(p) => p.len(),
             Self::Pixi(p) => p.len(),
             Self::Ispe(p) => p.len(),
             Self::AuxC(p) => p.len(),
+            Self::Colr(p) => p.len(),
+            Self::Clap(p) => p.len(),
+            Self::Lsel(_) => BASIC_BOX_SIZE + 2,
         }
     }
 
@@ -267,28 +880,48 @@ impl IpcoProp {
             Self::Pixi(p) => p.write(w),
             Self::Ispe(p) => p.write(w),
             Self::AuxC(p) => p.write(w),
+            Self::Colr(p) => p.write(w),
+            Self::Clap(p) => p.write(w),
+            Self::Lsel(layer_id) => {
+                let mut b = w.new_box(self.len());
+                b.basic_box(*b"lsel")?;
+                b.u16(*layer_id)
+            },
         }
     }
 }
 
+/// The codec configuration can't be ignored, and HEIF requires transformative properties (`clap`, `irot`, `imir`) to be essential.
+/// AVIF requires `lsel` to be essential too, since showing all layers instead would be wrong.
+pub fn essential_by_default(fourcc: FourCC) -> bool {
+    matches!(&fourcc.0, b"av1C" | b"clap" | b"irot" | b"imir" | b"lsel")
+}
+
 /// Item Property Container box
 #[derive(Debug, Clone)]
-pub struct IpcoBox {
-    props: ArrayVec<IpcoProp, 6>,
+pub struct IpcoBox<'data> {
+    props: ArrayVec<IpcoProp<'data>, 32>,
 }
 
-impl IpcoBox {
+impl<'data> IpcoBox<'data> {
     pub fn new() -> Self {
         Self { props: ArrayVec::new() }
     }
 
-    pub fn push(&mut self, prop: IpcoProp) -> u8 {
-        self.props.push(prop);
-        self.props.len() as u8 // the spec wants them off by one
+    /// Identical properties are stored only once, and get the same id
+    pub fn push(&mut self, prop: IpcoProp<'data>) -> u8 {
+        let idx = match self.props.iter().position(|p| *p == prop) {
+            Some(idx) => idx,
+            None => {
+                self.props.push(prop);
+                self.props.len() - 1
+            },
+        };
+        (idx + 1) as u8 // the spec wants them off by one
     }
 }
 
-impl MpegBox for IpcoBox {
+impl MpegBox for IpcoBox<'_> {
     #[inline]
     fn len(&self) -> usize {
         BASIC_BOX_SIZE
@@ -305,50 +938,50 @@ impl MpegBox for IpcoBox {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct AuxCBox {
-    pub urn: &'static str,
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AuxCBox<'data> {
+    pub urn: &'data str,
+    /// Specific to the type of the auxiliary image, may be empty
+    pub aux_subtype: &'data [u8],
 }
 
-impl AuxCBox {
+impl AuxCBox<'_> {
     pub fn len(&self) -> usize {
-        FULL_BOX_SIZE + self.urn.len() + 1
+        FULL_BOX_SIZE + self.urn.len() + 1 + self.aux_subtype.len()
     }
 
     pub fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"auxC", 0)?;
         b.push(self.urn.as_bytes())?;
-        b.u8(0)
+        b.u8(0)?;
+        b.push(self.aux_subtype)
     }
 }
 
 /// Pixies, I guess.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PixiBox {
-    pub depth: u8,
-    pub channels: u8,
+    /// Bit depth of each channel
+    pub depths: ArrayVec<u8, 3>,
 }
 
 impl PixiBox {
     pub fn len(&self) -> usize {
         FULL_BOX_SIZE
-            + 1 + self.channels as usize
+            + 1 + self.depths.len()
     }
 
     pub fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"pixi", 0)?;
-        b.u8(self.channels)?;
-        for _ in 0..self.channels {
-            b.u8(self.depth)?;
-        }
-        Ok(())
+        b.u8(self.depths.len() as u8)?;
+        b.push(&self.depths)
     }
 }
 
 /// This is HEVC-specific and not for AVIF, but Chrome wants it :(
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct IspeBox {
     pub width: u32,
     pub height: u32,
@@ -368,16 +1001,138 @@ impl MpegBox for IspeBox {
     }
 }
 
+/// CICP color codes of the `nclx` form of `colr`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Nclx {
+    pub color_primaries: ColorPrimaries,
+    pub transfer_characteristics: TransferCharacteristics,
+    pub matrix_coefficients: MatrixCoefficients,
+    pub full_range_flag: bool,
+}
+
+impl Default for Nclx {
+    fn default() -> Self {
+        Self {
+            color_primaries: ColorPrimaries::Bt709,
+            transfer_characteristics: TransferCharacteristics::Srgb,
+            matrix_coefficients: MatrixCoefficients::Bt601,
+            full_range_flag: true,
+        }
+    }
+}
+
+impl Nclx {
+    /// Written for the alpha image, which has no color, and is always full range
+    pub(crate) const ALPHA: Self = Self {
+        color_primaries: ColorPrimaries::Unspecified,
+        transfer_characteristics: TransferCharacteristics::Unspecified,
+        matrix_coefficients: MatrixCoefficients::Unspecified,
+        full_range_flag: true,
+    };
+}
+
+/// Color information. An image can have one of each kind.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColrBox<'data> {
+    Nclx(Nclx),
+    /// Unrestricted ICC profile (`prof`)
+    Icc(&'data [u8]),
+}
+
+impl MpegBox for ColrBox<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + match self {
+            Self::Nclx(_) => 2 + 2 + 2 + 1,
+            Self::Icc(profile) => profile.len(),
+        }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"colr")?;
+        match self {
+            Self::Nclx(nclx) => {
+                b.push(b"nclx")?;
+                b.u16(nclx.color_primaries as u16)?;
+                b.u16(nclx.transfer_characteristics as u16)?;
+                b.u16(nclx.matrix_coefficients as u16)?;
+                b.u8(if nclx.full_range_flag { 1 << 7 } else { 0 })
+            },
+            Self::Icc(profile) => {
+                b.push(b"prof")?;
+                b.push(profile)
+            },
+        }
+    }
+}
+
+/// Clean aperture (crop), as fractions. Offsets are from the center of the image to the center of the crop.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClapBox {
+    pub width_n: u32,
+    pub width_d: u32,
+    pub height_n: u32,
+    pub height_d: u32,
+    pub horiz_off_n: i32,
+    pub horiz_off_d: u32,
+    pub vert_off_n: i32,
+    pub vert_off_d: u32,
+}
+
+impl ClapBox {
+    /// Crop of the top-left `crop_width`×`crop_height` pixels of a `width`×`height` image
+    pub fn top_left(width: u32, height: u32, crop_width: u32, crop_height: u32) -> Self {
+        // (crop / 2) - (size / 2), in halves to keep it integer
+        let offset = |size: u32, crop: u32| {
+            let halves = i64::from(crop) - i64::from(size);
+            if halves % 2 == 0 { ((halves / 2) as i32, 1) } else { (halves as i32, 2) }
+        };
+        let (horiz_off_n, horiz_off_d) = offset(width, crop_width);
+        let (vert_off_n, vert_off_d) = offset(height, crop_height);
+        Self {
+            width_n: crop_width,
+            width_d: 1,
+            height_n: crop_height,
+            height_d: 1,
+            horiz_off_n,
+            horiz_off_d,
+            vert_off_n,
+            vert_off_d,
+        }
+    }
+}
+
+impl MpegBox for ClapBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 8 * 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"clap")?;
+        b.u32(self.width_n)?;
+        b.u32(self.width_d)?;
+        b.u32(self.height_n)?;
+        b.u32(self.height_d)?;
+        b.u32(self.horiz_off_n as u32)?;
+        b.u32(self.horiz_off_d)?;
+        b.u32(self.vert_off_n as u32)?;
+        b.u32(self.vert_off_d)
+    }
+}
+
 /// Property→image associations
 #[derive(Debug, Clone)]
 pub struct IpmaEntry {
     pub item_id: u16,
-    pub prop_ids: ArrayVec<u8, 4>,
+    pub prop_ids: ArrayVec<u8, 8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IpmaBox {
-    pub entries: ArrayVec<IpmaEntry, 2>,
+    pub entries: ArrayVec<IpmaEntry, MAX_ITEMS>,
 }
 
 impl MpegBox for IpmaBox {
@@ -402,11 +1157,11 @@ impl MpegBox for IpmaBox {
     }
 }
 
-/// Item Reference box
-#[derive(Debug, Copy, Clone)]
+/// Single item reference: `from_id` refers to all of `to_ids` with the reference type `typ`
+#[derive(Debug, Clone)]
 pub struct IrefEntryBox {
     pub from_id: u16,
-    pub to_id: u16,
+    pub to_ids: ArrayVec<u16, 2>,
     pub typ: FourCC,
 }
 
@@ -416,33 +1171,117 @@ impl MpegBox for IrefEntryBox {
         BASIC_BOX_SIZE
             + 2 // from
             + 2 // refcount
-            + 2 // to
+            + 2 * self.to_ids.len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.basic_box(self.typ.0)?;
         b.u16(self.from_id)?;
-        b.u16(1)?;
-        b.u16(self.to_id)
+        b.u16(self.to_ids.len() as u16)?;
+        for &to_id in &self.to_ids {
+            b.u16(to_id)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Item Reference box. There can be only one per `meta`, so all reference types go in here.
+#[derive(Debug, Clone, Default)]
 pub struct IrefBox {
-    pub entry: IrefEntryBox,
+    pub entries: ArrayVec<IrefEntryBox, { 3 + MAX_METADATA }>,
+}
+
+impl IrefBox {
+    /// Adds a reference, grouping it with earlier references of the same type from the same item
+    pub fn push(&mut self, from_id: u16, to_id: u16, typ: FourCC) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.from_id == from_id && e.typ == typ) {
+            entry.to_ids.push(to_id);
+        } else {
+            let mut to_ids = ArrayVec::new();
+            to_ids.push(to_id);
+            self.entries.push(IrefEntryBox { from_id, to_ids, typ });
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 }
 
 impl MpegBox for IrefBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + self.entry.len()
+        FULL_BOX_SIZE + self.entries.iter().map(|e| e.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"iref", 0)?;
-        self.entry.write(&mut b)
+        for entry in &self.entries {
+            entry.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups of items (entities), such as alternatives
+#[derive(Debug, Clone, Default)]
+pub struct GrplBox {
+    pub groups: ArrayVec<EntityToGroupBox, 2>,
+}
+
+impl GrplBox {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl MpegBox for GrplBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.groups.iter().map(|g| g.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"grpl")?;
+        for group in &self.groups {
+            group.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Group of items, e.g. `altr` for alternatives in order of preference, or `ster` for a stereo pair
+#[derive(Debug, Clone)]
+pub struct EntityToGroupBox {
+    pub typ: FourCC,
+    /// Must be different from all item ids
+    pub group_id: u32,
+    pub entity_ids: ArrayVec<u32, MAX_ITEMS>,
+}
+
+impl MpegBox for EntityToGroupBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE
+            + 4 // group id
+            + 4 // count
+            + 4 * self.entity_ids.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(self.typ.0, 0)?;
+        b.u32(self.group_id)?;
+        b.u32(self.entity_ids.len() as u32)?;
+        for &id in &self.entity_ids {
+            b.u32(id)?;
+        }
+        Ok(())
     }
 }
 
@@ -462,8 +1301,8 @@ impl MpegBox for AuxlBox {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Av1CBox {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Av1CBox<'data> {
     pub seq_profile: u8,
     pub seq_level_idx_0: u8,
     pub seq_tier_0: bool,
@@ -473,12 +1312,26 @@ pub struct Av1CBox {
     pub chroma_subsampling_x: bool,
     pub chroma_subsampling_y: bool,
     pub chroma_sample_position: u8,
+    /// `initial_presentation_delay_minus_one`, 0-15
+    pub initial_presentation_delay_minus_one: Option<u8>,
+    /// Sequence header OBU (with `obu_size`), or empty
+    pub config_obus: &'data [u8],
 }
 
-impl MpegBox for Av1CBox {
+impl Av1CBox<'_> {
+    pub fn bit_depth(&self) -> u8 {
+        match (self.high_bitdepth, self.twelve_bit) {
+            (true, true) => 12,
+            (true, false) => 10,
+            _ => 8,
+        }
+    }
+}
+
+impl MpegBox for Av1CBox<'_> {
     #[inline(always)]
     fn len(&self) -> usize {
-        BASIC_BOX_SIZE + 4
+        BASIC_BOX_SIZE + 4 + self.config_obus.len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -491,14 +1344,15 @@ impl MpegBox for Av1CBox {
             (self.monochrome as u8) << 4 |
             (self.chroma_subsampling_x as u8) << 3 |
             (self.chroma_subsampling_y as u8) << 2 |
-            (self.chroma_sample_position as u8);
+            self.chroma_sample_position;
 
         b.push(&[
             0x81, // marker and version
-            ((self.seq_profile as u8) << 5) | self.seq_level_idx_0, // x2d == 45
+            (self.seq_profile << 5) | self.seq_level_idx_0, // x2d == 45
             flags1,
-            0,
-        ])
+            self.initial_presentation_delay_minus_one.map_or(0, |delay| 1 << 4 | delay),
+        ])?;
+        b.push(self.config_obus)
     }
 }
 
@@ -520,19 +1374,46 @@ impl MpegBox for PitmBox {
 
 #[derive(Debug, Clone)]
 pub struct IlocBox {
-    pub items: ArrayVec<IlocItem, 2>,
+    pub items: ArrayVec<IlocItem, MAX_ITEMS>,
+    /// Offsets and lengths are 64-bit. It's set automatically when they don't fit in 32 bits.
+    pub large: bool,
+    /// 0-2. Version 1 adds the construction method, and 2 has 32-bit item ids.
+    pub version: u8,
+    /// All items are in `meta`'s `idat` (construction method 1), with offsets relative to its payload. Requires version 1 or 2.
+    pub in_idat: bool,
+    /// Items have a `base_offset` (set automatically to the start of their `mdat` payload), and their extents are relative to it
+    pub base_offsets: bool,
 }
 
+/// Max number of separate pieces of data per item
+pub const MAX_EXTENTS: usize = 16;
+
+/// Pieces of color and alpha, and one per alternative image, the other eye, the depth map, and metadata item
+pub const MAX_DATA_CHUNKS: usize = 2 * MAX_EXTENTS + MAX_ALTERNATIVES + 2 + MAX_METADATA;
+
+/// Color, alpha, alternatives of the color image, the other eye of a stereo pair, depth, and metadata
+pub const MAX_ITEMS: usize = 2 + MAX_ALTERNATIVES + 2 + MAX_METADATA;
+pub const MAX_ALTERNATIVES: usize = 8;
+/// Image data, other images, and metadata can each have their own `mdat`
+pub const MAX_MDAT: usize = 3;
+pub const MAX_METADATA: usize = 4;
+/// Boxes added with `Aviffy::append_box`
+pub const MAX_TRAILING: usize = 4;
+
 #[derive(Debug, Clone)]
 pub struct IlocItem {
     pub id: u16,
-    pub extents: ArrayVec<IlocExtent, 1>,
+    /// 0 if the data is in this file, otherwise 1 + index of its URL in `DinfBox::urls`
+    pub data_ref_index: u16,
+    /// Only written if `IlocBox::base_offsets` is set
+    pub base_offset: u64,
+    pub extents: ArrayVec<IlocExtent, MAX_EXTENTS>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum IlocOffset {
     Relative(usize),
-    Absolute(u32),
+    Absolute(u64),
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -541,41 +1422,114 @@ pub struct IlocExtent {
     pub len: usize,
 }
 
+impl IlocBox {
+    fn field_size(&self) -> usize {
+        if self.large { 8 } else { 4 }
+    }
+
+    fn base_offset_size(&self) -> usize {
+        if self.base_offsets { self.field_size() } else { 0 }
+    }
+
+    /// Replaces relative offsets with file offsets. `starts` are from `AvifFile::data_starts`, or where the data has actually been written.
+    fn resolve_offsets(&mut self, starts: &[(usize, u64)]) {
+        let base_offsets = self.base_offsets;
+        for iloc_item in self.items.iter_mut() {
+            if base_offsets {
+                if let Some(&IlocExtent { offset: IlocOffset::Relative(n), .. }) = iloc_item.extents.first() {
+                    iloc_item.base_offset = payload_start(starts, n).1;
+                }
+            }
+            for ex in iloc_item.extents.iter_mut() {
+                let abs = match ex.offset {
+                    IlocOffset::Relative(ref mut n) => {
+                        absolute_offset(starts, *n)
+                    },
+                    IlocOffset::Absolute(_) => continue,
+                };
+                ex.offset = IlocOffset::Absolute(abs - iloc_item.base_offset);
+            }
+        }
+    }
+
+    /// Offsets can be omitted when they're all 0, which happens when the only piece of data is at the start of `idat`.
+    ///
+    /// Offsets relative to base offsets aren't known to be 0 until the base offsets are, which would change the size of `iloc` too late.
+    fn offset_size(&self) -> usize {
+        let all_zero = !self.base_offsets && self.items.iter().flat_map(|item| &item.extents).all(|ex| match ex.offset {
+            IlocOffset::Relative(n) => self.in_idat && n == 0,
+            IlocOffset::Absolute(n) => n == 0,
+        });
+        if all_zero { 0 } else { self.field_size() }
+    }
+}
+
 impl MpegBox for IlocBox {
     #[inline(always)]
     fn len(&self) -> usize {
+        let id_size = if self.version < 2 { 2 } else { 4 };
         FULL_BOX_SIZE
         + 1 // offset_size, length_size
-        + 1 // base_offset_size, reserved
-        + 2 // num items
+        + 1 // base_offset_size, index_size
+        + id_size // num items
         + self.items.iter().map(|i| ( // for each item
-            2 // id
+            id_size // id
+            + if self.version > 0 { 2 } else { 0 } // construction method
             + 2 // dat ref idx
-            + 0 // base_offset_size
+            + self.base_offset_size() // base_offset
             + 2 // extent count
             + i.extents.len() * ( // for each extent
-               4 // extent_offset
-               + 4 // extent_len
+               self.offset_size() // extent_offset
+               + self.field_size() // extent_len
             )
         )).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"iloc", 0)?;
-        b.push(&[4 << 4 | 4, 0])?; // offset and length are 4 bytes
-
-        b.u16(self.items.len() as _)?; // num items
+        b.full_box(*b"iloc", self.version)?;
+        let offset_size = self.offset_size();
+        let size = self.field_size() as u8;
+        let base_offset_size = self.base_offset_size();
+        b.push(&[(offset_size as u8) << 4 | size, (base_offset_size as u8) << 4])?; // offset and length sizes, base offset size, no index
+
+        if self.version < 2 {
+            b.u16(self.items.len() as _)?; // num items
+        } else {
+            b.u32(self.items.len() as _)?;
+        }
         for item in self.items.iter() {
-            b.u16(item.id)?;
-            b.u16(0)?;
+            if self.version < 2 {
+                b.u16(item.id)?;
+            } else {
+                b.u32(item.id.into())?;
+            }
+            if self.version > 0 {
+                // construction method: idat or file offset. Data in other files is always at file offsets.
+                b.u16(if self.in_idat && item.data_ref_index == 0 { 1 } else { 0 })?;
+            }
+            b.u16(item.data_ref_index)?;
+            match base_offset_size {
+                0 => {},
+                8 => b.u64(item.base_offset)?,
+                _ => b.u32(item.base_offset as u32)?,
+            }
             b.u16(item.extents.len() as _)?; // num extents
             for ex in &item.extents {
-                b.u32(match ex.offset {
+                let offset = match ex.offset {
                     IlocOffset::Absolute(val) => val,
                     IlocOffset::Relative(_) => panic!("absolute offset must be set"),
-                })?;
-                b.u32(ex.len as _)?;
+                };
+                match offset_size {
+                    0 => {},
+                    8 => b.u64(offset)?,
+                    _ => b.u32(offset as u32)?,
+                }
+                if self.large {
+                    b.u64(ex.len as u64)?;
+                } else {
+                    b.u32(ex.len as u32)?;
+                }
             }
         }
         Ok(())
@@ -584,10 +1538,91 @@ impl MpegBox for IlocBox {
 
 #[derive(Debug, Copy, Clone)]
 pub struct MdatBox<'data> {
-    pub data_chunks: &'data [&'data [u8]],
+    pub data_chunks: &'data [DataChunk<'data>],
+    /// Use the 64-bit size even if the data is small
+    pub large: bool,
+}
+
+impl MdatBox<'_> {
+    /// The size doesn't fit in the basic box header when the data is over 4GB
+    pub fn header_len(&self) -> usize {
+        if self.large || self.payload_len() + BASIC_BOX_SIZE > u32::MAX as usize { BASIC_BOX_SIZE + 8 } else { BASIC_BOX_SIZE }
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.data_chunks.iter().map(|c| c.len()).sum::<usize>()
+    }
+
+    /// Only the header, for data written separately
+    fn write_header<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.header_len());
+        if self.header_len() > BASIC_BOX_SIZE {
+            b.u32(1)?;
+            b.push(b"mdat")?;
+            b.u64(self.len() as u64)
+        } else {
+            b.u32(self.len() as u32)?;
+            b.push(b"mdat")
+        }
+    }
 }
 
 impl MpegBox for MdatBox<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.header_len() + self.payload_len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        if self.header_len() > BASIC_BOX_SIZE {
+            b.large_box(*b"mdat")?;
+        } else {
+            b.basic_box(*b"mdat")?;
+        }
+        b.push_slices(&DataChunk::slices(self.data_chunks))
+    }
+}
+
+/// A piece of image data
+#[derive(Debug, Copy, Clone)]
+pub enum DataChunk<'data> {
+    Slice(&'data [u8]),
+    /// Only the length is known up front, and the data is copied from a stream by `AvifFile::write_streaming`
+    Streamed(usize),
+    /// Only the length, for computing the file size without the data
+    Placeholder(usize),
+}
+
+impl<'data> DataChunk<'data> {
+    pub fn len(&self) -> usize {
+        match *self {
+            Self::Slice(data) => data.len(),
+            Self::Streamed(len) | Self::Placeholder(len) => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Panics on streamed data, since only `AvifFile::write_streaming` can write it
+    fn slices(chunks: &[Self]) -> ArrayVec<&'data [u8], MAX_FILE_CHUNKS> {
+        chunks.iter().map(|chunk| match *chunk {
+            Self::Slice(data) => data,
+            Self::Streamed(_) => panic!("streamed data must be written with write_streaming"),
+            Self::Placeholder(_) => panic!("placeholders can't be written"),
+        }).collect()
+    }
+}
+
+/// Item data stored inside `meta`, for images so small that `mdat` would be a waste
+#[derive(Debug, Clone)]
+pub struct IdatBox<'data> {
+    pub data_chunks: &'data [DataChunk<'data>],
+}
+
+impl MpegBox for IdatBox<'_> {
     #[inline(always)]
     fn len(&self) -> usize {
         BASIC_BOX_SIZE + self.data_chunks.iter().map(|c| c.len()).sum::<usize>()
@@ -595,10 +1630,264 @@ impl MpegBox for MdatBox<'_> {
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.basic_box(*b"mdat")?;
-        for ch in self.data_chunks {
-            b.push(ch)?;
+        b.basic_box(*b"idat")?;
+        b.push_slices(&DataChunk::slices(self.data_chunks))
+    }
+}
+
+/// Any box with opaque content
+#[derive(Debug, Copy, Clone)]
+pub struct RawBox<'data> {
+    pub typ: FourCC,
+    pub data: &'data [u8],
+}
+
+impl MpegBox for RawBox<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.data.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(self.typ.0)?;
+        b.push(self.data)
+    }
+}
+
+/// Padding
+#[derive(Debug, Copy, Clone)]
+pub struct FreeBox {
+    /// Including the box header
+    pub len: usize,
+}
+
+impl MpegBox for FreeBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"free")?;
+        let zeros = [0; 64];
+        // the header has a 64-bit size if it's huge
+        let mut remaining = self.len - if self.len > u32::MAX as usize { BASIC_BOX_SIZE + 8 } else { BASIC_BOX_SIZE };
+        while remaining > 0 {
+            let n = remaining.min(zeros.len());
+            b.push(&zeros[..n])?;
+            remaining -= n;
         }
         Ok(())
     }
 }
+
+impl FreeBox {
+    /// Only the header, for space that's already there
+    fn write_header<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        if self.len > u32::MAX as usize {
+            let mut b = w.new_box(BASIC_BOX_SIZE + 8);
+            b.u32(1)?;
+            b.push(b"free")?;
+            b.u64(self.len as u64)
+        } else {
+            let mut b = w.new_box(BASIC_BOX_SIZE);
+            b.u32(self.len as u32)?;
+            b.push(b"free")
+        }
+    }
+}
+
+#[test]
+fn ipco_dedup() {
+    let mut ipco = IpcoBox::new();
+    let mut ipma = IpmaBox { entries: ArrayVec::new() };
+    // a 3x3 grid of same-sized tiles
+    for tile in 0..9 {
+        let ispe = ipco.push(IpcoProp::Ispe(IspeBox { width: 64, height: 64 }));
+        let av1c = ipco.push(IpcoProp::Av1C(Av1CBox {
+            seq_profile: 0,
+            seq_level_idx_0: 31,
+            seq_tier_0: false,
+            high_bitdepth: false,
+            twelve_bit: false,
+            monochrome: false,
+            chroma_subsampling_x: true,
+            chroma_subsampling_y: true,
+            chroma_sample_position: 0,
+            initial_presentation_delay_minus_one: None,
+            config_obus: &[],
+        }));
+        assert_eq!((1, 2), (ispe, av1c));
+        if tile < 2 {
+            ipma.entries.push(IpmaEntry { item_id: tile + 1, prop_ids: [ispe, av1c | 0x80].iter().copied().collect() });
+        }
+    }
+    let pixi = ipco.push(IpcoProp::Pixi(PixiBox { depths: ArrayVec::from([8; 3]) }));
+    assert_eq!(3, pixi);
+    assert_eq!(3, ipco.props.len());
+
+    let mut out = Vec::new();
+    let mut w = Writer::new(&mut out);
+    let _ = ipco.write(&mut w);
+    let _ = ipma.write(&mut w);
+    drop(w);
+    assert_eq!(1, out.windows(4).filter(|w| w == b"av1C").count());
+    assert_eq!(1, out.windows(4).filter(|w| w == b"ispe").count());
+    assert_eq!(&[0,1, 2, 1, 0x82, 0,2, 2, 1, 0x82], &out[out.len() - 10..]);
+}
+
+#[test]
+fn fourcc() {
+    let av01 = FourCC::try_from("av01").unwrap();
+    assert_eq!(FourCC::new(b"av01"), av01);
+    assert_eq!(FourCC(*b"av01"), av01);
+    assert_eq!("av01", av01.to_string());
+    assert_eq!("\"av01\"", format!("{:?}", av01));
+    assert_eq!("uri ", FourCC::new(b"uri ").to_string());
+    assert_eq!("a\\x00\\xff1", FourCC::new(b"a\0\xff1").to_string());
+
+    assert!(matches!(FourCC::try_from("av1"), Err(Error::InvalidFourCC(code)) if code == "av1"));
+    assert!(matches!(FourCC::try_from("av01 "), Err(Error::InvalidFourCC(code)) if code == "av01 "));
+    assert!(matches!(FourCC::try_from(""), Err(Error::InvalidFourCC(code)) if code.is_empty()));
+    assert_eq!("FourCC must have 4 bytes, not \"av1\"", FourCC::try_from("av1").unwrap_err().to_string());
+}
+
+#[test]
+fn hdlr_name() {
+    let mut out = Vec::new();
+    let _ = HdlrBox { handler_type: FourCC(*b"pict"), name: "Test" }.write(&mut Writer::new(&mut out));
+    assert_eq!(&[0,0,0,37, b'h',b'd',b'l',b'r', 0,0,0,0, 0,0,0,0, b'p',b'i',b'c',b't'][..], &out[..20]);
+    assert_eq!(&[0,0,0,0, 0,0,0,0, 0,0,0,0, b'T',b'e',b's',b't',0][..], &out[20..]);
+}
+
+#[test]
+fn auxc_subtype() {
+    let mut out = Vec::new();
+    let _ = AuxCBox { urn: "urn:x", aux_subtype: &[] }.write(&mut Writer::new(&mut out));
+    assert_eq!(&[0,0,0,18, b'a',b'u',b'x',b'C', 0,0,0,0, b'u',b'r',b'n',b':',b'x',0][..], &out[..]);
+
+    let mut out = Vec::new();
+    let auxc = AuxCBox { urn: "urn:x", aux_subtype: &[0xAB, 0xCD, 0] };
+    let _ = auxc.write(&mut Writer::new(&mut out));
+    assert_eq!(auxc.len(), out.len());
+    assert_eq!(&[0,0,0,21, b'a',b'u',b'x',b'C', 0,0,0,0, b'u',b'r',b'n',b':',b'x',0, 0xAB,0xCD,0][..], &out[..]);
+}
+
+#[test]
+fn iref_entries() {
+    let mut iref = IrefBox::default();
+    assert!(iref.is_empty());
+    iref.push(2, 1, FourCC(*b"auxl"));
+    iref.push(1, 2, FourCC(*b"prem"));
+    iref.push(2, 3, FourCC(*b"auxl"));
+    assert_eq!(2, iref.entries.len());
+
+    let mut out = Vec::new();
+    let _ = iref.write(&mut Writer::new(&mut out));
+    assert_eq!(iref.len(), out.len());
+    assert_eq!(&[0,0,0,42, b'i',b'r',b'e',b'f', 0,0,0,0][..], &out[..12]);
+    assert_eq!(&[0,0,0,16, b'a',b'u',b'x',b'l', 0,2, 0,2, 0,1, 0,3][..], &out[12..28]);
+    assert_eq!(&[0,0,0,14, b'p',b'r',b'e',b'm', 0,1, 0,1, 0,2][..], &out[28..]);
+}
+
+#[test]
+fn pixi_channels() {
+    let mut out = Vec::new();
+    let _ = PixiBox { depths: ArrayVec::from([10, 10, 10]) }.write(&mut Writer::new(&mut out));
+    assert_eq!(&[0,0,0,16, b'p',b'i',b'x',b'i', 0,0,0,0, 3, 10,10,10][..], &out[..]);
+
+    let mut out = Vec::new();
+    let _ = PixiBox { depths: [8].iter().copied().collect() }.write(&mut Writer::new(&mut out));
+    assert_eq!(&[0,0,0,14, b'p',b'i',b'x',b'i', 0,0,0,0, 1, 8][..], &out[..]);
+}
+
+#[test]
+fn essential_defaults() {
+    for &(fourcc, essential) in &[(b"av1C", true), (b"irot", true), (b"imir", true), (b"clap", true), (b"lsel", true), (b"colr", false), (b"pixi", false), (b"ispe", false)] {
+        assert_eq!(essential, essential_by_default(FourCC(*fourcc)), "{:?}", FourCC(*fourcc));
+    }
+    assert!(!IpcoProp::Colr(ColrBox::Nclx(Nclx::default())).essential());
+}
+
+#[test]
+fn colr_types() {
+    let mut out = Vec::new();
+    let nclx = ColrBox::Nclx(Nclx { full_range_flag: false, ..Nclx::default() });
+    let _ = nclx.write(&mut Writer::new(&mut out));
+    assert_eq!(nclx.len(), out.len());
+    assert_eq!(&[0,0,0,19, b'c',b'o',b'l',b'r', b'n',b'c',b'l',b'x', 0,1, 0,13, 0,6, 0][..], &out[..]);
+
+    let mut out = Vec::new();
+    let icc = ColrBox::Icc(&[1, 2, 3]);
+    let _ = icc.write(&mut Writer::new(&mut out));
+    assert_eq!(icc.len(), out.len());
+    assert_eq!(&[0,0,0,15, b'c',b'o',b'l',b'r', b'p',b'r',b'o',b'f', 1,2,3][..], &out[..]);
+}
+
+#[test]
+fn iloc_large() {
+    let mut extents = ArrayVec::new();
+    extents.push(IlocExtent { offset: IlocOffset::Absolute(5 << 32), len: 3 << 32 });
+    let mut items = ArrayVec::new();
+    items.push(IlocItem { id: 1, data_ref_index: 0, base_offset: 0, extents });
+    let iloc = IlocBox { items, large: true, version: 0, in_idat: false, base_offsets: false };
+    let mut out = Vec::new();
+    let _ = iloc.write(&mut Writer::new(&mut out));
+    assert_eq!(iloc.len(), out.len());
+    assert_eq!(&[0,0,0,38, b'i',b'l',b'o',b'c', 0,0,0,0, 0x88, 0, 0,1, 0,1, 0,0, 0,1][..], &out[..22]);
+    assert_eq!(&[0,0,0,5, 0,0,0,0, 0,0,0,3, 0,0,0,0][..], &out[22..]);
+}
+
+#[test]
+fn large_offsets_detected() {
+    let data = [0u8; 4];
+    let chunks = [DataChunk::Slice(&data)];
+    let mut extents = ArrayVec::new();
+    extents.push(IlocExtent { offset: IlocOffset::Relative(u32::MAX as usize), len: 4 });
+    let mut items = ArrayVec::new();
+    items.push(IlocItem { id: 1, data_ref_index: 0, base_offset: 0, extents });
+    let mut file = AvifFile {
+        ftyp: FtypBox { major_brand: FourCC(*b"avif"), minor_version: 0, compatible_brands: ArrayVec::new() },
+        meta: MetaBox {
+            hdlr: HdlrBox { handler_type: FourCC(*b"pict"), name: "" },
+            dinf: DinfBox::default(),
+            iloc: IlocBox { items, large: false, version: 0, in_idat: false, base_offsets: false },
+            iinf: IinfBox { items: ArrayVec::new() },
+            pitm: PitmBox(1),
+            iprp: IprpBox { ipco: IpcoBox::new(), ipma: IpmaBox { entries: ArrayVec::new() } },
+            iref: IrefBox::default(),
+            grpl: GrplBox::default(),
+            idat: None,
+        },
+        mdat: [MdatBox { data_chunks: &chunks, large: false }].iter().cloned().collect(),
+        trailing: ArrayVec::new(),
+        end_padding: None,
+        mdat_alignment: 1,
+        mdat_first: false,
+    };
+    file.fix_iloc_positions();
+    assert!(file.meta.iloc.large);
+    let start = file.mdat_payload_start_offset();
+    assert_eq!(IlocOffset::Absolute(u64::from(u32::MAX) + start), file.meta.iloc.items[0].extents[0].offset);
+}
+
+#[test]
+fn iloc_versions() {
+    let iloc = |version| {
+        let mut extents = ArrayVec::new();
+        extents.push(IlocExtent { offset: IlocOffset::Absolute(100), len: 5 });
+        let mut items = ArrayVec::new();
+        items.push(IlocItem { id: 1, data_ref_index: 0, base_offset: 0, extents });
+        let iloc = IlocBox { items, large: false, version, in_idat: false, base_offsets: false };
+        let mut out = Vec::new();
+        let _ = iloc.write(&mut Writer::new(&mut out));
+        assert_eq!(iloc.len(), out.len());
+        out
+    };
+    let extent = [0,0,0,100, 0,0,0,5];
+    assert_eq!([&[0,0,0,30, b'i',b'l',b'o',b'c', 0,0,0,0, 0x44,0, 0,1, 0,1, 0,0, 0,1][..], &extent].concat(), iloc(0));
+    assert_eq!([&[0,0,0,32, b'i',b'l',b'o',b'c', 1,0,0,0, 0x44,0, 0,1, 0,1, 0,0, 0,0, 0,1][..], &extent].concat(), iloc(1));
+    assert_eq!([&[0,0,0,36, b'i',b'l',b'o',b'c', 2,0,0,0, 0x44,0, 0,0,0,1, 0,0,0,1, 0,0, 0,0, 0,1][..], &extent].concat(), iloc(2));
+}
@@ -0,0 +1,123 @@
+//! Color information codes from ITU-T H.273 (CICP), used by the `nclx` color box and the AV1 bitstream.
+
+/// Chromaticity of the color primaries
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ColorPrimaries {
+    /// Rec.709 and sRGB
+    Bt709 = 1,
+    Unspecified = 2,
+    /// ITU-R BT.470 System M
+    Bt470m = 4,
+    /// ITU-R BT.470 System B, G (PAL)
+    Bt470bg = 5,
+    /// SMPTE 170M (NTSC)
+    Bt601 = 6,
+    Smpte240 = 7,
+    /// Generic film (color filters using illuminant C)
+    GenericFilm = 8,
+    /// Rec.2020 and Rec.2100
+    Bt2020 = 9,
+    /// SMPTE ST 428-1 (CIE 1931 XYZ)
+    Xyz = 10,
+    /// SMPTE RP 431-2 (DCI P3)
+    Smpte431 = 11,
+    /// SMPTE EG 432-1 (Display P3)
+    Smpte432 = 12,
+    /// EBU Tech. 3213-E
+    Ebu3213 = 22,
+}
+
+impl ColorPrimaries {
+    pub(crate) fn from_code(code: u16) -> Option<Self> {
+        [Self::Bt709, Self::Unspecified, Self::Bt470m, Self::Bt470bg, Self::Bt601, Self::Smpte240, Self::GenericFilm,
+            Self::Bt2020, Self::Xyz, Self::Smpte431, Self::Smpte432, Self::Ebu3213]
+            .iter().copied().find(|&c| c as u16 == code)
+    }
+}
+
+/// Opto-electronic transfer function (gamma)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TransferCharacteristics {
+    /// Rec.709
+    Bt709 = 1,
+    Unspecified = 2,
+    /// Gamma 2.2
+    Bt470m = 4,
+    /// Gamma 2.8
+    Bt470bg = 5,
+    /// SMPTE 170M, same as Rec.709
+    Bt601 = 6,
+    Smpte240 = 7,
+    /// Linear light
+    Linear = 8,
+    /// Logarithmic (100:1 range)
+    Log = 9,
+    /// Logarithmic (316.22777:1 range)
+    LogSqrt = 10,
+    /// IEC 61966-2-4 (xvYCC)
+    Iec61966 = 11,
+    /// ITU-R BT.1361 extended color gamut
+    Bt1361 = 12,
+    /// sRGB
+    Srgb = 13,
+    /// Rec.2020 10-bit, same as Rec.709
+    Bt2020_10 = 14,
+    /// Rec.2020 12-bit, same as Rec.709
+    Bt2020_12 = 15,
+    /// SMPTE ST 2084 (Perceptual Quantizer, PQ) for HDR
+    Smpte2084 = 16,
+    /// SMPTE ST 428-1
+    Smpte428 = 17,
+    /// ARIB STD-B67 (Hybrid Log-Gamma, HLG) for HDR
+    Hlg = 18,
+}
+
+impl TransferCharacteristics {
+    pub(crate) fn from_code(code: u16) -> Option<Self> {
+        [Self::Bt709, Self::Unspecified, Self::Bt470m, Self::Bt470bg, Self::Bt601, Self::Smpte240, Self::Linear, Self::Log,
+            Self::LogSqrt, Self::Iec61966, Self::Bt1361, Self::Srgb, Self::Bt2020_10, Self::Bt2020_12, Self::Smpte2084, Self::Smpte428, Self::Hlg]
+            .iter().copied().find(|&c| c as u16 == code)
+    }
+}
+
+/// Conversion from RGB to the encoded color channels (YUV)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MatrixCoefficients {
+    /// The channels are RGB as-is (in GBR order). Requires 4:4:4 chroma.
+    Identity = 0,
+    /// Rec.709
+    Bt709 = 1,
+    Unspecified = 2,
+    /// US FCC 73.628
+    Fcc = 4,
+    /// ITU-R BT.470 System B, G (PAL)
+    Bt470bg = 5,
+    /// SMPTE 170M (the usual JPEG-like YUV)
+    Bt601 = 6,
+    Smpte240 = 7,
+    /// YCgCo
+    Ycgco = 8,
+    /// Rec.2020 non-constant luminance
+    Bt2020Ncl = 9,
+    /// Rec.2020 constant luminance
+    Bt2020Cl = 10,
+    /// SMPTE ST 2085 (Y'D'zD'x)
+    Smpte2085 = 11,
+    /// Chromaticity-derived non-constant luminance
+    ChromatNcl = 12,
+    /// Chromaticity-derived constant luminance
+    ChromatCl = 13,
+    /// ITU-R BT.2100 ICtCp
+    Ictcp = 14,
+}
+
+impl MatrixCoefficients {
+    pub(crate) fn from_code(code: u16) -> Option<Self> {
+        [Self::Identity, Self::Bt709, Self::Unspecified, Self::Fcc, Self::Bt470bg, Self::Bt601, Self::Smpte240, Self::Ycgco,
+            Self::Bt2020Ncl, Self::Bt2020Cl, Self::Smpte2085, Self::ChromatNcl, Self::ChromatCl, Self::Ictcp]
+            .iter().copied().find(|&c| c as u16 == code)
+    }
+}
@@ -0,0 +1,353 @@
+use crate::ChromaSubsampling;
+use crate::FourCC;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// The AV1 data doesn't match the image properties given to [`Aviffy`](crate::Aviffy).
+///
+/// Returned wrapped in `io::Error` (or as [`Error::MismatchedBitstream`]) when [`Aviffy::validate_bitstream`](crate::Aviffy::validate_bitstream) is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BitstreamMismatch {
+    Width { configured: u32, bitstream: u32 },
+    Height { configured: u32, bitstream: u32 },
+    Depth { configured: u8, bitstream: u8 },
+    Monochrome { configured: bool, bitstream: bool },
+    ChromaSubsampling { configured: ChromaSubsampling, bitstream: ChromaSubsampling },
+    /// The alpha image must have the same size as the color image
+    AlphaSize { configured: (u32, u32), bitstream: (u32, u32) },
+    /// The alpha image must be monochrome (4:0:0)
+    AlphaHasColor,
+    /// The AV1 data has chroma subsampled only vertically, which AV1 doesn't allow
+    InvalidSubsampling,
+}
+
+impl fmt::Display for BitstreamMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Width { configured, bitstream } => write!(f, "Image width is {}, but the AV1 frame is {} pixels wide", configured, bitstream),
+            Self::Height { configured, bitstream } => write!(f, "Image height is {}, but the AV1 frame is {} pixels high", configured, bitstream),
+            Self::Depth { configured, bitstream } => write!(f, "Image depth is {} bits, but the AV1 data is {}-bit", configured, bitstream),
+            Self::Monochrome { configured: true, .. } => f.write_str("Image is monochrome, but the AV1 data has color"),
+            Self::Monochrome { configured: false, .. } => f.write_str("Image has color, but the AV1 data is monochrome"),
+            Self::ChromaSubsampling { configured, bitstream } => write!(f, "Image chroma subsampling is {:?}, but the AV1 data is {:?}", configured, bitstream),
+            Self::AlphaSize { configured: (width, height), bitstream: (alpha_width, alpha_height) } => write!(f, "Image size is {}x{}, but the AV1 alpha frame is {}x{}", width, height, alpha_width, alpha_height),
+            Self::AlphaHasColor => f.write_str("Alpha must be monochrome, but the AV1 alpha data has color"),
+            Self::InvalidSubsampling => f.write_str("Invalid chroma subsampling in AV1 data"),
+        }
+    }
+}
+
+impl StdError for BitstreamMismatch {}
+
+impl From<BitstreamMismatch> for io::Error {
+    fn from(err: BitstreamMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The AV1 data or the `av1C` record is malformed, or uses syntax that this crate can't read.
+///
+/// Returned wrapped in `io::Error` (or as [`Error::InvalidAv1Data`]) by options that read the AV1 data, such as [`Aviffy::validate_bitstream`](crate::Aviffy::validate_bitstream),
+/// and by [`Av1Config::from_record`](crate::Av1Config::from_record).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InvalidAv1Data {
+    /// The data ends where an OBU should start
+    MissingObu,
+    /// The forbidden bit of an OBU header is set
+    ObuForbiddenBit,
+    /// The reserved bit of the first OBU header is set
+    ObuReservedBit,
+    /// The data ends in the middle of an OBU header
+    TruncatedObuHeader,
+    /// `obu_size` is longer than 8 bytes
+    BadObuSize,
+    /// `obu_size` is larger than the rest of the data
+    ObuTooLarge,
+    /// The data is an IVF file, but it must be just the OBUs of the frame
+    Ivf,
+    /// The data must start with a temporal delimiter or a sequence header OBU, not this `obu_type`
+    BadFirstObu(u8),
+    /// A sequence or frame header ends before all of its fields
+    TruncatedHeader,
+    /// There's no sequence header OBU
+    NoSequenceHeader,
+    /// The sequence header OBU has no `obu_size`, which `av1C` requires
+    SequenceHeaderWithoutSize,
+    /// `seq_profile` of the sequence header is above 2
+    SequenceProfile(u8),
+    /// There's no frame header OBU
+    NoFrameHeader,
+    /// The first frame is `show_existing_frame`
+    ShowExistingFrame,
+    /// The first frame isn't a key frame or an intra-only frame
+    NotIntraFrame,
+    /// The `av1C` record is shorter than 4 bytes
+    RecordTooShort,
+    /// The first byte of the `av1C` record (marker and version) isn't 0x81
+    RecordVersion(u8),
+    /// The `av1C` record has `twelve_bit` without `high_bitdepth`
+    RecordTwelveBit,
+    /// The `av1C` record has subsampling that AV1 doesn't allow, or monochrome that isn't 4:2:0
+    RecordSubsampling,
+    /// The `av1C` record has the reserved chroma sample position 3
+    RecordChromaSamplePosition,
+    /// The reserved bits of the `av1C` record are set
+    RecordReservedBits,
+}
+
+impl fmt::Display for InvalidAv1Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingObu => f.write_str("Missing OBU header"),
+            Self::ObuForbiddenBit => f.write_str("OBU forbidden bit is set"),
+            Self::ObuReservedBit => f.write_str("OBU reserved bit is set"),
+            Self::TruncatedObuHeader => f.write_str("Truncated OBU header"),
+            Self::BadObuSize => f.write_str("Bad OBU size"),
+            Self::ObuTooLarge => f.write_str("OBU is larger than the data"),
+            Self::Ivf => f.write_str("AV1 data is in an IVF container, but must be just the OBUs of the frame"),
+            Self::BadFirstObu(obu_type) => write!(f, "AV1 data must start with a temporal delimiter or sequence header OBU, not OBU type {}", obu_type),
+            Self::TruncatedHeader => f.write_str("Truncated AV1 header"),
+            Self::NoSequenceHeader => f.write_str("AV1 data has no sequence header"),
+            Self::SequenceHeaderWithoutSize => f.write_str("Sequence header OBU has no size field"),
+            Self::SequenceProfile(seq_profile) => write!(f, "Unsupported AV1 seq_profile {}", seq_profile),
+            Self::NoFrameHeader => f.write_str("AV1 data has no frame header"),
+            Self::ShowExistingFrame => f.write_str("The first frame is show_existing_frame"),
+            Self::NotIntraFrame => f.write_str("The first frame is not an intra frame"),
+            Self::RecordTooShort => f.write_str("av1C record is too short"),
+            Self::RecordVersion(marker_version) => write!(f, "av1C record has a bad marker or unsupported version ({:#04x})", marker_version),
+            Self::RecordTwelveBit => f.write_str("av1C record has twelve_bit without high_bitdepth"),
+            Self::RecordSubsampling => f.write_str("av1C record has invalid chroma subsampling"),
+            Self::RecordChromaSamplePosition => f.write_str("av1C record has reserved chroma sample position"),
+            Self::RecordReservedBits => f.write_str("av1C record has reserved bits set"),
+        }
+    }
+}
+
+impl StdError for InvalidAv1Data {}
+
+impl From<InvalidAv1Data> for io::Error {
+    fn from(err: InvalidAv1Data) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Why a file couldn't be made.
+///
+/// Returned by [`Aviffy::write`](crate::Aviffy::write) and the other methods that make or read files, and by [`Av1Config::new`](crate::Av1Config::new).
+/// [`serialize`](crate::serialize) and `io::Write` implementations return it wrapped in `io::Error`
+/// (with [`io::ErrorKind::InvalidInput`] if the arguments or settings are invalid, and [`io::ErrorKind::InvalidData`] if the AV1 data or the file is).
+/// `Error::from` unwraps it again, and [`Error::from_io_error`] gets it by reference.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Writing the output has failed, or another error (such as of streamed image data, or of an [`Av1Encoder`](crate::still::Av1Encoder)) is passed through
+    Io(io::Error),
+    /// The AV1 data doesn't match the image properties, see [`Aviffy::validate_bitstream`](crate::Aviffy::validate_bitstream)
+    MismatchedBitstream(BitstreamMismatch),
+    /// The AV1 data or the `av1C` record can't be read
+    InvalidAv1Data(InvalidAv1Data),
+    /// AV1 supports only 8, 10 or 12 bits per channel
+    InvalidDepth(u8),
+    /// The given `av1C` has a different bit depth than the image
+    Av1CDepth { av1c: u8, depth_bits: u8 },
+    /// No AV1 profile supports this subsampling at this depth
+    UnsupportedSubsampling { subsampling: ChromaSubsampling, depth_bits: u8 },
+    /// `seq_profile` is above 2
+    InvalidProfile(u8),
+    /// [`Av1Config::new`](crate::Av1Config::new) got a `seq_profile` that isn't the one AV1 requires for the depth and subsampling,
+    /// or [`Aviffy::av1_seq_profile`](crate::Aviffy::av1_seq_profile) is lower than that
+    ProfileMismatch { seq_profile: u8, expected: u8 },
+    /// `seq_level_idx` is above 31
+    InvalidLevel(u8),
+    /// High tier is only available from level 4.0
+    NoHighTier(u8),
+    /// Chroma sample position can only be set for 4:2:0 color images
+    ChromaSamplePositionSubsampling { subsampling: ChromaSubsampling, monochrome: bool },
+    /// `MatrixCoefficients::Identity` requires 4:4:4 chroma
+    IdentityMatrixSubsampling(ChromaSubsampling),
+    /// AV1 doesn't allow `MatrixCoefficients::Identity` for monochrome images
+    IdentityMatrixMonochrome,
+    /// AV1 always uses full range for `MatrixCoefficients::Identity` with BT.709 primaries and sRGB transfer
+    SrgbLimitedRange,
+    /// Subsampled chroma requires even dimensions, see [`Aviffy::allow_odd_dimensions`](crate::Aviffy::allow_odd_dimensions)
+    OddDimensions { subsampling: ChromaSubsampling, width: u32, height: u32 },
+    /// `initial_presentation_delay` must be 1-16 frames
+    InitialPresentationDelay(u8),
+    /// Crop is empty or larger than the image
+    InvalidCrop { width: u32, height: u32, crop_width: u32, crop_height: u32 },
+    /// [`Aviffy::encoded_size`](crate::Aviffy::encoded_size) and [`Aviffy::crop_to`](crate::Aviffy::crop_to) are both set
+    EncodedSizeWithCrop,
+    /// `mdat` alignment of 0
+    ZeroAlignment,
+    /// [`Aviffy::append_box`](crate::Aviffy::append_box) can't add boxes that the file already has
+    ReservedBox(FourCC),
+    /// Image data can have at most 16 pieces
+    TooManyPieces,
+    /// The length of the data doesn't fit in `usize`
+    PayloadTooLarge(u64),
+    /// Image data pieces can't be empty
+    EmptyPiece,
+    /// [`Aviffy::alpha_dimensions`](crate::Aviffy::alpha_dimensions) can't be different from the color size when cropping
+    AlphaDimensionsWithCrop,
+    /// Alpha urn can't contain NUL bytes
+    InvalidUrn,
+    /// There's no color image data
+    EmptyColor,
+    /// Alpha is given, but it has no data
+    EmptyAlpha,
+    /// [`Aviffy::depth_map`](crate::Aviffy::depth_map) is set, but it has no data
+    EmptyDepth,
+    /// [`IlocVersion::V0`](crate::IlocVersion::V0) can't point to data in `idat`, see [`Aviffy::idat_threshold`](crate::Aviffy::idat_threshold)
+    IdatIlocVersion,
+    /// The `ftyp` major brand must be `avif` or `avis`
+    UnsupportedBrand(FourCC),
+    /// [`Aviffy::write_streaming`](crate::Aviffy::write_streaming) and [`Aviffy::serialized_len`](crate::Aviffy::serialized_len) can't be used with options that read the AV1 data
+    StreamedBitstream,
+    /// Metadata items need data (or a URL without NUL bytes, and a length), and a content type without NUL bytes if (and only if) they're `mime`
+    InvalidMetadata,
+    /// [`encode_still`](crate::still::encode_still) needs exactly `width * height` pixels
+    PixelCount { width: u32, height: u32, pixels: usize },
+    /// The file is larger than [`Aviffy::pad_to_size`](crate::Aviffy::pad_to_size), or too close to it to fit a `free` box
+    PadToSize { target_len: u64, content_len: u64 },
+    /// [`Reservation::finish`](crate::Reservation::finish) got more data than [`Aviffy::reserve`](crate::Aviffy::reserve) left space for
+    ExceedsReservation { reserved: u64, len: u64 },
+    /// [`Aviffy::output_depth_bits`](crate::Aviffy::output_depth_bits) is over 16 or 0, or different from the AV1 depth without [`Aviffy::allow_output_depth_mismatch`](crate::Aviffy::allow_output_depth_mismatch)
+    OutputDepth { output_depth_bits: u8, depth_bits: u8 },
+    /// Width or height of an image is 0, or larger than AV1 allows (65536)
+    InvalidDimensions { width: u32, height: u32 },
+    /// Too many alternative images, metadata items or appended boxes have been added
+    TooManyItems { what: &'static str, max: usize },
+    /// A streamed image has ended before its length, or [`PendingPayload::finish`](crate::PendingPayload::finish) is missing this many bytes of the data
+    MissingData(u64),
+    /// A streamed image has more data than its length
+    ExcessData(u64),
+    /// The `rav1e` `EncoderConfig` given to `Aviffy::write_rav1e_packets` has a width or height that doesn't fit in `u32`
+    EncoderDimensions { width: usize, height: usize },
+    /// The `rav1e` `EncoderConfig` given to `Aviffy::write_rav1e_packets` has a bit depth that doesn't fit in `u8`
+    EncoderDepth(usize),
+    /// [`FourCC::try_from`](crate::FourCC) got a string that isn't 4 bytes long
+    InvalidFourCC(String),
+    /// [`Aviffy::from_existing`](crate::Aviffy::from_existing) can't keep everything this file has (or it's not an AVIF image)
+    Unsupported(&'static str),
+    /// [`Aviffy::from_existing`](crate::Aviffy::from_existing) got a file that isn't valid HEIF
+    Malformed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::MismatchedBitstream(err) => err.fmt(f),
+            Self::InvalidAv1Data(err) => err.fmt(f),
+            Self::InvalidDepth(depth_bits) => write!(f, "AV1 supports only 8, 10 or 12 bits per channel, not {}", depth_bits),
+            Self::Av1CDepth { av1c, depth_bits } => write!(f, "av1C is {}-bit, but depth_bits is {}", av1c, depth_bits),
+            Self::UnsupportedSubsampling { subsampling, depth_bits } => write!(f, "AV1 can't encode {:?} at {} bits per channel", subsampling, depth_bits),
+            Self::InvalidProfile(seq_profile) => write!(f, "Invalid AV1 seq_profile {}", seq_profile),
+            Self::ProfileMismatch { seq_profile, expected } => write!(f, "AV1 requires seq_profile {} for this depth and subsampling, not {}", expected, seq_profile),
+            Self::InvalidLevel(seq_level_idx) => write!(f, "Invalid AV1 seq_level_idx {}", seq_level_idx),
+            Self::NoHighTier(seq_level_idx) => write!(f, "AV1 level seq_level_idx={} has no high tier", seq_level_idx),
+            Self::ChromaSamplePositionSubsampling { monochrome: true, .. } => f.write_str("Chroma sample position is only for 4:2:0 color, not monochrome"),
+            Self::ChromaSamplePositionSubsampling { subsampling, .. } => write!(f, "Chroma sample position is only for 4:2:0 color, not {:?}", subsampling),
+            Self::IdentityMatrixSubsampling(subsampling) => write!(f, "Identity matrix coefficients require 4:4:4 chroma, not {:?}", subsampling),
+            Self::IdentityMatrixMonochrome => f.write_str("Identity matrix coefficients can't be used for monochrome images"),
+            Self::SrgbLimitedRange => f.write_str("sRGB with identity matrix coefficients must be full range"),
+            Self::OddDimensions { subsampling, width, height } => write!(f, "{:?} chroma requires even dimensions, but the image is {}x{}", subsampling, width, height),
+            Self::InitialPresentationDelay(frames) => write!(f, "initial_presentation_delay must be 1-16 frames, not {}", frames),
+            Self::InvalidCrop { width, height, crop_width, crop_height } => write!(f, "Can't crop {}x{} image to {}x{}", width, height, crop_width, crop_height),
+            Self::EncodedSizeWithCrop => f.write_str("encoded_size and crop_to can't be used together"),
+            Self::ZeroAlignment => f.write_str("mdat alignment can't be 0"),
+            Self::ReservedBox(typ) => write!(f, "Can't append another {:?} box", typ),
+            Self::TooManyPieces => write!(f, "Image data can have at most {} pieces", crate::boxes::MAX_EXTENTS),
+            Self::PayloadTooLarge(len) => write!(f, "{} bytes of data is too large for this platform", len),
+            Self::EmptyPiece => f.write_str("Image data pieces can't be empty"),
+            Self::AlphaDimensionsWithCrop => f.write_str("Can't crop an alpha image of a different size"),
+            Self::InvalidUrn => f.write_str("Alpha urn can't contain NUL bytes"),
+            Self::EmptyColor => f.write_str("Color image data is empty"),
+            Self::EmptyAlpha => f.write_str("Alpha image data is empty"),
+            Self::EmptyDepth => f.write_str("Depth image data is empty"),
+            Self::IdatIlocVersion => f.write_str("iloc version 0 can't store data in idat"),
+            Self::UnsupportedBrand(brand) => write!(f, "{:?} is not an AVIF brand", brand),
+            Self::StreamedBitstream => f.write_str("Can't read the AV1 bitstream without the data"),
+            Self::InvalidMetadata => f.write_str("Metadata item is empty or has an invalid content type"),
+            Self::PixelCount { width, height, pixels } => write!(f, "Image is {}x{}, but there are {} pixels", width, height, pixels),
+            Self::PadToSize { target_len, content_len } => write!(f, "The file is {} bytes, so it can't be padded to {} bytes", content_len, target_len),
+            Self::ExceedsReservation { reserved, len } => write!(f, "The image data is {} bytes, but only {} bytes have been reserved", len, reserved),
+            Self::OutputDepth { output_depth_bits, depth_bits } => write!(f, "Output depth is {} bits, but the AV1 data is {}-bit", output_depth_bits, depth_bits),
+            Self::InvalidDimensions { width, height } => write!(f, "Image size {}x{} is invalid, AV1 images must be 1-{} pixels wide and high", width, height, crate::level::MAX_DIMENSION),
+            Self::TooManyItems { what, max } => write!(f, "At most {} {} can be added", max, what),
+            Self::MissingData(missing) => write!(f, "{} bytes of the image data are missing", missing),
+            Self::ExcessData(len) => write!(f, "The image data is longer than {} bytes", len),
+            Self::EncoderDimensions { width, height } => write!(f, "Image size {}x{} is invalid, AV1 images must be 1-{} pixels wide and high", width, height, crate::level::MAX_DIMENSION),
+            Self::EncoderDepth(depth_bits) => write!(f, "AV1 supports only 8, 10 or 12 bits per channel, not {}", depth_bits),
+            Self::InvalidFourCC(code) => write!(f, "FourCC must have 4 bytes, not {:?}", code),
+            Self::Unsupported(msg) | Self::Malformed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error {
+    /// The invalid argument or setting in an `io::Error` returned by this crate, or `None` if it's an error of the output or the AV1 data.
+    ///
+    /// It's never [`Error::Io`], [`Error::MismatchedBitstream`] or [`Error::InvalidAv1Data`]. Use `Error::from` to get those too.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::MismatchedBitstream(err) => Some(err),
+            Self::InvalidAv1Data(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<BitstreamMismatch> for Error {
+    fn from(err: BitstreamMismatch) -> Self {
+        Self::MismatchedBitstream(err)
+    }
+}
+
+impl From<InvalidAv1Data> for Error {
+    fn from(err: InvalidAv1Data) -> Self {
+        Self::InvalidAv1Data(err)
+    }
+}
+
+/// Unwraps the errors of this crate that have been returned as `io::Error`
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        let is_ours = matches!(err.get_ref(), Some(inner) if inner.is::<Self>() || inner.is::<BitstreamMismatch>() || inner.is::<InvalidAv1Data>());
+        if !is_ours {
+            return Self::Io(err);
+        }
+        let inner = err.into_inner().expect("checked above");
+        let inner = match inner.downcast::<Self>() {
+            Ok(err) => return *err,
+            Err(inner) => inner,
+        };
+        match inner.downcast::<BitstreamMismatch>() {
+            Ok(err) => Self::MismatchedBitstream(*err),
+            Err(inner) => Self::InvalidAv1Data(*inner.downcast().expect("checked above")),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::MismatchedBitstream(err) => err.into(),
+            Error::InvalidAv1Data(err) => err.into(),
+            err @ Error::MissingData(_) => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err @ (Error::Unsupported(_) | Error::Malformed(_)) => io::Error::new(io::ErrorKind::InvalidData, err),
+            err => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
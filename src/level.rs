@@ -0,0 +1,56 @@
+//! AV1 levels (Annex A.3 of the AV1 spec)
+
+/// `seq_level_idx` meaning "no level restrictions". All decoders must accept it, but some hardware decoders refuse to even try.
+pub const LEVEL_MAX: u8 = 31;
+
+/// Sequence headers have at most 16 bits for `max_frame_width_minus_1` and `max_frame_height_minus_1`, at any level
+pub const MAX_DIMENSION: u32 = 1 << 16;
+
+/// The lowest level that has a high tier
+pub const LEVEL_4_0: u8 = 8;
+
+/// `(seq_level_idx, MaxPicSize, MaxHSize, MaxVSize)` of the defined levels.
+///
+/// Levels 2.2, 2.3, 3.2, 3.3, 4.2, 4.3 and 7.x are not defined. Levels within 4.x, 5.x and 6.x
+/// differ only by decoding speed and bitrate, which don't matter for a single still image.
+const LEVELS: [(u8, u32, u32, u32); 7] = [
+    (0, 147_456, 2048, 1152),     // 2.0
+    (1, 278_784, 2816, 1584),     // 2.1
+    (4, 665_856, 4352, 2448),     // 3.0
+    (5, 1_065_024, 5504, 3096),   // 3.1
+    (8, 2_359_296, 6144, 3456),   // 4.0
+    (12, 8_912_896, 8192, 4352),  // 5.0
+    (16, 35_651_584, 16384, 8704), // 6.0
+];
+
+/// The smallest level that allows a frame of this size
+pub fn seq_level_idx_for_size(width: u32, height: u32) -> u8 {
+    LEVELS.iter()
+        .find(|&&(_, max_pic_size, max_h_size, max_v_size)| {
+            width <= max_h_size && height <= max_v_size && u64::from(width) * u64::from(height) <= u64::from(max_pic_size)
+        })
+        .map_or(LEVEL_MAX, |&(idx, ..)| idx)
+}
+
+#[test]
+fn level_boundaries() {
+    assert_eq!(0, seq_level_idx_for_size(1, 1));
+    assert_eq!(0, seq_level_idx_for_size(2048, 72));
+    assert_eq!(0, seq_level_idx_for_size(426, 240));
+    assert_eq!(1, seq_level_idx_for_size(2049, 1));
+    assert_eq!(1, seq_level_idx_for_size(640, 360));
+    assert_eq!(4, seq_level_idx_for_size(1280, 520));
+    assert_eq!(5, seq_level_idx_for_size(1280, 720));
+    assert_eq!(8, seq_level_idx_for_size(1920, 1080));
+    assert_eq!(8, seq_level_idx_for_size(2048, 1152));
+    assert_eq!(12, seq_level_idx_for_size(2560, 1440));
+    assert_eq!(12, seq_level_idx_for_size(4096, 2176));
+    assert_eq!(16, seq_level_idx_for_size(4097, 2176));
+    assert_eq!(16, seq_level_idx_for_size(4096, 2177));
+    assert_eq!(16, seq_level_idx_for_size(8192, 4352));
+    assert_eq!(16, seq_level_idx_for_size(1, 8193));
+    assert_eq!(16, seq_level_idx_for_size(16384, 2176));
+    assert_eq!(LEVEL_MAX, seq_level_idx_for_size(16385, 1));
+    assert_eq!(LEVEL_MAX, seq_level_idx_for_size(8192, 8192));
+    assert_eq!(LEVEL_MAX, seq_level_idx_for_size(u32::MAX, u32::MAX));
+}
@@ -4,22 +4,364 @@
 //!
 //! 1. Compress pixels using an AV1 encoder, such as [rav1e](//lib.rs/rav1e). [libaom](//lib.rs/libaom-sys) works too.
 //!
-//! 2. Call `avif_serialize::serialize_to_vec(av1_data, None, width, height, 8)`
+//! 2. Call `avif_serialize::Aviffy::new().try_to_vec(av1_data, None, width, height, 8)`
+//!
+//! Alternatively, wrap the encoder in the [`still::Av1Encoder`] trait, and call [`still::encode_still`] to do both steps at once.
 //!
 //! See [cavif](https://github.com/kornelski/cavif-rs) for a complete implementation.
 
+#[cfg(feature = "async")]
+mod async_write;
 mod boxes;
+pub mod constants;
+mod error;
+mod level;
+mod obu;
+#[cfg(feature = "rav1e")]
+mod packets;
+mod reader;
+pub mod still;
 mod writer;
 
 use crate::boxes::*;
+use crate::constants::{ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
+use crate::obu::SequenceHeader;
 use arrayvec::ArrayVec;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
 use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub use crate::boxes::BoxInfo;
+pub use crate::boxes::FourCC;
+pub use crate::error::BitstreamMismatch;
+pub use crate::error::Error;
+pub use crate::error::InvalidAv1Data;
+pub use crate::reader::ExistingAvif;
 
 /// Config for the serialization (allows setting advanced image properties).
 ///
 /// See [`Aviffy::new`].
+///
+/// It's `Send` and `Sync`, and writing only borrows it, so one config can be shared by many threads writing files at the same time.
 pub struct Aviffy {
-    premultiplied_alpha: bool,
+    alpha_mode: AlphaMode,
+    hidden_alpha: bool,
+    alpha_colr: bool,
+    alpha_depth_bits: Option<u8>,
+    data_order: DataOrder,
+    alpha_urn: AlphaUrn,
+    alpha_dimensions: Option<(u32, u32)>,
+    strict_av1c: bool,
+    derive_av1c_from_bitstream: bool,
+    validate_bitstream: bool,
+    check_obu_start: bool,
+    seq_profile: Option<u8>,
+    seq_level_idx: Option<u8>,
+    seq_tier: bool,
+    alpha_seq_level_idx: Option<u8>,
+    alpha_seq_tier: Option<bool>,
+    av1c: Option<Av1Config>,
+    alpha_av1c: Option<Av1Config>,
+    embed_sequence_header: bool,
+    strip_temporal_delimiters: bool,
+    trim_trailing_obus: bool,
+    tile_group_extents: bool,
+    initial_presentation_delay: Option<u8>,
+    chroma_subsampling: ChromaSubsampling,
+    monochrome: bool,
+    allow_odd_dimensions: bool,
+    write_pixi: bool,
+    output_depth_bits: Option<u8>,
+    allow_output_depth_mismatch: bool,
+    major_brand: FourCC,
+    minor_version: u32,
+    mdat_alignment: u32,
+    layout: Layout,
+    separate_other_images: bool,
+    separate_metadata: bool,
+    idat_threshold: usize,
+    large_mdat: bool,
+    large_offsets: bool,
+    iloc_base_offsets: bool,
+    iloc_version: IlocVersion,
+    essential_properties: Vec<(FourCC, bool)>,
+    chroma_sample_position: ChromaSamplePosition,
+    nclx: Nclx,
+    icc_profile: Option<Vec<u8>>,
+    crop: Option<(u32, u32)>,
+    layer_id: Option<u16>,
+    encoded_size: Option<(u32, u32)>,
+    trailing_boxes: Vec<(FourCC, Vec<u8>)>,
+    target_len: Option<u64>,
+    alternatives: Vec<(Vec<u8>, u32, u32)>,
+    group_alternatives: bool,
+    right_eye: Option<Vec<u8>>,
+    depth: Option<Vec<u8>>,
+    metadata: Vec<(FourCC, String, ItemData)>,
+}
+
+// Sharing the config between threads is part of the API
+fn _assert_send_sync() where Aviffy: Send + Sync {}
+
+/// Where a metadata item's data is
+#[derive(Debug, Clone, PartialEq)]
+enum ItemData {
+    Embedded(Vec<u8>),
+    /// In another file, with the length it's declared to have
+    External { url: String, len: u64 },
+}
+
+/// Byte ranges of the image data in the file.
+///
+/// See [`Aviffy::data_extents`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataExtents {
+    pub color: Vec<Range<u64>>,
+    pub alpha: Option<Vec<Range<u64>>>,
+}
+
+/// How the chroma channels of the color image have been subsampled by the AV1 encoder.
+///
+/// See [`Aviffy::chroma_subsampling`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// No subsampling (`Cs444` in `rav1e`). This is the default.
+    Cs444,
+    /// Half horizontal resolution of chroma (`Cs422` in `rav1e`)
+    Cs422,
+    /// Half horizontal and half vertical resolution of chroma (`Cs420` in `rav1e`)
+    Cs420,
+}
+
+/// How the color channels relate to the alpha channel.
+///
+/// See [`Aviffy::alpha_mode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Unassociated alpha: the color channels are independent of alpha. This is the default.
+    ///
+    /// This is signalled by the absence of the `prem` item reference.
+    Straight,
+    /// Associated alpha: the color channels have been multiplied by alpha, so transparent areas are black.
+    ///
+    /// This is signalled by the `prem` item reference from the color image to the alpha image.
+    Premultiplied,
+}
+
+/// Identifier of the alpha channel type in the `auxC` property.
+///
+/// See [`Aviffy::alpha_urn`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AlphaUrn {
+    /// `urn:mpeg:mpegB:cicp:systems:auxiliary:alpha`, required by AVIF and MIAF. This is the default.
+    Cicp,
+    /// `urn:mpeg:hevc:2015:auxid:1`, used by HEIF files with HEVC
+    Hevc,
+    /// Any other identifier. It can't contain NUL bytes.
+    Custom(String),
+}
+
+impl AlphaUrn {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Cicp => "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
+            Self::Hevc => "urn:mpeg:hevc:2015:auxid:1",
+            Self::Custom(urn) => urn,
+        }
+    }
+}
+
+/// Order of the color and alpha data in the file.
+///
+/// See [`Aviffy::data_order`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataOrder {
+    /// Alpha comes first, so that a partially loaded file can be displayed with its transparency. This is the default.
+    AlphaFirst,
+    /// Color comes first, so that a partially loaded file can show the color image sooner.
+    ColorFirst,
+}
+
+/// Order of the top-level boxes in the file.
+///
+/// See [`Aviffy::layout`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Layout {
+    /// `ftyp`, `meta`, then `mdat`, so that readers can find the images before downloading their data. This is the default.
+    MetaFirst,
+    /// `ftyp`, `mdat`, then `meta`, for tools that want the image data early in the file
+    MdatFirst,
+}
+
+/// Data that can be put in its own `mdat` box, after the data of the primary image.
+///
+/// See [`Aviffy::separate_mdat`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MdatGroup {
+    /// Alternative images, the other eye of a stereo pair, and the depth map
+    OtherImages,
+    /// Items added with [`Aviffy::add_metadata`]
+    Metadata,
+}
+
+/// Version of the `iloc` box.
+///
+/// See [`Aviffy::iloc_version`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IlocVersion {
+    /// The oldest version that can describe the file. This is the default.
+    Auto,
+    /// The original version, understood by all readers
+    V0,
+    /// Adds `construction_method`, for data stored in `idat` or in other items
+    V1,
+    /// Adds 32-bit item ids
+    V2,
+}
+
+/// Where the chroma samples of a 4:2:0 image are located relative to luma samples.
+///
+/// See [`Aviffy::chroma_sample_position`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChromaSamplePosition {
+    /// Not specified. This is the default.
+    Unknown = 0,
+    /// Horizontally co-located with the top-left luma sample, vertically between two luma rows (like MPEG-2 video)
+    Vertical = 1,
+    /// Co-located with the top-left luma sample (like BT.2020)
+    Colocated = 2,
+}
+
+/// AV1 codec configuration record (the `av1C` property), for [`Aviffy::av1c`] and [`Aviffy::alpha_av1c`].
+///
+/// It's checked on construction to be a combination of fields that AV1 allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Av1Config {
+    av1c: Av1CBox<'static>,
+    config_obus: Vec<u8>,
+}
+
+impl Av1Config {
+    /// `seq_profile` must be the profile AV1 requires for the depth and subsampling (see [`Aviffy::chroma_subsampling`]).
+    /// `seq_level_idx_0` is 0-31, and `seq_tier_0` (high tier) can be set only for levels 4.0 (8) and up.
+    ///
+    /// `bit_depth` is 8, 10 or 12. `monochrome` overrides the `subsampling`, and `chroma_sample_position` can
+    /// only be given for color 4:2:0 images.
+    pub fn new(seq_profile: u8, seq_level_idx_0: u8, seq_tier_0: bool, bit_depth: u8, subsampling: ChromaSubsampling, monochrome: bool, chroma_sample_position: ChromaSamplePosition) -> Result<Self, Error> {
+        let subsampling = if monochrome { ChromaSubsampling::Cs420 } else { subsampling };
+        match subsampling.av1_seq_profile(bit_depth) {
+            Some(expected) if expected == seq_profile => {},
+            Some(expected) => return Err(Error::ProfileMismatch { seq_profile, expected }),
+            None => return Err(Error::UnsupportedSubsampling { subsampling, depth_bits: bit_depth }),
+        }
+        if seq_level_idx_0 > level::LEVEL_MAX {
+            return Err(Error::InvalidLevel(seq_level_idx_0));
+        }
+        if seq_tier_0 && seq_level_idx_0 < level::LEVEL_4_0 {
+            return Err(Error::NoHighTier(seq_level_idx_0));
+        }
+        if chroma_sample_position != ChromaSamplePosition::Unknown && (monochrome || subsampling != ChromaSubsampling::Cs420) {
+            return Err(Error::ChromaSamplePositionSubsampling { subsampling, monochrome });
+        }
+        let (high_bitdepth, twelve_bit) = av1_depth_flags(bit_depth)?;
+        let (chroma_subsampling_x, chroma_subsampling_y) = subsampling.xy();
+        let av1c = Av1CBox {
+            seq_profile,
+            seq_level_idx_0,
+            seq_tier_0,
+            high_bitdepth,
+            twelve_bit,
+            monochrome,
+            chroma_subsampling_x,
+            chroma_subsampling_y,
+            chroma_sample_position: chroma_sample_position as u8,
+            initial_presentation_delay_minus_one: None,
+            config_obus: &[],
+        };
+        Ok(Self { av1c, config_obus: Vec::new() })
+    }
+
+    /// Parses a serialized `AV1CodecConfigurationRecord` (the payload of the `av1C` box), as made by other muxers.
+    ///
+    /// It's 4 bytes long, optionally followed by `configOBUs`, which are kept.
+    pub fn from_record(record: &[u8]) -> Result<Self, Error> {
+        let (marker_version, profile_level, flags, delay, config_obus) = match *record {
+            [a, b, c, d, ref rest @ ..] => (a, b, c, d, rest),
+            _ => return Err(InvalidAv1Data::RecordTooShort.into()),
+        };
+        if marker_version != 0x81 {
+            return Err(InvalidAv1Data::RecordVersion(marker_version).into());
+        }
+        let flag = |bit: u8| flags & (1 << bit) != 0;
+        let (high_bitdepth, twelve_bit) = (flag(6), flag(5));
+        let bit_depth = match (high_bitdepth, twelve_bit) {
+            (false, false) => 8,
+            (true, false) => 10,
+            (true, true) => 12,
+            (false, true) => return Err(InvalidAv1Data::RecordTwelveBit.into()),
+        };
+        let monochrome = flag(4);
+        let subsampling = ChromaSubsampling::from_xy((flag(3), flag(2)))
+            .filter(|&s| !monochrome || s == ChromaSubsampling::Cs420)
+            .ok_or(InvalidAv1Data::RecordSubsampling)?;
+        let chroma_sample_position = match flags & 3 {
+            0 => ChromaSamplePosition::Unknown,
+            1 => ChromaSamplePosition::Vertical,
+            2 => ChromaSamplePosition::Colocated,
+            _ => return Err(InvalidAv1Data::RecordChromaSamplePosition.into()),
+        };
+        let mut config = Self::new(profile_level >> 5, profile_level & 0x1F, flag(7), bit_depth, subsampling, monochrome, chroma_sample_position)?;
+        if delay & 0xE0 != 0 {
+            return Err(InvalidAv1Data::RecordReservedBits.into());
+        }
+        config.av1c.initial_presentation_delay_minus_one = if delay & 0x10 != 0 { Some(delay & 0xF) } else { None };
+        config.config_obus = config_obus.to_vec();
+        Ok(config)
+    }
+
+    fn to_av1c(&self) -> Av1CBox<'_> {
+        Av1CBox { config_obus: &self.config_obus, ..self.av1c }
+    }
+}
+
+impl ChromaSubsampling {
+    /// `chroma_subsampling_x` and `chroma_subsampling_y` flags of AV1
+    fn xy(self) -> (bool, bool) {
+        match self {
+            Self::Cs444 => (false, false),
+            Self::Cs422 => (true, false),
+            Self::Cs420 => (true, true),
+        }
+    }
+
+    fn from_xy(xy: (bool, bool)) -> Option<Self> {
+        match xy {
+            (false, false) => Some(Self::Cs444),
+            (true, false) => Some(Self::Cs422),
+            (true, true) => Some(Self::Cs420),
+            (false, true) => None,
+        }
+    }
+
+    /// `seq_profile` that AV1 requires for this subsampling at this bit depth, if it's a legal combination.
+    ///
+    /// Monochrome is flagged as 4:2:0, and isn't allowed in profile 1.
+    fn av1_seq_profile(self, depth_bits: u8) -> Option<u8> {
+        match (depth_bits, self) {
+            (8 | 10, Self::Cs420) => Some(0),
+            (8 | 10, Self::Cs444) => Some(1),
+            (8 | 10, Self::Cs422) | (12, _) => Some(2),
+            _ => None,
+        }
+    }
 }
 
 /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
@@ -27,6 +369,7 @@ pub struct Aviffy {
 /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
 /// The color image MUST have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`)
 /// AV1 handles full-res color so effortlessly, you should never need chroma subsampling ever again.
+/// This function always declares 4:4:4. For other layouts, use [`Aviffy::chroma_subsampling`] instead.
 ///
 /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
 /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
@@ -38,13 +381,72 @@ pub struct Aviffy {
 ///
 /// Data is written (streamed) to `into_output`.
 pub fn serialize<W: io::Write>(into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> io::Result<()> {
-    Aviffy::new().write(into_output, color_av1_data, alpha_av1_data, width, height, depth_bits)
+    Ok(Aviffy::new().write(into_output, color_av1_data, alpha_av1_data, width, height, depth_bits)?)
+}
+
+impl Default for Aviffy {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Aviffy {
     pub fn new() -> Self {
         Self {
-            premultiplied_alpha: false,
+            alpha_mode: AlphaMode::Straight,
+            hidden_alpha: false,
+            alpha_colr: false,
+            alpha_depth_bits: None,
+            data_order: DataOrder::AlphaFirst,
+            alpha_urn: AlphaUrn::Cicp,
+            alpha_dimensions: None,
+            strict_av1c: true,
+            derive_av1c_from_bitstream: false,
+            validate_bitstream: false,
+            check_obu_start: false,
+            seq_profile: None,
+            seq_level_idx: None,
+            seq_tier: false,
+            alpha_seq_level_idx: None,
+            alpha_seq_tier: None,
+            av1c: None,
+            alpha_av1c: None,
+            embed_sequence_header: false,
+            strip_temporal_delimiters: false,
+            trim_trailing_obus: false,
+            tile_group_extents: false,
+            initial_presentation_delay: None,
+            chroma_subsampling: ChromaSubsampling::Cs444,
+            monochrome: false,
+            allow_odd_dimensions: false,
+            write_pixi: true,
+            output_depth_bits: None,
+            allow_output_depth_mismatch: false,
+            major_brand: FourCC(*b"avif"),
+            minor_version: 0,
+            mdat_alignment: 1,
+            layout: Layout::MetaFirst,
+            separate_other_images: false,
+            separate_metadata: false,
+            idat_threshold: 0,
+            large_mdat: false,
+            large_offsets: false,
+            iloc_base_offsets: false,
+            iloc_version: IlocVersion::Auto,
+            essential_properties: Vec::new(),
+            chroma_sample_position: ChromaSamplePosition::Unknown,
+            nclx: Nclx::default(),
+            icc_profile: None,
+            crop: None,
+            layer_id: None,
+            encoded_size: None,
+            trailing_boxes: Vec::new(),
+            target_len: None,
+            alternatives: Vec::new(),
+            group_alternatives: true,
+            right_eye: None,
+            depth: None,
+            metadata: Vec::new(),
         }
     }
 
@@ -55,239 +457,3929 @@ impl Aviffy {
     /// may not be supported correctly by less capable AVIF decoders.
     ///
     /// This just sets the configuration property. The pixel data must have already been processed before compression.
+    ///
+    /// It has no effect on images without alpha.
+    pub fn alpha_mode(&mut self, mode: AlphaMode) -> &mut Self {
+        self.alpha_mode = mode;
+        self
+    }
+
+    /// Same as [`Aviffy::alpha_mode`] with [`AlphaMode::Premultiplied`] if `true`, or [`AlphaMode::Straight`] if `false`.
     pub fn premultiplied_alpha(&mut self, is_premultiplied: bool) -> &mut Self {
-        self.premultiplied_alpha = is_premultiplied;
+        self.alpha_mode(if is_premultiplied { AlphaMode::Premultiplied } else { AlphaMode::Straight })
+    }
+
+    /// Set the bit depth of the alpha image, if it's been encoded at a different depth than the color image (8, 10 or 12).
+    ///
+    /// By default alpha has the same depth as color. 8-bit alpha is usually enough, even for 10-bit color.
+    pub fn alpha_depth_bits(&mut self, depth_bits: u8) -> &mut Self {
+        self.alpha_depth_bits = Some(depth_bits);
         self
     }
 
-    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
+    /// Set whether the alpha image data is written before the color image data (the default), or after it.
     ///
-    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
-    /// The color image MUST have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`)
-    /// AV1 handles full-res color so effortlessly, you should never need chroma subsampling ever again.
+    /// This matters only for displaying partially loaded files.
+    pub fn data_order(&mut self, order: DataOrder) -> &mut Self {
+        self.data_order = order;
+        self
+    }
+
+    /// Set the size of the alpha image, if it's been encoded at a different resolution than the color image.
     ///
-    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
-    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
+    /// Decoders are expected to scale it to the color image size. By default alpha is the same size as color.
+    /// It can't be combined with cropping.
+    pub fn alpha_dimensions(&mut self, width: u32, height: u32) -> &mut Self {
+        self.alpha_dimensions = Some((width, height));
+        self
+    }
+
+    /// Set the identifier that marks the auxiliary image as alpha. Only change it for compatibility with non-AVIF tools.
+    pub fn alpha_urn(&mut self, urn: AlphaUrn) -> &mut Self {
+        self.alpha_urn = urn;
+        self
+    }
+
+    /// Set whether the alpha item is marked as hidden in `infe` (off by default), so that viewers that list
+    /// all items won't display the alpha channel as a standalone grayscale image.
     ///
-    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
-    /// `depth_bits` should be 8, 10 or 12, depending on how the image was encoded (typically 8).
+    /// The primary color item is never hidden, since HEIF forbids that.
+    pub fn hidden_alpha(&mut self, is_hidden: bool) -> &mut Self {
+        self.hidden_alpha = is_hidden;
+        self
+    }
+
+    /// Set whether to write an `nclx` `colr` property for the alpha image too (off by default).
     ///
-    /// Color and alpha must have the same dimensions and depth.
+    /// It has unspecified primaries, transfer and matrix, since alpha has no color, and is full range.
+    /// Decoders ignore it, but some strict MIAF validators complain when the alpha item has no `colr`.
+    pub fn alpha_colr(&mut self, write_colr: bool) -> &mut Self {
+        self.alpha_colr = write_colr;
+        self
+    }
+
+    /// Set whether the fields of `av1C` are forced to the configured values, rather than taken from the AV1 data (on by default).
     ///
-    /// Data is written (streamed) to `into_output`.
-    pub fn write<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> io::Result<()> {
-        let mut image_items = ArrayVec::new();
-        let mut iloc_items = ArrayVec::new();
-        let mut compatible_brands = ArrayVec::new();
-        let mut ipma_entries = ArrayVec::new();
-        let mut data_chunks = ArrayVec::<&[u8], 4>::new();
-        let mut irefs = ArrayVec::new();
-        let mut ipco = IpcoBox::new();
-        let color_image_id = 1;
-        let alpha_image_id = 2;
-        let high_bitdepth = depth_bits >= 10;
-        let twelve_bit = depth_bits >= 12;
-        const ESSENTIAL_BIT: u8 = 0x80;
+    /// The AV1 codec configuration in `av1C` only repeats what is already in the AV1 sequence header,
+    /// but Chrome refuses to decode images without it, and checks that the redundant fields match the bitstream.
+    /// When it's enabled, the profile, level, depth and subsampling come from the arguments given to [`Aviffy::write`]
+    /// and settings such as [`Aviffy::chroma_subsampling`], so they're only as correct as what the encoder reported.
+    ///
+    /// When it's disabled, the bitstream is trusted instead: the fields are copied from the sequence header of each image,
+    /// and the configured values are used only for data without a parseable sequence header.
+    /// [`Aviffy::derive_av1c_from_bitstream`] is the same, except that it fails on such data. The `av1C` is always written,
+    /// and [`Aviffy::av1c`] overrides both modes.
+    pub fn strict_av1c(&mut self, use_configured: bool) -> &mut Self {
+        self.strict_av1c = use_configured;
+        self
+    }
 
-        image_items.push(InfeBox {
-            id: color_image_id,
-            typ: FourCC(*b"av01"),
-            name: "",
-        });
-        let ispe_prop = ipco.push(IpcoProp::Ispe(IspeBox { width, height }));
-        // This is redundant, but Chrome wants it, and checks that it matches :(
-        let av1c_prop = ipco.push(IpcoProp::Av1C(Av1CBox {
-            seq_profile: if twelve_bit { 2 } else { 1 },
-            seq_level_idx_0: 31,
-            seq_tier_0: false,
-            high_bitdepth,
-            twelve_bit,
-            monochrome: false,
-            chroma_subsampling_x: false,
-            chroma_subsampling_y: false,
-            chroma_sample_position: 0,
-        }));
-        // Useless bloat
-        let pixi_3 = ipco.push(IpcoProp::Pixi(PixiBox {
-            channels: 3,
-            depth: 8,
-        }));
-        ipma_entries.push(IpmaEntry {
-            item_id: color_image_id,
-            prop_ids: [ispe_prop, av1c_prop | ESSENTIAL_BIT, pixi_3].iter().copied().collect(),
-        });
+    /// Set whether the `av1C` configuration should be copied from the AV1 sequence headers in the color and alpha data (off by default).
+    ///
+    /// This makes the configuration match the bitstream exactly, regardless of [`Aviffy::chroma_subsampling`]
+    /// and other settings. [`Aviffy::write`] will fail if the data doesn't start with a valid sequence header,
+    /// or if its bit depth isn't `depth_bits`.
+    ///
+    /// The level, tier and `initial_presentation_delay` are taken from the first operating point, which in layered images
+    /// is the one that decodes all the layers. [`Aviffy::initial_presentation_delay`] overrides the delay.
+    pub fn derive_av1c_from_bitstream(&mut self, from_bitstream: bool) -> &mut Self {
+        self.derive_av1c_from_bitstream = from_bitstream;
+        self
+    }
 
-        if let Some(alpha_data) = alpha_av1_data {
-            image_items.push(InfeBox {
-                id: alpha_image_id,
-                typ: FourCC(*b"av01"),
-                name: "",
-            });
-            let av1c_prop = ipco.push(boxes::IpcoProp::Av1C(Av1CBox {
-                seq_profile: if twelve_bit { 2 } else { 0 },
-                seq_level_idx_0: 31,
-                seq_tier_0: false,
-                high_bitdepth,
-                twelve_bit,
-                monochrome: true,
-                chroma_subsampling_x: true,
-                chroma_subsampling_y: true,
-                chroma_sample_position: 0,
-            }));
-            // So pointless
-            let pixi_1 = ipco.push(IpcoProp::Pixi(PixiBox {
-                channels: 1,
-                depth: 8,
-            }));
+    /// Set whether [`Aviffy::write`] should check that the color image's AV1 data matches the given
+    /// width, height, depth, [`Aviffy::chroma_subsampling`] and [`Aviffy::monochrome`] (off by default).
+    ///
+    /// The alpha image's AV1 data must be monochrome (4:0:0), and the size of the alpha image, because that's what its `av1C` says.
+    ///
+    /// This reads the sequence header and the first frame header. Mismatches are reported as
+    /// [`Error::MismatchedBitstream`] (or `io::ErrorKind::InvalidData` errors wrapping [`BitstreamMismatch`] from [`serialize`]).
+    pub fn validate_bitstream(&mut self, validate: bool) -> &mut Self {
+        self.validate_bitstream = validate;
+        self
+    }
 
-            // that's a silly way to add 1 bit of information, isn't it?
-            let auxc_prop = ipco.push(IpcoProp::AuxC(AuxCBox {
-                urn: "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
-            }));
-            irefs.push(IrefBox {
-                entry: IrefEntryBox {
-                    from_id: alpha_image_id,
-                    to_id: color_image_id,
-                    typ: FourCC(*b"auxl"),
-                },
-            });
-            if self.premultiplied_alpha {
-                irefs.push(IrefBox {
-                    entry: IrefEntryBox {
-                        from_id: color_image_id,
-                        to_id: alpha_image_id,
-                        typ: FourCC(*b"prem"),
-                    },
-                });
-            }
-            ipma_entries.push(IpmaEntry {
-                item_id: alpha_image_id,
-                prop_ids: [ispe_prop, av1c_prop | ESSENTIAL_BIT, auxc_prop, pixi_1].iter().copied().collect(),
-            });
+    /// Set whether to check that the AV1 data of every image starts with a temporal delimiter or sequence header OBU (off by default).
+    ///
+    /// This is a quick check for the most common mistake: passing an IVF file, or frame data without the headers,
+    /// instead of the OBUs the encoder made. [`Aviffy::write`] reports it as [`Error::InvalidAv1Data`], e.g. [`InvalidAv1Data::Ivf`].
+    pub fn check_obu_start(&mut self, check: bool) -> &mut Self {
+        self.check_obu_start = check;
+        self
+    }
 
-            // Use interleaved color and alpha, with alpha first.
-            // Makes it possible to display partial image.
-            iloc_items.push(IlocItem {
-                id: color_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(alpha_data.len()),
-                        len: color_av1_data.len(),
-                    },
-                ].into(),
-            });
-            iloc_items.push(IlocItem {
-                id: alpha_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(0),
-                        len: alpha_data.len(),
-                    },
-                ].into(),
-            });
-            data_chunks.push(alpha_data);
-            data_chunks.push(color_av1_data);
-        } else {
-            iloc_items.push(IlocItem {
-                id: color_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(0),
-                        len: color_av1_data.len(),
-                    },
-                ].into(),
-            });
-            data_chunks.push(color_av1_data);
-        };
+    /// Override the AV1 profile declared in the color image's `av1C` (`seq_profile`, 0-2).
+    ///
+    /// By default it's the lowest profile that supports the chroma subsampling and depth (see [`Aviffy::chroma_subsampling`]),
+    /// but encoders may use a higher one, e.g. profile 2 for 8-bit 4:4:4. It should match the profile in the AV1 sequence header.
+    /// It can't be lower than the default (e.g. 12-bit images are always profile 2).
+    /// It has no effect on `av1C` given with [`Aviffy::av1c`] or derived from the bitstream, nor on the alpha image.
+    pub fn av1_seq_profile(&mut self, seq_profile: u8) -> &mut Self {
+        self.seq_profile = Some(seq_profile);
+        self
+    }
 
-        compatible_brands.push(FourCC(*b"mif1"));
-        compatible_brands.push(FourCC(*b"miaf"));
-        let mut boxes = AvifFile {
-            ftyp: FtypBox {
-                major_brand: FourCC(*b"avif"),
-                minor_version: 0,
-                compatible_brands,
-            },
-            meta: MetaBox {
-                hdlr: HdlrBox {},
-                iinf: IinfBox { items: image_items },
-                pitm: PitmBox(color_image_id),
-                iloc: IlocBox { items: iloc_items },
-                iprp: IprpBox {
-                    ipco,
-                    // It's not enough to define these properties,
-                    // they must be assigned to the image
-                    ipma: IpmaBox {
-                        entries: ipma_entries,
-                    },
-                },
-                iref: irefs,
-            },
-            // Here's the actual data. If HEIF wasn't such a kitchen sink, this
-            // would have been the only data this file needs.
-            mdat: MdatBox {
-                data_chunks: &data_chunks,
-            },
-        };
+    /// Override the AV1 level declared in `av1C` (`seq_level_idx`, e.g. 8 for level 4.0, or 31 for no limits).
+    ///
+    /// By default the smallest level that fits the image's width and height is used, because some hardware decoders
+    /// refuse to decode anything declared above their level. It should match the level in the AV1 sequence header.
+    pub fn seq_level_idx(&mut self, seq_level_idx: u8) -> &mut Self {
+        self.seq_level_idx = Some(seq_level_idx);
+        self
+    }
 
-        boxes.write(into_output)
+    /// Set whether the AV1 data has been encoded in the high tier (`seq_tier_0`, main tier by default).
+    ///
+    /// It applies to both color and alpha, unless [`Aviffy::alpha_seq_tier`] is set. AV1 defines the high tier
+    /// only for levels 4.0 and above, so [`Aviffy::write`] will fail if the level is lower (see [`Aviffy::seq_level_idx`]).
+    pub fn seq_tier(&mut self, high: bool) -> &mut Self {
+        self.seq_tier = high;
+        self
     }
 
-    pub fn to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Vec<u8> {
-        let mut out = Vec::with_capacity(color_av1_data.len() + alpha_av1_data.map_or(0, |a| a.len()) + 400);
-        self.write(&mut out, color_av1_data, alpha_av1_data, width, height, depth_bits).unwrap(); // Vec can't fail
-        out
+    /// Set the AV1 level of the alpha image, if it's been encoded with a different level than the color image.
+    ///
+    /// By default the alpha has the same level as color (see [`Aviffy::seq_level_idx`]).
+    /// It should match the level in the alpha's AV1 sequence header.
+    pub fn alpha_seq_level_idx(&mut self, seq_level_idx: u8) -> &mut Self {
+        self.alpha_seq_level_idx = Some(seq_level_idx);
+        self
     }
-}
 
-/// See [`serialize`] for description. This one makes a `Vec` instead of using `io::Write`.
-pub fn serialize_to_vec(color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Vec<u8> {
-    Aviffy::new().to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits)
-}
+    /// Set the AV1 tier of the alpha image, if it's different from the color image (see [`Aviffy::seq_tier`]).
+    pub fn alpha_seq_tier(&mut self, high: bool) -> &mut Self {
+        self.alpha_seq_tier = Some(high);
+        self
+    }
 
-#[test]
-fn test_roundtrip_parse_mp4() {
-    let test_img = b"av12356abc";
-    let avif = serialize_to_vec(test_img, None, 10, 20, 8);
+    /// Set whether to copy the sequence header OBU from the AV1 data into `configOBUs` of `av1C` (off by default).
+    ///
+    /// This lets decoders set up before reading the image data. The sequence header stays in the image data too,
+    /// because AVIF requires every image to have one. [`Aviffy::write`] will fail if there's no sequence header.
+    pub fn embed_sequence_header(&mut self, embed: bool) -> &mut Self {
+        self.embed_sequence_header = embed;
+        self
+    }
 
-    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    /// Set whether to remove temporal delimiter OBUs from the color and alpha data (off by default).
+    ///
+    /// Some encoders (e.g. aom) start every frame with one, but MIAF doesn't allow them in image items,
+    /// and strict decoders complain. [`Aviffy::write`] will fail if the data isn't a valid sequence of OBUs.
+    ///
+    /// Leading delimiters are skipped without copying. Delimiters elsewhere make a copy of the data.
+    pub fn strip_temporal_delimiters(&mut self, strip: bool) -> &mut Self {
+        self.strip_temporal_delimiters = strip;
+        self
+    }
 
-    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
-}
+    /// Set whether to remove temporal delimiter and padding OBUs that some encoders add after the frame (off by default).
+    ///
+    /// They're useless at the end of an image, and the file is smaller without them. The data is never copied for this,
+    /// but [`Aviffy::write`] will fail if it isn't a valid sequence of OBUs.
+    pub fn trim_trailing_obus(&mut self, trim: bool) -> &mut Self {
+        self.trim_trailing_obus = trim;
+        self
+    }
 
-#[test]
-fn test_roundtrip_parse_mp4_alpha() {
-    let test_img = b"av12356abc";
-    let test_a = b"alpha";
-    let avif = serialize_to_vec(test_img, Some(test_a), 10, 20, 8);
+    /// Set whether the color and alpha data should get a separate `iloc` extent for every AV1 tile group (off by default).
+    ///
+    /// The data is written unchanged, but readers that fetch extents separately (e.g. with HTTP range requests)
+    /// can get and decode only some of the tile groups. [`Aviffy::data_extents`] tells where they are in the file.
+    /// [`Aviffy::write`] will fail if the data isn't a valid sequence of OBUs, or if it has over 16 tile groups.
+    pub fn tile_group_extents(&mut self, split: bool) -> &mut Self {
+        self.tile_group_extents = split;
+        self
+    }
 
-    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    /// Set `initial_presentation_delay` of `av1C` of both color and alpha (absent by default).
+    ///
+    /// It's the number of frames (1-16) a decoder must buffer before presenting the first one.
+    /// It's meaningful only for streams with multiple frames per image, such as layered images.
+    pub fn initial_presentation_delay(&mut self, frames: u8) -> &mut Self {
+        self.initial_presentation_delay = Some(frames);
+        self
+    }
 
-    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
-    assert_eq!(&test_a[..], ctx.alpha_item_coded_data());
-}
+    /// Set the exact `av1C` configuration of the color image, instead of deriving it from other settings or the bitstream.
+    ///
+    /// It's used regardless of [`Aviffy::strict_av1c`]. It must match the AV1 bitstream.
+    pub fn av1c(&mut self, config: Av1Config) -> &mut Self {
+        self.av1c = Some(config);
+        self
+    }
 
-#[test]
-fn test_roundtrip_parse_avif() {
-    let test_img = [1,2,3,4,5,6];
+    /// Same as [`Aviffy::av1c`] with [`Av1Config::from_record`], for the payload of an `av1C` box made elsewhere.
+    pub fn av1c_record(&mut self, record: &[u8]) -> Result<&mut Self, Error> {
+        Ok(self.av1c(Av1Config::from_record(record)?))
+    }
+
+    /// Set the exact `av1C` configuration of the alpha image. See [`Aviffy::av1c`].
+    pub fn alpha_av1c(&mut self, config: Av1Config) -> &mut Self {
+        self.alpha_av1c = Some(config);
+        self
+    }
+
+    /// Set the chroma subsampling the color image has been encoded with (4:4:4 by default).
+    ///
+    /// It must match the AV1 bitstream, because Chrome checks that the `av1C` agrees with the data.
+    /// It also decides which AV1 profile is declared: 4:2:0 is profile 0 (main), 4:4:4 is profile 1 (high),
+    /// and 4:2:2 and all 12-bit images are profile 2 (professional).
+    ///
+    /// For 4:2:0 also set [`Aviffy::chroma_sample_position`] if the encoder has been told where the chroma samples are.
+    ///
+    /// Subsampled images must have even width (and even height for 4:2:0), unless [`Aviffy::allow_odd_dimensions`] is set.
+    pub fn chroma_subsampling(&mut self, subsampling: ChromaSubsampling) -> &mut Self {
+        self.chroma_subsampling = subsampling;
+        self
+    }
+
+    /// Set whether [`Aviffy::write`] should accept odd width or height with subsampled chroma (off by default).
+    ///
+    /// AV1 can encode them, but the last row or column of chroma covers only half a pixel,
+    /// which some decoders get wrong, causing color fringes at the edge.
+    pub fn allow_odd_dimensions(&mut self, allow: bool) -> &mut Self {
+        self.allow_odd_dimensions = allow;
+        self
+    }
+
+    /// Set whether the color image has been encoded as grayscale, without chroma channels (`Cs400` in `rav1e`).
+    ///
+    /// This overrides [`Aviffy::chroma_subsampling`], since monochrome AV1 images have no chroma to subsample.
+    /// Alpha can still be added as usual.
+    ///
+    /// [`Aviffy::color_primaries`] and [`Aviffy::transfer_characteristics`] still apply to the gray channel (e.g. for PQ HDR).
+    /// Matrix coefficients don't matter without chroma, so [`MatrixCoefficients::Unspecified`] is fine,
+    /// but AV1 doesn't allow [`MatrixCoefficients::Identity`] for monochrome images.
+    pub fn monochrome(&mut self, is_monochrome: bool) -> &mut Self {
+        self.monochrome = is_monochrome;
+        self
+    }
+
+    /// Set position of chroma samples encoded in the AV1 bitstream. It's written to `av1C` only for [`ChromaSubsampling::Cs420`] images.
+    ///
+    /// Images converted from video usually use [`ChromaSamplePosition::Vertical`].
+    pub fn chroma_sample_position(&mut self, position: ChromaSamplePosition) -> &mut Self {
+        self.chroma_sample_position = position;
+        self
+    }
+
+    /// Set color primaries of the image, which will be stored in the `colr` property (BT.709/sRGB by default).
+    ///
+    /// The `colr` property is only written when any of the color settings differ from the defaults.
+    pub fn color_primaries(&mut self, color_primaries: ColorPrimaries) -> &mut Self {
+        self.nclx.color_primaries = color_primaries;
+        self
+    }
+
+    /// Set transfer characteristics of the image, which will be stored in the `colr` property (sRGB by default).
+    pub fn transfer_characteristics(&mut self, transfer_characteristics: TransferCharacteristics) -> &mut Self {
+        self.nclx.transfer_characteristics = transfer_characteristics;
+        self
+    }
+
+    /// Set matrix coefficients that have been used to convert RGB to the encoded channels (BT.601 by default).
+    ///
+    /// Use [`MatrixCoefficients::Identity`] for images encoded from RGB directly (e.g. lossless).
+    /// That requires [`ChromaSubsampling::Cs444`], and with BT.709 primaries and sRGB transfer it also requires full range,
+    /// because AV1 has no way to signal limited range for sRGB.
+    pub fn matrix_coefficients(&mut self, matrix_coefficients: MatrixCoefficients) -> &mut Self {
+        self.nclx.matrix_coefficients = matrix_coefficients;
+        self
+    }
+
+    /// Set whether the channels use the full range of values (the default),
+    /// or the "studio"/"TV" range where black isn't 0.
+    pub fn full_color_range(&mut self, is_full_range: bool) -> &mut Self {
+        self.nclx.full_range_flag = is_full_range;
+        self
+    }
+
+    /// Set the ICC color profile of the image, which will be stored in a `colr` property of type `prof`.
+    ///
+    /// Decoders that support ICC use it instead of [`Aviffy::color_primaries`] and [`Aviffy::transfer_characteristics`].
+    /// These should still be set to the closest match, since the `nclx` `colr` is written as well when they aren't the defaults.
+    /// [`Aviffy::matrix_coefficients`] and [`Aviffy::full_color_range`] always apply, because ICC profiles can't describe YUV.
+    pub fn icc_profile(&mut self, profile: &[u8]) -> &mut Self {
+        self.icc_profile = Some(profile.to_vec());
+        self
+    }
+
+    /// Set whether to write the `pixi` property with the number of channels and their bit depth (on by default).
+    ///
+    /// MIAF requires it, but the same information is in `av1C`, and decoders don't need it.
+    /// Omitting it saves a few bytes per image, which may matter for tiny thumbnails.
+    pub fn write_pixi(&mut self, write_pixi: bool) -> &mut Self {
+        self.write_pixi = write_pixi;
+        self
+    }
+
+    /// Set the bit depth that `pixi` gives for the color image, if it's meant to be delivered at a different depth than it has been encoded at.
+    ///
+    /// `pixi` only describes the image, and `av1C` still has the depth of the AV1 data. A different depth is usually a mistake,
+    /// so it's an error unless [`Aviffy::allow_output_depth_mismatch`] is set too. It must be 1-16 bits either way.
+    pub fn output_depth_bits(&mut self, depth_bits: u8) -> &mut Self {
+        self.output_depth_bits = Some(depth_bits);
+        self
+    }
+
+    /// Set whether [`Aviffy::output_depth_bits`] can be different from the depth of the AV1 data (off by default).
+    ///
+    /// This is for advanced use, e.g. 8-bit images encoded at 10 bits for better compression.
+    pub fn allow_output_depth_mismatch(&mut self, allow: bool) -> &mut Self {
+        self.allow_output_depth_mismatch = allow;
+        self
+    }
+
+    /// Display only one spatial layer of the color image, e.g. the base layer of an AV1 stream with several operating points.
+    ///
+    /// This adds the `lsel` property to the color image. By default decoders show all layers.
+    /// The layers are in the same AV1 data, so this doesn't make a separate image.
+    pub fn select_layer(&mut self, layer_id: u16) -> &mut Self {
+        self.layer_id = Some(layer_id);
+        self
+    }
+
+    /// Set the size of the image to display, if it's smaller than the encoded size, e.g. `crop_to(1920, 1080)` for a 1920×1088 image.
+    ///
+    /// The image is cropped to its top-left `display_width`×`display_height` pixels, using the `clap` property.
+    /// The size given to [`Aviffy::write`] remains the encoded size. See [`Aviffy::encoded_size`] for the opposite.
+    pub fn crop_to(&mut self, display_width: u32, display_height: u32) -> &mut Self {
+        self.crop = Some((display_width, display_height));
+        self
+    }
+
+    /// Set the size of the image as encoded in the AV1 bitstream, if it's larger than the size to display.
+    ///
+    /// The size given to [`Aviffy::write`] then becomes the display size, and the image is cropped to it like with [`Aviffy::crop_to`].
+    /// The encoded size is used for `ispe`, the AV1 level and bitstream validation. It can't be combined with [`Aviffy::crop_to`].
+    pub fn encoded_size(&mut self, encoded_width: u32, encoded_height: u32) -> &mut Self {
+        self.encoded_size = Some((encoded_width, encoded_height));
+        self
+    }
+
+    /// Set the major brand of the `ftyp` box. Only `avif` (the default) and `avis` (for sequences) are allowed.
+    ///
+    /// The file is still a still image, so with `avis` the `avif` brand is added to the compatible brands.
+    pub fn major_brand(&mut self, brand: FourCC) -> &mut Self {
+        self.major_brand = brand;
+        self
+    }
+
+    /// Set the minor version of the `ftyp` box (0 by default). It's informative only.
+    pub fn minor_version(&mut self, version: u32) -> &mut Self {
+        self.minor_version = version;
+        self
+    }
+
+    /// Align the start of the image data in the file to a multiple of `alignment` bytes, e.g. 4096 for memory-mapped loading.
+    ///
+    /// This inserts a `free` box before `mdat`, and `iloc` offsets account for it. 1 (the default) disables the padding, and 0 is invalid.
+    ///
+    /// The alpha data is first in `mdat` by default, so use [`DataOrder::ColorFirst`] to align the primary image's data instead.
+    pub fn align_mdat(&mut self, alignment: u32) -> &mut Self {
+        self.mdat_alignment = alignment;
+        self
+    }
+
+    /// Set the order of `meta` and `mdat` in the file ([`Layout::MetaFirst`] by default).
+    ///
+    /// `iloc` has absolute offsets, so with [`Layout::MdatFirst`] they point back to before `meta`. Viewers that load files
+    /// progressively can't show anything until they get to `meta`. It has no effect when the data is in `idat`.
+    pub fn layout(&mut self, layout: Layout) -> &mut Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Set whether the data of the `group` is in its own `mdat` box (off by default), e.g. so that the primary image
+    /// can be fetched with one range request without bulky metadata.
+    ///
+    /// The `mdat` boxes are written one after another: the color and alpha data first, then the other images, then the metadata.
+    /// Groups that have no data don't get a box. [`Aviffy::align_mdat`] aligns only the first box.
+    pub fn separate_mdat(&mut self, group: MdatGroup, separate: bool) -> &mut Self {
+        match group {
+            MdatGroup::OtherImages => self.separate_other_images = separate,
+            MdatGroup::Metadata => self.separate_metadata = separate,
+        }
+        self
+    }
+
+    /// Store the image data inside `meta` (in an `idat` box) instead of a separate `mdat`, if all of it together is at most `max_len` bytes.
+    ///
+    /// This makes the whole file one `meta` box after `ftyp`, which is slightly smaller for tiny images without alpha, like favicons.
+    /// With alpha it's a few bytes larger instead, because `iloc` needs version 1 or later. 0 (the default) always uses `mdat`. Alignment from [`Aviffy::align_mdat`] doesn't apply to `idat`.
+    pub fn idat_threshold(&mut self, max_len: usize) -> &mut Self {
+        self.idat_threshold = max_len;
+        self
+    }
+
+    /// Set whether the `mdat` box header should have a 64-bit size even if the data is smaller than 4GB (off by default).
+    ///
+    /// It's used automatically when needed. Forcing it allows streaming writers to patch in the final size of the data later.
+    pub fn large_mdat(&mut self, large: bool) -> &mut Self {
+        self.large_mdat = large;
+        self
+    }
+
+    /// Set whether `iloc` should use 64-bit offsets and lengths of the image data even if the file is smaller than 4GB (off by default).
+    ///
+    /// They're used automatically when needed, so this is only for testing readers of large files.
+    pub fn large_offsets(&mut self, large: bool) -> &mut Self {
+        self.large_offsets = large;
+        self
+    }
+
+    /// Set whether `iloc` items should have a `base_offset` at the start of their `mdat` payload, with extent offsets relative to it (off by default).
+    ///
+    /// Tools that insert or remove bytes before `mdat` then only need to update one offset per item, instead of every extent.
+    /// It adds 4 bytes per item. It's ignored when the data is in `idat` (see [`Aviffy::idat_threshold`]).
+    pub fn iloc_base_offsets(&mut self, base_offsets: bool) -> &mut Self {
+        self.iloc_base_offsets = base_offsets;
+        self
+    }
+
+    /// Set the version of the `iloc` box, for readers that support only some versions.
+    ///
+    /// [`IlocVersion::Auto`] (the default) picks the oldest version that can describe the file,
+    /// which is version 0, because this crate uses 16-bit item ids, unless the data is in `idat` (see [`Aviffy::idat_threshold`]), which needs version 1.
+    pub fn iloc_version(&mut self, version: IlocVersion) -> &mut Self {
+        self.iloc_version = version;
+        self
+    }
+
+    /// Override whether properties of the given type are marked as essential in `ipma`.
+    ///
+    /// By default `av1C` and transformative properties like `clap` are essential, and all other properties aren't.
+    /// Decoders must skip items with essential properties they don't understand, which some get wrong.
+    pub fn property_essential(&mut self, fourcc: FourCC, essential: bool) -> &mut Self {
+        self.essential_properties.retain(|&(typ, _)| typ != fourcc);
+        self.essential_properties.push((fourcc, essential));
+        self
+    }
+
+    /// Add a custom top-level box with the given type and payload, written after all the image data.
+    ///
+    /// This can be used for padding (`free` or `skip` boxes) or proprietary metadata.
+    /// The payload is written as-is after the 8-byte box header.
+    ///
+    /// Up to 4 boxes can be added (more is an error in [`Aviffy::write`]). Box types that this library writes itself (`ftyp`, `meta`, `mdat`) are rejected too.
+    pub fn append_box(&mut self, fourcc: FourCC, payload: &[u8]) -> &mut Self {
+        self.trailing_boxes.push((fourcc, payload.to_vec()));
+        self
+    }
+
+    /// Make the file exactly `len` bytes long, by adding a `free` box at the end (none by default).
+    ///
+    /// [`Aviffy::write`] fails if the file is larger than that, or if it's 1 to 7 bytes smaller, because no box is that small.
+    pub fn pad_to_size(&mut self, len: u64) -> &mut Self {
+        self.target_len = Some(len);
+        self
+    }
+
+    /// Add another color image, e.g. the same picture in a smaller size, as an alternative to the primary image.
+    ///
+    /// It must have been encoded with the same settings as the primary color image, except for its size, and it has no alpha.
+    /// Its `av1C` declares the smallest level that fits its size in the main tier, since the level given with [`Aviffy::seq_level_idx`]
+    /// or [`Aviffy::av1c`] is the primary image's. It's written after the primary image's data. Up to 8 images can be added (more is an error in [`Aviffy::write`]).
+    pub fn add_alternative(&mut self, av1_data: &[u8], width: u32, height: u32) -> &mut Self {
+        self.alternatives.push((av1_data.to_vec(), width, height));
+        self
+    }
+
+    /// Set whether the images added with [`Aviffy::add_alternative`] are grouped with the primary image in an `altr` group (on by default).
+    ///
+    /// Grouped images are alternatives in order of preference, starting with the primary image, and decoders pick the first they support.
+    /// Ungrouped images are independent items of a collection, like a gallery. HEIF still requires the primary image to be marked as such.
+    pub fn group_alternatives(&mut self, group: bool) -> &mut Self {
+        self.group_alternatives = group;
+        self
+    }
+
+    /// Make a stereoscopic image, where the color image given to [`Aviffy::write`] is for the left eye, and this is for the right eye.
+    ///
+    /// The images are grouped as a pair in a `ster` entity group. The right eye image must have the same size
+    /// and encoding settings as the left one, and it has no alpha. Viewers without 3D support show the left eye image.
+    pub fn stereo_right_eye(&mut self, av1_data: &[u8]) -> &mut Self {
+        self.right_eye = Some(av1_data.to_vec());
+        self
+    }
+
+    /// Add a depth map of the color image, as an auxiliary image with the `urn:mpeg:mpegB:cicp:systems:auxiliary:depth` type.
+    ///
+    /// Like the alpha, it's a monochrome image of the same size, with its own `auxl` reference to the color image.
+    /// It must have the same depth as the color image. It's written after the alternative images.
+    pub fn depth_map(&mut self, av1_data: &[u8]) -> &mut Self {
+        self.depth = Some(av1_data.to_vec());
+        self
+    }
+
+    /// Add a metadata item that describes the primary image, linked to it with a `cdsc` (content describes) reference.
+    ///
+    /// `item_type` is e.g. `Exif`, or `mime` for data identified by its `content_type`, such as XMP (`application/rdf+xml`).
+    /// The `content_type` is written only for `mime` items (and is the URI type of `uri ` items, see [`Aviffy::add_uri_item`]),
+    /// and should be empty for other types.
+    /// HEIF requires `Exif` data to start with a 4-byte offset to its TIFF header (usually 0).
+    ///
+    /// The data is written after all the images. Up to 4 items can be added (more is an error in [`Aviffy::write`]).
+    pub fn add_metadata(&mut self, item_type: FourCC, content_type: &str, data: &[u8]) -> &mut Self {
+        self.metadata.push((item_type, content_type.to_owned(), ItemData::Embedded(data.to_vec())));
+        self
+    }
+
+    /// Add a `uri ` item with data in a format identified by `uri_type`, e.g. a JSON or CBOR sidecar of an application.
+    ///
+    /// It's a metadata item like the ones from [`Aviffy::add_metadata`] (linked to the primary image with `cdsc`),
+    /// and counts towards their limit. The URI can't be empty or contain NUL bytes.
+    pub fn add_uri_item(&mut self, uri_type: &str, data: &[u8]) -> &mut Self {
+        self.add_metadata(FourCC(*b"uri "), uri_type, data)
+    }
+
+    /// Like [`Aviffy::add_metadata`], but the data is in another file (or other resource) at `url`, and nothing is written for it in this one.
+    ///
+    /// The URL is added to the `dref` box in `dinf`, and the item's `iloc` extent is the first `len` bytes of it.
+    /// Items in the same file share their `dref` entry. Support for this in decoders is rare,
+    /// but they don't need the metadata to show the image, and the other items still have their data in this file.
+    pub fn add_external_metadata(&mut self, item_type: FourCC, content_type: &str, url: &str, len: u64) -> &mut Self {
+        self.metadata.push((item_type, content_type.to_owned(), ItemData::External { url: url.to_owned(), len }));
+        self
+    }
+
+    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
+    ///
+    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
+    /// The color image must have been encoded with the chroma subsampling set in [`Aviffy::chroma_subsampling`],
+    /// which is YUV444 (`Cs444` in `rav1e`) by default.
+    ///
+    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
+    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
+    ///
+    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
+    /// `depth_bits` should be 8, 10 or 12, depending on how the image was encoded (typically 8).
+    ///
+    /// Alpha must have the same dimensions and depth as color, unless they're set with [`Aviffy::alpha_dimensions`] and [`Aviffy::alpha_depth_bits`].
+    ///
+    /// Data is written (streamed) to `into_output`. The image data is written straight from the given slices,
+    /// without copying it into a temporary buffer first, so `into_output` should be buffered if it's a file.
+    ///
+    /// Fails if the bit depth isn't supported by AV1 or the chosen chroma subsampling, or if the width or height is 0 or above 65536.
+    /// All invalid arguments and settings are reported as an [`Error`] rather than by panicking, and errors of `into_output` are [`Error::Io`].
+    /// Empty `alpha_av1_data` is an error too, rather than an alpha item without data, so use `None` for images without alpha.
+    pub fn write<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        self.write_with_extents(into_output, &[color_av1_data], alpha_av1_data.as_ref().map(slice::from_ref), width, height, depth_bits)
+    }
+
+    /// Like [`Aviffy::write`], but the color and alpha data can be given in several pieces (e.g. buffers of separate tile groups),
+    /// which don't need to be concatenated first.
+    ///
+    /// Every piece gets its own extent in `iloc`, and the pieces are written one after another in `mdat` (or `idat`, see [`Aviffy::idat_threshold`]).
+    /// Decoders will see each image as the concatenation of its pieces. There can be up to 16 pieces per image, and they can't be empty.
+    ///
+    /// Options that read the AV1 bitstream (such as [`Aviffy::validate_bitstream`]) make a concatenated copy of the pieces.
+    pub fn write_with_extents<W: io::Write>(&self, into_output: W, color_chunks: &[&[u8]], alpha_chunks: Option<&[&[u8]]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        let color_chunks = slice_chunks(color_chunks)?;
+        let alpha_chunks = alpha_chunks.map(slice_chunks).transpose()?;
+        Ok(self.build_file(&color_chunks, alpha_chunks.as_deref(), width, height, depth_bits, |file| file.write(into_output))?)
+    }
+
+    /// Like [`Aviffy::write`], but for outputs that can seek, such as files. It makes the same file.
+    ///
+    /// Instead of computing the size of `meta` and the positions of the data up front, it writes them first,
+    /// and then seeks back to fill them in. The file is written after the current position of `into_output`.
+    pub fn write_seekable<W: io::Write + io::Seek>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        let alpha_chunks = alpha_av1_data.map(|data| [DataChunk::Slice(data)]);
+        Ok(self.build_file(&[DataChunk::Slice(color_av1_data)], alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| file.write_seekable(into_output))?)
+    }
+
+    /// Like [`Aviffy::write`], but the color and alpha data is copied from readers, without buffering all of it in memory.
+    ///
+    /// The readers are given with the lengths of their data, which must be known up front, because they're written before the data.
+    /// If a reader ends early or has more data than its length, it's an error, and the output is incomplete.
+    ///
+    /// Options that read the AV1 bitstream (such as [`Aviffy::validate_bitstream`]) can't be used, and [`Aviffy::idat_threshold`] is ignored.
+    pub fn write_streaming<W: io::Write, R: io::Read>(&self, into_output: W, color: (R, u64), alpha: Option<(R, u64)>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        let (color, color_len) = color;
+        let color_chunks = [streamed_chunk(color_len)?];
+        let alpha_chunks = alpha.as_ref().map(|&(_, len)| streamed_chunk(len).map(|chunk| [chunk])).transpose()?;
+
+        // Called in the order the data is in the file
+        let mut readers = ArrayVec::<R, 2>::new();
+        match (alpha, self.data_order) {
+            (Some((alpha, _)), DataOrder::AlphaFirst) => readers.extend([alpha, color]),
+            (Some((alpha, _)), DataOrder::ColorFirst) => readers.extend([color, alpha]),
+            (None, _) => readers.push(color),
+        }
+        let mut readers = readers.into_iter();
+        Ok(self.build_file(&color_chunks, alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            file.write_streaming(into_output, |out, len| {
+                let mut reader = readers.next().expect("a reader for every streamed piece");
+                let copied = io::copy(&mut io::Read::take(&mut reader, len as u64), out)?;
+                if copied != len as u64 {
+                    return Err(Error::MissingData(len as u64 - copied).into());
+                }
+                if reader.read(&mut [0])? != 0 {
+                    return Err(Error::ExcessData(len as u64).into());
+                }
+                Ok(())
+            })
+        })?)
+    }
+
+    /// Writes the file up to the color and alpha data, which is then written to the returned [`PendingPayload`].
+    ///
+    /// This is for data that arrives later, e.g. from a network connection. Like in [`Aviffy::write_streaming`],
+    /// its length must be known up front, options that read the AV1 bitstream can't be used, and [`Aviffy::idat_threshold`] is ignored.
+    pub fn start_write<W: io::Write>(&self, mut into_output: W, color_len: u64, alpha_len: Option<u64>, width: u32, height: u32, depth_bits: u8) -> Result<PendingPayload<W>, Error> {
+        let color_chunks = [streamed_chunk(color_len)?];
+        let alpha_chunks = alpha_len.map(|len| streamed_chunk(len).map(|chunk| [chunk])).transpose()?;
+        let (remaining, rest) = self.build_file(&color_chunks, alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            file.write_until_streamed(&mut into_output)
+        })?;
+        Ok(PendingPayload { out: into_output, remaining, rest })
+    }
+
+    /// Writes a complete file with space for up to `max_color_len` and `max_alpha_len` bytes of data, filled with zeros.
+    ///
+    /// This is for encoders that know how large their output can be before they have it. Write the data at
+    /// [`Reservation::color_range`] and [`Reservation::alpha_range`], and then call [`Reservation::finish`] with the actual lengths.
+    /// Like in [`Aviffy::write_streaming`], options that read the AV1 bitstream can't be used, and [`Aviffy::idat_threshold`] is ignored.
+    pub fn reserve<W: io::Write + io::Seek>(&self, mut into_output: W, max_color_len: u64, max_alpha_len: Option<u64>, width: u32, height: u32, depth_bits: u8) -> Result<Reservation<W>, Error> {
+        let color_chunks = [streamed_chunk(max_color_len)?];
+        let alpha_chunks = max_alpha_len.map(|len| streamed_chunk(len).map(|chunk| [chunk])).transpose()?;
+        let reserved_ids: &[u16] = if max_alpha_len.is_some() { &[1, 2] } else { &[1] };
+        let layout = self.build_file(&color_chunks, alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            file.write_reserved(&mut into_output, reserved_ids)
+        })?;
+        Ok(Reservation { out: into_output, layout })
+    }
+
+    /// Checks the settings and makes the boxes, which are given to `output` to be written
+    fn build_file<R>(&self, color_chunks: &[DataChunk<'_>], alpha_chunks: Option<&[DataChunk<'_>]>, width: u32, height: u32, depth_bits: u8, output: impl FnOnce(&mut AvifFile<'_>) -> io::Result<R>) -> io::Result<R> {
+        // From here on width and height are the encoded size
+        let (width, height, crop) = match (self.encoded_size, self.crop) {
+            (Some(_), Some(_)) => return Err(Error::EncodedSizeWithCrop.into()),
+            (Some((encoded_width, encoded_height)), None) => {
+                (encoded_width, encoded_height, Some((width, height)).filter(|&display| display != (encoded_width, encoded_height)))
+            },
+            (None, crop) => (width, height, crop),
+        };
+        let alpha_size = self.alpha_dimensions.unwrap_or((width, height));
+        let alternative_sizes = self.alternatives.iter().map(|&(_, width, height)| (width, height));
+        if let Some((width, height)) = std::iter::once((width, height)).chain(Some(alpha_size)).chain(alternative_sizes).find(|&(width, height)| !(1..=level::MAX_DIMENSION).contains(&width) || !(1..=level::MAX_DIMENSION).contains(&height)) {
+            return Err(Error::InvalidDimensions { width, height }.into());
+        }
+        // The setters don't fail, so the limits are checked here
+        if self.alternatives.len() > MAX_ALTERNATIVES {
+            return Err(Error::TooManyItems { what: "alternative images", max: MAX_ALTERNATIVES }.into());
+        }
+        if self.metadata.len() > MAX_METADATA {
+            return Err(Error::TooManyItems { what: "metadata items", max: MAX_METADATA }.into());
+        }
+        if self.trailing_boxes.len() > MAX_TRAILING {
+            return Err(Error::TooManyItems { what: "boxes", max: MAX_TRAILING }.into());
+        }
+        // A zero-length extent would mean the rest of the file
+        if color_chunks.iter().all(|c| c.is_empty()) {
+            return Err(Error::EmptyColor.into());
+        }
+        if let Some(alpha_chunks) = alpha_chunks {
+            if alpha_chunks.iter().all(|c| c.is_empty()) {
+                return Err(Error::EmptyAlpha.into());
+            }
+        }
+        // These are color images too, placed after the primary one
+        let extra_images = self.alternatives.iter().map(|(data, width, height)| (&data[..], *width, *height))
+            .chain(self.right_eye.as_ref().map(|data| (&data[..], width, height)))
+            .collect::<ArrayVec<_, { MAX_ALTERNATIVES + 1 }>>();
+        if extra_images.iter().any(|(data, ..)| data.is_empty()) {
+            return Err(Error::EmptyColor.into());
+        }
+        // Only these options look inside the data, so there's no need to concatenate it otherwise
+        let reads_bitstream = self.strip_temporal_delimiters || self.trim_trailing_obus || self.check_obu_start || self.tile_group_extents || self.validate_bitstream || self.derive_av1c_from_bitstream || !self.strict_av1c || self.embed_sequence_header;
+        let (color_av1_data, alpha_av1_data) = if reads_bitstream {
+            (self.bitstream(color_chunks)?, alpha_chunks.map(|chunks| self.bitstream(chunks)).transpose()?)
+        } else {
+            (Cow::Borrowed(&[][..]), None)
+        };
+        let color_av1_data = &*color_av1_data;
+        let alpha_av1_data = alpha_av1_data.as_deref();
+        let extra_images_data = extra_images.iter()
+            .map(|&(data, ..)| if reads_bitstream { self.bitstream(&[DataChunk::Slice(data)]) } else { Ok(Cow::Borrowed(data)) })
+            .collect::<io::Result<ArrayVec<_, { MAX_ALTERNATIVES + 1 }>>>()?;
+        if matches!(&self.depth, Some(data) if data.is_empty()) {
+            return Err(Error::EmptyDepth.into());
+        }
+        let depth_data = self.depth.as_ref()
+            .map(|data| if reads_bitstream { self.bitstream(&[DataChunk::Slice(data)]) } else { Ok(Cow::Borrowed(&data[..])) })
+            .transpose()?;
+        // Stripping and trimming change the data, so it's all in one piece now
+        let stripped_color;
+        let stripped_alpha;
+        let tile_group_chunks = |data| -> io::Result<Vec<_>> {
+            Ok(obu::split_at_tile_groups(data)?.into_iter().map(DataChunk::Slice).collect())
+        };
+        let split_color;
+        let split_alpha;
+        let (color_chunks, alpha_chunks) = if self.tile_group_extents {
+            split_color = tile_group_chunks(color_av1_data)?;
+            split_alpha = alpha_av1_data.map(tile_group_chunks).transpose()?;
+            (&split_color[..], split_alpha.as_deref())
+        } else if self.strip_temporal_delimiters || self.trim_trailing_obus {
+            stripped_color = [DataChunk::Slice(color_av1_data)];
+            stripped_alpha = alpha_av1_data.map(|alpha| [DataChunk::Slice(alpha)]);
+            (&stripped_color[..], stripped_alpha.as_ref().map(|alpha| &alpha[..]))
+        } else {
+            (color_chunks, alpha_chunks)
+        };
+
+        let mut image_items = ArrayVec::new();
+        let mut iloc_items = ArrayVec::new();
+        let mut compatible_brands = ArrayVec::new();
+        let mut ipma_entries = ArrayVec::new();
+        let mut data_chunks = ArrayVec::<DataChunk<'_>, MAX_DATA_CHUNKS>::new();
+        let mut iref = IrefBox::default();
+        let mut ipco = IpcoBox::new();
+        let color_image_id = 1;
+        let alpha_image_id = 2;
+        let (high_bitdepth, twelve_bit) = av1_depth_flags(depth_bits)?;
+        let alpha_depth_bits = self.alpha_depth_bits.unwrap_or(depth_bits);
+        let (alpha_high_bitdepth, alpha_twelve_bit) = av1_depth_flags(alpha_depth_bits)?;
+        let pixi_depth_bits = match self.output_depth_bits {
+            Some(output_depth_bits) if output_depth_bits == 0 || output_depth_bits > 16 || (output_depth_bits != depth_bits && !self.allow_output_depth_mismatch) => {
+                return Err(Error::OutputDepth { output_depth_bits, depth_bits }.into());
+            },
+            Some(output_depth_bits) => output_depth_bits,
+            None => depth_bits,
+        };
+        // The av1C may come from elsewhere, and it must not contradict depth_bits
+        let check_av1c_depth = |av1c: &Av1CBox<'_>, depth_bits: u8| {
+            if av1c.bit_depth() != depth_bits {
+                return Err(Error::Av1CDepth { av1c: av1c.bit_depth(), depth_bits });
+            }
+            Ok(())
+        };
+        // Chrome checks the redundant fields of av1C against the sequence header, so they're either forced to the configured values, or copied from it
+        let av1c_for = |av1_data: &[u8], configured: Av1CBox<'static>| -> io::Result<Av1CBox<'static>> {
+            if self.derive_av1c_from_bitstream {
+                return Ok(SequenceHeader::find(av1_data)?.to_av1c());
+            }
+            if !self.strict_av1c {
+                if let Ok(seq) = SequenceHeader::find(av1_data) {
+                    return Ok(seq.to_av1c());
+                }
+            }
+            Ok(configured)
+        };
+
+        // AV1 flags monochrome as subsampled, and allows it wherever 4:2:0 is
+        let chroma_subsampling = if self.monochrome { ChromaSubsampling::Cs420 } else { self.chroma_subsampling };
+        let seq_profile_for = |subsampling: ChromaSubsampling, depth_bits: u8| subsampling.av1_seq_profile(depth_bits).ok_or(Error::UnsupportedSubsampling { subsampling, depth_bits });
+        let seq_profile = match self.seq_profile {
+            Some(seq_profile @ 0..=2) => {
+                // Encoders may use a higher profile than necessary, but a lower one can't have the data (e.g. 12-bit is only in profile 2)
+                let expected = seq_profile_for(chroma_subsampling, depth_bits)?;
+                if seq_profile < expected {
+                    return Err(Error::ProfileMismatch { seq_profile, expected }.into());
+                }
+                seq_profile
+            },
+            Some(seq_profile) => return Err(Error::InvalidProfile(seq_profile).into()),
+            None => seq_profile_for(chroma_subsampling, depth_bits)?,
+        };
+        // alpha is always monochrome
+        let alpha_seq_profile = seq_profile_for(ChromaSubsampling::Cs420, alpha_depth_bits)?;
+        let checked_level = |seq_level_idx: u8, high_tier: bool| {
+            if seq_level_idx > level::LEVEL_MAX {
+                return Err(Error::InvalidLevel(seq_level_idx));
+            }
+            if high_tier && seq_level_idx < level::LEVEL_4_0 {
+                return Err(Error::NoHighTier(seq_level_idx));
+            }
+            Ok(seq_level_idx)
+        };
+        let seq_level_idx_0 = checked_level(self.seq_level_idx.unwrap_or_else(|| level::seq_level_idx_for_size(width, height)), self.seq_tier)?;
+        let alpha_seq_tier_0 = self.alpha_seq_tier.unwrap_or(self.seq_tier);
+        let alpha_seq_level_idx_0 = checked_level(self.alpha_seq_level_idx.unwrap_or(seq_level_idx_0), alpha_seq_tier_0)?;
+        if self.nclx.matrix_coefficients == MatrixCoefficients::Identity {
+            if self.monochrome {
+                return Err(Error::IdentityMatrixMonochrome.into());
+            }
+            if chroma_subsampling != ChromaSubsampling::Cs444 {
+                return Err(Error::IdentityMatrixSubsampling(chroma_subsampling).into());
+            }
+            // AV1's sequence header implies full range for this combination
+            if self.nclx.color_primaries == ColorPrimaries::Bt709 && self.nclx.transfer_characteristics == TransferCharacteristics::Srgb && !self.nclx.full_range_flag {
+                return Err(Error::SrgbLimitedRange.into());
+            }
+        }
+        let check_dimensions = |width: u32, height: u32| {
+            if !self.monochrome && !self.allow_odd_dimensions {
+                let (subsampled_x, subsampled_y) = chroma_subsampling.xy();
+                if (subsampled_x && width & 1 != 0) || (subsampled_y && height & 1 != 0) {
+                    return Err(Error::OddDimensions { subsampling: chroma_subsampling, width, height });
+                }
+            }
+            Ok(())
+        };
+        check_dimensions(width, height)?;
+        for &(_, width, height) in &extra_images {
+            check_dimensions(width, height)?;
+        }
+        let initial_presentation_delay_minus_one = match self.initial_presentation_delay {
+            Some(frames @ 1..=16) => Some(frames - 1),
+            Some(frames) => return Err(Error::InitialPresentationDelay(frames).into()),
+            None => None,
+        };
+        let clap = match crop {
+            Some((crop_width, crop_height)) if crop_width == 0 || crop_height == 0 || crop_width > width || crop_height > height => {
+                return Err(Error::InvalidCrop { width, height, crop_width, crop_height }.into());
+            },
+            Some((crop_width, crop_height)) => Some(ClapBox::top_left(width, height, crop_width, crop_height)),
+            None => None,
+        };
+        let (alpha_width, alpha_height) = self.alpha_dimensions.unwrap_or((width, height));
+        if (alpha_width, alpha_height) != (width, height) && clap.is_some() {
+            return Err(Error::AlphaDimensionsWithCrop.into());
+        }
+        if self.validate_bitstream {
+            let seq = SequenceHeader::find(color_av1_data)?;
+            let (frame_width, frame_height) = seq.frame_size(color_av1_data)?;
+            if frame_width != width {
+                return Err(BitstreamMismatch::Width { configured: width, bitstream: frame_width }.into());
+            }
+            if frame_height != height {
+                return Err(BitstreamMismatch::Height { configured: height, bitstream: frame_height }.into());
+            }
+            if seq.bit_depth != depth_bits {
+                return Err(BitstreamMismatch::Depth { configured: depth_bits, bitstream: seq.bit_depth }.into());
+            }
+            if seq.mono_chrome != self.monochrome {
+                return Err(BitstreamMismatch::Monochrome { configured: self.monochrome, bitstream: seq.mono_chrome }.into());
+            }
+            let bitstream_subsampling = seq.chroma_subsampling()?;
+            if bitstream_subsampling != chroma_subsampling {
+                return Err(BitstreamMismatch::ChromaSubsampling { configured: chroma_subsampling, bitstream: bitstream_subsampling }.into());
+            }
+            if let Some(alpha_av1_data) = alpha_av1_data {
+                let alpha_seq = SequenceHeader::find(alpha_av1_data)?;
+                // The alpha av1C is always written as monochrome
+                if !alpha_seq.mono_chrome {
+                    return Err(BitstreamMismatch::AlphaHasColor.into());
+                }
+                let alpha_size = alpha_seq.frame_size(alpha_av1_data)?;
+                if alpha_size != (alpha_width, alpha_height) {
+                    return Err(BitstreamMismatch::AlphaSize { configured: (alpha_width, alpha_height), bitstream: alpha_size }.into());
+                }
+            }
+        }
+        if self.alpha_urn.as_str().contains('\0') {
+            return Err(Error::InvalidUrn.into());
+        }
+        let has_content_type = |typ: FourCC| typ.0 == *b"mime" || typ.0 == *b"uri ";
+        let is_valid_data = |data: &ItemData| match data {
+            ItemData::Embedded(data) => !data.is_empty(),
+            ItemData::External { url, len } => !url.is_empty() && !url.contains('\0') && *len > 0 && usize::try_from(*len).is_ok(),
+        };
+        if self.metadata.iter().any(|(typ, content_type, data)| !is_valid_data(data) || content_type.contains('\0') || content_type.is_empty() == has_content_type(*typ)) {
+            return Err(Error::InvalidMetadata.into());
+        }
+        if self.mdat_alignment == 0 {
+            return Err(Error::ZeroAlignment.into());
+        }
+        if ![*b"avif", *b"avis"].contains(&self.major_brand.0) {
+            return Err(Error::UnsupportedBrand(self.major_brand).into());
+        }
+        if let Some((typ, _)) = self.trailing_boxes.iter().find(|(typ, _)| [*b"ftyp", *b"meta", *b"mdat"].contains(&typ.0)) {
+            return Err(Error::ReservedBox(*typ).into());
+        }
+
+        let (chroma_subsampling_x, chroma_subsampling_y) = chroma_subsampling.xy();
+        let computed_av1c = Av1CBox {
+            seq_profile,
+            seq_level_idx_0,
+            seq_tier_0: self.seq_tier,
+            high_bitdepth,
+            twelve_bit,
+            monochrome: self.monochrome,
+            chroma_subsampling_x,
+            chroma_subsampling_y,
+            chroma_sample_position: if !self.monochrome && chroma_subsampling == ChromaSubsampling::Cs420 { self.chroma_sample_position as u8 } else { 0 },
+            initial_presentation_delay_minus_one: None,
+            config_obus: &[],
+        };
+        let color_pixi = PixiBox {
+            depths: if self.monochrome { [pixi_depth_bits].iter().copied().collect() } else { ArrayVec::from([pixi_depth_bits; 3]) },
+        };
+
+        image_items.push(InfeBox {
+            id: color_image_id,
+            typ: FourCC(*b"av01"),
+            name: "",
+            content_type: None,
+            // HEIF doesn't allow the primary item to be hidden
+            hidden: false,
+        });
+        let ispe_prop = self.push_prop(&mut ipco, IpcoProp::Ispe(IspeBox { width, height }));
+        let mut prop_ids = ArrayVec::new();
+        prop_ids.push(ispe_prop);
+        // This is redundant, but Chrome wants it, and checks that it matches :(
+        let mut av1c = match &self.av1c {
+            Some(config) => config.to_av1c(),
+            None => av1c_for(color_av1_data, computed_av1c)?,
+        };
+        if initial_presentation_delay_minus_one.is_some() {
+            av1c.initial_presentation_delay_minus_one = initial_presentation_delay_minus_one;
+        }
+        if self.embed_sequence_header {
+            av1c.config_obus = obu::sequence_header_obu(color_av1_data)?;
+        }
+        check_av1c_depth(&av1c, depth_bits)?;
+        let av1c_prop = self.push_prop(&mut ipco, IpcoProp::Av1C(av1c));
+        prop_ids.push(av1c_prop);
+        // Useless bloat
+        if self.write_pixi {
+            let pixi_color = self.push_prop(&mut ipco, IpcoProp::Pixi(color_pixi.clone()));
+            prop_ids.push(pixi_color);
+        }
+        let colr_prop = if self.nclx != Nclx::default() {
+            Some(self.push_prop(&mut ipco, IpcoProp::Colr(ColrBox::Nclx(self.nclx))))
+        } else {
+            None
+        };
+        prop_ids.extend(colr_prop);
+        let icc_prop = self.icc_profile.as_ref().map(|profile| self.push_prop(&mut ipco, IpcoProp::Colr(ColrBox::Icc(profile))));
+        prop_ids.extend(icc_prop);
+        if let Some(layer_id) = self.layer_id {
+            prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Lsel(layer_id)));
+        }
+        // Transformative properties must come after the descriptive ones
+        let clap_prop = clap.map(|clap| self.push_prop(&mut ipco, IpcoProp::Clap(clap)));
+        prop_ids.extend(clap_prop);
+        ipma_entries.push(IpmaEntry {
+            item_id: color_image_id,
+            prop_ids,
+        });
+
+        if let Some(alpha_chunks) = alpha_chunks {
+            image_items.push(InfeBox {
+                id: alpha_image_id,
+                typ: FourCC(*b"av01"),
+                name: "",
+                content_type: None,
+                hidden: self.hidden_alpha,
+            });
+            let mut prop_ids = ArrayVec::new();
+            // shared with color if it's the same size
+            let alpha_ispe_prop = self.push_prop(&mut ipco, IpcoProp::Ispe(IspeBox { width: alpha_width, height: alpha_height }));
+            prop_ids.push(alpha_ispe_prop);
+            let mut av1c = match &self.alpha_av1c {
+                Some(config) => config.to_av1c(),
+                None => av1c_for(alpha_av1_data.unwrap_or_default(), Av1CBox {
+                    seq_profile: alpha_seq_profile,
+                    seq_level_idx_0: alpha_seq_level_idx_0,
+                    seq_tier_0: alpha_seq_tier_0,
+                    high_bitdepth: alpha_high_bitdepth,
+                    twelve_bit: alpha_twelve_bit,
+                    monochrome: true,
+                    chroma_subsampling_x: true,
+                    chroma_subsampling_y: true,
+                    chroma_sample_position: 0,
+                    initial_presentation_delay_minus_one: None,
+                    config_obus: &[],
+                })?,
+            };
+            if initial_presentation_delay_minus_one.is_some() {
+                av1c.initial_presentation_delay_minus_one = initial_presentation_delay_minus_one;
+            }
+            if self.embed_sequence_header {
+                av1c.config_obus = obu::sequence_header_obu(alpha_av1_data.unwrap_or_default())?;
+            }
+            check_av1c_depth(&av1c, alpha_depth_bits)?;
+            let av1c_prop = self.push_prop(&mut ipco, IpcoProp::Av1C(av1c));
+            prop_ids.push(av1c_prop);
+            // So pointless
+            let pixi_1 = if self.write_pixi {
+                Some(self.push_prop(&mut ipco, IpcoProp::Pixi(PixiBox {
+                    depths: [alpha_depth_bits].iter().copied().collect(),
+                })))
+            } else {
+                None
+            };
+            let alpha_colr_prop = if self.alpha_colr {
+                Some(self.push_prop(&mut ipco, IpcoProp::Colr(ColrBox::Nclx(Nclx::ALPHA))))
+            } else {
+                None
+            };
+
+            // that's a silly way to add 1 bit of information, isn't it?
+            let auxc_prop = self.push_prop(&mut ipco, IpcoProp::AuxC(AuxCBox {
+                urn: self.alpha_urn.as_str(),
+                aux_subtype: &[],
+            }));
+            iref.push(alpha_image_id, color_image_id, FourCC(*b"auxl"));
+            if self.alpha_mode == AlphaMode::Premultiplied {
+                iref.push(color_image_id, alpha_image_id, FourCC(*b"prem"));
+            }
+            prop_ids.push(auxc_prop);
+            prop_ids.extend(pixi_1);
+            prop_ids.extend(alpha_colr_prop);
+            // the alpha must be cropped the same way
+            prop_ids.extend(clap_prop);
+            ipma_entries.push(IpmaEntry {
+                item_id: alpha_image_id,
+                prop_ids,
+            });
+
+            // Use interleaved color and alpha, by default with alpha first.
+            // Makes it possible to display partial image.
+            let (color_extents, alpha_extents) = match self.data_order {
+                DataOrder::AlphaFirst => {
+                    let alpha_extents = extents(alpha_chunks, &mut data_chunks)?;
+                    (extents(color_chunks, &mut data_chunks)?, alpha_extents)
+                },
+                DataOrder::ColorFirst => {
+                    let color_extents = extents(color_chunks, &mut data_chunks)?;
+                    (color_extents, extents(alpha_chunks, &mut data_chunks)?)
+                },
+            };
+            iloc_items.push(IlocItem {
+                id: color_image_id,
+                data_ref_index: 0,
+                base_offset: 0,
+                extents: color_extents,
+            });
+            iloc_items.push(IlocItem {
+                id: alpha_image_id,
+                data_ref_index: 0,
+                base_offset: 0,
+                extents: alpha_extents,
+            });
+        } else {
+            iloc_items.push(IlocItem {
+                id: color_image_id,
+                data_ref_index: 0,
+                base_offset: 0,
+                extents: extents(color_chunks, &mut data_chunks)?,
+            });
+        };
+
+        // Where the data of each group after the primary image starts, if it has its own mdat
+        let mut mdat_splits = ArrayVec::<usize, { MAX_MDAT - 1 }>::new();
+        if self.separate_other_images {
+            mdat_splits.push(data_chunks.len());
+        }
+        let mut grpl = GrplBox::default();
+        // ids of items and groups must be unique, so groups are numbered after the items
+        let depth_image_id = alpha_image_id + 1 + extra_images.len() as u16;
+        let first_metadata_id = depth_image_id + u16::from(depth_data.is_some());
+        let mut next_group_id = u32::from(first_metadata_id) + self.metadata.len() as u32;
+        if !extra_images.is_empty() {
+            let mut alternative_ids = ArrayVec::new();
+            alternative_ids.push(color_image_id.into());
+            for (i, (data, &(_, width, height))) in extra_images_data.iter().zip(&extra_images).enumerate() {
+                let id = alpha_image_id + 1 + i as u16;
+                let is_right_eye = i == self.alternatives.len();
+                image_items.push(InfeBox {
+                    id,
+                    typ: FourCC(*b"av01"),
+                    name: "",
+                    content_type: None,
+                    hidden: false,
+                });
+                let mut prop_ids = ArrayVec::new();
+                prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Ispe(IspeBox { width, height })));
+                // The level describes the primary image, which may be much smaller. The right eye has the same size.
+                // The configOBUs of a given av1C are the primary image's sequence header, so they're never copied.
+                let primary = self.av1c.as_ref().map_or(computed_av1c, |config| Av1CBox { config_obus: &[], ..config.av1c });
+                let configured = if is_right_eye {
+                    primary
+                } else {
+                    Av1CBox { seq_level_idx_0: level::seq_level_idx_for_size(width, height), seq_tier_0: false, ..primary }
+                };
+                let mut av1c = av1c_for(data, configured)?;
+                if initial_presentation_delay_minus_one.is_some() {
+                    av1c.initial_presentation_delay_minus_one = initial_presentation_delay_minus_one;
+                }
+                if self.embed_sequence_header {
+                    av1c.config_obus = obu::sequence_header_obu(data)?;
+                }
+                check_av1c_depth(&av1c, depth_bits)?;
+                prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Av1C(av1c)));
+                if self.write_pixi {
+                    prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Pixi(color_pixi.clone())));
+                }
+                prop_ids.extend(colr_prop);
+                prop_ids.extend(icc_prop);
+                if is_right_eye {
+                    // same size as the left eye, so it's cropped the same way
+                    prop_ids.extend(clap_prop);
+                }
+                ipma_entries.push(IpmaEntry {
+                    item_id: id,
+                    prop_ids,
+                });
+                iloc_items.push(IlocItem {
+                    id,
+                    data_ref_index: 0,
+                    base_offset: 0,
+                    extents: extents(&[DataChunk::Slice(data)], &mut data_chunks)?,
+                });
+                if is_right_eye {
+                    let mut entity_ids = ArrayVec::new();
+                    entity_ids.push(color_image_id.into());
+                    entity_ids.push(id.into());
+                    grpl.groups.push(EntityToGroupBox {
+                        typ: FourCC(*b"ster"),
+                        group_id: next_group_id,
+                        entity_ids,
+                    });
+                    next_group_id += 1;
+                } else {
+                    alternative_ids.push(id.into());
+                }
+            }
+            if self.group_alternatives && alternative_ids.len() > 1 {
+                grpl.groups.push(EntityToGroupBox {
+                    typ: FourCC(*b"altr"),
+                    group_id: next_group_id,
+                    entity_ids: alternative_ids,
+                });
+            }
+        }
+
+        if let Some(data) = &depth_data {
+            image_items.push(InfeBox {
+                id: depth_image_id,
+                typ: FourCC(*b"av01"),
+                name: "",
+                content_type: None,
+                hidden: false,
+            });
+            let mut prop_ids = ArrayVec::new();
+            prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Ispe(IspeBox { width, height })));
+            let mut av1c = av1c_for(data, Av1CBox {
+                seq_profile: seq_profile_for(ChromaSubsampling::Cs420, depth_bits)?,
+                monochrome: true,
+                chroma_subsampling_x: true,
+                chroma_subsampling_y: true,
+                chroma_sample_position: 0,
+                ..computed_av1c
+            })?;
+            if initial_presentation_delay_minus_one.is_some() {
+                av1c.initial_presentation_delay_minus_one = initial_presentation_delay_minus_one;
+            }
+            if self.embed_sequence_header {
+                av1c.config_obus = obu::sequence_header_obu(data)?;
+            }
+            check_av1c_depth(&av1c, depth_bits)?;
+            prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Av1C(av1c)));
+            if self.write_pixi {
+                prop_ids.push(self.push_prop(&mut ipco, IpcoProp::Pixi(PixiBox {
+                    depths: [depth_bits].iter().copied().collect(),
+                })));
+            }
+            prop_ids.push(self.push_prop(&mut ipco, IpcoProp::AuxC(AuxCBox {
+                urn: "urn:mpeg:mpegB:cicp:systems:auxiliary:depth",
+                aux_subtype: &[],
+            })));
+            prop_ids.extend(clap_prop);
+            ipma_entries.push(IpmaEntry {
+                item_id: depth_image_id,
+                prop_ids,
+            });
+            // Each auxiliary image has its own reference
+            iref.push(depth_image_id, color_image_id, FourCC(*b"auxl"));
+            iloc_items.push(IlocItem {
+                id: depth_image_id,
+                data_ref_index: 0,
+                base_offset: 0,
+                extents: extents(&[DataChunk::Slice(data)], &mut data_chunks)?,
+            });
+        }
+
+        if self.separate_metadata {
+            mdat_splits.push(data_chunks.len());
+        }
+        let mut dinf = DinfBox::default();
+        for (i, (typ, content_type, data)) in self.metadata.iter().enumerate() {
+            let id = first_metadata_id + i as u16;
+            image_items.push(InfeBox {
+                id,
+                typ: *typ,
+                name: "",
+                content_type: Some(&content_type[..]).filter(|_| has_content_type(*typ)),
+                hidden: false,
+            });
+            iref.push(id, color_image_id, FourCC(*b"cdsc"));
+            let (data_ref_index, extents) = match data {
+                ItemData::Embedded(data) => (0, extents(&[DataChunk::Slice(data)], &mut data_chunks)?),
+                ItemData::External { url, len } => {
+                    let index = match dinf.urls.iter().position(|&u| u == url) {
+                        Some(index) => index,
+                        None => {
+                            dinf.urls.push(url);
+                            dinf.urls.len() - 1
+                        },
+                    };
+                    // from the start of the other file, so it's not relative to mdat
+                    let extent = IlocExtent { offset: IlocOffset::Absolute(0), len: *len as usize };
+                    // 0 is this file
+                    (index as u16 + 1, std::iter::once(extent).collect())
+                },
+            };
+            iloc_items.push(IlocItem {
+                id,
+                data_ref_index,
+                base_offset: 0,
+                extents,
+            });
+        }
+
+        if self.major_brand.0 != *b"avif" {
+            compatible_brands.push(FourCC(*b"avif"));
+        }
+        compatible_brands.push(FourCC(*b"mif1"));
+        compatible_brands.push(FourCC(*b"miaf"));
+        // Streamed data is written after meta
+        let in_idat = data_chunks.iter().map(|c| c.len()).sum::<usize>() <= self.idat_threshold
+            && !data_chunks.iter().any(|c| matches!(c, DataChunk::Streamed(_)));
+        let iloc_version = match self.iloc_version {
+            IlocVersion::V0 if in_idat => return Err(Error::IdatIlocVersion.into()),
+            IlocVersion::Auto | IlocVersion::V0 => if in_idat { 1 } else { 0 },
+            IlocVersion::V1 => 1,
+            IlocVersion::V2 => 2,
+        };
+        let mut mdat = ArrayVec::new();
+        if !in_idat {
+            let mut start = 0;
+            for end in mdat_splits.iter().copied().chain(std::iter::once(data_chunks.len())) {
+                // Groups without data don't get an empty box
+                if end > start {
+                    mdat.push(MdatBox { data_chunks: &data_chunks[start..end], large: self.large_mdat });
+                }
+                start = end;
+            }
+        }
+        let mut boxes = AvifFile {
+            ftyp: FtypBox {
+                major_brand: self.major_brand,
+                minor_version: self.minor_version,
+                compatible_brands,
+            },
+            meta: MetaBox {
+                // Only still images are supported. Image sequences would also need a `moov` box with a `pict` track.
+                hdlr: HdlrBox {
+                    handler_type: FourCC(*b"pict"),
+                    name: "",
+                },
+                dinf,
+                iinf: IinfBox { items: image_items },
+                pitm: PitmBox(color_image_id),
+                iloc: IlocBox {
+                    items: iloc_items,
+                    large: self.large_offsets,
+                    version: iloc_version,
+                    in_idat,
+                    base_offsets: self.iloc_base_offsets && !in_idat,
+                },
+                iprp: IprpBox {
+                    ipco,
+                    // It's not enough to define these properties,
+                    // they must be assigned to the image
+                    ipma: IpmaBox {
+                        entries: ipma_entries,
+                    },
+                },
+                iref,
+                grpl,
+                idat: if in_idat { Some(IdatBox { data_chunks: &data_chunks }) } else { None },
+            },
+            // Here's the actual data. If HEIF wasn't such a kitchen sink, this
+            // would have been the only data this file needs.
+            mdat,
+            trailing: self.trailing_boxes.iter().map(|(typ, data)| RawBox { typ: *typ, data }).collect(),
+            end_padding: None,
+            mdat_alignment: self.mdat_alignment,
+            mdat_first: self.layout == Layout::MdatFirst,
+        };
+        if let Some(target_len) = self.target_len {
+            let content_len = boxes.serialized_len() as u64;
+            // the smallest box is 8 bytes
+            boxes.end_padding = match target_len.checked_sub(content_len) {
+                Some(0) => None,
+                Some(len) if len >= 8 => Some(FreeBox { len: usize::try_from(len).map_err(|_| Error::PadToSize { target_len, content_len })? }),
+                _ => return Err(Error::PadToSize { target_len, content_len }.into()),
+            };
+        }
+
+        output(&mut boxes)
+    }
+
+    /// [`Aviffy::write_with_extents`] with one extent per encoder packet
+    #[cfg(any(test, feature = "rav1e"))]
+    fn write_packets<'data, W: io::Write>(&self, into_output: W, color: impl Iterator<Item = &'data [u8]>, alpha: Option<impl Iterator<Item = &'data [u8]>>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        fn pieces<'data>(packets: impl Iterator<Item = &'data [u8]>) -> Result<ArrayVec<&'data [u8], MAX_EXTENTS>, Error> {
+            let mut pieces = ArrayVec::new();
+            for data in packets {
+                pieces.try_push(data).map_err(|_| Error::TooManyPieces)?;
+            }
+            Ok(pieces)
+        }
+        let color = pieces(color)?;
+        let alpha = alpha.map(pieces).transpose()?;
+        self.write_with_extents(into_output, &color, alpha.as_deref(), width, height, depth_bits)
+    }
+
+    /// Adds the property to `ipco`, and returns its `ipma` index with the essential flag
+    fn push_prop<'data>(&self, ipco: &mut IpcoBox<'data>, prop: IpcoProp<'data>) -> u8 {
+        const ESSENTIAL_BIT: u8 = 0x80;
+        let fourcc = prop.fourcc();
+        let essential = self.essential_properties.iter().find(|&&(typ, _)| typ == fourcc).map_or(prop.essential(), |&(_, essential)| essential);
+        ipco.push(prop) | if essential { ESSENTIAL_BIT } else { 0 }
+    }
+
+    /// Contiguous AV1 data for options that parse it
+    fn bitstream<'data>(&self, chunks: &[DataChunk<'data>]) -> io::Result<Cow<'data, [u8]>> {
+        let slices = chunks.iter().map(|chunk| match *chunk {
+            DataChunk::Slice(data) => Ok(data),
+            DataChunk::Streamed(_) | DataChunk::Placeholder(_) => Err(Error::StreamedBitstream),
+        }).collect::<Result<ArrayVec<_, MAX_EXTENTS>, _>>()?;
+        let joined = match *slices {
+            [data] => Cow::Borrowed(data),
+            _ => Cow::Owned(slices.concat()),
+        };
+        if self.check_obu_start {
+            obu::check_start(&joined)?;
+        }
+        let stripped = if !self.strip_temporal_delimiters {
+            joined
+        } else {
+            match joined {
+                Cow::Borrowed(data) => obu::strip_temporal_delimiters(data)?,
+                Cow::Owned(data) => Cow::Owned(obu::strip_temporal_delimiters(&data)?.into_owned()),
+            }
+        };
+        if !self.trim_trailing_obus {
+            return Ok(stripped);
+        }
+        Ok(match stripped {
+            Cow::Borrowed(data) => Cow::Borrowed(obu::trim_trailing_obus(data)?),
+            Cow::Owned(data) => Cow::Owned(obu::trim_trailing_obus(&data)?.to_vec()),
+        })
+    }
+
+    /// Computes the exact size of the file that [`Aviffy::write`] would make with data of these lengths, without writing it.
+    ///
+    /// This can be used to set `Content-Length` or preallocate storage. It fails in the same cases as [`Aviffy::write`],
+    /// and also with options that read the AV1 data (such as [`Aviffy::strip_temporal_delimiters`]), because they can change the size.
+    pub fn serialized_len(&self, color_len: usize, alpha_len: Option<usize>, width: u32, height: u32, depth_bits: u8) -> Result<u64, Error> {
+        let alpha_chunks = alpha_len.map(|len| [DataChunk::Placeholder(len)]);
+        Ok(self.build_file(&[DataChunk::Placeholder(color_len)], alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            Ok(file.serialized_len() as u64)
+        })?)
+    }
+
+    /// Where [`Aviffy::write`] would put the color and alpha data in the file, as byte ranges of every `iloc` extent, in order.
+    ///
+    /// This can be used to make an index for range requests, e.g. of the tile groups with [`Aviffy::tile_group_extents`].
+    pub fn data_extents(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<DataExtents, Error> {
+        Ok(self.build_file(&[DataChunk::Slice(color_av1_data)], alpha_av1_data.map(|data| [DataChunk::Slice(data)]).as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            let ranges = |extents: ArrayVec<(u64, usize), MAX_EXTENTS>| extents.into_iter().map(|(offset, len)| offset..offset + len as u64).collect();
+            Ok(DataExtents {
+                color: ranges(file.item_extents(1)),
+                alpha: alpha_av1_data.map(|_| ranges(file.item_extents(2))),
+            })
+        })?)
+    }
+
+    /// Describes the boxes that [`Aviffy::write`] would write, for inspecting what the settings do without parsing the file.
+    ///
+    /// The top-level boxes are in the file order. It fails in the same cases as [`Aviffy::write`].
+    pub fn box_tree(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<Vec<BoxInfo>, Error> {
+        Ok(self.build_file(&[DataChunk::Slice(color_av1_data)], alpha_av1_data.map(|data| [DataChunk::Slice(data)]).as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            Ok(file.box_tree())
+        })?)
+    }
+
+    /// Like [`Aviffy::write`], but saves the file at `path`.
+    ///
+    /// The data is written to a temporary file in the same directory first, and then renamed,
+    /// so that the file at `path` is never left half-written.
+    pub fn write_to_path(&self, path: impl AsRef<Path>, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = path.as_ref();
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+        // The name is unique to this call, so it can't clobber a user's file or another writer's temp file
+        let (file, tmp_path) = loop {
+            let mut tmp_name = OsString::from(".");
+            tmp_name.push(file_name);
+            tmp_name.push(format!(".{}.{}.tmp", process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+            let tmp_path = path.with_file_name(tmp_name);
+            match fs::OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+                Ok(file) => break (file, tmp_path),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::Io(e)),
+            }
+        };
+
+        let mut out = io::BufWriter::new(file);
+        let res = self.write(&mut out, color_av1_data, alpha_av1_data, width, height, depth_bits)
+            .and_then(|_| out.into_inner().map_err(|e| Error::Io(e.into_error())))
+            .and_then(|file| Ok(file.sync_all()?))
+            .and_then(|_| Ok(fs::rename(&tmp_path, path)?));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        res
+    }
+
+    /// Like [`Aviffy::write`], but makes a `Vec`.
+    ///
+    /// Panics if the arguments are invalid (e.g. unsupported `depth_bits`), so use [`Aviffy::try_to_vec`] instead.
+    #[deprecated(note = "use try_to_vec")]
+    pub fn to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Vec<u8> {
+        self.try_to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits).unwrap() // Vec can't fail, only the arguments can be wrong
+    }
+
+    /// Like [`Aviffy::write`], but makes a `Vec`.
+    pub fn try_to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        self.write_into_vec(&mut out, color_av1_data, alpha_av1_data, width, height, depth_bits)?;
+        Ok(out)
+    }
+
+    /// Like [`Aviffy::try_to_vec`], but replaces the contents of an existing `Vec`, reusing its capacity.
+    ///
+    /// On error the `Vec` is left empty.
+    pub fn write_into_vec(&self, out: &mut Vec<u8>, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Result<(), Error> {
+        out.clear();
+        let alpha_chunks = alpha_av1_data.map(|alpha| [DataChunk::Slice(alpha)]);
+        let res = self.build_file(&[DataChunk::Slice(color_av1_data)], alpha_chunks.as_ref().map(|c| &c[..]), width, height, depth_bits, |file| {
+            file.write_to_vec(out);
+            Ok(())
+        });
+        if res.is_err() {
+            out.clear();
+        }
+        Ok(res?)
+    }
+}
+
+/// A file started with [`Aviffy::start_write`], waiting for its color and alpha data.
+///
+/// Write the data of both images to it, in the order set with [`Aviffy::data_order`] (alpha first by default),
+/// and then call [`PendingPayload::finish`]. If it's dropped before that, the output is left incomplete.
+pub struct PendingPayload<W> {
+    out: W,
+    remaining: u64,
+    /// Everything after the color and alpha data
+    rest: Vec<u8>,
+}
+
+impl<W: io::Write> PendingPayload<W> {
+    /// Number of bytes of the color and alpha data that haven't been written yet
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Writes the rest of the file (alternative images, metadata and appended boxes) and returns the output.
+    ///
+    /// Fails with [`Error::MissingData`] if not all of the promised data has been written.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.remaining != 0 {
+            return Err(Error::MissingData(self.remaining));
+        }
+        self.out.write_all(&self.rest)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: io::Write> io::Write for PendingPayload<W> {
+    /// Fails with `InvalidInput` if the data is longer than promised, without writing any of it
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The image data is longer than promised, and only {} more bytes can be written", self.remaining)));
+        }
+        let written = self.out.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A file written with [`Aviffy::reserve`], with space for the color and alpha data.
+///
+/// The data can be written at any time before [`Reservation::finish`], through [`Reservation::get_mut`] or another handle to the same file.
+/// If it's dropped without finishing, the file is still valid, but its images are all zeros of the reserved lengths.
+pub struct Reservation<W> {
+    out: W,
+    layout: ReservedLayout,
+}
+
+impl<W: io::Write + io::Seek> Reservation<W> {
+    /// Where in the output the color data goes. It may be shorter than this.
+    pub fn color_range(&self) -> Range<u64> {
+        let item = self.layout.items[0];
+        item.offset..item.offset + item.len as u64
+    }
+
+    /// Where in the output the alpha data goes, if space has been reserved for it
+    pub fn alpha_range(&self) -> Option<Range<u64>> {
+        self.layout.items.get(1).map(|item| item.offset..item.offset + item.len as u64)
+    }
+
+    /// The output, for writing the data
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Sets the lengths of the data that has been written at the start of the reserved ranges, and returns the output, positioned at the end of the file.
+    ///
+    /// `iloc` gets the new lengths, and unused space at the end of `mdat` becomes a `free` box, so the file keeps its size.
+    /// Fails with [`Error::ExceedsReservation`], [`Error::EmptyColor`] or [`Error::EmptyAlpha`] if a length is larger than reserved or 0, or if alpha is given without having been reserved (or the other way around).
+    pub fn finish(mut self, color_len: u64, alpha_len: Option<u64>) -> Result<W, Error> {
+        let mut lens = ArrayVec::<usize, 2>::new();
+        for (item, len) in self.layout.items.iter().zip([Some(color_len), alpha_len]) {
+            let len = match len {
+                Some(0) | None if item.id == 1 => return Err(Error::EmptyColor),
+                Some(0) | None => return Err(Error::EmptyAlpha),
+                Some(len) if len > item.len as u64 => return Err(Error::ExceedsReservation { reserved: item.len as u64, len }),
+                Some(len) => len as usize,
+            };
+            lens.push(len);
+        }
+        if let (Some(len), None) = (alpha_len, self.alpha_range()) {
+            return Err(Error::ExceedsReservation { reserved: 0, len });
+        }
+        self.layout.patch(&mut self.out, &lens)?;
+        Ok(self.out)
+    }
+}
+
+fn streamed_chunk(len: u64) -> io::Result<DataChunk<'static>> {
+    usize::try_from(len).map(DataChunk::Streamed)
+        .map_err(|_| Error::PayloadTooLarge(len).into())
+}
+
+/// `high_bitdepth` and `twelve_bit` flags of `av1C` for the bit depth.
+///
+/// AV1 has no way to signal other depths, so e.g. 16-bit sources must be encoded at 12 bits.
+fn av1_depth_flags(depth_bits: u8) -> Result<(bool, bool), Error> {
+    match depth_bits {
+        8 => Ok((false, false)),
+        10 => Ok((true, false)),
+        12 => Ok((true, true)),
+        _ => Err(Error::InvalidDepth(depth_bits)),
+    }
+}
+
+fn slice_chunks<'data>(chunks: &[&'data [u8]]) -> io::Result<ArrayVec<DataChunk<'data>, MAX_EXTENTS>> {
+    if chunks.len() > MAX_EXTENTS {
+        return Err(Error::TooManyPieces.into());
+    }
+    Ok(chunks.iter().map(|&data| DataChunk::Slice(data)).collect())
+}
+
+/// Adds the pieces to the end of `mdat`, and makes `iloc` extents for them
+fn extents<'data>(chunks: &[DataChunk<'data>], data_chunks: &mut ArrayVec<DataChunk<'data>, MAX_DATA_CHUNKS>) -> io::Result<ArrayVec<IlocExtent, MAX_EXTENTS>> {
+    if chunks.len() > MAX_EXTENTS {
+        return Err(Error::TooManyPieces.into());
+    }
+    // zero length would mean the rest of the file
+    if chunks.len() > 1 && chunks.iter().any(|c| c.is_empty()) {
+        return Err(Error::EmptyPiece.into());
+    }
+    let mut offset = data_chunks.iter().map(|c| c.len()).sum();
+    let mut extents = ArrayVec::new();
+    for &chunk in chunks {
+        extents.push(IlocExtent {
+            offset: IlocOffset::Relative(offset),
+            len: chunk.len(),
+        });
+        offset += chunk.len();
+        data_chunks.push(chunk);
+    }
+    Ok(extents)
+}
+
+/// See [`serialize`] for description. This one makes a `Vec` instead of using `io::Write`.
+///
+/// Panics if the arguments are invalid (e.g. unsupported `depth_bits`), so use [`Aviffy::try_to_vec`] instead.
+#[deprecated(note = "use Aviffy::try_to_vec")]
+pub fn serialize_to_vec(color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8) -> Vec<u8> {
+    Aviffy::new().try_to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits).unwrap()
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_roundtrip_parse_mp4() {
+    let test_img = b"av12356abc";
+    let avif = serialize_to_vec(test_img, None, 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_roundtrip_parse_mp4_alpha() {
+    let test_img = b"av12356abc";
+    let test_a = b"alpha";
+    let avif = serialize_to_vec(test_img, Some(test_a), 10, 20, 8);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_a[..], ctx.alpha_item_coded_data());
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_roundtrip_parse_avif() {
+    let test_img = [1,2,3,4,5,6];
     let test_alpha = [77,88,99];
     let avif = serialize_to_vec(&test_img, Some(&test_alpha), 10, 20, 8);
 
     let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
 
-    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
-    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn premultiplied_flag() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [55,66,77,88,99];
+    let avif = Aviffy::new().premultiplied_alpha(true).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert!(ctx.premultiplied_alpha);
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn alpha_modes() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [55,66,77,88,99];
+    for &(mode, is_premultiplied) in &[(AlphaMode::Straight, false), (AlphaMode::Premultiplied, true)] {
+        let avif = Aviffy::new().alpha_mode(mode).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+        assert_eq!(avif, Aviffy::new().premultiplied_alpha(is_premultiplied).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap());
+        assert_eq!(is_premultiplied, avif.windows(4).any(|w| w == b"prem"));
+
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(is_premultiplied, ctx.premultiplied_alpha);
+        assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    }
+    assert_eq!(Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap(), Aviffy::new().alpha_mode(AlphaMode::Straight).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap());
+}
+
+#[cfg(test)]
+fn box_body<'a>(file: &'a [u8], typ: &[u8; 4]) -> Option<&'a [u8]> {
+    let pos = file.windows(4).position(|w| w == typ)?;
+    let size = u32::from_be_bytes([file[pos - 4], file[pos - 3], file[pos - 2], file[pos - 1]]) as usize;
+    Some(&file[pos + 4..pos - 4 + size])
+}
+
+#[test]
+fn strict_av1c_on() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let avif = Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 5, 5, 10).unwrap();
+
+    assert_eq!(2, avif.windows(4).filter(|w| w == b"av1C").count());
+    // marker, profile 1 level 2.0, 10-bit 4:4:4 for color
+    assert_eq!(&[0x81, 1 << 5, 1 << 6, 0], box_body(&avif, b"av1C").unwrap());
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+}
+
+#[test]
+fn strict_av1c_off() {
+    use crate::obu::test_streams::*;
+
+    // the encoder reported 4:4:4, but made 10-bit 4:2:0 at level 3.0
+    let color = stream(&SequenceConfig { bit_depth: 10, seq_profile: 0, seq_level_idx_0: 4, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let alpha = stream(&SequenceConfig { bit_depth: 10, seq_profile: 0, seq_level_idx_0: 4, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let forced = Aviffy::new().try_to_vec(&color, Some(&alpha), 64, 48, 10).unwrap();
+    let trusted = Aviffy::new().strict_av1c(false).try_to_vec(&color, Some(&alpha), 64, 48, 10).unwrap();
+    assert_eq!(2, trusted.windows(4).filter(|w| w == b"av1C").count());
+    // profile 1 level 2.0 4:4:4 from the arguments, or profile 0 level 3.0 4:2:0 from the sequence header
+    assert_eq!(&[0x81, 1 << 5, 0b0100_0000, 0], box_body(&forced, b"av1C").unwrap());
+    assert_eq!(&[0x81, 4, 0b0100_1100, 0], box_body(&trusted, b"av1C").unwrap());
+    let alpha_av1c = trusted.windows(4).rposition(|w| w == b"av1C").unwrap();
+    assert_eq!(&[0x81, 4, 0b0101_1100, 0], &trusted[alpha_av1c + 4..alpha_av1c + 8]);
+
+    let ctx = avif_parse::read_avif(&mut trusted.as_slice()).unwrap();
+    assert_eq!(&color[..], ctx.primary_item.as_slice());
+    assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+
+    // without a sequence header there's nothing to trust but the arguments
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    assert_eq!(Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap(), Aviffy::new().strict_av1c(false).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap());
+    // but the bit depth must still agree
+    let err = Aviffy::new().strict_av1c(false).try_to_vec(&color, None, 64, 48, 8).unwrap_err();
+    assert!(matches!(err, Error::Av1CDepth { av1c: 10, depth_bits: 8 }), "{:?}", err);
+}
+
+#[test]
+fn chroma_subsampling_420() {
+    let test_img = [1,2,3,4,5];
+    let test_alpha = [6,7];
+    let avif = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).try_to_vec(&test_img, Some(&test_alpha), 6, 4, 8).unwrap();
+
+    let av1c = box_body(&avif, b"av1C").unwrap();
+    assert_eq!(0, av1c[1] >> 5, "profile 0");
+    assert_eq!(0b1100, av1c[2] & 0b1111_1100, "8-bit color, subsampled x and y");
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+
+    let avif = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).try_to_vec(&test_img, None, 6, 4, 12).unwrap();
+    let av1c = box_body(&avif, b"av1C").unwrap();
+    assert_eq!(2, av1c[1] >> 5, "12-bit is always profile 2");
+    assert_eq!(0b0110_1100, av1c[2]);
+}
+
+#[test]
+fn chroma_subsampling_422() {
+    for &(depth, depth_flags) in &[(8, 0), (10, 0b10), (12, 0b11)] {
+        let avif = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs422).try_to_vec(&[1,2,3], None, 8, 3, depth).unwrap();
+        let av1c = box_body(&avif, b"av1C").unwrap();
+        assert_eq!(0x81, av1c[0]);
+        assert_eq!(2 << 5, av1c[1], "profile 2 level 2.0 at {} bits", depth);
+        // high_bitdepth, twelve_bit, monochrome, subsampling_x, subsampling_y, position
+        assert_eq!(depth_flags << 5 | 0b01000, av1c[2] & 0x7F);
+    }
+
+    let mut out = Vec::new();
+    let err = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs422).write(&mut out, &[1,2,3], None, 8, 3, 9).unwrap_err();
+    assert!(matches!(err, Error::InvalidDepth(9)), "{:?}", err);
+}
+
+#[test]
+fn identity_matrix() {
+    let test_img = [1,2,3,4,5];
+    let avif = Aviffy::new()
+        .matrix_coefficients(MatrixCoefficients::Identity)
+        .try_to_vec(&test_img, None, 10, 10, 8).unwrap();
+
+    let colr = box_body(&avif, b"colr").unwrap();
+    assert_eq!(b"nclx", &colr[..4]);
+    assert_eq!(&[0,1, 0,13, 0,0, 0x80], &colr[4..], "BT.709 primaries, sRGB transfer, identity matrix, full range");
+    let av1c = box_body(&avif, b"av1C").unwrap();
+    assert_eq!(1, av1c[1] >> 5, "profile 1");
+    assert_eq!(0, av1c[2] & 0b1100, "4:4:4");
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+
+    let mut out = Vec::new();
+    let err = Aviffy::new()
+        .matrix_coefficients(MatrixCoefficients::Identity)
+        .chroma_subsampling(ChromaSubsampling::Cs420)
+        .write(&mut out, &test_img, None, 10, 10, 8).unwrap_err();
+    assert!(matches!(err, Error::IdentityMatrixSubsampling(ChromaSubsampling::Cs420)), "{:?}", err);
+    let error = |aviffy: &mut Aviffy| {
+        aviffy.matrix_coefficients(MatrixCoefficients::Identity).try_to_vec(&test_img, None, 10, 10, 8).unwrap_err()
+    };
+    assert!(matches!(error(Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs422)), Error::IdentityMatrixSubsampling(ChromaSubsampling::Cs422)));
+    assert!(matches!(error(Aviffy::new().full_color_range(false)), Error::SrgbLimitedRange));
+    // only sRGB can't be limited range
+    let avif = Aviffy::new().matrix_coefficients(MatrixCoefficients::Identity).transfer_characteristics(TransferCharacteristics::Linear)
+        .full_color_range(false).try_to_vec(&test_img, None, 10, 10, 8).unwrap();
+    assert_eq!(&[0,1, 0,8, 0,0, 0], &box_body(&avif, b"colr").unwrap()[4..]);
+
+    // defaults don't add bloat
+    let avif = Aviffy::new().matrix_coefficients(MatrixCoefficients::Bt601).try_to_vec(&test_img, None, 10, 10, 8).unwrap();
+    assert!(box_body(&avif, b"colr").is_none());
+}
+
+#[test]
+fn appended_box() {
+    let test_img = [1,2,3,4,5];
+    let avif = Aviffy::new()
+        .append_box(FourCC(*b"free"), &[0; 16])
+        .append_box(FourCC(*b"Test"), b"hi")
+        .try_to_vec(&test_img, None, 10, 10, 8).unwrap();
+
+    let (rest, test) = avif.split_at(avif.len() - 10);
+    assert_eq!(b"\0\0\0\x0aTesthi", test);
+    let (_, free) = rest.split_at(rest.len() - 24);
+    assert_eq!(b"\0\0\0\x18free", &free[..8]);
+    assert!(free[8..].iter().all(|&b| b == 0));
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+
+    let mut out = Vec::new();
+    let err = Aviffy::new().append_box(FourCC(*b"mdat"), &[]).write(&mut out, &test_img, None, 10, 10, 8).unwrap_err();
+    assert!(matches!(err, Error::ReservedBox(typ) if typ.0 == *b"mdat"), "{:?}", err);
+}
+
+#[test]
+fn chroma_sample_position_bits() {
+    let position = |subsampling, position| {
+        let avif = Aviffy::new().chroma_subsampling(subsampling).chroma_sample_position(position).try_to_vec(&[1], None, 2, 2, 8).unwrap();
+        box_body(&avif, b"av1C").unwrap()[2] & 0b11
+    };
+    assert_eq!(0, position(ChromaSubsampling::Cs420, ChromaSamplePosition::Unknown));
+    assert_eq!(1, position(ChromaSubsampling::Cs420, ChromaSamplePosition::Vertical));
+    assert_eq!(2, position(ChromaSubsampling::Cs420, ChromaSamplePosition::Colocated));
+    assert_eq!(0, position(ChromaSubsampling::Cs444, ChromaSamplePosition::Colocated));
+
+    let avif = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).chroma_sample_position(ChromaSamplePosition::Colocated).try_to_vec(&[1], None, 2, 2, 12).unwrap();
+    assert_eq!(0b0110_1110, box_body(&avif, b"av1C").unwrap()[2], "12-bit profile 2 keeps position");
+    assert_eq!(0, position(ChromaSubsampling::Cs422, ChromaSamplePosition::Vertical));
+}
+
+#[test]
+fn monochrome_color() {
+    let test_img = [1,2,3,4,5];
+    let test_alpha = [6,7,8];
+    for &alpha in &[None, Some(&test_alpha[..])] {
+        let avif = Aviffy::new().monochrome(true).try_to_vec(&test_img, alpha, 10, 10, 8).unwrap();
+
+        let av1c = box_body(&avif, b"av1C").unwrap();
+        assert_eq!(0, av1c[1] >> 5, "profile 0");
+        assert_eq!(0b0001_1100, av1c[2], "monochrome and subsampled");
+        let pixi = box_body(&avif, b"pixi").unwrap();
+        assert_eq!(&[0,0,0,0, 1, 8], pixi);
+        // alpha has identical properties, so they're shared
+        assert_eq!(1, avif.windows(4).filter(|w| w == b"pixi").count());
+        assert_eq!(1, avif.windows(4).filter(|w| w == b"av1C").count());
+
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+        assert_eq!(alpha, ctx.alpha_item.as_deref());
+    }
+}
+
+#[test]
+fn write_to_path() {
+    let test_img = [1,2,3,4,5];
+    let test_alpha = [6,7,8];
+    let file_name = format!("avif-serialize-test-{}.avif", std::process::id());
+    let path = std::env::temp_dir().join(&file_name);
+    let temp_prefix = format!(".{}.", file_name);
+    let temp_files = || fs::read_dir(std::env::temp_dir()).unwrap()
+        .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with(&temp_prefix))
+        .count();
+    // a file that merely looks like a temp file belongs to the user
+    let user_tmp = path.with_extension("avif.tmp");
+    fs::write(&user_tmp, b"keep").unwrap();
+    // this is the only test using write_to_path, so these are the first names it tries
+    let taken: Vec<_> = (0..3).map(|n| std::env::temp_dir().join(format!("{}{}.{}.tmp", temp_prefix, std::process::id(), n))).collect();
+    for t in &taken {
+        fs::write(t, b"taken").unwrap();
+    }
+
+    Aviffy::new().write_to_path(&path, &test_img, Some(&test_alpha), 10, 10, 8).unwrap();
+
+    let avif = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 10, 10, 8).unwrap(), avif);
+    assert_eq!(taken.len(), temp_files());
+
+    assert!(Aviffy::new().write_to_path(&path, &test_img, None, 10, 10, 9).is_err());
+    assert!(!path.exists());
+    assert_eq!(taken.len(), temp_files());
+
+    assert_eq!(&b"keep"[..], &fs::read(&user_tmp).unwrap()[..]);
+    fs::remove_file(&user_tmp).unwrap();
+    for t in &taken {
+        assert_eq!(&b"taken"[..], &fs::read(t).unwrap()[..]);
+        fs::remove_file(t).unwrap();
+    }
+}
+
+#[test]
+fn av1c_from_bitstream() {
+    use crate::obu::test_streams::*;
+
+    let cases = [
+        // (encoder output, expected av1C)
+        (SequenceConfig { bit_depth: 8, ..Default::default() }, [0x81, 1 << 5 | 8, 0b0000_0000, 0]),
+        (SequenceConfig { bit_depth: 10, seq_profile: 0, subsampling_x: true, subsampling_y: true, chroma_sample_position: 1, ..Default::default() }, [0x81, 8, 0b0100_1101, 0]),
+        (SequenceConfig { bit_depth: 12, seq_profile: 2, subsampling_x: true, subsampling_y: true, reduced_still_picture_header: false, ..Default::default() }, [0x81, 2 << 5 | 8, 0b0110_1100, 0]),
+        (SequenceConfig { bit_depth: 12, seq_profile: 2, seq_level_idx_0: 12, seq_tier_0: true, reduced_still_picture_header: false, timing_info: true, ..Default::default() }, [0x81, 2 << 5 | 12, 0b1110_0000, 0]),
+        // layered, with the level of all layers, not the base layer's level 3.1
+        (SequenceConfig { seq_level_idx_0: 13, seq_tier_0: true, reduced_still_picture_header: false, initial_display_delay_minus_1: Some(2), extra_operating_points: vec![(0x101, 5, false)], ..Default::default() }, [0x81, 1 << 5 | 13, 0b1000_0000, 0b1_0010]),
+    ];
+    for (config, expected) in &cases {
+        let color = stream(config);
+        let twelve_bit = config.bit_depth == 12;
+        let alpha = stream(&SequenceConfig { seq_profile: if twelve_bit { 2 } else { 0 }, bit_depth: config.bit_depth, mono_chrome: true, subsampling_x: true, subsampling_y: true, seq_level_idx_0: 4, ..Default::default() });
+        // deliberately wrong subsampling, which should be ignored
+        let avif = Aviffy::new().derive_av1c_from_bitstream(true).try_to_vec(&color, Some(&alpha), 64, 48, config.bit_depth).unwrap();
+        assert_eq!(&expected[..], box_body(&avif, b"av1C").unwrap(), "{:?}", config);
+
+        let alpha_av1c = avif.windows(4).rposition(|w| w == b"av1C").unwrap();
+        let alpha_expected = [0x81, if twelve_bit { 2 << 5 | 4 } else { 4 }, expected[2] & 0b0110_0000 | 0b0001_1100, 0];
+        assert_eq!(&alpha_expected, &avif[alpha_av1c + 4..alpha_av1c + 8]);
+
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&color[..], ctx.primary_item.as_slice());
+        assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+    }
+
+    // the delay of the first operating point can be overridden
+    let layered = stream(&cases[4].0);
+    let avif = Aviffy::new().derive_av1c_from_bitstream(true).initial_presentation_delay(1).try_to_vec(&layered, None, 64, 48, 8).unwrap();
+    assert_eq!(&[0x81, 1 << 5 | 13, 0b1000_0000, 0b1_0000], box_body(&avif, b"av1C").unwrap());
+
+    let mut out = Vec::new();
+    let err = Aviffy::new().derive_av1c_from_bitstream(true).write(&mut out, &[1,2,3], None, 64, 48, 8).unwrap_err();
+    assert!(matches!(err, Error::InvalidAv1Data(_)), "{:?}", err);
+}
+
+#[test]
+fn bitstream_validation() {
+    use crate::obu::test_streams::*;
+
+    let check = |config: &SequenceConfig, aviffy: &mut Aviffy, width, height, depth_bits| -> Result<(), BitstreamMismatch> {
+        let mut out = Vec::new();
+        aviffy.validate_bitstream(true).write(&mut out, &stream(config), None, width, height, depth_bits)
+            .map_err(|e| match e {
+                Error::MismatchedBitstream(mismatch) => mismatch,
+                e => panic!("{:?}", e),
+            })
+    };
+
+    let av1_420_10bit = SequenceConfig { temporal_delimiter: true, seq_profile: 0, bit_depth: 10, subsampling_x: true, subsampling_y: true, ..Default::default() };
+    let superres = SequenceConfig { enable_superres: true, superres_denom: Some(16), ..Default::default() };
+    let smaller_frame = SequenceConfig { reduced_still_picture_header: false, frame_size: Some((60, 40)), ..Default::default() };
+    let mono = SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() };
+
+    assert_eq!(Ok(()), check(&av1_420_10bit, Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420), 64, 48, 10));
+    assert_eq!(Ok(()), check(&superres, &mut Aviffy::new(), 64, 48, 8));
+    assert_eq!(Ok(()), check(&smaller_frame, &mut Aviffy::new(), 60, 40, 8));
+    assert_eq!(Ok(()), check(&mono, Aviffy::new().monochrome(true), 64, 48, 8));
+
+    assert_eq!(Err(BitstreamMismatch::Width { configured: 64, bitstream: 60 }), check(&smaller_frame, &mut Aviffy::new(), 64, 40, 8));
+    assert_eq!(Err(BitstreamMismatch::Height { configured: 50, bitstream: 48 }), check(&superres, &mut Aviffy::new(), 64, 50, 8));
+    assert_eq!(Err(BitstreamMismatch::Depth { configured: 8, bitstream: 10 }), check(&av1_420_10bit, Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420), 64, 48, 8));
+    assert_eq!(Err(BitstreamMismatch::ChromaSubsampling { configured: ChromaSubsampling::Cs444, bitstream: ChromaSubsampling::Cs420 }), check(&av1_420_10bit, &mut Aviffy::new(), 64, 48, 10));
+    assert_eq!(Err(BitstreamMismatch::Monochrome { configured: false, bitstream: true }), check(&mono, Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420), 64, 48, 8));
+}
+
+#[test]
+fn seq_level_from_size() {
+    let level_of = |file: &[u8]| box_body(file, b"av1C").unwrap()[1] & 0x1F;
+    assert_eq!(12, level_of(&Aviffy::new().try_to_vec(&[1], None, 4096, 2176, 8).unwrap()));
+    assert_eq!(16, level_of(&Aviffy::new().try_to_vec(&[1], None, 4097, 2176, 8).unwrap()));
+    assert_eq!(31, level_of(&Aviffy::new().try_to_vec(&[1], None, 65536, 1, 8).unwrap()));
+    assert_eq!(5, level_of(&Aviffy::new().seq_level_idx(5).try_to_vec(&[1], None, 4097, 2176, 8).unwrap()));
+    assert!(Aviffy::new().seq_level_idx(32).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+
+    let alpha_av1c = |file: &[u8]| { let pos = file.windows(4).rposition(|w| w == b"av1C").unwrap(); file[pos + 5] & 0x1F };
+    assert_eq!(8, alpha_av1c(&Aviffy::new().try_to_vec(&[1], Some(&[2]), 1920, 1080, 8).unwrap()));
+}
+
+#[test]
+fn seq_profile_matrix() {
+    // depth, subsampling, monochrome => seq_profile, high_bitdepth|twelve_bit|mono_chrome|subsampling_x|subsampling_y
+    let legal = [
+        (8, ChromaSubsampling::Cs420, false, 0, 0b00011),
+        (10, ChromaSubsampling::Cs420, false, 0, 0b10011),
+        (8, ChromaSubsampling::Cs444, true, 0, 0b00111),
+        (10, ChromaSubsampling::Cs444, true, 0, 0b10111),
+        (8, ChromaSubsampling::Cs444, false, 1, 0b00000),
+        (10, ChromaSubsampling::Cs444, false, 1, 0b10000),
+        (8, ChromaSubsampling::Cs422, false, 2, 0b00010),
+        (10, ChromaSubsampling::Cs422, false, 2, 0b10010),
+        (12, ChromaSubsampling::Cs420, false, 2, 0b11011),
+        (12, ChromaSubsampling::Cs422, false, 2, 0b11010),
+        (12, ChromaSubsampling::Cs444, false, 2, 0b11000),
+        (12, ChromaSubsampling::Cs444, true, 2, 0b11111),
+    ];
+    for &(depth, subsampling, monochrome, profile, flags) in &legal {
+        let avif = Aviffy::new().chroma_subsampling(subsampling).monochrome(monochrome).try_to_vec(&[1], Some(&[2]), 4, 4, depth).unwrap();
+        let av1c = box_body(&avif, b"av1C").unwrap();
+        assert_eq!(profile, av1c[1] >> 5, "{} {:?} {}", depth, subsampling, monochrome);
+        assert_eq!(flags, (av1c[2] >> 2) & 0b11111, "{} {:?} {}", depth, subsampling, monochrome);
+
+        let alpha_pos = avif.windows(4).rposition(|w| w == b"av1C").unwrap();
+        let alpha_av1c = &avif[alpha_pos + 4..alpha_pos + 8];
+        assert_eq!(if depth == 12 { 2 } else { 0 }, alpha_av1c[1] >> 5);
+        assert_eq!(flags & 0b11000 | 0b111, (alpha_av1c[2] >> 2) & 0b11111);
+    }
+    for &depth in &[0, 1, 7, 9, 11, 16] {
+        for &subsampling in &[ChromaSubsampling::Cs420, ChromaSubsampling::Cs422, ChromaSubsampling::Cs444] {
+            for &monochrome in &[false, true] {
+                let err = Aviffy::new().chroma_subsampling(subsampling).monochrome(monochrome).write(&mut Vec::new(), &[1], None, 4, 4, depth).unwrap_err();
+                assert!(matches!(err, Error::InvalidDepth(d) if d == depth), "{:?}", err);
+            }
+        }
+    }
+}
+
+#[test]
+fn hidden_alpha_item() {
+    let infe_flags = |file: &[u8]| -> Vec<u8> {
+        file.windows(4).enumerate().filter(|(_, w)| w == b"infe").map(|(pos, _)| file[pos + 7]).collect()
+    };
+    let visible = Aviffy::new().try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap();
+    assert_eq!(vec![0, 0], infe_flags(&visible));
+
+    let hidden = Aviffy::new().hidden_alpha(true).try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap();
+    assert_eq!(vec![0, 1], infe_flags(&hidden));
+    assert_eq!(visible.len(), hidden.len());
+    let ctx = avif_parse::read_avif(&mut hidden.as_slice()).unwrap();
+    assert_eq!(&[2][..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn seq_tier_bits() {
+    // (seq_profile << 5 | seq_level_idx_0, seq_tier_0) of every av1C
+    let av1c_bits = |file: &[u8]| -> Vec<(u8, u8)> {
+        file.windows(4).enumerate().filter(|(_, w)| w == b"av1C").map(|(pos, _)| (file[pos + 5], file[pos + 6] >> 7)).collect()
+    };
+    let main = Aviffy::new().try_to_vec(&[1], Some(&[2]), 1920, 1080, 8).unwrap();
+    assert_eq!(vec![(1 << 5 | 8, 0), (8, 0)], av1c_bits(&main));
+    let high = Aviffy::new().seq_tier(true).try_to_vec(&[1], Some(&[2]), 1920, 1080, 8).unwrap();
+    assert_eq!(vec![(1 << 5 | 8, 1), (8, 1)], av1c_bits(&high));
+    assert_eq!(main.len(), high.len());
+    assert_eq!(vec![(1 << 5 | 31, 1)], av1c_bits(&Aviffy::new().seq_tier(true).seq_level_idx(31).try_to_vec(&[1], None, 1, 1, 8).unwrap()));
+
+    let err = Aviffy::new().seq_tier(true).write(&mut Vec::new(), &[1], None, 1280, 720, 8).unwrap_err();
+    assert!(matches!(err, Error::NoHighTier(5)), "{:?}", err);
+    assert!(Aviffy::new().seq_tier(true).seq_level_idx(7).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+    assert!(Aviffy::new().seq_tier(true).seq_level_idx(8).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_ok());
+}
+
+#[test]
+fn av1c_override() {
+    let color = Av1Config::new(0, 13, true, 10, ChromaSubsampling::Cs420, false, ChromaSamplePosition::Colocated).unwrap();
+    let alpha = Av1Config::new(0, 9, false, 10, ChromaSubsampling::Cs444, true, ChromaSamplePosition::Unknown).unwrap();
+    // these are ignored
+    let avif = Aviffy::new().seq_level_idx(5).derive_av1c_from_bitstream(true)
+        .av1c(color.clone()).alpha_av1c(alpha)
+        .try_to_vec(&[1], Some(&[2]), 10, 10, 10).unwrap();
+
+    let av1c = avif.windows(4).enumerate().filter(|(_, w)| w == b"av1C").map(|(pos, _)| &avif[pos - 4..pos + 8]).collect::<Vec<_>>();
+    assert_eq!(&[0,0,0,12, b'a',b'v',b'1',b'C', 0x81, 13, 0b1100_1110, 0][..], av1c[0]);
+    assert_eq!(&[0,0,0,12, b'a',b'v',b'1',b'C', 0x81, 9, 0b0101_1100, 0][..], av1c[1]);
+
+    let error = |res: Result<Av1Config, Error>| res.unwrap_err();
+    assert!(matches!(error(Av1Config::new(1, 31, false, 8, ChromaSubsampling::Cs420, false, ChromaSamplePosition::Unknown)), Error::ProfileMismatch { seq_profile: 1, expected: 0 }));
+    assert!(matches!(error(Av1Config::new(1, 31, false, 8, ChromaSubsampling::Cs444, true, ChromaSamplePosition::Unknown)), Error::ProfileMismatch { seq_profile: 1, expected: 0 }));
+    assert!(matches!(error(Av1Config::new(3, 31, false, 12, ChromaSubsampling::Cs444, false, ChromaSamplePosition::Unknown)), Error::ProfileMismatch { seq_profile: 3, expected: 2 }));
+    assert!(matches!(error(Av1Config::new(2, 31, false, 9, ChromaSubsampling::Cs444, false, ChromaSamplePosition::Unknown)), Error::UnsupportedSubsampling { subsampling: ChromaSubsampling::Cs444, depth_bits: 9 }));
+    assert!(matches!(error(Av1Config::new(0, 32, false, 8, ChromaSubsampling::Cs420, false, ChromaSamplePosition::Unknown)), Error::InvalidLevel(32)));
+    assert!(matches!(error(Av1Config::new(0, 7, true, 8, ChromaSubsampling::Cs420, false, ChromaSamplePosition::Unknown)), Error::NoHighTier(7)));
+    let monochrome = error(Av1Config::new(0, 8, false, 8, ChromaSubsampling::Cs420, true, ChromaSamplePosition::Vertical));
+    assert_eq!("Chroma sample position is only for 4:2:0 color, not monochrome", monochrome.to_string());
+    assert!(matches!(error(Av1Config::new(2, 8, false, 12, ChromaSubsampling::Cs422, false, ChromaSamplePosition::Vertical)),
+        Error::ChromaSamplePositionSubsampling { subsampling: ChromaSubsampling::Cs422, monochrome: false }));
+}
+
+#[test]
+fn reused_vec() {
+    let mut buf = Vec::new();
+    Aviffy::new().write_into_vec(&mut buf, &[1; 1000], Some(&[2; 500]), 10, 10, 8).unwrap();
+    assert_eq!(Aviffy::new().try_to_vec(&[1; 1000], Some(&[2; 500]), 10, 10, 8).unwrap(), buf);
+    let capacity = buf.capacity();
+    let ptr = buf.as_ptr();
+
+    Aviffy::new().premultiplied_alpha(true).write_into_vec(&mut buf, &[3; 100], Some(&[4; 50]), 5, 5, 10).unwrap();
+    assert_eq!(Aviffy::new().premultiplied_alpha(true).try_to_vec(&[3; 100], Some(&[4; 50]), 5, 5, 10).unwrap(), buf);
+    assert_eq!(capacity, buf.capacity());
+    assert_eq!(ptr, buf.as_ptr());
+    let ctx = avif_parse::read_avif(&mut buf.as_slice()).unwrap();
+    assert_eq!(&[3; 100][..], ctx.primary_item.as_slice());
+
+    assert!(Aviffy::new().write_into_vec(&mut buf, &[1], None, 1, 1, 9).is_err());
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn handler_type() {
+    let avif = Aviffy::new().try_to_vec(&[1], None, 1, 1, 8).unwrap();
+    let hdlr = box_body(&avif, b"hdlr").unwrap();
+    assert_eq!(b"pict", &hdlr[8..12]);
+    assert_eq!(4 + 4 + 4 + 12 + 1, hdlr.len());
+}
+
+#[test]
+fn embedded_sequence_header() {
+    use crate::obu::test_streams::*;
+
+    let color = stream(&SequenceConfig { temporal_delimiter: true, ..Default::default() });
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let color_seq = obu(obu::OBU_SEQUENCE_HEADER, &sequence_header_payload(&SequenceConfig::default()));
+
+    let plain = Aviffy::new().try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap();
+    let embedded = Aviffy::new().embed_sequence_header(true).try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap();
+    let alpha_seq_len = obu::sequence_header_obu(&alpha).unwrap().len();
+    assert_eq!(plain.len() + color_seq.len() + alpha_seq_len, embedded.len());
+
+    let av1c = box_body(&embedded, b"av1C").unwrap();
+    assert_eq!(box_body(&plain, b"av1C").unwrap(), &av1c[..4]);
+    assert_eq!(&color_seq[..], &av1c[4..]);
+
+    let ctx = avif_parse::read_avif(&mut embedded.as_slice()).unwrap();
+    assert_eq!(&color[..], ctx.primary_item.as_slice());
+    assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+
+    assert!(Aviffy::new().embed_sequence_header(true).write(&mut Vec::new(), &[0x12, 0], None, 64, 48, 8).is_err());
+}
+
+#[test]
+fn stripped_temporal_delimiters() {
+    use crate::obu::test_streams::*;
+
+    let td = obu(obu::OBU_TEMPORAL_DELIMITER, &[]);
+    let color = stream(&SequenceConfig { temporal_delimiter: true, ..Default::default() });
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let alpha_seq_len = obu::sequence_header_obu(&alpha).unwrap().len();
+    let alpha_with_tds = [&td[..], &alpha[..alpha_seq_len], &td, &alpha[alpha_seq_len..]].concat();
+
+    let kept = Aviffy::new().try_to_vec(&color, Some(&alpha_with_tds), 64, 48, 8).unwrap();
+    let ctx = avif_parse::read_avif(&mut kept.as_slice()).unwrap();
+    assert_eq!(&color[..], ctx.primary_item.as_slice());
+
+    let stripped = Aviffy::new().strip_temporal_delimiters(true).validate_bitstream(true).try_to_vec(&color, Some(&alpha_with_tds), 64, 48, 8).unwrap();
+    assert_eq!(kept.len() - 3 * td.len(), stripped.len());
+    let ctx = avif_parse::read_avif(&mut stripped.as_slice()).unwrap();
+    assert_eq!(&color[td.len()..], ctx.primary_item.as_slice());
+    assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+
+    assert!(Aviffy::new().strip_temporal_delimiters(true).write(&mut Vec::new(), &[0xFF], None, 1, 1, 8).is_err());
+}
+
+#[test]
+fn checked_obu_start() {
+    use crate::obu::test_streams::*;
+
+    let color = stream(&SequenceConfig::default());
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let ivf = [&b"DKIF\0\0\x20\0AV01"[..], &[0; 20], &color].concat();
+
+    let avif = Aviffy::new().check_obu_start(true).try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap();
+    assert_eq!(avif, Aviffy::new().try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap());
+    // unchecked by default
+    Aviffy::new().try_to_vec(&ivf, None, 64, 48, 8).unwrap();
+
+    let err = Aviffy::new().check_obu_start(true).write(&mut Vec::new(), &ivf, None, 64, 48, 8).unwrap_err();
+    assert!(matches!(err, Error::InvalidAv1Data(InvalidAv1Data::Ivf)), "{:?}", err);
+    assert!(err.to_string().contains("IVF"), "{}", err);
+    let frame_only = obu(obu::OBU_FRAME, &[1, 2, 3]);
+    assert!(Aviffy::new().check_obu_start(true).write(&mut Vec::new(), &color, Some(&frame_only), 64, 48, 8).is_err());
+    assert!(Aviffy::new().check_obu_start(true).add_alternative(&frame_only, 2, 2).write(&mut Vec::new(), &color, None, 64, 48, 8).is_err());
+    assert!(Aviffy::new().check_obu_start(true).serialized_len(color.len(), None, 64, 48, 8).is_err());
+}
+
+#[test]
+fn trimmed_trailing_obus() {
+    use crate::obu::test_streams::*;
+
+    let td = obu(obu::OBU_TEMPORAL_DELIMITER, &[]);
+    let padding = obu(obu::OBU_PADDING, &[0; 10]);
+    let color = stream(&SequenceConfig { temporal_delimiter: true, ..Default::default() });
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let color_with_td = [&color[..], &td].concat();
+    let alpha_with_padding = [&alpha[..], &padding, &td].concat();
+
+    let kept = Aviffy::new().try_to_vec(&color_with_td, Some(&alpha_with_padding), 64, 48, 8).unwrap();
+    let ctx = avif_parse::read_avif(&mut kept.as_slice()).unwrap();
+    assert_eq!(&color_with_td[..], ctx.primary_item.as_slice());
+
+    let trimmed = Aviffy::new().trim_trailing_obus(true).validate_bitstream(true).try_to_vec(&color_with_td, Some(&alpha_with_padding), 64, 48, 8).unwrap();
+    assert_eq!(kept.len() - 2 * td.len() - padding.len(), trimmed.len());
+    let ctx = avif_parse::read_avif(&mut trimmed.as_slice()).unwrap();
+    // the leading delimiter is kept, unless strip_temporal_delimiters is also set
+    assert_eq!(&color[..], ctx.primary_item.as_slice());
+    assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+
+    let both = Aviffy::new().trim_trailing_obus(true).strip_temporal_delimiters(true).try_to_vec(&color_with_td, None, 64, 48, 8).unwrap();
+    let ctx = avif_parse::read_avif(&mut both.as_slice()).unwrap();
+    assert_eq!(&color[td.len()..], ctx.primary_item.as_slice());
+
+    assert!(Aviffy::new().trim_trailing_obus(true).write(&mut Vec::new(), &[0xFF], None, 1, 1, 8).is_err());
+}
+
+#[test]
+fn multiple_extents() {
+    let color: &[&[u8]] = &[&[1, 2, 3], &[4, 5], &[6]];
+    let alpha: &[&[u8]] = &[&[7], &[8, 9]];
+    let mut avif = Vec::new();
+    Aviffy::new().write_with_extents(&mut avif, color, Some(alpha), 4, 4, 8).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1, 2, 3, 4, 5, 6][..], ctx.primary_item.as_slice());
+    assert_eq!(&[7, 8, 9][..], ctx.alpha_item.as_deref().unwrap());
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1, 2, 3, 4, 5, 6][..], ctx.primary_item_coded_data());
+
+    // same mdat as the concatenated data, alpha first
+    let single = Aviffy::new().try_to_vec(&[1, 2, 3, 4, 5, 6], Some(&[7, 8, 9]), 4, 4, 8).unwrap();
+    assert_eq!(box_body(&single, b"mdat"), box_body(&avif, b"mdat"));
+    assert_eq!(single.len() + 3 * 8, avif.len()); // 3 more extents
+
+    // the same pieces in idat
+    let mut avif = Vec::new();
+    Aviffy::new().idat_threshold(9).write_with_extents(&mut avif, color, Some(alpha), 4, 4, 8).unwrap();
+    assert_eq!(Some(&[7, 8, 9, 1, 2, 3, 4, 5, 6][..]), box_body(&avif, b"idat"));
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1, 2, 3, 4, 5, 6][..], ctx.primary_item_coded_data());
+    assert_eq!(&[7, 8, 9][..], ctx.alpha_item_coded_data());
+
+    let mut out = Vec::new();
+    assert!(Aviffy::new().write_with_extents(&mut out, &[&[1], &[]], None, 4, 4, 8).is_err());
+    assert!(Aviffy::new().write_with_extents(&mut out, &[&[1][..]; 17], None, 4, 4, 8).is_err());
+}
+
+#[test]
+fn tile_group_extents() {
+    use crate::obu::test_streams::*;
+
+    let headers = [obu(obu::OBU_SEQUENCE_HEADER, &sequence_header_payload(&SequenceConfig::default())), obu(obu::OBU_FRAME_HEADER, &[1])].concat();
+    let tile_groups = [obu(obu::OBU_TILE_GROUP, &[2, 2]), obu(obu::OBU_TILE_GROUP, &[3]), obu(obu::OBU_TILE_GROUP, &[4, 4, 4])];
+    let color = [&headers[..], &tile_groups[0], &tile_groups[1], &tile_groups[2]].concat();
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+
+    for &idat_threshold in &[0, 1000] {
+        let mut aviffy = Aviffy::new();
+        aviffy.tile_group_extents(true).idat_threshold(idat_threshold);
+        let avif = aviffy.try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap();
+        let extents = aviffy.data_extents(&color, Some(&alpha), 64, 48, 8).unwrap();
+        assert_eq!(3, extents.color.len());
+        let range = |r: &Range<u64>| &avif[r.start as usize..r.end as usize];
+        // every extent after the first starts at a tile group OBU
+        assert_eq!(&[&headers[..], &tile_groups[0]].concat()[..], range(&extents.color[0]));
+        assert_eq!(&tile_groups[1][..], range(&extents.color[1]));
+        assert_eq!(&tile_groups[2][..], range(&extents.color[2]));
+        assert_eq!(extents.color[0].end, extents.color[1].start);
+        assert_eq!(vec![&alpha[..]], extents.alpha.unwrap().iter().map(range).collect::<Vec<_>>());
+
+        // the data is the same, only iloc has more extents
+        assert_eq!(Aviffy::new().idat_threshold(idat_threshold).try_to_vec(&color, Some(&alpha), 64, 48, 8).unwrap().len() + 2 * 8, avif.len());
+        let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+        assert_eq!(&color[..], ctx.primary_item_coded_data());
+        assert_eq!(&alpha[..], ctx.alpha_item_coded_data());
+    }
+
+    // the ranges are in the file with other options too
+    let mut aviffy = Aviffy::new();
+    aviffy.tile_group_extents(true).iloc_base_offsets(true).layout(Layout::MdatFirst).align_mdat(64);
+    let avif = aviffy.try_to_vec(&color, None, 64, 48, 8).unwrap();
+    let extents = aviffy.data_extents(&color, None, 64, 48, 8).unwrap();
+    assert_eq!(None, extents.alpha);
+    assert_eq!(&tile_groups[2][..], &avif[extents.color[2].start as usize..extents.color[2].end as usize]);
+    let whole = Aviffy::new().data_extents(&color, None, 64, 48, 8).unwrap().color;
+    assert_eq!(1, whole.len());
+    assert_eq!(color.len() as u64, whole[0].end - whole[0].start);
+
+    let too_many = [&headers[..], &tile_groups[1].repeat(17)].concat();
+    let err = Aviffy::new().tile_group_extents(true).try_to_vec(&too_many, None, 64, 48, 8).unwrap_err();
+    assert!(matches!(err, Error::TooManyPieces));
+    assert!(Aviffy::new().tile_group_extents(true).try_to_vec(&[0xFF], None, 64, 48, 8).is_err());
+}
+
+#[test]
+fn crop_1088_to_1080() {
+    let avif = Aviffy::new().crop_to(1920, 1080).try_to_vec(&[1], Some(&[2]), 1920, 1088, 8).unwrap();
+    let clap = box_body(&avif, b"clap").unwrap();
+    let fields: Vec<u32> = clap.chunks(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect();
+    assert_eq!(vec![1920, 1, 1080, 1, 0, 1, -4i32 as u32, 1], fields);
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"clap").count());
+
+    let avif = Aviffy::new().crop_to(5, 3).try_to_vec(&[1], None, 8, 8, 8).unwrap();
+    let clap = box_body(&avif, b"clap").unwrap();
+    let fields: Vec<u32> = clap.chunks(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect();
+    assert_eq!(vec![5, 1, 3, 1, -3i32 as u32, 2, -5i32 as u32, 2], fields);
+
+    assert!(box_body(&Aviffy::new().try_to_vec(&[1], None, 8, 8, 8).unwrap(), b"clap").is_none());
+    assert!(Aviffy::new().crop_to(9, 8).write(&mut Vec::new(), &[1], None, 8, 8, 8).is_err());
+    assert!(Aviffy::new().crop_to(8, 0).write(&mut Vec::new(), &[1], None, 8, 8, 8).is_err());
+}
+
+#[test]
+fn initial_presentation_delay_byte() {
+    let last_av1c_bytes = |file: &[u8]| -> Vec<u8> {
+        file.windows(4).enumerate().filter(|(_, w)| w == b"av1C").map(|(pos, _)| file[pos + 7]).collect()
+    };
+    assert_eq!(vec![0, 0], last_av1c_bytes(&Aviffy::new().try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap()));
+    assert_eq!(vec![0b1_0000, 0b1_0000], last_av1c_bytes(&Aviffy::new().initial_presentation_delay(1).try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap()));
+    assert_eq!(vec![0b1_0100, 0b1_0100], last_av1c_bytes(&Aviffy::new().initial_presentation_delay(5).try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap()));
+    assert_eq!(vec![0b1_1111], last_av1c_bytes(&Aviffy::new().initial_presentation_delay(16).try_to_vec(&[1], None, 1, 1, 8).unwrap()));
+
+    assert!(Aviffy::new().initial_presentation_delay(0).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+    assert!(Aviffy::new().initial_presentation_delay(17).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+}
+
+#[test]
+fn av1c_records() {
+    // libavif's 8-bit 4:2:0 level 4.0, 10-bit 4:4:4 level 5.1 high tier, and 12-bit monochrome with a delay
+    let records: [&[u8]; 3] = [&[0x81, 0x08, 0x0C, 0x00], &[0x81, 0x2D, 0xC0, 0x00], &[0x81, 0x5F, 0x7C, 0x13]];
+    for (&record, &depth) in records.iter().zip(&[8, 10, 12]) {
+        let avif = Aviffy::new().av1c_record(record).unwrap().try_to_vec(&[1], None, 1, 1, depth).unwrap();
+        assert_eq!(record, box_body(&avif, b"av1C").unwrap());
+    }
+
+    let config_obus = [0x0A, 0x03, 0, 0, 0];
+    let record = [&[0x81, 0x08, 0x0C, 0x00][..], &config_obus].concat();
+    let config = Av1Config::from_record(&record).unwrap();
+    let avif = Aviffy::new().av1c(config).try_to_vec(&[1], None, 1, 1, 8).unwrap();
+    assert_eq!(&record[..], box_body(&avif, b"av1C").unwrap());
+
+    let bad_records = [
+        (&[0x81, 0x08, 0x0C][..], InvalidAv1Data::RecordTooShort),
+        (&[0x01, 0x08, 0x0C, 0x00], InvalidAv1Data::RecordVersion(0x01)),
+        (&[0x82, 0x08, 0x0C, 0x00], InvalidAv1Data::RecordVersion(0x82)),
+        (&[0x81, 0x08, 0x04, 0x00], InvalidAv1Data::RecordSubsampling),
+        (&[0x81, 0x08, 0x0F, 0x00], InvalidAv1Data::RecordChromaSamplePosition),
+        (&[0x81, 0x08, 0x2C, 0x00], InvalidAv1Data::RecordTwelveBit),
+        (&[0x81, 0x08, 0x1C, 0x20], InvalidAv1Data::RecordReservedBits),
+    ];
+    for (bad, expected) in bad_records.iter() {
+        let err = Av1Config::from_record(bad).unwrap_err();
+        assert!(matches!(&err, Error::InvalidAv1Data(e) if e == expected), "{:02x?} {:?}", bad, err);
+        assert_eq!(io::ErrorKind::InvalidData, io::Error::from(err).kind());
+    }
+    // valid syntax, but not a profile AV1 allows
+    assert!(matches!(Av1Config::from_record(&[0x81, 0x28, 0x0C, 0x00]), Err(Error::ProfileMismatch { seq_profile: 1, expected: 0 })));
+}
+
+#[test]
+fn packets() {
+    // stand-in for rav1e::Packet
+    struct Packet { data: Vec<u8> }
+    let color = [Packet { data: vec![1, 2] }, Packet { data: vec![3] }];
+    let alpha = [Packet { data: vec![4, 5, 6] }];
+
+    let mut avif = Vec::new();
+    Aviffy::new().write_packets(&mut avif, color.iter().map(|p| &p.data[..]), Some(alpha.iter().map(|p| &p.data[..])), 2, 2, 8).unwrap();
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1, 2, 3][..], ctx.primary_item.as_slice());
+    assert_eq!(&[4, 5, 6][..], ctx.alpha_item.as_deref().unwrap());
+
+    let many: Vec<_> = (0..17).map(|_| Packet { data: vec![1] }).collect();
+    assert!(Aviffy::new().write_packets(&mut Vec::new(), many.iter().map(|p| &p.data[..]), None::<std::iter::Empty<_>>, 2, 2, 8).is_err());
+}
+
+#[test]
+fn alpha_level() {
+    // (seq_profile << 5 | seq_level_idx_0, seq_tier_0) of every av1C
+    let av1c_bits = |file: &[u8]| -> Vec<(u8, u8)> {
+        file.windows(4).enumerate().filter(|(_, w)| w == b"av1C").map(|(pos, _)| (file[pos + 5], file[pos + 6] >> 7)).collect()
+    };
+    let avif = Aviffy::new().seq_level_idx(13).alpha_seq_level_idx(0).try_to_vec(&[1], Some(&[2]), 4096, 2160, 8).unwrap();
+    assert_eq!(vec![(1 << 5 | 13, 0), (0, 0)], av1c_bits(&avif));
+
+    let avif = Aviffy::new().seq_level_idx(13).seq_tier(true).alpha_seq_level_idx(0).alpha_seq_tier(false).try_to_vec(&[1], Some(&[2]), 4096, 2160, 8).unwrap();
+    assert_eq!(vec![(1 << 5 | 13, 1), (0, 0)], av1c_bits(&avif));
+
+    // alpha follows color by default
+    let avif = Aviffy::new().seq_level_idx(13).try_to_vec(&[1], Some(&[2]), 4096, 2160, 8).unwrap();
+    assert_eq!(vec![(1 << 5 | 13, 0), (13, 0)], av1c_bits(&avif));
+
+    assert!(Aviffy::new().seq_level_idx(13).seq_tier(true).alpha_seq_level_idx(0).write(&mut Vec::new(), &[1], Some(&[2]), 1, 1, 8).is_err());
+    assert!(Aviffy::new().alpha_seq_level_idx(32).write(&mut Vec::new(), &[1], Some(&[2]), 1, 1, 8).is_err());
+}
+
+#[test]
+fn depth_consistency() {
+    for depth in (0..=16).filter(|d| ![8, 10, 12].contains(d)) {
+        let err = Aviffy::new().write(&mut Vec::new(), &[1], None, 1, 1, depth).unwrap_err();
+        assert!(err.to_string().contains("8, 10 or 12"), "{}", err);
+        assert!(matches!(err, Error::InvalidDepth(d) if d == depth));
+        let err = Aviffy::new().alpha_depth_bits(depth).write(&mut Vec::new(), &[1], Some(&[2]), 1, 1, 8).unwrap_err();
+        assert!(matches!(err, Error::InvalidDepth(d) if d == depth));
+    }
+    // high_bitdepth and twelve_bit, and profile 2 for 12-bit
+    for &(depth, flags) in &[(8, 0x0C), (10, 0x4C), (12, 0x6C)] {
+        let avif = Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).try_to_vec(&[1], None, 2, 2, depth).unwrap();
+        let profile = if depth == 12 { 2 } else { 0 };
+        assert_eq!(&[0x81, profile << 5, flags, 0], box_body(&avif, b"av1C").unwrap());
+    }
+
+    let ten_bit = Av1Config::new(1, 31, false, 10, ChromaSubsampling::Cs444, false, ChromaSamplePosition::Unknown).unwrap();
+    assert!(Aviffy::new().av1c(ten_bit.clone()).write(&mut Vec::new(), &[1], None, 1, 1, 10).is_ok());
+    assert!(Aviffy::new().av1c(ten_bit.clone()).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+    assert!(Aviffy::new().alpha_av1c(ten_bit).write(&mut Vec::new(), &[1], Some(&[2]), 1, 1, 12).is_err());
+    // the given av1C is written in both modes
+    assert!(Aviffy::new().strict_av1c(false).av1c(Av1Config::from_record(&[0x81, 0, 0x4C, 0]).unwrap()).write(&mut Vec::new(), &[1], None, 1, 1, 8).is_err());
+
+    use crate::obu::test_streams::*;
+    let eight_bit_stream = stream(&SequenceConfig::default());
+    let err = Aviffy::new().derive_av1c_from_bitstream(true).write(&mut Vec::new(), &eight_bit_stream, None, 64, 48, 10).unwrap_err();
+    assert!(err.to_string().contains("8-bit"), "{}", err);
+}
+
+#[test]
+fn odd_dimensions() {
+    let write = |aviffy: &Aviffy, width, height| aviffy.write(&mut Vec::new(), &[1], None, width, height, 8);
+
+    let mut cs420 = Aviffy::new();
+    cs420.chroma_subsampling(ChromaSubsampling::Cs420);
+    assert!(write(&cs420, 1000, 750).is_ok());
+    assert!(matches!(write(&cs420, 1001, 750), Err(Error::OddDimensions { width: 1001, height: 750, .. })));
+    assert!(matches!(write(&cs420, 1000, 751), Err(Error::OddDimensions { width: 1000, height: 751, .. })));
+    assert!(write(cs420.allow_odd_dimensions(true), 1001, 751).is_ok());
+
+    let mut cs422 = Aviffy::new();
+    cs422.chroma_subsampling(ChromaSubsampling::Cs422);
+    assert!(write(&cs422, 1000, 751).is_ok());
+    assert!(write(&cs422, 1001, 750).is_err());
+
+    assert!(write(&Aviffy::new(), 1001, 751).is_ok());
+    assert!(write(Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).monochrome(true), 1001, 751).is_ok());
+}
+
+#[test]
+fn alpha_references() {
+    let auxl = [0,0,0,14, b'a',b'u',b'x',b'l', 0,2, 0,1, 0,1];
+    let prem = [0,0,0,14, b'p',b'r',b'e',b'm', 0,1, 0,1, 0,2];
+
+    let avif = Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"iref").count());
+    assert_eq!(&[&[0,0,0,0][..], &auxl].concat(), box_body(&avif, b"iref").unwrap());
+
+    let avif = Aviffy::new().premultiplied_alpha(true).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"iref").count());
+    assert_eq!(&[&[0,0,0,0][..], &auxl, &prem].concat(), box_body(&avif, b"iref").unwrap());
+
+    let avif = Aviffy::new().try_to_vec(&[1], None, 5, 5, 8).unwrap();
+    assert!(box_body(&avif, b"iref").is_none());
+}
+
+#[test]
+fn pixi_depth() {
+    for &depth in &[8, 10, 12] {
+        let avif = Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, depth).unwrap();
+        let mut pixis = avif.windows(4).enumerate().filter(|&(_, w)| w == b"pixi").map(|(pos, _)| &avif[pos + 8..pos + 12]);
+        assert_eq!(&[3, depth, depth, depth][..], pixis.next().unwrap());
+        assert_eq!(&[1, depth][..], &pixis.next().unwrap()[..2]);
+        assert!(pixis.next().is_none());
+    }
+}
+
+#[test]
+fn output_depth() {
+    let output_depth_err = |aviffy: &mut Aviffy| {
+        let err = io::Error::from(aviffy.try_to_vec(&[1], Some(&[2]), 5, 5, 10).unwrap_err());
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Error::from(err)
+    };
+    let mut aviffy = Aviffy::new();
+    assert!(matches!(output_depth_err(aviffy.output_depth_bits(8)), Error::OutputDepth { output_depth_bits: 8, depth_bits: 10 }));
+
+    // 8-bit delivery of a 10-bit encode
+    let avif = aviffy.allow_output_depth_mismatch(true).try_to_vec(&[1], Some(&[2]), 5, 5, 10).unwrap();
+    let mut pixis = avif.windows(4).enumerate().filter(|&(_, w)| w == b"pixi").map(|(pos, _)| &avif[pos + 8..pos + 12]);
+    assert_eq!(&[3, 8, 8, 8][..], pixis.next().unwrap());
+    // alpha has its own depth
+    assert_eq!(&[1, 10][..], &pixis.next().unwrap()[..2]);
+    assert!(pixis.next().is_none());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item.as_slice());
+    assert_eq!(10, Av1Config::from_record(box_body(&avif, b"av1C").unwrap()).unwrap().av1c.bit_depth());
+
+    assert!(matches!(output_depth_err(aviffy.output_depth_bits(17)), Error::OutputDepth { output_depth_bits: 17, depth_bits: 10 }));
+    assert!(matches!(output_depth_err(aviffy.output_depth_bits(0)), Error::OutputDepth { output_depth_bits: 0, depth_bits: 10 }));
+    // same as the data is the same as not setting it
+    assert_eq!(Aviffy::new().try_to_vec(&[1], None, 5, 5, 10).unwrap(), Aviffy::new().output_depth_bits(10).try_to_vec(&[1], None, 5, 5, 10).unwrap());
+}
+
+#[test]
+fn encoded_size() {
+    let avif = Aviffy::new().encoded_size(1920, 1088).try_to_vec(&[1], Some(&[2]), 1920, 1080, 8).unwrap();
+    assert_eq!(&[0,0,0,0, 0,0,0x07,0x80, 0,0,0x04,0x40], box_body(&avif, b"ispe").unwrap());
+    assert!(box_body(&avif, b"clap").is_some());
+    assert_eq!(avif, Aviffy::new().crop_to(1920, 1080).try_to_vec(&[1], Some(&[2]), 1920, 1088, 8).unwrap());
+
+    // no crop needed
+    let avif = Aviffy::new().encoded_size(1920, 1088).try_to_vec(&[1], None, 1920, 1088, 8).unwrap();
+    assert_eq!(avif, Aviffy::new().try_to_vec(&[1], None, 1920, 1088, 8).unwrap());
+
+    assert!(Aviffy::new().encoded_size(1920, 1088).write(&mut Vec::new(), &[1], None, 1921, 1080, 8).is_err());
+    assert!(Aviffy::new().encoded_size(1920, 1088).crop_to(1920, 1080).write(&mut Vec::new(), &[1], None, 1920, 1080, 8).is_err());
+}
+
+#[test]
+fn without_pixi() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let with = Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 5, 5, 10).unwrap();
+    let without = Aviffy::new().write_pixi(false).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 10).unwrap();
+    assert!(!without.windows(4).any(|w| w == b"pixi"));
+    assert!(without.len() < with.len());
+
+    let ctx = mp4parse::read_avif(&mut without.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+    let ctx = avif_parse::read_avif(&mut without.as_slice()).unwrap();
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn mdat_alignment() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    for &alignment in &[1, 4, 16, 4096] {
+        let avif = Aviffy::new().align_mdat(alignment).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+        let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+        assert_eq!(0, payload % alignment as usize);
+        assert_eq!(&[5,6,7,1,2,3,4], &avif[payload..]);
+
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+        assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    }
+    assert!(Aviffy::new().align_mdat(0).write(&mut Vec::new(), &[1], None, 5, 5, 8).is_err());
+}
+
+#[test]
+fn primary_item_alignment() {
+    // Offset of the first extent of the first item in iloc, which is the color image
+    let color_offset = |avif: &[u8]| {
+        let iloc = box_body(avif, b"iloc").unwrap();
+        assert_eq!(&[0, 1], &iloc[8..10]);
+        u32::from_be_bytes([iloc[14], iloc[15], iloc[16], iloc[17]]) as usize
+    };
+    let test_img = [1,2,3,4];
+    for &alpha in &[None, Some(&[5,6,7][..])] {
+        for &metadata in &[false, true] {
+            let mut aviffy = Aviffy::new();
+            aviffy.align_mdat(4096).data_order(DataOrder::ColorFirst);
+            if metadata {
+                aviffy.icc_profile(&[0; 99]).append_box(FourCC(*b"Test"), b"hi").crop_to(3, 3);
+            }
+            let avif = aviffy.try_to_vec(&test_img, alpha, 5, 5, 8).unwrap();
+            let offset = color_offset(&avif);
+            assert_eq!(0, offset % 4096);
+            assert_eq!(&test_img, &avif[offset..offset + 4]);
+            let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+            assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+        }
+    }
+}
+
+#[test]
+fn alpha_depth() {
+    let avif = Aviffy::new().alpha_depth_bits(8).try_to_vec(&[1], Some(&[2]), 5, 5, 10).unwrap();
+    let mut av1cs = avif.windows(4).enumerate().filter(|&(_, w)| w == b"av1C").map(|(pos, _)| &avif[pos + 4..pos + 8]);
+    // 10-bit 4:4:4 color, 8-bit monochrome alpha
+    assert_eq!(&[0x81, 1 << 5, 1 << 6, 0][..], av1cs.next().unwrap());
+    assert_eq!(&[0x81, 0, 0b0001_1100, 0][..], av1cs.next().unwrap());
+    let mut pixis = avif.windows(4).enumerate().filter(|&(_, w)| w == b"pixi").map(|(pos, _)| &avif[pos + 8..pos + 10]);
+    assert_eq!(&[3, 10][..], pixis.next().unwrap());
+    assert_eq!(&[1, 8][..], pixis.next().unwrap());
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[2][..], ctx.alpha_item.as_deref().unwrap());
+
+    assert_eq!(Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 10).unwrap(), Aviffy::new().alpha_depth_bits(10).try_to_vec(&[1], Some(&[2]), 5, 5, 10).unwrap());
+    assert!(Aviffy::new().alpha_depth_bits(9).write(&mut Vec::new(), &[1], Some(&[2]), 5, 5, 10).is_err());
+    assert!(Aviffy::new().alpha_av1c(Av1Config::new(0, 0, false, 10, ChromaSubsampling::Cs420, true, ChromaSamplePosition::Unknown).unwrap()).alpha_depth_bits(8)
+        .write(&mut Vec::new(), &[1], Some(&[2]), 5, 5, 10).is_err());
+}
+
+#[test]
+fn color_first() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let avif = Aviffy::new().data_order(DataOrder::ColorFirst).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[1,2,3,4,5,6,7], &avif[payload..]);
+
+    // iloc: 2 items, each with one extent (offset, length)
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    let offset = (payload as u32).to_be_bytes();
+    assert_eq!(&[0,1, 0,0, 0,1, offset[0],offset[1],offset[2],offset[3], 0,0,0,4][..], &iloc[8..22]);
+    let offset = (payload as u32 + 4).to_be_bytes();
+    assert_eq!(&[0,2, 0,0, 0,1, offset[0],offset[1],offset[2],offset[3], 0,0,0,3][..], &iloc[22..]);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+}
+
+#[test]
+fn error_types() {
+    let error = |aviffy: &Aviffy, depth_bits| -> Error {
+        let err = io::Error::from(aviffy.try_to_vec(&[1], Some(&[2]), 5, 5, depth_bits).unwrap_err());
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Error::from(err)
+    };
+    assert!(matches!(error(&Aviffy::new(), 16), Error::InvalidDepth(16)));
+    assert!(matches!(error(Aviffy::new().alpha_depth_bits(9), 8), Error::InvalidDepth(9)));
+    assert!(matches!(error(Aviffy::new().seq_level_idx(32), 8), Error::InvalidLevel(32)));
+    assert!(matches!(error(Aviffy::new().seq_level_idx(0).seq_tier(true), 8), Error::NoHighTier(0)));
+    assert!(matches!(error(Aviffy::new().initial_presentation_delay(0), 8), Error::InitialPresentationDelay(0)));
+    assert!(matches!(error(Aviffy::new().crop_to(6, 5), 8), Error::InvalidCrop { width: 5, height: 5, crop_width: 6, crop_height: 5 }));
+    assert!(matches!(error(Aviffy::new().append_box(FourCC(*b"meta"), &[]), 8), Error::ReservedBox(FourCC(typ)) if &typ == b"meta"));
+    assert!(matches!(error(Aviffy::new().align_mdat(0), 8), Error::ZeroAlignment));
+    assert_eq!("AV1 supports only 8, 10 or 12 bits per channel, not 16", Error::InvalidDepth(16).to_string());
+
+    assert!(Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).is_ok());
+}
+
+#[test]
+fn data_orders() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let color_offset = |avif: &[u8]| {
+        let iloc = box_body(avif, b"iloc").unwrap();
+        u32::from_be_bytes([iloc[14], iloc[15], iloc[16], iloc[17]])
+    };
+    let alpha_first = Aviffy::new().data_order(DataOrder::AlphaFirst).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+    let color_first = Aviffy::new().data_order(DataOrder::ColorFirst).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+    assert_eq!(alpha_first, Aviffy::new().try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap());
+    assert_eq!(alpha_first.len(), color_first.len());
+    assert!(color_offset(&color_first) < color_offset(&alpha_first));
+
+    for avif in &[alpha_first, color_first] {
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+        assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    }
+}
+
+#[test]
+fn essential_properties() {
+    // (item id, property type, essential) for all associations
+    let associations = |avif: &[u8]| -> Vec<(u16, [u8; 4], bool)> {
+        let ipco = box_body(avif, b"ipco").unwrap();
+        let mut types = Vec::new();
+        let mut pos = 0;
+        while pos < ipco.len() {
+            types.push([ipco[pos + 4], ipco[pos + 5], ipco[pos + 6], ipco[pos + 7]]);
+            pos += u32::from_be_bytes([ipco[pos], ipco[pos + 1], ipco[pos + 2], ipco[pos + 3]]) as usize;
+        }
+        let ipma = box_body(avif, b"ipma").unwrap();
+        let mut all = Vec::new();
+        let mut pos = 8;
+        while pos < ipma.len() {
+            let item_id = u16::from_be_bytes([ipma[pos], ipma[pos + 1]]);
+            let count = ipma[pos + 2] as usize;
+            for &prop in &ipma[pos + 3..pos + 3 + count] {
+                all.push((item_id, types[(prop & 0x7F) as usize - 1], prop & 0x80 != 0));
+            }
+            pos += 3 + count;
+        }
+        all
+    };
+
+    let mut aviffy = Aviffy::new();
+    aviffy.matrix_coefficients(MatrixCoefficients::Bt709).crop_to(4, 4);
+    let avif = aviffy.try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!(vec![
+        (1, *b"ispe", false), (1, *b"av1C", true), (1, *b"pixi", false), (1, *b"colr", false), (1, *b"clap", true),
+        (2, *b"ispe", false), (2, *b"av1C", true), (2, *b"auxC", false), (2, *b"pixi", false), (2, *b"clap", true),
+    ], associations(&avif));
+
+    let avif = aviffy.property_essential(FourCC(*b"clap"), false).property_essential(FourCC(*b"colr"), true).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!(vec![
+        (1, *b"ispe", false), (1, *b"av1C", true), (1, *b"pixi", false), (1, *b"colr", true), (1, *b"clap", false),
+        (2, *b"ispe", false), (2, *b"av1C", true), (2, *b"auxC", false), (2, *b"pixi", false), (2, *b"clap", false),
+    ], associations(&avif));
+
+    // the alpha has its own colr
+    let with_colr = aviffy.alpha_colr(true).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!(vec![
+        (1, *b"ispe", false), (1, *b"av1C", true), (1, *b"pixi", false), (1, *b"colr", true), (1, *b"clap", false),
+        (2, *b"ispe", false), (2, *b"av1C", true), (2, *b"auxC", false), (2, *b"pixi", false), (2, *b"colr", true), (2, *b"clap", false),
+    ], associations(&with_colr));
+    let pos = with_colr.windows(4).rposition(|w| w == b"colr").unwrap();
+    assert_eq!(&[b'n',b'c',b'l',b'x', 0,2, 0,2, 0,2, 0x80][..], box_body(&with_colr[pos - 4..], b"colr").unwrap());
+    assert_eq!(avif.len() + 19 + 1, with_colr.len());
+    assert!(mp4parse::read_avif(&mut with_colr.as_slice(), mp4parse::ParseStrictness::Strict).is_ok());
+}
+
+#[test]
+fn depth_16() {
+    let err = Aviffy::new().try_to_vec(&[1], None, 5, 5, 16).unwrap_err();
+    assert!(matches!(err, Error::InvalidDepth(16)));
+    assert_eq!("AV1 supports only 8, 10 or 12 bits per channel, not 16", err.to_string());
+    assert!(Aviffy::new().alpha_depth_bits(16).try_to_vec(&[1], Some(&[2]), 5, 5, 12).is_err());
+    assert!(Av1Config::new(2, 0, false, 16, ChromaSubsampling::Cs444, false, ChromaSamplePosition::Unknown).is_err());
+}
+
+#[test]
+fn monochrome_pq() {
+    let mut aviffy = Aviffy::new();
+    aviffy.monochrome(true)
+        .color_primaries(ColorPrimaries::Bt2020)
+        .transfer_characteristics(TransferCharacteristics::Smpte2084)
+        .matrix_coefficients(MatrixCoefficients::Unspecified);
+    let avif = aviffy.try_to_vec(&[1], None, 5, 5, 10).unwrap();
+    assert_eq!(&[b'n',b'c',b'l',b'x', 0,9, 0,16, 0,2, 0x80][..], box_body(&avif, b"colr").unwrap());
+    // monochrome, 4:2:0 flags
+    assert_eq!(0b0101_1100, box_body(&avif, b"av1C").unwrap()[2]);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item_coded_data());
+
+    let err = aviffy.matrix_coefficients(MatrixCoefficients::Identity).try_to_vec(&[1], None, 5, 5, 10).unwrap_err();
+    assert!(matches!(err, Error::IdentityMatrixMonochrome));
+}
+
+#[test]
+fn empty_data() {
+    let error = |color: &[u8], alpha: Option<&[u8]>| {
+        Aviffy::new().try_to_vec(color, alpha, 5, 5, 8).unwrap_err()
+    };
+    assert!(matches!(error(&[], None), Error::EmptyColor));
+    assert!(matches!(error(&[], Some(&[2])), Error::EmptyColor));
+    assert!(matches!(error(&[1], Some(&[])), Error::EmptyAlpha));
+    assert!(Aviffy::new().write_with_extents(&mut Vec::new(), &[], None, 5, 5, 8).is_err());
+
+    // nothing is written, so there's no half-made file with a broken alpha item
+    let mut out = Vec::new();
+    assert!(Aviffy::new().write(&mut out, &[1], Some(&[]), 5, 5, 8).is_err());
+    assert!(out.is_empty());
+    assert!(Aviffy::new().write_with_extents(&mut out, &[&[1]], Some(&[&[], &[]]), 5, 5, 8).is_err());
+    assert!(out.is_empty());
+}
+
+#[test]
+fn alpha_size_validation() {
+    use crate::obu::test_streams::*;
+
+    let color = stream(&SequenceConfig { frame_size: Some((60, 40)), reduced_still_picture_header: false, ..Default::default() });
+    let mono = SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, reduced_still_picture_header: false, ..Default::default() };
+    let alpha = stream(&SequenceConfig { frame_size: Some((60, 40)), ..mono.clone() });
+    let small_alpha = stream(&SequenceConfig { frame_size: Some((30, 20)), ..mono });
+
+    let mut aviffy = Aviffy::new();
+    aviffy.validate_bitstream(true);
+    assert!(aviffy.write(&mut Vec::new(), &color, Some(&alpha), 60, 40, 8).is_ok());
+    let err = aviffy.write(&mut Vec::new(), &color, Some(&small_alpha), 60, 40, 8).unwrap_err();
+    assert!(matches!(err, Error::MismatchedBitstream(BitstreamMismatch::AlphaSize { configured: (60, 40), bitstream: (30, 20) })), "{:?}", err);
+    // not checked by default
+    assert!(Aviffy::new().write(&mut Vec::new(), &color, Some(&small_alpha), 60, 40, 8).is_ok());
+}
+
+#[test]
+fn alpha_monochrome_validation() {
+    use crate::obu::test_streams::*;
+
+    let color = stream(&SequenceConfig::default());
+    let alpha = stream(&SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() });
+    let mut aviffy = Aviffy::new();
+    aviffy.validate_bitstream(true);
+    assert!(aviffy.write(&mut Vec::new(), &color, Some(&alpha), 64, 48, 8).is_ok());
+    // the color data given as alpha by mistake
+    let err = aviffy.write(&mut Vec::new(), &color, Some(&color), 64, 48, 8).unwrap_err();
+    assert!(matches!(err, Error::MismatchedBitstream(BitstreamMismatch::AlphaHasColor)), "{:?}", err);
+    assert!(Aviffy::new().write(&mut Vec::new(), &color, Some(&color), 64, 48, 8).is_ok());
+}
+
+#[test]
+fn vec_allocation() {
+    let mut aviffy = Aviffy::new();
+    aviffy.align_mdat(64).append_box(FourCC(*b"free"), &[0; 10]);
+    let avif = aviffy.try_to_vec(&[1; 100], Some(&[2; 50]), 5, 5, 8).unwrap();
+    // allocated exactly once
+    assert_eq!(avif.capacity(), avif.len());
+
+    let mut out = Vec::new();
+    aviffy.write(&mut out, &[1; 100], Some(&[2; 50]), 5, 5, 8).unwrap();
+    assert_eq!(out, avif);
+}
+
+#[test]
+fn monochrome_with_alpha() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let avif = Aviffy::new().monochrome(true).premultiplied_alpha(true).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+
+    // both items are monochrome, and share the same av1C and pixi
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"av1C").count());
+    assert_eq!(&[0x81, 0, 0b0001_1100, 0][..], box_body(&avif, b"av1C").unwrap());
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"pixi").count());
+    assert_eq!(&[0,0,0,0, 1, 8][..], box_body(&avif, b"pixi").unwrap());
+    assert!(box_body(&avif, b"auxC").is_some());
+    assert!(box_body(&avif, b"prem").is_some());
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert!(ctx.premultiplied_alpha);
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+}
+
+#[test]
+fn shared_properties() {
+    let count = |avif: &[u8], typ: &[u8; 4]| avif.windows(4).filter(|w| w == typ).count();
+
+    // color and alpha av1C differ
+    let avif = Aviffy::new().crop_to(4, 4).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!((1, 2, 2, 1), (count(&avif, b"ispe"), count(&avif, b"av1C"), count(&avif, b"pixi"), count(&avif, b"clap")));
+
+    // monochrome color has the same av1C and pixi as alpha
+    let avif = Aviffy::new().monochrome(true).crop_to(4, 4).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!((1, 1, 1, 1), (count(&avif, b"ispe"), count(&avif, b"av1C"), count(&avif, b"pixi"), count(&avif, b"clap")));
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    // ispe, av1C, pixi, clap for color; ispe, av1C, auxC, pixi, clap for alpha
+    assert_eq!(&[0,1, 4, 1, 0x82, 3, 0x84, 0,2, 5, 1, 0x82, 5, 3, 0x84][..], &ipma[8..]);
+
+    // different level
+    let avif = Aviffy::new().monochrome(true).alpha_seq_level_idx(1).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!((2, 1), (count(&avif, b"av1C"), count(&avif, b"pixi")));
+}
+
+#[test]
+fn unbuffered_image_data() {
+    // Records the buffer given to each write() call
+    struct Writes(Vec<(*const u8, usize)>, Vec<u8>);
+    impl io::Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push((buf.as_ptr(), buf.len()));
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let color = [&[1; 100][..], &[2; 200]];
+    let alpha = [&[3; 50][..]];
+    let mut out = Writes(Vec::new(), Vec::new());
+    Aviffy::new().write_with_extents(&mut out, &color, Some(&alpha), 5, 5, 8).unwrap();
+    for chunk in color.iter().chain(&alpha) {
+        assert!(out.0.contains(&(chunk.as_ptr(), chunk.len())));
+    }
+
+    let avif = out.1;
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[&[1; 100][..], &[2; 200]].concat(), ctx.primary_item.as_slice());
+    assert_eq!(&[3; 50][..], ctx.alpha_item.as_deref().unwrap());
+
+    let mut out = Writes(Vec::new(), Vec::new());
+    Aviffy::new().write(&mut out, &color.concat(), Some(alpha[0]), 5, 5, 8).unwrap();
+    assert_eq!(out.1, Aviffy::new().try_to_vec(&color.concat(), Some(alpha[0]), 5, 5, 8).unwrap());
 }
 
 #[test]
-fn premultiplied_flag() {
-    let test_img = [1,2,3,4];
-    let test_alpha = [55,66,77,88,99];
-    let avif = Aviffy::new().premultiplied_alpha(true).to_vec(&test_img, Some(&test_alpha), 5, 5, 8);
+fn vectored_image_data() {
+    // Accepts at most `limit` bytes per call
+    struct Vectored { calls: usize, limit: usize, out: Vec<u8> }
+    impl io::Write for Vectored {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[io::IoSlice::new(buf)])
+        }
+        fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let n = buf.len().min(self.limit - written);
+                self.out.extend_from_slice(&buf[..n]);
+                written += n;
+            }
+            Ok(written)
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let color = [&[1; 100][..], &[2; 200], &[3; 300]];
+    let alpha = [&[4; 50][..], &[5; 60]];
+    let expected = {
+        let mut out = Vec::new();
+        Aviffy::new().write_with_extents(&mut out, &color, Some(&alpha), 5, 5, 8).unwrap();
+        out
+    };
+
+    let mut unlimited = Vectored { calls: 0, limit: usize::MAX, out: Vec::new() };
+    Aviffy::new().write_with_extents(&mut unlimited, &color, Some(&alpha), 5, 5, 8).unwrap();
+    assert_eq!(expected, unlimited.out);
+    // all boxes and all of the data at once
+    assert_eq!(1, unlimited.calls);
+
+    // partial writes
+    let mut limited = Vectored { calls: 0, limit: 77, out: Vec::new() };
+    Aviffy::new().write_with_extents(&mut limited, &color, Some(&alpha), 5, 5, 8).unwrap();
+    assert_eq!(expected, limited.out);
+
+    // boxes before and after mdat are in the same write too
+    let mut aviffy = Aviffy::new();
+    aviffy.align_mdat(16).append_box(FourCC(*b"Test"), b"hi").append_box(FourCC(*b"skip"), &[0; 300]);
+    let mut expected = Vec::new();
+    aviffy.write_with_extents(&mut expected, &color, Some(&alpha), 5, 5, 8).unwrap();
+    let mut unlimited = Vectored { calls: 0, limit: usize::MAX, out: Vec::new() };
+    aviffy.write_with_extents(&mut unlimited, &color, Some(&alpha), 5, 5, 8).unwrap();
+    assert_eq!(expected, unlimited.out);
+    assert_eq!(1, unlimited.calls);
 
+    // writers without vectored I/O get one slice at a time
+    struct Sequential(Vec<u8>);
+    impl io::Write for Sequential {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+    let mut sequential = Sequential(Vec::new());
+    aviffy.write_with_extents(&mut sequential, &color, Some(&alpha), 5, 5, 8).unwrap();
+    assert_eq!(expected, sequential.0);
+}
+
+#[test]
+fn alpha_urns() {
+    let auxc = |urn: AlphaUrn| {
+        let avif = Aviffy::new().alpha_urn(urn).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+        box_body(&avif, b"auxC").unwrap()[4..].to_vec()
+    };
+    assert_eq!(&b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0"[..], auxc(AlphaUrn::Cicp));
+    assert_eq!(&b"urn:mpeg:hevc:2015:auxid:1\0"[..], auxc(AlphaUrn::Hevc));
+    assert_eq!(&b"urn:x\0"[..], auxc(AlphaUrn::Custom("urn:x".into())));
+    assert_eq!(Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap(), Aviffy::new().alpha_urn(AlphaUrn::Cicp).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap());
+
+    let avif = Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
     let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[2][..], ctx.alpha_item.as_deref().unwrap());
 
-    assert!(ctx.premultiplied_alpha);
+    assert!(Aviffy::new().alpha_urn(AlphaUrn::Custom("urn\0x".into())).write(&mut Vec::new(), &[1], Some(&[2]), 5, 5, 8).is_err());
+}
+
+#[test]
+fn alpha_dimensions() {
+    let count = |avif: &[u8]| avif.windows(4).filter(|w| w == b"ispe").count();
+    let same = Aviffy::new().alpha_dimensions(64, 48).try_to_vec(&[1], Some(&[2]), 64, 48, 8).unwrap();
+    assert_eq!(1, count(&same));
+    assert_eq!(same, Aviffy::new().try_to_vec(&[1], Some(&[2]), 64, 48, 8).unwrap());
+
+    let half = Aviffy::new().alpha_dimensions(32, 24).try_to_vec(&[1], Some(&[2]), 64, 48, 8).unwrap();
+    assert_eq!(2, count(&half));
+    let pos = half.windows(4).rposition(|w| w == b"ispe").unwrap();
+    assert_eq!(&[0,0,0,0, 0,0,0,32, 0,0,0,24], &half[pos + 4..pos + 16]);
+    assert_eq!(&[0,0,0,0, 0,0,0,64, 0,0,0,48], box_body(&half, b"ispe").unwrap());
+    let ipma = box_body(&half, b"ipma").unwrap();
+    assert_eq!(&[0,1, 3, 1], &ipma[8..12]);
+    assert_eq!(&[0,2, 4, 4], &ipma[14..18]);
+
+    assert!(Aviffy::new().alpha_dimensions(32, 24).crop_to(60, 40).write(&mut Vec::new(), &[1], Some(&[2]), 64, 48, 8).is_err());
+}
+
+#[test]
+fn icc_profile() {
+    let avif = Aviffy::new().icc_profile(b"ICC").try_to_vec(&[1], None, 5, 5, 8).unwrap();
+    assert_eq!(&b"profICC"[..], box_body(&avif, b"colr").unwrap());
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"colr").count());
+    assert!(avif_parse::read_avif(&mut avif.as_slice()).is_ok());
+
+    // ICC can't describe the matrix, so nclx is still needed
+    let avif = Aviffy::new().icc_profile(b"ICC").matrix_coefficients(MatrixCoefficients::Bt709).try_to_vec(&[1], None, 5, 5, 8).unwrap();
+    assert_eq!(&[b'n',b'c',b'l',b'x', 0,1, 0,13, 0,1, 0x80][..], box_body(&avif, b"colr").unwrap());
+    let pos = avif.windows(4).rposition(|w| w == b"colr").unwrap();
+    assert_eq!(&b"profICC"[..], &avif[pos + 4..pos + 11]);
+    assert_eq!(&[0,1, 5, 1, 0x82, 3, 4, 5], &box_body(&avif, b"ipma").unwrap()[8..]);
+}
+
+#[test]
+fn large_offsets() {
+    let small = Aviffy::new().try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    let avif = Aviffy::new().large_offsets(true).try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    assert_eq!(small.len() + 16, avif.len());
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(&[0,0,0,0, 0x88, 0, 0,2], &iloc[..8]);
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() as u64 + 4;
+    assert_eq!(&[0,1, 0,0, 0,1][..], &iloc[8..14]);
+    assert_eq!(&(payload + 1).to_be_bytes()[..], &iloc[14..22]);
+    assert_eq!(&2u64.to_be_bytes()[..], &iloc[22..30]);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1, 2][..], ctx.primary_item.as_slice());
+    assert_eq!(&[3][..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn iloc_base_offsets() {
+    use std::convert::TryInto;
+
+    let exif = [0, 0, 0, 0, 4];
+    let mut aviffy = Aviffy::new();
+    aviffy.add_metadata(FourCC(*b"Exif"), "", &exif);
+    let plain = aviffy.try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    let avif = aviffy.iloc_base_offsets(true).try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    assert_eq!(plain.len() + 3 * 4, avif.len());
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(&[0,0,0,0, 0x44, 0x40, 0,3], &iloc[..8]);
+    let payload = (avif.windows(4).position(|w| w == b"mdat").unwrap() + 4) as u32;
+    // (id, base_offset, extent offset, extent length), with alpha first in mdat
+    let items = iloc[8..].chunks(18).map(|item| {
+        let field = |pos: usize| u32::from_be_bytes(item[pos..pos + 4].try_into().unwrap());
+        assert_eq!(&[0,0], &item[2..4]);
+        assert_eq!(&[0,1], &item[8..10]);
+        (item[1], field(4), field(10), field(14))
+    }).collect::<Vec<_>>();
+    assert_eq!(vec![(1, payload, 1, 2), (2, payload, 0, 1), (3, payload, 3, 5)], items);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1, 2][..], ctx.primary_item_coded_data());
+    assert_eq!(&[3][..], ctx.alpha_item_coded_data());
+
+    // inserting a box before mdat only needs the base offsets updated
+    let mut moved = avif[..payload as usize - 8].to_vec();
+    moved.extend_from_slice(b"\0\0\0\x08free");
+    moved.extend_from_slice(&avif[payload as usize - 8..]);
+    let iloc_start = moved.windows(4).position(|w| w == b"iloc").unwrap() + 4;
+    for item in 0..3 {
+        let base = iloc_start + 8 + item * 18 + 4;
+        moved[base..base + 4].copy_from_slice(&(payload + 8).to_be_bytes());
+    }
+    let ctx = avif_parse::read_avif(&mut moved.as_slice()).unwrap();
+    assert_eq!(&[1, 2][..], ctx.primary_item.as_slice());
+    assert_eq!(&[3][..], ctx.alpha_item.as_deref().unwrap());
+
+    // each mdat is a separate base
+    let avif = aviffy.separate_mdat(MdatGroup::Metadata, true).try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    let exif_payload = (avif.windows(4).rposition(|w| w == b"mdat").unwrap() + 4) as u32;
+    assert_eq!(&exif_payload.to_be_bytes(), &iloc[8 + 2 * 18 + 4..8 + 2 * 18 + 8]);
+    assert_eq!(&[0,0,0,0, 0,0,0,5], &iloc[8 + 2 * 18 + 10..]);
+    assert_eq!(&exif[..], &avif[exif_payload as usize..]);
+
+    // offsets stay even if they're all 0
+    let avif = Aviffy::new().iloc_base_offsets(true).try_to_vec(&[1, 2], None, 5, 5, 8).unwrap();
+    assert_eq!(&[0x44, 0x40], &box_body(&avif, b"iloc").unwrap()[4..6]);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1, 2][..], ctx.primary_item_coded_data());
+
+    // idat has no base
+    let avif = aviffy.idat_threshold(100).try_to_vec(&[1, 2], Some(&[3]), 5, 5, 8).unwrap();
+    assert_eq!(0, box_body(&avif, b"iloc").unwrap()[5]);
+}
+
+/// Writes over 4GB without allocating it
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn over_4gb() {
+    #[derive(Default)]
+    struct Sink { head: Vec<u8>, len: u64 }
+    impl io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[io::IoSlice::new(buf)])
+        }
+        fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+            let mut written = 0;
+            for buf in bufs {
+                if self.head.len() < 4096 {
+                    self.head.extend_from_slice(&buf[..buf.len().min(4096)]);
+                }
+                written += buf.len();
+            }
+            self.len += written as u64;
+            Ok(written)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // zeroed memory is mapped lazily, and never read by the sink
+    let gigabyte = vec![0u8; 1 << 30];
+    let mut sink = Sink::default();
+    Aviffy::new().write_with_extents(&mut sink, &[&gigabyte[..]; 5], Some(&[&[1]]), 5, 5, 8).unwrap();
+
+    let mdat = sink.head.windows(4).position(|w| w == b"mdat").unwrap();
+    assert_eq!(&[0,0,0,1], &sink.head[mdat - 4..mdat], "64-bit size");
+    let mdat_len = 16 + 1 + (5u64 << 30);
+    assert_eq!(&mdat_len.to_be_bytes(), &sink.head[mdat + 4..mdat + 12]);
+    assert_eq!(mdat as u64 - 4 + mdat_len, sink.len);
+
+    let iloc = box_body(&sink.head, b"iloc").unwrap();
+    assert_eq!(0x88, iloc[4]);
+    assert_eq!(&[0,1, 0,0, 0,5][..], &iloc[8..14]);
+    let payload = mdat as u64 + 12;
+    let last_color_extent = &iloc[14 + 4 * 16..14 + 5 * 16];
+    assert_eq!(&(payload + 1 + (4 << 30)).to_be_bytes()[..], &last_color_extent[..8]);
+    assert_eq!(&(1u64 << 30).to_be_bytes()[..], &last_color_extent[8..]);
+    assert_eq!(&[0,2, 0,0, 0,1][..], &iloc[14 + 5 * 16..20 + 5 * 16]);
+    assert_eq!(&payload.to_be_bytes()[..], &iloc[20 + 5 * 16..28 + 5 * 16]);
+}
+
+#[test]
+fn iloc_field_sizes() {
+    let avif = Aviffy::new().try_to_vec(&[1], None, 1, 1, 8).unwrap();
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(4 << 4 | 4, iloc[4]);
+    // regression guard for the size of the smallest file
+    assert_eq!(243, avif.len());
+
+    // only 4 and 8 are allowed, and 8 bytes are used only when needed
+    let avif = Aviffy::new().align_mdat(1 << 16).try_to_vec(&[1], Some(&[2]), 1, 1, 8).unwrap();
+    assert_eq!(4 << 4 | 4, box_body(&avif, b"iloc").unwrap()[4]);
+}
+
+#[test]
+fn alternatives_group() {
+    let mut aviffy = Aviffy::new();
+    aviffy.add_alternative(&[3, 4], 2, 2);
+    let avif = aviffy.try_to_vec(&[1], Some(&[2]), 4, 4, 8).unwrap();
+    assert_eq!(&[0,0,0,0, 0,3], &box_body(&avif, b"iinf").unwrap()[..6]);
+    // altr group 4 of items 1 and 3
+    assert_eq!(&[0,0,0,0, 0,0,0,4, 0,0,0,2, 0,0,0,1, 0,0,0,3][..], box_body(&avif, b"altr").unwrap());
+    assert_eq!(&[0,0,0,36, b'g',b'r',b'p',b'l'], &avif[avif.windows(4).position(|w| w == b"grpl").unwrap() - 4..][..8]);
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[2, 1, 3, 4], &avif[payload..]);
+    // own ispe, and the same av1C and pixi as the primary image
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    assert_eq!(&[0,3, 3, 7, 0x82, 3], &ipma[ipma.len() - 6..]);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item.as_slice());
+    assert_eq!(&[2][..], ctx.alpha_item.as_deref().unwrap());
+
+    let ungrouped = aviffy.group_alternatives(false).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert!(box_body(&ungrouped, b"grpl").is_none());
+    assert_eq!(&[0,0,0,0, 0,2], &box_body(&ungrouped, b"iinf").unwrap()[..6]);
+
+    assert!(Aviffy::new().add_alternative(&[], 2, 2).write(&mut Vec::new(), &[1], None, 4, 4, 8).is_err());
+    assert!(Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420).add_alternative(&[3], 3, 3).write(&mut Vec::new(), &[1], None, 4, 4, 8).is_err());
+
+    // the primary image's level and configOBUs aren't the large alternative's
+    let avif = Aviffy::new().seq_level_idx(0).add_alternative(&[3], 8000, 4000).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert_eq!(&[0x81, 1 << 5, 0, 0], box_body(&avif, b"av1C").unwrap());
+    let alternative_av1c = avif.windows(4).rposition(|w| w == b"av1C").unwrap();
+    assert_eq!(&[0,0,0,12, b'a',b'v',b'1',b'C', 0x81, 1 << 5 | 16, 0, 0], &avif[alternative_av1c - 4..][..12]);
+    let config = Av1Config::from_record(&[0x81, 8, 0b0000_1100, 0, 0x0A, 0]).unwrap();
+    let avif = Aviffy::new().av1c(config).add_alternative(&[3], 8000, 4000).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert_eq!(&[0x81, 8, 0b0000_1100, 0, 0x0A, 0], box_body(&avif, b"av1C").unwrap());
+    let alternative_av1c = avif.windows(4).rposition(|w| w == b"av1C").unwrap();
+    assert_eq!(&[0,0,0,12, b'a',b'v',b'1',b'C', 0x81, 16, 0b0000_1100, 0], &avif[alternative_av1c - 4..][..12]);
+}
+
+#[test]
+fn iloc_versions() {
+    let auto = Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+    assert_eq!(auto, Aviffy::new().iloc_version(IlocVersion::V0).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap());
+    assert_eq!(0, box_body(&auto, b"iloc").unwrap()[0]);
+    for &(version, byte, extra_len) in &[(IlocVersion::V1, 1, 2 * 2), (IlocVersion::V2, 2, 2 * 2 + 2 + 2 * 2)] {
+        let avif = Aviffy::new().iloc_version(version).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+        assert_eq!(auto.len() + extra_len, avif.len());
+        assert_eq!(byte, box_body(&avif, b"iloc").unwrap()[0]);
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&[1][..], ctx.primary_item.as_slice());
+        assert_eq!(&[2][..], ctx.alpha_item.as_deref().unwrap());
+        let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+        assert_eq!(&[1][..], ctx.primary_item_coded_data());
+    }
+}
+
+#[test]
+fn stereo_pair() {
+    let avif = Aviffy::new().stereo_right_eye(&[2]).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert_eq!(&[0,0,0,0, 0,2], &box_body(&avif, b"iinf").unwrap()[..6]);
+    // ster group 4 of left eye 1 and right eye 3
+    assert_eq!(&[0,0,0,0, 0,0,0,4, 0,0,0,2, 0,0,0,1, 0,0,0,3][..], box_body(&avif, b"ster").unwrap());
+    assert!(box_body(&avif, b"altr").is_none());
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[1, 2], &avif[payload..]);
+    // same properties as the left eye
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    assert_eq!(&[0,1, 3, 1, 0x82, 3, 0,3, 3, 1, 0x82, 3], &ipma[8..]);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item.as_slice());
+
+    // with alpha, cropping and an alternative
+    let avif = Aviffy::new().stereo_right_eye(&[3]).add_alternative(&[4], 2, 2).crop_to(3, 3).try_to_vec(&[1], Some(&[2]), 4, 4, 8).unwrap();
+    assert_eq!(&[0,0,0,0, 0,0,0,5, 0,0,0,2, 0,0,0,1, 0,0,0,4][..], box_body(&avif, b"ster").unwrap());
+    assert_eq!(&[0,0,0,0, 0,0,0,6, 0,0,0,2, 0,0,0,1, 0,0,0,3][..], box_body(&avif, b"altr").unwrap());
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    assert_eq!(&[0,4, 4, 1, 0x82, 3, 0x84], &ipma[ipma.len() - 7..], "cropped right eye");
+}
+
+#[test]
+fn idat() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [5,6,7];
+    let in_mdat = Aviffy::new().idat_threshold(3).try_to_vec(&test_img, None, 5, 5, 8).unwrap();
+    let avif = Aviffy::new().idat_threshold(4).try_to_vec(&test_img, None, 5, 5, 8).unwrap();
+    assert!(box_body(&in_mdat, b"idat").is_none());
+    assert!(box_body(&avif, b"mdat").is_none());
+    assert!(avif.len() < in_mdat.len());
+    assert_eq!(&test_img[..], box_body(&avif, b"idat").unwrap());
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    // version 1, no offsets, item 1 with construction method 1
+    assert_eq!(&[1,0,0,0, 0x04, 0, 0,1, 0,1, 0,1, 0,0, 0,1, 0,0,0,4][..], iloc);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+
+    let avif = Aviffy::new().idat_threshold(7).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap();
+    assert!(box_body(&avif, b"mdat").is_none());
+    assert_eq!(&[5,6,7,1,2,3,4][..], box_body(&avif, b"idat").unwrap());
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    // color at offset 3 and alpha at offset 0 in idat
+    assert_eq!(&[0,1, 0,1, 0,0, 0,1, 0,0,0,3, 0,0,0,4, 0,2, 0,1, 0,0, 0,1, 0,0,0,0, 0,0,0,3], &iloc[8..]);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_alpha[..], ctx.alpha_item_coded_data());
+
+    let err = Aviffy::new().idat_threshold(100).iloc_version(IlocVersion::V0).try_to_vec(&test_img, None, 5, 5, 8).unwrap_err();
+    assert!(matches!(err, Error::IdatIlocVersion));
+    assert_eq!(avif, Aviffy::new().idat_threshold(7).iloc_version(IlocVersion::V1).try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap());
+}
+
+#[test]
+fn ftyp_brand() {
+    let avif = Aviffy::new().try_to_vec(&[1], None, 1, 1, 8).unwrap();
+    assert_eq!(b"avif\0\0\0\0mif1miaf", box_body(&avif, b"ftyp").unwrap());
+
+    let avif = Aviffy::new().major_brand(FourCC(*b"avis")).minor_version(0x0102_0304).try_to_vec(&[1], None, 1, 1, 8).unwrap();
+    assert_eq!(b"avis\x01\x02\x03\x04avifmif1miaf", box_body(&avif, b"ftyp").unwrap());
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item_coded_data());
+
+    let err = Aviffy::new().major_brand(FourCC(*b"heic")).try_to_vec(&[1], None, 1, 1, 8).unwrap_err();
+    assert!(matches!(err, Error::UnsupportedBrand(FourCC(typ)) if &typ == b"heic"));
+}
+
+#[test]
+fn large_mdat() {
+    let test_img = [1,2,3,4];
+    let small = Aviffy::new().try_to_vec(&test_img, None, 5, 5, 8).unwrap();
+    let avif = Aviffy::new().large_mdat(true).try_to_vec(&test_img, None, 5, 5, 8).unwrap();
+    assert_eq!(small.len() + 8, avif.len());
+    let mdat = avif.windows(4).position(|w| w == b"mdat").unwrap();
+    assert_eq!(&[0,0,0,1], &avif[mdat - 4..mdat], "64-bit size");
+    assert_eq!(&20u64.to_be_bytes(), &avif[mdat + 4..mdat + 12]);
+    assert_eq!(&test_img, &avif[mdat + 12..]);
+    // iloc points after the longer header
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(&(mdat as u32 + 12).to_be_bytes(), &iloc[14..18]);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
     assert_eq!(&test_img[..], ctx.primary_item.as_slice());
-    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn streaming() {
+    /// Reads at most 3 bytes at a time
+    struct Chunked<'a>(&'a [u8]);
+    impl io::Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3).min(self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+    let test_img = [1,2,3,4,5,6,7,8];
+    let test_alpha = [9,10,11,12];
+    for &order in &[DataOrder::AlphaFirst, DataOrder::ColorFirst] {
+        let mut aviffy = Aviffy::new();
+        aviffy.data_order(order).append_box(FourCC(*b"Test"), b"hi").align_mdat(64);
+        let mut avif = Vec::new();
+        aviffy.write_streaming(&mut avif, (Chunked(&test_img), 8), Some((Chunked(&test_alpha), 4)), 5, 5, 8).unwrap();
+        assert_eq!(aviffy.try_to_vec(&test_img, Some(&test_alpha), 5, 5, 8).unwrap(), avif);
+    }
+    let mut avif = Vec::new();
+    Aviffy::new().write_streaming(&mut avif, (&test_img[..], 8), None, 5, 5, 8).unwrap();
+    assert_eq!(Aviffy::new().try_to_vec(&test_img, None, 5, 5, 8).unwrap(), avif);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+
+    // length mismatches
+    let err = Aviffy::new().write_streaming(&mut Vec::new(), (Chunked(&test_img), 9), None, 5, 5, 8).unwrap_err();
+    assert!(matches!(err, Error::MissingData(1)), "{:?}", err);
+    let err = Aviffy::new().write_streaming(&mut Vec::new(), (Chunked(&test_img), 8), Some((Chunked(&test_alpha), 3)), 5, 5, 8).unwrap_err();
+    assert!(matches!(err, Error::ExcessData(3)), "{:?}", err);
+    let err = Aviffy::new().validate_bitstream(true).write_streaming(&mut Vec::new(), (Chunked(&test_img), 8), None, 5, 5, 8).unwrap_err();
+    assert!(matches!(err, Error::StreamedBitstream));
+}
+
+#[test]
+fn layer_selection() {
+    let avif = Aviffy::new().select_layer(2).crop_to(4, 4).try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap();
+    assert_eq!(&[0, 2], box_body(&avif, b"lsel").unwrap());
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    // ispe, av1C, pixi, essential lsel, then clap for the color image
+    assert_eq!(&[0,1, 5, 1, 0x82, 3, 0x84, 0x85], &ipma[8..16]);
+    assert!(!ipma[16..].contains(&0x84), "no lsel for alpha");
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item_coded_data());
+
+    assert!(box_body(&Aviffy::new().try_to_vec(&[1], Some(&[2]), 6, 6, 8).unwrap(), b"lsel").is_none());
+}
+
+#[test]
+fn write_errors() {
+    /// Fails after `self.0` bytes
+    struct Failing(usize);
+    impl io::Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let len = Aviffy::new().append_box(FourCC(*b"Test"), b"hi").try_to_vec(&[1,2,3], Some(&[4]), 5, 5, 8).unwrap().len();
+    // incomplete boxes are not a bug in box sizes
+    for n in 0..len {
+        let err = Aviffy::new().append_box(FourCC(*b"Test"), b"hi").write(Failing(n), &[1,2,3], Some(&[4]), 5, 5, 8).unwrap_err();
+        assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::BrokenPipe), "{:?}", err);
+    }
+    assert!(Aviffy::new().append_box(FourCC(*b"Test"), b"hi").write(Failing(len), &[1,2,3], Some(&[4]), 5, 5, 8).is_ok());
+}
+
+#[test]
+fn error_conversions() {
+    let invalid_input = io::Error::from(Error::ZeroAlignment);
+    assert_eq!(io::ErrorKind::InvalidInput, invalid_input.kind());
+    assert!(matches!(Error::from_io_error(&invalid_input), Some(Error::ZeroAlignment)));
+    assert!(matches!(Error::from(invalid_input), Error::ZeroAlignment));
+
+    // wrapped as before, so that downcasting to BitstreamMismatch keeps working
+    let mismatch = io::Error::from(Error::MismatchedBitstream(BitstreamMismatch::AlphaHasColor));
+    assert_eq!(io::ErrorKind::InvalidData, mismatch.kind());
+    assert_eq!(Some(&BitstreamMismatch::AlphaHasColor), mismatch.get_ref().and_then(|e| e.downcast_ref()));
+    assert!(matches!(Error::from(mismatch), Error::MismatchedBitstream(BitstreamMismatch::AlphaHasColor)));
+
+    let invalid_data = io::Error::from(Error::InvalidAv1Data(InvalidAv1Data::Ivf));
+    assert_eq!(io::ErrorKind::InvalidData, invalid_data.kind());
+    assert!(Error::from_io_error(&invalid_data).is_none());
+    assert!(matches!(Error::from(invalid_data), Error::InvalidAv1Data(InvalidAv1Data::Ivf)));
+
+    let err = Error::from(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+    assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::BrokenPipe));
+    assert_eq!("closed", std::error::Error::source(&err).unwrap().to_string());
+    let err = io::Error::from(err);
+    assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+    assert_eq!("closed", err.to_string());
+
+    assert_eq!(io::ErrorKind::UnexpectedEof, io::Error::from(Error::MissingData(1)).kind());
+    let malformed = io::Error::from(Error::Malformed("Truncated box"));
+    assert_eq!(io::ErrorKind::InvalidData, malformed.kind());
+    assert_eq!("Truncated box", malformed.to_string());
+
+    let err = Aviffy::new().check_obu_start(true).write(&mut Vec::new(), b"DKIF\0\0\x20\0AV01", None, 1, 1, 8).unwrap_err();
+    assert!(matches!(err, Error::InvalidAv1Data(InvalidAv1Data::Ivf)));
+    let err = Aviffy::new().derive_av1c_from_bitstream(true).write(&mut Vec::new(), &[0x12, 0], None, 1, 1, 8).unwrap_err();
+    assert!(matches!(err, Error::InvalidAv1Data(InvalidAv1Data::NoSequenceHeader)));
+}
+
+
+#[test]
+fn metadata_items() {
+    let exif = [0,0,0,0, b'M',b'M',0,42];
+    let avif = Aviffy::new()
+        .add_metadata(FourCC(*b"Exif"), "", &exif)
+        .add_metadata(FourCC(*b"mime"), "application/rdf+xml", b"<x/>")
+        .try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    // items 3 and 4 describe the primary item
+    assert_eq!(&[0,0,0,0, 0,0,0,14, b'c',b'd',b's',b'c', 0,3, 0,1, 0,1, 0,0,0,14, b'c',b'd',b's',b'c', 0,4, 0,1, 0,1][..], box_body(&avif, b"iref").unwrap());
+    assert!(avif.windows(25).any(|w| w == b"mime\0application/rdf+xml\0"));
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[1, 0,0,0,0, b'M',b'M',0,42, b'<',b'x',b'/',b'>'], &avif[payload..]);
+    // no properties
+    assert_eq!(1, box_body(&avif, b"ipma").unwrap()[7]);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1], ctx.primary_item_coded_data());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item.as_slice());
+
+    // numbered after the alpha and the alternatives, before the groups
+    let avif = Aviffy::new().premultiplied_alpha(true).add_alternative(&[3], 2, 2).add_metadata(FourCC(*b"Exif"), "", &exif).try_to_vec(&[1], Some(&[2]), 4, 4, 8).unwrap();
+    assert_eq!(&[0,0,0,14, b'c',b'd',b's',b'c', 0,4, 0,1, 0,1][..], &box_body(&avif, b"iref").unwrap()[32..]);
+    assert_eq!(&[0,0,0,0, 0,0,0,5, 0,0,0,2, 0,0,0,1, 0,0,0,3][..], box_body(&avif, b"altr").unwrap());
+    mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    let error = |item_type: &[u8; 4], content_type, data: &[u8]| {
+        Aviffy::new().add_metadata(FourCC(*item_type), content_type, data).try_to_vec(&[1], None, 4, 4, 8).unwrap_err()
+    };
+    assert!(matches!(error(b"Exif", "", &[]), Error::InvalidMetadata));
+    assert!(matches!(error(b"Exif", "image/tiff", &exif), Error::InvalidMetadata));
+    assert!(matches!(error(b"mime", "", b"<x/>"), Error::InvalidMetadata));
+    assert!(matches!(error(b"mime", "text/\0", b"<x/>"), Error::InvalidMetadata));
+}
+
+#[test]
+fn uri_items() {
+    let uri_type = "https://example.com/sidecar/v1";
+    let sidecar = br#"{"exposure":4}"#;
+    let avif = Aviffy::new().add_uri_item(uri_type, sidecar).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    // infe version 2, id 3, no protection, name "", uri type
+    let infe = avif.windows(4).position(|w| w == b"infe").unwrap() + 4;
+    let infe = avif[infe..].windows(4).position(|w| w == b"infe").unwrap() + infe + 4;
+    assert_eq!([&[2,0,0,0, 0,3, 0,0][..], b"uri \0", uri_type.as_bytes(), b"\0"].concat(), &avif[infe..infe + 14 + uri_type.len()]);
+    assert_eq!(&[0,0,0,0, 0,0,0,14, b'c',b'd',b's',b'c', 0,3, 0,1, 0,1][..], box_body(&avif, b"iref").unwrap());
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!([&[1][..], sidecar].concat(), &avif[payload..]);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&[1][..], ctx.primary_item.as_slice());
+
+    let existing = Aviffy::from_existing(&avif).unwrap();
+    assert_eq!((FourCC(*b"uri "), uri_type.to_owned(), ItemData::Embedded(sidecar.to_vec())), existing.aviffy.metadata[0]);
+
+    let error = |uri_type| {
+        Aviffy::new().add_uri_item(uri_type, sidecar).try_to_vec(&[1], None, 4, 4, 8).unwrap_err()
+    };
+    assert!(matches!(error(""), Error::InvalidMetadata));
+    assert!(matches!(error("urn:\0"), Error::InvalidMetadata));
+}
+
+#[test]
+fn box_trees() {
+    use std::convert::TryInto;
+
+    // every box starts where the tree says, and children fill the end of their parent
+    fn check(file: &[u8], boxes: &[BoxInfo], end: usize) {
+        let mut pos = end - boxes.iter().map(|b| b.len as usize).sum::<usize>();
+        for b in boxes {
+            assert_eq!(b.len, u64::from(u32::from_be_bytes(file[pos..pos + 4].try_into().unwrap())), "{:?}", b.typ);
+            assert_eq!(b.typ.0, file[pos + 4..pos + 8], "{:?}", b.typ);
+            check(file, &b.children, pos + b.len as usize);
+            pos += b.len as usize;
+        }
+    }
+    fn types(boxes: &[BoxInfo]) -> String {
+        boxes.iter().map(|b| if b.children.is_empty() { b.typ.to_string() } else { format!("{}({})", b.typ, types(&b.children)) }).collect::<Vec<_>>().join(" ")
+    }
+
+    let tree = Aviffy::new().box_tree(&[1; 10], Some(&[2; 5]), 4, 4, 8).unwrap();
+    assert_eq!("ftyp meta(hdlr pitm iloc iinf(infe infe) iref(auxl) iprp(ipco(ispe av1C pixi av1C pixi auxC) ipma)) mdat", types(&tree));
+    let avif = Aviffy::new().try_to_vec(&[1; 10], Some(&[2; 5]), 4, 4, 8).unwrap();
+    check(&avif, &tree, avif.len());
+    assert_eq!(8 + 15, tree[2].len);
+    assert!(tree[1].find(FourCC(*b"colr")).is_none());
+
+    let mut aviffy = Aviffy::new();
+    aviffy.matrix_coefficients(MatrixCoefficients::Bt709).layout(Layout::MdatFirst).align_mdat(64)
+        .add_metadata(FourCC(*b"Exif"), "", &[0; 8]).add_external_metadata(FourCC(*b"Exif"), "", "exif.bin", 9)
+        .add_alternative(&[3], 2, 2).append_box(FourCC(*b"skip"), b"end").pad_to_size(1000);
+    let tree = aviffy.box_tree(&[1; 10], Some(&[2; 5]), 4, 4, 8).unwrap();
+    assert_eq!(["ftyp", "free", "mdat", "meta", "skip", "free"], &tree.iter().map(|b| b.typ.to_string()).collect::<Vec<_>>()[..]);
+    let avif = aviffy.try_to_vec(&[1; 10], Some(&[2; 5]), 4, 4, 8).unwrap();
+    check(&avif, &tree, avif.len());
+    assert_eq!(1000, tree.iter().map(|b| b.len).sum::<u64>());
+    let meta = &tree[3];
+    assert_eq!(19, meta.find(FourCC(*b"colr")).unwrap().len);
+    assert_eq!(1, meta.find(FourCC(*b"dref")).unwrap().children.len());
+    assert!(meta.find(FourCC(*b"altr")).is_some());
+
+    assert!(Aviffy::new().box_tree(&[1], None, 0, 4, 8).is_err());
+}
+
+#[test]
+fn invalid_arguments() {
+    let error = |aviffy: &Aviffy, width, height, depth_bits| {
+        let err = io::Error::from(aviffy.try_to_vec(&[1], Some(&[2]), width, height, depth_bits).unwrap_err());
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        Error::from(err)
+    };
+    assert!(matches!(error(&Aviffy::new(), 0, 5, 8), Error::InvalidDimensions { width: 0, height: 5 }));
+    assert!(matches!(error(&Aviffy::new(), 5, 0, 8), Error::InvalidDimensions { width: 5, height: 0 }));
+    assert!(matches!(error(Aviffy::new().encoded_size(0, 0), 5, 5, 8), Error::InvalidDimensions { width: 0, height: 0 }));
+    assert!(matches!(error(Aviffy::new().alpha_dimensions(0, 0), 5, 5, 8), Error::InvalidDimensions { width: 0, height: 0 }));
+    assert!(matches!(error(Aviffy::new().add_alternative(&[3], 2, 0), 5, 5, 8), Error::InvalidDimensions { width: 2, height: 0 }));
+    assert!(matches!(error(&Aviffy::new(), 5, 5, 9), Error::InvalidDepth(9)));
+    assert!(matches!(error(&Aviffy::new(), 5, 5, 16), Error::InvalidDepth(16)));
+    assert!(serialize(&mut Vec::new(), &[1], None, 0, 0, 8).is_err());
+    for &(size, valid) in &[(0, false), (1, true), (65536, true), (65537, false)] {
+        for &(width, height) in &[(size, 1), (1, size)] {
+            let res = Aviffy::new().try_to_vec(&[1], Some(&[2]), width, height, 8);
+            assert_eq!(valid, res.is_ok(), "{}x{}", width, height);
+            if !valid {
+                assert!(matches!(error(&Aviffy::new(), width, height, 8), Error::InvalidDimensions { width: w, height: h } if (w, h) == (width, height)));
+                assert!(matches!(error(Aviffy::new().alpha_dimensions(width, height), 1, 1, 8), Error::InvalidDimensions { width: w, height: h } if (w, h) == (width, height)));
+            }
+        }
+    }
+    // beyond every level's MaxPicSize, so it can only have level 31
+    let avif = Aviffy::new().try_to_vec(&[1], None, 65536, 65536, 8).unwrap();
+    assert_eq!(31, box_body(&avif, b"av1C").unwrap()[1] & 0x1F);
+
+    let mut aviffy = Aviffy::new();
+    for _ in 0..9 {
+        aviffy.add_alternative(&[3], 2, 2);
+    }
+    assert!(matches!(error(&aviffy, 5, 5, 8), Error::TooManyItems { what: "alternative images", max: 8 }));
+    let mut aviffy = Aviffy::new();
+    for _ in 0..5 {
+        aviffy.add_metadata(FourCC(*b"Exif"), "", &[0; 4]).append_box(FourCC(*b"skip"), b"");
+    }
+    assert!(matches!(error(&aviffy, 5, 5, 8), Error::TooManyItems { what: "metadata items", max: 4 }));
+    let mut aviffy = Aviffy::new();
+    for _ in 0..5 {
+        aviffy.append_box(FourCC(*b"skip"), b"");
+    }
+    assert!(matches!(error(&aviffy, 5, 5, 8), Error::TooManyItems { what: "boxes", max: 4 }));
+
+    // the errors tell the arguments, the data, and the output apart
+    let mut out = Vec::new();
+    Aviffy::new().write(&mut out, &[1], Some(&[2]), 5, 5, 8).unwrap();
+    assert_eq!(Aviffy::new().try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap(), out);
+    assert!(matches!(Aviffy::new().write(&mut Vec::new(), &[1], None, 0, 5, 8), Err(Error::InvalidDimensions { width: 0, height: 5 })));
+    assert!(matches!(Aviffy::new().check_obu_start(true).write(&mut Vec::new(), &[0x80], None, 5, 5, 8), Err(Error::InvalidAv1Data(InvalidAv1Data::ObuForbiddenBit))));
+    let err = Aviffy::new().write(&mut [0u8; 10][..], &[1], None, 5, 5, 8).unwrap_err();
+    assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::WriteZero), "{:?}", err);
+}
+
+#[test]
+fn external_metadata() {
+    let exif = [0,0,0,0, b'M',b'M',0,42];
+    let mut aviffy = Aviffy::new();
+    aviffy.add_metadata(FourCC(*b"Exif"), "", &exif)
+        .add_external_metadata(FourCC(*b"mime"), "application/rdf+xml", "https://example.com/tile.xmp", 500)
+        .add_external_metadata(FourCC(*b"Exif"), "", "exif.bin", 70000)
+        .add_external_metadata(FourCC(*b"mime"), "text/plain", "https://example.com/tile.xmp", 20);
+    let avif = aviffy.try_to_vec(&[1], None, 4, 4, 8).unwrap();
+
+    // the same URL has one entry
+    let entry = |url: &[u8]| [&(12 + url.len() as u32 + 1).to_be_bytes()[..], b"url \0\0\0\0", url, b"\0"].concat();
+    let entries = [entry(b"https://example.com/tile.xmp"), entry(b"exif.bin")].concat();
+    assert_eq!([&(16 + entries.len() as u32).to_be_bytes()[..], b"dref\0\0\0\0\0\0\0\x02", &entries].concat(), box_body(&avif, b"dinf").unwrap());
+    // right after hdlr
+    let hdlr = avif.windows(4).position(|w| w == b"hdlr").unwrap();
+    assert_eq!(b"dinf", &avif[hdlr + box_body(&avif, b"hdlr").unwrap().len() + 8..][..4]);
+    // data_reference_index, and the start of the other file
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(&[0,4, 0,1, 0,1, 0,0,0,0, 0,0,1,0xF4, 0,5, 0,2, 0,1, 0,0,0,0, 0,1,0x11,0x70, 0,6, 0,1, 0,1, 0,0,0,0, 0,0,0,20][..], &iloc[8 + 2 * 14..]);
+    assert_eq!(&[0,3, 0,0], &iloc[8 + 14..][..4]);
+    // only the local data is in the file
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[1, 0,0,0,0, b'M',b'M',0,42], &avif[payload..]);
+
+    let existing = Aviffy::from_existing(&avif).unwrap();
+    assert_eq!(&[1][..], &*existing.color_av1_data);
+    assert_eq!((FourCC(*b"Exif"), String::new(), ItemData::Embedded(exif.to_vec())), existing.aviffy.metadata[0]);
+    assert_eq!(ItemData::External { url: "exif.bin".into(), len: 70000 }, existing.aviffy.metadata[2].2);
+    assert_eq!(avif, existing.to_vec().unwrap());
+
+    // the local data is in idat, and the external data at a file offset
+    let avif = aviffy.idat_threshold(100).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert!(!avif.windows(4).any(|w| w == b"mdat"));
+    let iloc = box_body(&avif, b"iloc").unwrap();
+    assert_eq!(1, iloc[0]);
+    assert_eq!((&[0,3, 0,1, 0,0][..], &[0,4, 0,0, 0,1][..]), (&iloc[8 + 16..][..6], &iloc[8 + 2 * 16..][..6]));
+    assert_eq!(avif, Aviffy::from_existing(&avif).unwrap().to_vec().unwrap());
+
+    let error = |url: &str, len| {
+        Aviffy::new().add_external_metadata(FourCC(*b"Exif"), "", url, len).try_to_vec(&[1], None, 4, 4, 8).unwrap_err()
+    };
+    assert!(matches!(error("", 10), Error::InvalidMetadata));
+    assert!(matches!(error("exif\0.bin", 10), Error::InvalidMetadata));
+    assert!(matches!(error("exif.bin", 0), Error::InvalidMetadata));
+}
+
+#[test]
+fn serialized_len() {
+    let configs: [fn(&mut Aviffy) -> &mut Aviffy; 12] = [
+        |a| a,
+        |a| a.hidden_alpha(true).alpha_colr(true),
+        |a| a.write_pixi(false).premultiplied_alpha(true),
+        |a| a.color_primaries(ColorPrimaries::Bt2020).icc_profile(b"ICC").select_layer(0),
+        |a| a.crop_to(3, 5).large_offsets(true),
+        |a| a.align_mdat(4096).large_mdat(true),
+        |a| a.idat_threshold(100),
+        |a| a.iloc_version(IlocVersion::V2).major_brand(FourCC(*b"avis")),
+        |a| a.add_metadata(FourCC(*b"Exif"), "", &[0; 10]).add_metadata(FourCC(*b"mime"), "application/rdf+xml", b"<x/>"),
+        |a| a.add_alternative(&[1, 2], 2, 2).stereo_right_eye(&[3]),
+        |a| a.append_box(FourCC(*b"skip"), b"end").encoded_size(6, 6),
+        |a| a.add_metadata(FourCC(*b"Exif"), "", &[0; 4]).idat_threshold(20).align_mdat(16),
+    ];
+    for config in &configs {
+        let mut aviffy = Aviffy::new();
+        config(&mut aviffy);
+        for &alpha in &[None, Some(&[4, 5, 6][..])] {
+            let avif = aviffy.try_to_vec(&[1; 7], alpha, 5, 5, 8).unwrap();
+            assert_eq!(avif.len() as u64, aviffy.serialized_len(7, alpha.map(|a| a.len()), 5, 5, 8).unwrap());
+        }
+    }
+
+    assert!(Aviffy::new().serialized_len(0, None, 1, 1, 8).is_err());
+    let err = Aviffy::new().strip_temporal_delimiters(true).serialized_len(1, None, 1, 1, 8).unwrap_err();
+    assert!(matches!(err, Error::StreamedBitstream));
+    // the av1C would depend on the data
+    let err = Aviffy::new().strict_av1c(false).serialized_len(1, None, 1, 1, 8).unwrap_err();
+    assert!(matches!(err, Error::StreamedBitstream));
+}
+
+#[test]
+fn seekable() {
+    let configs: [fn(&mut Aviffy) -> &mut Aviffy; 10] = [
+        |a| a,
+        |a| a.crop_to(3, 5).large_offsets(true).premultiplied_alpha(true),
+        |a| a.align_mdat(4096).large_mdat(true),
+        |a| a.idat_threshold(100),
+        |a| a.iloc_version(IlocVersion::V2).iloc_base_offsets(true),
+        |a| a.add_metadata(FourCC(*b"Exif"), "", &[0; 10]).add_metadata(FourCC(*b"mime"), "application/rdf+xml", b"<x/>"),
+        |a| a.add_alternative(&[1, 2], 2, 2).stereo_right_eye(&[3]).separate_mdat(MdatGroup::OtherImages, true),
+        |a| a.append_box(FourCC(*b"skip"), b"end").layout(Layout::MdatFirst).align_mdat(16),
+        |a| a.add_metadata(FourCC(*b"Exif"), "", &[0; 4]).separate_mdat(MdatGroup::Metadata, true).iloc_base_offsets(true).add_external_metadata(FourCC(*b"Exif"), "", "exif.bin", 9),
+        |a| a.depth_map(&[9]).layout(Layout::MdatFirst).separate_mdat(MdatGroup::OtherImages, true),
+    ];
+    for config in &configs {
+        let mut aviffy = Aviffy::new();
+        config(&mut aviffy);
+        for &alpha in &[None, Some(&[4, 5, 6][..])] {
+            let mut streamed = Vec::new();
+            aviffy.write(&mut streamed, &[1; 7], alpha, 5, 5, 8).unwrap();
+            // after some other data, to check that the offsets are from the start of the file
+            let mut cursor = io::Cursor::new(b"prefix".to_vec());
+            cursor.set_position(6);
+            aviffy.write_seekable(&mut cursor, &[1; 7], alpha, 5, 5, 8).unwrap();
+            assert_eq!(b"prefix", &cursor.get_ref()[..6]);
+            assert_eq!(streamed, cursor.get_ref()[6..]);
+        }
+    }
+    assert!(Aviffy::new().write_seekable(io::Cursor::new(Vec::new()), &[], None, 1, 1, 8).is_err());
+}
+
+#[test]
+fn pad_to_size() {
+    let mut aviffy = Aviffy::new();
+    aviffy.append_box(FourCC(*b"skip"), b"end");
+    let natural = aviffy.try_to_vec(&[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap();
+
+    // exact fit needs no padding
+    assert_eq!(natural, aviffy.pad_to_size(natural.len() as u64).try_to_vec(&[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap());
+
+    let avif = aviffy.pad_to_size(64 * 1024).try_to_vec(&[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap();
+    assert_eq!(64 * 1024, avif.len());
+    assert_eq!(natural, avif[..natural.len()]);
+    let free_len = (64 * 1024 - natural.len()) as u32;
+    assert_eq!(&[&free_len.to_be_bytes()[..], b"free"].concat(), &avif[natural.len()..natural.len() + 8]);
+    assert!(avif[natural.len() + 8..].iter().all(|&b| b == 0));
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1, 2, 3][..], ctx.primary_item_coded_data());
+    assert_eq!(64 * 1024, aviffy.serialized_len(3, Some(1), 5, 5, 8).unwrap());
+    let mut seekable = io::Cursor::new(Vec::new());
+    aviffy.write_seekable(&mut seekable, &[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap();
+    assert_eq!(avif, seekable.into_inner());
+
+    // the smallest free box
+    let smallest = aviffy.pad_to_size(natural.len() as u64 + 8).try_to_vec(&[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap();
+    assert_eq!(b"\0\0\0\x08free", &smallest[natural.len()..]);
+
+    let error = |aviffy: &mut Aviffy, target_len: u64| {
+        aviffy.pad_to_size(target_len).try_to_vec(&[1, 2, 3], Some(&[4]), 5, 5, 8).unwrap_err()
+    };
+    let content_len = natural.len() as u64;
+    for gap in 1..8 {
+        assert!(matches!(error(&mut aviffy, content_len + gap), Error::PadToSize { target_len, content_len: c } if (target_len, c) == (content_len + gap, content_len)));
+    }
+    assert!(matches!(error(&mut aviffy, content_len - 1), Error::PadToSize { target_len, content_len: c } if (target_len, c) == (content_len - 1, content_len)));
+    assert!(matches!(error(&mut aviffy, 0), Error::PadToSize { target_len: 0, content_len: c } if c == content_len));
+}
+
+#[test]
+fn seq_profile_override() {
+    let avif = Aviffy::new().av1_seq_profile(2).try_to_vec(&[1], Some(&[2]), 5, 5, 8).unwrap();
+    // 8-bit 4:4:4 would be profile 1
+    assert_eq!(&[0x81, 2 << 5, 0, 0], box_body(&avif, b"av1C").unwrap());
+    // the alpha keeps its own profile 0
+    let alpha_av1c = avif.windows(4).rposition(|w| w == b"av1C").unwrap() + 4;
+    assert_eq!(&[0x81, 0, 0x1C, 0], &avif[alpha_av1c..alpha_av1c + 4]);
+    mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    let err = Aviffy::new().av1_seq_profile(3).try_to_vec(&[1], None, 5, 5, 8).unwrap_err();
+    assert!(matches!(err, Error::InvalidProfile(3)));
+    let err = Aviffy::new().av1_seq_profile(1).chroma_subsampling(ChromaSubsampling::Cs420).try_to_vec(&[1], None, 6, 6, 12).unwrap_err();
+    assert!(matches!(err, Error::ProfileMismatch { seq_profile: 1, expected: 2 }));
+    let err = Aviffy::new().av1_seq_profile(0).try_to_vec(&[1], None, 5, 5, 10).unwrap_err();
+    assert!(matches!(err, Error::ProfileMismatch { seq_profile: 0, expected: 1 }));
+}
+
+#[test]
+fn start_write() {
+    use std::io::Write;
+
+    let color = [1; 10];
+    let alpha = [2; 7];
+    let mut aviffy = Aviffy::new();
+    aviffy.add_alternative(&[3, 3], 2, 2).append_box(FourCC(*b"skip"), b"end");
+    let mut pending = aviffy.start_write(Vec::new(), 10, Some(7), 5, 5, 8).unwrap();
+    assert_eq!(17, pending.remaining());
+    // alpha goes first
+    let payload = [&alpha[..], &color[..]].concat();
+    for chunk in payload.chunks(3) {
+        pending.write_all(chunk).unwrap();
+    }
+    assert_eq!(0, pending.remaining());
+    let avif = pending.finish().unwrap();
+    assert_eq!(aviffy.try_to_vec(&color, Some(&alpha), 5, 5, 8).unwrap(), avif);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&color[..], ctx.primary_item_coded_data());
+    assert_eq!(&alpha[..], ctx.alpha_item_coded_data());
+
+    let mut pending = Aviffy::new().start_write(Vec::new(), 10, None, 5, 5, 8).unwrap();
+    pending.write_all(&color[..9]).unwrap();
+    let err = pending.write_all(&[1, 1]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert_eq!(1, pending.remaining(), "nothing is written on error");
+    assert!(matches!(pending.finish(), Err(Error::MissingData(1))));
+
+    let err = Aviffy::new().validate_bitstream(true).start_write(Vec::new(), 10, None, 5, 5, 8).err().unwrap();
+    assert!(matches!(err, Error::StreamedBitstream));
+}
+
+#[test]
+fn reserve() {
+    use std::io::{Seek, Write};
+
+    let color = [1; 10];
+    let alpha = [2; 7];
+    let fill = |reservation: &mut Reservation<io::Cursor<Vec<u8>>>, color: &[u8], alpha: Option<&[u8]>| {
+        let start = reservation.color_range().start;
+        reservation.get_mut().seek(io::SeekFrom::Start(start)).unwrap();
+        reservation.get_mut().write_all(color).unwrap();
+        if let (Some(range), Some(alpha)) = (reservation.alpha_range(), alpha) {
+            reservation.get_mut().seek(io::SeekFrom::Start(range.start)).unwrap();
+            reservation.get_mut().write_all(alpha).unwrap();
+        }
+    };
+
+    // exact size is the same as writing the data
+    let configs: [fn(&mut Aviffy) -> &mut Aviffy; 4] = [
+        |a| a,
+        |a| a.add_alternative(&[3, 3], 2, 2).append_box(FourCC(*b"skip"), b"end").large_mdat(true),
+        |a| a.layout(Layout::MdatFirst).data_order(DataOrder::ColorFirst).iloc_base_offsets(true),
+        |a| a.add_metadata(FourCC(*b"Exif"), "", &[0; 4]).separate_mdat(MdatGroup::Metadata, true).align_mdat(64).add_external_metadata(FourCC(*b"Exif"), "", "exif.bin", 9),
+    ];
+    for config in &configs {
+        let mut aviffy = Aviffy::new();
+        config(&mut aviffy);
+        // after some other data, to check that the positions are in the output
+        let mut out = io::Cursor::new(b"prefix".to_vec());
+        out.set_position(6);
+        let mut reservation = aviffy.reserve(out, 10, Some(7), 5, 5, 8).unwrap();
+        assert_eq!(10, reservation.color_range().end - reservation.color_range().start);
+        fill(&mut reservation, &color, Some(&alpha));
+        let out = reservation.finish(10, Some(7)).unwrap();
+        assert_eq!(6 + aviffy.serialized_len(10, Some(7), 5, 5, 8).unwrap(), out.position());
+        let out = out.into_inner();
+        assert_eq!(b"prefix", &out[..6]);
+        assert_eq!(aviffy.try_to_vec(&color, Some(&alpha), 5, 5, 8).unwrap(), out[6..]);
+    }
+
+    // smaller than reserved, with the color data at the end of `mdat`, and alpha before it
+    for &(alpha_len, alpha_reserved) in &[(Some(7), Some(9)), (Some(7), Some(100)), (None, None)] {
+        let aviffy = Aviffy::new();
+        let reserved_len = aviffy.serialized_len(100, alpha_reserved, 5, 5, 8).unwrap();
+        let mut reservation = aviffy.reserve(io::Cursor::new(Vec::new()), 100, alpha_reserved.map(|len| len as u64), 5, 5, 8).unwrap();
+        let alpha = alpha_len.map(|len| &alpha[..len]);
+        fill(&mut reservation, &color, alpha);
+        let color_end = reservation.color_range().start as usize + color.len();
+        let avif = reservation.finish(10, alpha_len.map(|len| len as u64)).unwrap().into_inner();
+        assert_eq!(reserved_len, avif.len() as u64);
+        assert_eq!(b"\0\0\0\x5afree", &avif[color_end..color_end + 8]);
+        let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+        assert_eq!(&color[..], ctx.primary_item_coded_data());
+        assert_eq!(alpha.unwrap_or_default(), ctx.alpha_item_coded_data());
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&color[..], ctx.primary_item.as_slice());
+        assert_eq!(alpha, ctx.alpha_item.as_deref());
+    }
+
+    // less than a `free` box of space is left in `mdat`
+    let mut reservation = Aviffy::new().reserve(io::Cursor::new(Vec::new()), 15, None, 5, 5, 8).unwrap();
+    fill(&mut reservation, &color, None);
+    let avif = reservation.finish(10, None).unwrap().into_inner();
+    assert_eq!(&[[0, 0, 0, 23], *b"mdat"].concat(), &avif[avif.len() - 23..avif.len() - 15]);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&color[..], ctx.primary_item_coded_data());
+
+    let finish_err = |color_len, alpha_len| {
+        let reservation = Aviffy::new().reserve(io::Cursor::new(Vec::new()), 10, Some(7), 5, 5, 8).unwrap();
+        reservation.finish(color_len, alpha_len).unwrap_err()
+    };
+    assert!(matches!(finish_err(11, Some(7)), Error::ExceedsReservation { reserved: 10, len: 11 }));
+    assert!(matches!(finish_err(10, Some(8)), Error::ExceedsReservation { reserved: 7, len: 8 }));
+    assert!(matches!(finish_err(0, Some(7)), Error::EmptyColor));
+    assert!(matches!(finish_err(10, None), Error::EmptyAlpha));
+    let err = Aviffy::new().reserve(io::Cursor::new(Vec::new()), 10, None, 5, 5, 8).unwrap().finish(10, Some(1)).unwrap_err();
+    assert!(matches!(err, Error::ExceedsReservation { reserved: 0, len: 1 }));
+
+    let err = Aviffy::new().validate_bitstream(true).reserve(io::Cursor::new(Vec::new()), 10, None, 5, 5, 8).err().unwrap();
+    assert!(matches!(err, Error::StreamedBitstream));
+}
+
+#[test]
+fn depth_map() {
+    let avif = Aviffy::new().depth_map(&[3, 3]).try_to_vec(&[1], Some(&[2]), 4, 4, 8).unwrap();
+    // alpha 2 and depth 3 are both auxiliary images of the color image 1
+    assert_eq!(&[0,0,0,0, 0,0,0,14, b'a',b'u',b'x',b'l', 0,2, 0,1, 0,1, 0,0,0,14, b'a',b'u',b'x',b'l', 0,3, 0,1, 0,1][..], box_body(&avif, b"iref").unwrap());
+    assert!(avif.windows(43).any(|w| w == b"urn:mpeg:mpegB:cicp:systems:auxiliary:depth"));
+    let payload = avif.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+    assert_eq!(&[2, 1, 3, 3], &avif[payload..]);
+    // shares ispe, av1C and pixi with the alpha, but has its own auxC
+    let ipma = box_body(&avif, b"ipma").unwrap();
+    assert_eq!(&[0,3, 4, 1, 0x84, 5, 7], &ipma[ipma.len() - 7..]);
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&[1], ctx.primary_item_coded_data());
+    assert_eq!(&[2], ctx.alpha_item_coded_data());
+
+    // without alpha, the depth keeps its id after the alternatives, and the metadata after it
+    let avif = Aviffy::new().depth_map(&[3]).add_alternative(&[4], 2, 2).add_metadata(FourCC(*b"Exif"), "", &[0; 4]).try_to_vec(&[1], None, 4, 4, 8).unwrap();
+    assert_eq!(&[0,0,0,0, 0,0,0,14, b'a',b'u',b'x',b'l', 0,4, 0,1, 0,1, 0,0,0,14, b'c',b'd',b's',b'c', 0,5, 0,1, 0,1][..], box_body(&avif, b"iref").unwrap());
+    assert_eq!(&[0,0,0,0, 0,0,0,6, 0,0,0,2, 0,0,0,1, 0,0,0,3][..], box_body(&avif, b"altr").unwrap());
+    assert_eq!(avif.len() as u64, Aviffy::new().depth_map(&[3]).add_alternative(&[4], 2, 2).add_metadata(FourCC(*b"Exif"), "", &[0; 4]).serialized_len(1, None, 4, 4, 8).unwrap());
+
+    let err = Aviffy::new().depth_map(&[]).try_to_vec(&[1], None, 4, 4, 8).unwrap_err();
+    assert!(matches!(err, Error::EmptyDepth));
+}
+
+#[test]
+fn layouts() {
+    let color = [1, 2, 3, 4];
+    let alpha = [5, 6];
+    let exif = [0, 0, 0, 0, 7];
+    let mut payloads = Vec::new();
+    for &layout in &[Layout::MetaFirst, Layout::MdatFirst] {
+        for &alignment in &[1, 64] {
+            let mut aviffy = Aviffy::new();
+            aviffy.layout(layout).align_mdat(alignment).add_metadata(FourCC(*b"Exif"), "", &exif).append_box(FourCC(*b"skip"), b"end");
+            let avif = aviffy.try_to_vec(&color, Some(&alpha), 5, 5, 8).unwrap();
+            let meta = avif.windows(4).position(|w| w == b"meta").unwrap();
+            let mdat = avif.windows(4).position(|w| w == b"mdat").unwrap();
+            assert_eq!(layout == Layout::MdatFirst, mdat < meta);
+            assert_eq!(0, (mdat + 4) % alignment as usize);
+            assert_eq!(&alpha[..], &avif[mdat + 4..mdat + 6]);
+            assert!(avif.ends_with(b"skipend"));
+            assert_eq!(avif.len() as u64, aviffy.serialized_len(color.len(), Some(alpha.len()), 5, 5, 8).unwrap());
+            let mut streamed = Vec::new();
+            aviffy.write_streaming(&mut streamed, (&color[..], 4), Some((&alpha[..], 2)), 5, 5, 8).unwrap();
+            assert_eq!(avif, streamed);
+
+            let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+            payloads.push((ctx.primary_item_coded_data().to_vec(), ctx.alpha_item_coded_data().to_vec()));
+            let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+            assert_eq!(&color[..], ctx.primary_item.as_slice());
+            assert_eq!(&alpha[..], ctx.alpha_item.as_deref().unwrap());
+        }
+    }
+    assert!(payloads.iter().all(|p| *p == (color.to_vec(), alpha.to_vec())));
+
+    // meta stays first when there's no mdat
+    let avif = Aviffy::new().layout(Layout::MdatFirst).idat_threshold(100).try_to_vec(&color, None, 5, 5, 8).unwrap();
+    assert_eq!(Aviffy::new().idat_threshold(100).try_to_vec(&color, None, 5, 5, 8).unwrap(), avif);
+}
+
+#[test]
+fn multiple_mdat() {
+    use std::convert::TryInto;
+
+    fn top_level_boxes(file: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut rest = file;
+        while !rest.is_empty() {
+            let (len, header_len) = match u32::from_be_bytes(rest[..4].try_into().unwrap()) {
+                1 => (u64::from_be_bytes(rest[8..16].try_into().unwrap()) as usize, 16),
+                len => (len as usize, 8),
+            };
+            boxes.push((&rest[4..8], &rest[header_len..len]));
+            rest = &rest[len..];
+        }
+        boxes
+    }
+
+    let color = [1, 1, 1];
+    let alpha = [2, 2];
+    let exif = [0, 0, 0, 0, 3];
+    let alternative = [4];
+    let cases: [(&[MdatGroup], &[&[u8]]); 4] = [
+        (&[], &[&[2, 2, 1, 1, 1, 4, 0, 0, 0, 0, 3]]),
+        (&[MdatGroup::Metadata], &[&[2, 2, 1, 1, 1, 4], &exif]),
+        (&[MdatGroup::OtherImages], &[&[2, 2, 1, 1, 1], &[4, 0, 0, 0, 0, 3]]),
+        (&[MdatGroup::Metadata, MdatGroup::OtherImages], &[&[2, 2, 1, 1, 1], &alternative, &exif]),
+    ];
+    for &(groups, expected) in &cases {
+        for &(layout, large) in &[(Layout::MetaFirst, false), (Layout::MdatFirst, true)] {
+            let mut aviffy = Aviffy::new();
+            aviffy.add_alternative(&alternative, 2, 2).add_metadata(FourCC(*b"Exif"), "", &exif).layout(layout).large_mdat(large).align_mdat(16);
+            for &group in groups {
+                aviffy.separate_mdat(group, true);
+            }
+            let avif = aviffy.try_to_vec(&color, Some(&alpha), 4, 4, 8).unwrap();
+            let mdats = top_level_boxes(&avif).into_iter().filter(|&(typ, _)| typ == b"mdat").map(|(_, payload)| payload).collect::<Vec<_>>();
+            assert_eq!(expected, &mdats[..]);
+            assert_eq!(0, (mdats[0].as_ptr() as usize - avif.as_ptr() as usize) % 16);
+
+            assert_eq!(avif.len() as u64, aviffy.serialized_len(color.len(), Some(alpha.len()), 4, 4, 8).unwrap());
+            let mut streamed = Vec::new();
+            aviffy.write_streaming(&mut streamed, (&color[..], 3), Some((&alpha[..], 2)), 4, 4, 8).unwrap();
+            assert_eq!(avif, streamed);
+            let mut written = Vec::new();
+            aviffy.write(&mut written, &color, Some(&alpha), 4, 4, 8).unwrap();
+            assert_eq!(avif, written);
+
+            let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+            assert_eq!(&color[..], ctx.primary_item_coded_data());
+            assert_eq!(&alpha[..], ctx.alpha_item_coded_data());
+            // the last extent in iloc is the metadata's
+            let iloc = box_body(&avif, b"iloc").unwrap();
+            let exif_offset = u32::from_be_bytes(iloc[iloc.len() - 8..iloc.len() - 4].try_into().unwrap()) as usize;
+            assert_eq!(&exif[..], &avif[exif_offset..exif_offset + exif.len()]);
+        }
+    }
 }
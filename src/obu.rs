@@ -0,0 +1,946 @@
+//! Just enough of the AV1 bitstream syntax to read the sequence header.
+//!
+//! Section numbers refer to the AV1 Bitstream & Decoding Process Specification.
+
+use crate::boxes::Av1CBox;
+use crate::{BitstreamMismatch, ChromaSubsampling, InvalidAv1Data};
+use arrayvec::ArrayVec;
+use std::borrow::Cow;
+use std::io;
+
+pub const OBU_SEQUENCE_HEADER: u8 = 1;
+pub const OBU_TEMPORAL_DELIMITER: u8 = 2;
+pub const OBU_FRAME_HEADER: u8 = 3;
+pub const OBU_TILE_GROUP: u8 = 4;
+pub const OBU_FRAME: u8 = 6;
+pub const OBU_PADDING: u8 = 15;
+
+/// Open Bitstream Unit
+#[derive(Debug, Copy, Clone)]
+pub struct Obu<'data> {
+    pub obu_type: u8,
+    /// From the extension header, 0 if absent
+    pub temporal_id: u8,
+    pub spatial_id: u8,
+    /// Whole OBU, including the header
+    pub raw: &'data [u8],
+    /// Just the payload after the header and size field
+    pub payload: &'data [u8],
+}
+
+/// Splits AV1 data into OBUs (5.3)
+pub struct Obus<'data> {
+    data: &'data [u8],
+}
+
+pub fn obus(data: &[u8]) -> Obus<'_> {
+    Obus { data }
+}
+
+impl<'data> Iterator for Obus<'data> {
+    type Item = io::Result<Obu<'data>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let res = Obu::parse(self.data);
+        self.data = match res {
+            Ok(obu) => &self.data[obu.raw.len()..],
+            Err(_) => &[],
+        };
+        Some(res)
+    }
+}
+
+impl<'data> Obu<'data> {
+    fn parse(data: &'data [u8]) -> io::Result<Self> {
+        let header = *data.first().ok_or(InvalidAv1Data::MissingObu)?;
+        if header & 0x80 != 0 {
+            return Err(InvalidAv1Data::ObuForbiddenBit.into());
+        }
+        let obu_type = (header >> 3) & 0xF;
+        let has_extension = header & 0x4 != 0;
+        let has_size_field = header & 0x2 != 0;
+
+        let mut header_len = if has_extension { 2 } else { 1 };
+        if data.len() < header_len {
+            return Err(InvalidAv1Data::TruncatedObuHeader.into());
+        }
+        let (temporal_id, spatial_id) = if has_extension { (data[1] >> 5, (data[1] >> 3) & 3) } else { (0, 0) };
+        let payload_len = if has_size_field {
+            let (size, size_len) = leb128(&data[header_len..])?;
+            header_len += size_len;
+            size
+        } else {
+            // Only allowed for the last OBU
+            (data.len() - header_len) as u64
+        };
+        let end = (header_len as u64).checked_add(payload_len)
+            .filter(|&end| end <= data.len() as u64)
+            .ok_or(InvalidAv1Data::ObuTooLarge)? as usize;
+        Ok(Self {
+            obu_type,
+            temporal_id,
+            spatial_id,
+            raw: &data[..end],
+            payload: &data[header_len..end],
+        })
+    }
+}
+
+/// Checks that the data is bare OBUs that start like an image, with a temporal delimiter or a sequence header.
+///
+/// This catches data still in a container (such as IVF), or without the headers, which no decoder could open.
+pub fn check_start(av1_data: &[u8]) -> io::Result<()> {
+    if av1_data.starts_with(b"DKIF") {
+        return Err(InvalidAv1Data::Ivf.into());
+    }
+    if matches!(av1_data.first(), Some(header) if header & 1 != 0) {
+        return Err(InvalidAv1Data::ObuReservedBit.into());
+    }
+    let first = obus(av1_data).next().ok_or(InvalidAv1Data::MissingObu)??;
+    if first.obu_type != OBU_TEMPORAL_DELIMITER && first.obu_type != OBU_SEQUENCE_HEADER {
+        return Err(InvalidAv1Data::BadFirstObu(first.obu_type).into());
+    }
+    Ok(())
+}
+
+/// Returns value and number of bytes used (4.10.5)
+fn leb128(data: &[u8]) -> io::Result<(u64, usize)> {
+    let mut value = 0;
+    for (i, &byte) in data.iter().take(8).enumerate() {
+        value |= u64::from(byte & 0x7F) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(InvalidAv1Data::BadObuSize.into())
+}
+
+/// Reads bits MSB-first (4.10.2)
+struct BitReader<'data> {
+    data: &'data [u8],
+    bit_pos: usize,
+}
+
+impl<'data> BitReader<'data> {
+    fn new(data: &'data [u8]) -> Self {
+        Self { data, bit_pos: 0 }
+    }
+
+    /// `f(n)`
+    fn f(&mut self, bits: u8) -> io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.bit_pos / 8).ok_or(InvalidAv1Data::TruncatedHeader)?;
+            let bit = (byte >> (7 - self.bit_pos % 8)) & 1;
+            value = (value << 1) | u32::from(bit);
+            self.bit_pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn flag(&mut self) -> io::Result<bool> {
+        Ok(self.f(1)? != 0)
+    }
+
+    /// `uvlc()` (4.10.3)
+    fn uvlc(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+            if leading_zeros >= 32 {
+                return Ok(u32::MAX);
+            }
+        }
+        Ok(self.f(leading_zeros)? + ((1 << leading_zeros) - 1))
+    }
+}
+
+/// The parts of `sequence_header_obu()` (5.5) that matter for the container
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceHeader {
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    /// Level of the first operating point
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    /// Of the first operating point, if the sequence header has it
+    pub initial_display_delay_minus_1: Option<u8>,
+    pub max_frame_width: u32,
+    pub max_frame_height: u32,
+    pub bit_depth: u8,
+    pub mono_chrome: bool,
+    pub subsampling_x: bool,
+    pub subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    /// Needed to parse frame headers
+    frame_header_params: FrameHeaderParams,
+}
+
+/// Sequence header fields that affect the syntax of frame headers
+#[derive(Debug, Clone, PartialEq, Default)]
+struct FrameHeaderParams {
+    frame_width_bits: u8,
+    frame_height_bits: u8,
+    /// `idLen`, if `frame_id_numbers_present_flag`
+    frame_id_length: Option<u8>,
+    enable_order_hint: bool,
+    order_hint_bits: u8,
+    seq_force_screen_content_tools: u8,
+    seq_force_integer_mv: u8,
+    enable_superres: bool,
+    decoder_model_info: Option<DecoderModelInfo>,
+    /// `operating_point_idc` and `decoder_model_present_for_this_op`
+    operating_points: ArrayVec<(u16, bool), 32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DecoderModelInfo {
+    equal_picture_interval: bool,
+    buffer_removal_time_length: u8,
+    frame_presentation_time_length: u8,
+}
+
+const SELECT_SCREEN_CONTENT_TOOLS: u8 = 2;
+const SELECT_INTEGER_MV: u8 = 2;
+
+impl SequenceHeader {
+    /// Finds and parses the first sequence header in the AV1 data
+    pub fn find(av1_data: &[u8]) -> io::Result<Self> {
+        for obu in obus(av1_data) {
+            let obu = obu?;
+            if obu.obu_type == OBU_SEQUENCE_HEADER {
+                return Self::parse(obu.payload);
+            }
+        }
+        Err(InvalidAv1Data::NoSequenceHeader.into())
+    }
+
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut r = BitReader::new(payload);
+        let seq_profile = r.f(3)? as u8;
+        if seq_profile > 2 {
+            return Err(InvalidAv1Data::SequenceProfile(seq_profile).into());
+        }
+        let still_picture = r.flag()?;
+        let reduced_still_picture_header = r.flag()?;
+
+        let mut params = FrameHeaderParams::default();
+        let seq_level_idx_0;
+        let mut seq_tier_0 = false;
+        let mut initial_display_delay_minus_1 = None;
+        if reduced_still_picture_header {
+            seq_level_idx_0 = r.f(5)? as u8;
+            params.operating_points.push((0, false));
+        } else {
+            let mut buffer_delay_length = 0;
+            if r.flag()? {
+                // timing_info()
+                r.f(32)?; // num_units_in_display_tick
+                r.f(32)?; // time_scale
+                let equal_picture_interval = r.flag()?;
+                if equal_picture_interval {
+                    r.uvlc()?; // num_ticks_per_picture_minus_1
+                }
+                if r.flag()? {
+                    // decoder_model_info()
+                    buffer_delay_length = r.f(5)? as u8 + 1;
+                    r.f(32)?; // num_units_in_decoding_tick
+                    params.decoder_model_info = Some(DecoderModelInfo {
+                        equal_picture_interval,
+                        buffer_removal_time_length: r.f(5)? as u8 + 1,
+                        frame_presentation_time_length: r.f(5)? as u8 + 1,
+                    });
+                }
+            }
+            let initial_display_delay_present = r.flag()?;
+            let operating_points_cnt = r.f(5)? + 1;
+            let mut first = None;
+            for _ in 0..operating_points_cnt {
+                let operating_point_idc = r.f(12)? as u16;
+                let seq_level_idx = r.f(5)? as u8;
+                let seq_tier = seq_level_idx > 7 && r.flag()?;
+                let decoder_model_present = params.decoder_model_info.is_some() && r.flag()?;
+                if decoder_model_present {
+                    r.f(buffer_delay_length)?; // decoder_buffer_delay
+                    r.f(buffer_delay_length)?; // encoder_buffer_delay
+                    r.f(1)?; // low_delay_mode_flag
+                }
+                let initial_display_delay = if initial_display_delay_present && r.flag()? {
+                    Some(r.f(4)? as u8)
+                } else {
+                    None
+                };
+                params.operating_points.push((operating_point_idc, decoder_model_present));
+                first.get_or_insert((seq_level_idx, seq_tier, initial_display_delay));
+            }
+            let (level, tier, delay) = first.unwrap_or_default();
+            seq_level_idx_0 = level;
+            seq_tier_0 = tier;
+            initial_display_delay_minus_1 = delay;
+        }
+
+        params.frame_width_bits = r.f(4)? as u8 + 1;
+        params.frame_height_bits = r.f(4)? as u8 + 1;
+        let max_frame_width = r.f(params.frame_width_bits)? + 1;
+        let max_frame_height = r.f(params.frame_height_bits)? + 1;
+        if !reduced_still_picture_header && r.flag()? {
+            // frame_id_numbers_present_flag
+            let delta_frame_id_length = r.f(4)? as u8 + 2;
+            let additional_frame_id_length = r.f(3)? as u8 + 1;
+            params.frame_id_length = Some(delta_frame_id_length + additional_frame_id_length);
+        }
+        r.f(1)?; // use_128x128_superblock
+        r.f(1)?; // enable_filter_intra
+        r.f(1)?; // enable_intra_edge_filter
+        params.seq_force_screen_content_tools = SELECT_SCREEN_CONTENT_TOOLS;
+        params.seq_force_integer_mv = SELECT_INTEGER_MV;
+        if !reduced_still_picture_header {
+            r.f(1)?; // enable_interintra_compound
+            r.f(1)?; // enable_masked_compound
+            r.f(1)?; // enable_warped_motion
+            r.f(1)?; // enable_dual_filter
+            params.enable_order_hint = r.flag()?;
+            if params.enable_order_hint {
+                r.f(1)?; // enable_jnt_comp
+                r.f(1)?; // enable_ref_frame_mvs
+            }
+            let seq_choose_screen_content_tools = r.flag()?;
+            if !seq_choose_screen_content_tools {
+                params.seq_force_screen_content_tools = r.f(1)? as u8;
+            }
+            if params.seq_force_screen_content_tools > 0 {
+                let seq_choose_integer_mv = r.flag()?;
+                if !seq_choose_integer_mv {
+                    params.seq_force_integer_mv = r.f(1)? as u8;
+                }
+            }
+            if params.enable_order_hint {
+                params.order_hint_bits = r.f(3)? as u8 + 1;
+            }
+        }
+        params.enable_superres = r.flag()?;
+        r.f(1)?; // enable_cdef
+        r.f(1)?; // enable_restoration
+
+        // color_config() (5.5.2)
+        let high_bitdepth = r.flag()?;
+        let bit_depth = if seq_profile == 2 && high_bitdepth {
+            if r.flag()? { 12 } else { 10 }
+        } else if high_bitdepth { 10 } else { 8 };
+        let mono_chrome = seq_profile != 1 && r.flag()?;
+        let (color_primaries, transfer_characteristics, matrix_coefficients) = if r.flag()? {
+            (r.f(8)?, r.f(8)?, r.f(8)?)
+        } else {
+            (2, 2, 2)
+        };
+        let mut chroma_sample_position = 0;
+        let (subsampling_x, subsampling_y) = if mono_chrome {
+            r.f(1)?; // color_range
+            (true, true)
+        } else if (color_primaries, transfer_characteristics, matrix_coefficients) == (1, 13, 0) {
+            // sRGB
+            (false, false)
+        } else {
+            r.f(1)?; // color_range
+            let subsampling = match seq_profile {
+                0 => (true, true),
+                1 => (false, false),
+                _ if bit_depth == 12 => {
+                    let x = r.flag()?;
+                    (x, x && r.flag()?)
+                },
+                _ => (true, false),
+            };
+            if subsampling == (true, true) {
+                chroma_sample_position = r.f(2)? as u8;
+            }
+            subsampling
+        };
+
+        Ok(Self {
+            seq_profile,
+            still_picture,
+            reduced_still_picture_header,
+            seq_level_idx_0,
+            seq_tier_0,
+            initial_display_delay_minus_1,
+            max_frame_width,
+            max_frame_height,
+            bit_depth,
+            mono_chrome,
+            subsampling_x,
+            subsampling_y,
+            chroma_sample_position,
+            frame_header_params: params,
+        })
+    }
+
+    /// Size of the first frame in the AV1 data (after super-resolution upscaling)
+    ///
+    /// This can be smaller than the max size declared in the sequence header.
+    pub fn frame_size(&self, av1_data: &[u8]) -> io::Result<(u32, u32)> {
+        for obu in obus(av1_data) {
+            let obu = obu?;
+            if obu.obu_type == OBU_FRAME_HEADER || obu.obu_type == OBU_FRAME {
+                return self.parse_frame_size(&obu);
+            }
+        }
+        Err(InvalidAv1Data::NoFrameHeader.into())
+    }
+
+    /// Reads `uncompressed_header()` (5.9.2) up to `frame_size()`
+    fn parse_frame_size(&self, obu: &Obu<'_>) -> io::Result<(u32, u32)> {
+        const KEY_FRAME: u32 = 0;
+        const INTRA_ONLY_FRAME: u32 = 2;
+        const SWITCH_FRAME: u32 = 3;
+
+        let p = &self.frame_header_params;
+        let mut r = BitReader::new(obu.payload);
+        let (frame_type, show_frame, error_resilient_mode) = if self.reduced_still_picture_header {
+            (KEY_FRAME, true, true)
+        } else {
+            if r.flag()? {
+                return Err(InvalidAv1Data::ShowExistingFrame.into());
+            }
+            let frame_type = r.f(2)?;
+            let show_frame = r.flag()?;
+            if let Some(info) = p.decoder_model_info.as_ref().filter(|info| show_frame && !info.equal_picture_interval) {
+                r.f(info.frame_presentation_time_length)?; // temporal_point_info()
+            }
+            if !show_frame {
+                r.f(1)?; // showable_frame
+            }
+            let error_resilient_mode = frame_type == SWITCH_FRAME || (frame_type == KEY_FRAME && show_frame) || r.flag()?;
+            (frame_type, show_frame, error_resilient_mode)
+        };
+        if frame_type != KEY_FRAME && frame_type != INTRA_ONLY_FRAME {
+            return Err(InvalidAv1Data::NotIntraFrame.into());
+        }
+        r.f(1)?; // disable_cdf_update
+        let allow_screen_content_tools = if p.seq_force_screen_content_tools == SELECT_SCREEN_CONTENT_TOOLS {
+            r.f(1)? as u8
+        } else {
+            p.seq_force_screen_content_tools
+        };
+        if allow_screen_content_tools > 0 && p.seq_force_integer_mv == SELECT_INTEGER_MV {
+            r.f(1)?; // force_integer_mv
+        }
+        if let Some(frame_id_length) = p.frame_id_length {
+            r.f(frame_id_length)?; // current_frame_id
+        }
+        let frame_size_override_flag = !self.reduced_still_picture_header && r.flag()?;
+        r.f(p.order_hint_bits)?; // order_hint
+        // primary_ref_frame is absent for intra frames
+        if let Some(info) = &p.decoder_model_info {
+            if r.flag()? {
+                // buffer_removal_time_present_flag
+                for &(idc, decoder_model_present) in &p.operating_points {
+                    let in_temporal_layer = (idc >> obu.temporal_id) & 1 != 0;
+                    let in_spatial_layer = (idc >> (obu.spatial_id + 8)) & 1 != 0;
+                    if decoder_model_present && (idc == 0 || (in_temporal_layer && in_spatial_layer)) {
+                        r.f(info.buffer_removal_time_length)?; // buffer_removal_time
+                    }
+                }
+            }
+        }
+        let refresh_frame_flags = if frame_type == KEY_FRAME && show_frame { 0xFF } else { r.f(8)? };
+        if refresh_frame_flags != 0xFF && error_resilient_mode && p.enable_order_hint {
+            for _ in 0..8 {
+                r.f(p.order_hint_bits)?; // ref_order_hint
+            }
+        }
+
+        // frame_size()
+        let (frame_width, frame_height) = if frame_size_override_flag {
+            (r.f(p.frame_width_bits)? + 1, r.f(p.frame_height_bits)? + 1)
+        } else {
+            (self.max_frame_width, self.max_frame_height)
+        };
+        // superres_params() only makes the coded width smaller, the upscaled width stays as-is.
+        Ok((frame_width, frame_height))
+    }
+
+    /// Chroma subsampling of the color image (4:2:0 if it's monochrome)
+    pub fn chroma_subsampling(&self) -> Result<ChromaSubsampling, BitstreamMismatch> {
+        ChromaSubsampling::from_xy((self.subsampling_x, self.subsampling_y)).ok_or(BitstreamMismatch::InvalidSubsampling)
+    }
+
+    /// The configuration record that describes this sequence
+    pub fn to_av1c(&self) -> Av1CBox<'static> {
+        Av1CBox {
+            seq_profile: self.seq_profile,
+            seq_level_idx_0: self.seq_level_idx_0,
+            seq_tier_0: self.seq_tier_0,
+            high_bitdepth: self.bit_depth > 8,
+            twelve_bit: self.bit_depth == 12,
+            monochrome: self.mono_chrome,
+            chroma_subsampling_x: self.subsampling_x,
+            chroma_subsampling_y: self.subsampling_y,
+            chroma_sample_position: self.chroma_sample_position,
+            initial_presentation_delay_minus_one: self.initial_display_delay_minus_1,
+            config_obus: &[],
+        }
+    }
+}
+
+/// The whole first sequence header OBU, for `configOBUs` of `av1C`
+pub fn sequence_header_obu(av1_data: &[u8]) -> io::Result<&[u8]> {
+    for obu in obus(av1_data) {
+        let obu = obu?;
+        if obu.obu_type == OBU_SEQUENCE_HEADER {
+            // av1C requires the low overhead format
+            if obu.raw[0] & 0x2 == 0 {
+                return Err(InvalidAv1Data::SequenceHeaderWithoutSize.into());
+            }
+            return Ok(obu.raw);
+        }
+    }
+    Err(InvalidAv1Data::NoSequenceHeader.into())
+}
+
+/// Splits AV1 data at the start of every tile group OBU (or frame OBU, which has a tile group too) after the first one.
+///
+/// OBUs before the first tile group, such as the sequence header, are in the first piece, and other OBUs stay with the tile group before them.
+pub fn split_at_tile_groups(av1_data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut pieces = Vec::new();
+    let (mut piece_start, mut pos) = (0, 0);
+    let mut seen_tile_group = false;
+    for obu in obus(av1_data) {
+        let obu = obu?;
+        if obu.obu_type == OBU_TILE_GROUP || obu.obu_type == OBU_FRAME {
+            if seen_tile_group {
+                pieces.push(&av1_data[piece_start..pos]);
+                piece_start = pos;
+            }
+            seen_tile_group = true;
+        }
+        pos += obu.raw.len();
+    }
+    pieces.push(&av1_data[piece_start..]);
+    Ok(pieces)
+}
+
+/// AV1 data without temporal delimiter OBUs, which MIAF doesn't allow in items.
+///
+/// Borrows the data if the delimiters are only at the start (which is what encoders do).
+pub fn strip_temporal_delimiters(av1_data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    let mut leading_len = 0;
+    let mut seen_other_obu = false;
+    let mut only_leading = true;
+    for obu in obus(av1_data) {
+        let obu = obu?;
+        if obu.obu_type != OBU_TEMPORAL_DELIMITER {
+            seen_other_obu = true;
+        } else if !seen_other_obu {
+            leading_len += obu.raw.len();
+        } else {
+            only_leading = false;
+        }
+    }
+    let rest = &av1_data[leading_len..];
+    if only_leading {
+        return Ok(Cow::Borrowed(rest));
+    }
+    let mut stripped = Vec::with_capacity(rest.len());
+    for obu in obus(rest) {
+        let obu = obu?;
+        if obu.obu_type != OBU_TEMPORAL_DELIMITER {
+            stripped.extend_from_slice(obu.raw);
+        }
+    }
+    Ok(Cow::Owned(stripped))
+}
+
+/// AV1 data without the temporal delimiter and padding OBUs after the last OBU of any other type.
+///
+/// They don't do anything at the end of an image item, and only make the file larger.
+pub fn trim_trailing_obus(av1_data: &[u8]) -> io::Result<&[u8]> {
+    let mut end = 0;
+    let mut pos = 0;
+    for obu in obus(av1_data) {
+        let obu = obu?;
+        pos += obu.raw.len();
+        if obu.obu_type != OBU_TEMPORAL_DELIMITER && obu.obu_type != OBU_PADDING {
+            end = pos;
+        }
+    }
+    Ok(&av1_data[..end])
+}
+
+/// Minimal AV1 encoder output, for tests
+#[cfg(test)]
+pub mod test_streams {
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    pub struct SequenceConfig {
+        /// Full headers are written by aom's video path, reduced ones by rav1e and aom's still-picture path
+        pub reduced_still_picture_header: bool,
+        pub timing_info: bool,
+        pub seq_profile: u8,
+        pub seq_level_idx_0: u8,
+        pub seq_tier_0: bool,
+        pub width: u32,
+        pub height: u32,
+        pub bit_depth: u8,
+        pub mono_chrome: bool,
+        pub subsampling_x: bool,
+        pub subsampling_y: bool,
+        pub chroma_sample_position: u8,
+        /// (primaries, transfer, matrix)
+        pub color_description: Option<(u8, u8, u8)>,
+        pub enable_superres: bool,
+        /// Frame encoded at lower resolution, and upscaled by 8/denominator
+        pub superres_denom: Option<u8>,
+        /// Frame smaller than the max size in the sequence header (only in full headers)
+        pub frame_size: Option<(u32, u32)>,
+        /// Starts with an extra temporal delimiter OBU, like aom's output
+        pub temporal_delimiter: bool,
+        /// Of the first operating point (only in full headers)
+        pub initial_display_delay_minus_1: Option<u8>,
+        /// `(operating_point_idc, seq_level_idx, seq_tier)` of operating points after the first one, such as the base layer (only in full headers)
+        pub extra_operating_points: Vec<(u16, u8, bool)>,
+    }
+
+    impl Default for SequenceConfig {
+        fn default() -> Self {
+            Self {
+                reduced_still_picture_header: true,
+                timing_info: false,
+                seq_profile: 1,
+                seq_level_idx_0: 8,
+                seq_tier_0: false,
+                width: 64,
+                height: 48,
+                bit_depth: 8,
+                mono_chrome: false,
+                subsampling_x: false,
+                subsampling_y: false,
+                chroma_sample_position: 0,
+                color_description: None,
+                enable_superres: false,
+                superres_denom: None,
+                frame_size: None,
+                temporal_delimiter: false,
+                initial_display_delay_minus_1: None,
+                extra_operating_points: Vec::new(),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct BitWriter {
+        pub bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        pub fn put(&mut self, bits: u8, value: u32) {
+            for i in (0..bits).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i) as u8 & 1;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        pub fn flag(&mut self, value: bool) {
+            self.put(1, value as u32)
+        }
+
+        /// Adds `trailing_bits()`
+        pub fn finish(mut self) -> Vec<u8> {
+            self.put(1, 1);
+            while !self.bits.is_multiple_of(8) {
+                self.put(1, 0);
+            }
+            self.bytes
+        }
+    }
+
+    /// OBU with `obu_has_size_field`
+    pub fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 128);
+        let mut out = vec![obu_type << 3 | 0x2, payload.len() as u8];
+        out.extend_from_slice(payload);
+        out
+    }
+
+    pub fn sequence_header_payload(c: &SequenceConfig) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.put(3, c.seq_profile.into());
+        w.flag(true); // still_picture
+        w.flag(c.reduced_still_picture_header);
+        if c.reduced_still_picture_header {
+            w.put(5, c.seq_level_idx_0.into());
+        } else {
+            w.flag(c.timing_info);
+            if c.timing_info {
+                w.put(32, 1);
+                w.put(32, 25);
+                w.flag(true); // equal_picture_interval
+                w.put(5, 0b00111); // uvlc 6
+                w.flag(false); // decoder_model_info_present_flag
+            }
+            w.flag(c.initial_display_delay_minus_1.is_some());
+            w.put(5, c.extra_operating_points.len() as u32);
+            // the first operating point decodes all the layers
+            let all_layers = if c.extra_operating_points.is_empty() { 0 } else { 0x301 };
+            let first = (all_layers, c.seq_level_idx_0, c.seq_tier_0);
+            for (i, &(idc, level, tier)) in std::iter::once(&first).chain(&c.extra_operating_points).enumerate() {
+                w.put(12, idc.into());
+                w.put(5, level.into());
+                if level > 7 {
+                    w.flag(tier);
+                }
+                if let Some(delay) = c.initial_display_delay_minus_1 {
+                    w.flag(i == 0);
+                    if i == 0 {
+                        w.put(4, delay.into());
+                    }
+                }
+            }
+        }
+        w.put(4, 15);
+        w.put(4, 15);
+        w.put(16, c.width - 1);
+        w.put(16, c.height - 1);
+        if !c.reduced_still_picture_header {
+            w.flag(false); // frame_id_numbers_present_flag
+        }
+        w.put(3, 0b011); // 64x64 sb, no filter intra, intra edge filter
+        if !c.reduced_still_picture_header {
+            w.put(4, 0);
+            w.flag(true); // enable_order_hint
+            w.put(2, 0);
+            w.flag(true); // seq_choose_screen_content_tools
+            w.flag(true); // seq_choose_integer_mv
+            w.put(3, 6); // order_hint_bits_minus_1
+        }
+        w.flag(c.enable_superres);
+        w.put(2, 0b11); // cdef, restoration
+
+        let high_bitdepth = c.bit_depth > 8;
+        w.flag(high_bitdepth);
+        if c.seq_profile == 2 && high_bitdepth {
+            w.flag(c.bit_depth == 12);
+        }
+        if c.seq_profile != 1 {
+            w.flag(c.mono_chrome);
+        }
+        w.flag(c.color_description.is_some());
+        if let Some((cp, tc, mc)) = c.color_description {
+            w.put(8, cp.into());
+            w.put(8, tc.into());
+            w.put(8, mc.into());
+        }
+        if c.mono_chrome {
+            w.flag(true); // color_range
+        } else if c.color_description != Some((1, 13, 0)) {
+            w.flag(true); // color_range
+            if c.seq_profile == 2 && c.bit_depth == 12 {
+                w.flag(c.subsampling_x);
+                if c.subsampling_x {
+                    w.flag(c.subsampling_y);
+                }
+            }
+            if c.subsampling_x && c.subsampling_y {
+                w.put(2, c.chroma_sample_position.into());
+            }
+        }
+        if !c.mono_chrome {
+            w.flag(false); // separate_uv_delta_q
+        }
+        w.flag(false); // film_grain_params_present
+        w.finish()
+    }
+
+    /// Just the start of a key frame, up to the frame size
+    pub fn frame_header_payload(c: &SequenceConfig) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        if !c.reduced_still_picture_header {
+            w.flag(false); // show_existing_frame
+            w.put(2, 0); // KEY_FRAME
+            w.flag(true); // show_frame
+        }
+        w.flag(false); // disable_cdf_update
+        w.flag(false); // allow_screen_content_tools
+        if !c.reduced_still_picture_header {
+            w.flag(c.frame_size.is_some()); // frame_size_override_flag
+            w.put(7, 0); // order_hint
+        }
+        if let Some((width, height)) = c.frame_size {
+            w.put(16, width - 1);
+            w.put(16, height - 1);
+        }
+        if c.enable_superres {
+            w.flag(c.superres_denom.is_some());
+            if let Some(denom) = c.superres_denom {
+                w.put(3, u32::from(denom) - 9);
+            }
+        }
+        w.flag(false); // render_and_frame_size_different
+        w.finish()
+    }
+
+    /// Sequence header followed by a frame with a dummy tile group
+    pub fn stream(c: &SequenceConfig) -> Vec<u8> {
+        let mut out = Vec::new();
+        if c.temporal_delimiter {
+            out.extend(obu(OBU_TEMPORAL_DELIMITER, &[]));
+        }
+        out.extend(obu(OBU_SEQUENCE_HEADER, &sequence_header_payload(c)));
+        let mut frame = frame_header_payload(c);
+        frame.extend_from_slice(&[0xFF, 0xEE, 0xDD]);
+        out.extend(obu(OBU_FRAME, &frame));
+        out
+    }
+}
+
+#[test]
+fn parse_sequence_headers() {
+    use test_streams::*;
+
+    let configs = [
+        // rav1e 4:4:4
+        SequenceConfig::default(),
+        // rav1e 4:2:0 10-bit
+        SequenceConfig { seq_profile: 0, bit_depth: 10, subsampling_x: true, subsampling_y: true, chroma_sample_position: 2, ..Default::default() },
+        // aom 4:2:0 with video-like headers
+        SequenceConfig { reduced_still_picture_header: false, timing_info: true, seq_profile: 0, seq_level_idx_0: 13, seq_tier_0: true, subsampling_x: true, subsampling_y: true, color_description: Some((9, 16, 9)), ..Default::default() },
+        // aom 12-bit 4:4:4 and 4:2:0
+        SequenceConfig { reduced_still_picture_header: false, seq_profile: 2, bit_depth: 12, width: 4000, height: 3000, ..Default::default() },
+        SequenceConfig { reduced_still_picture_header: false, seq_profile: 2, bit_depth: 12, subsampling_x: true, subsampling_y: true, chroma_sample_position: 1, ..Default::default() },
+        // monochrome alpha
+        SequenceConfig { seq_profile: 0, bit_depth: 10, mono_chrome: true, subsampling_x: true, subsampling_y: true, ..Default::default() },
+        // sRGB identity
+        SequenceConfig { color_description: Some((1, 13, 0)), ..Default::default() },
+        // two spatial layers, with a lower level for the base layer
+        SequenceConfig { reduced_still_picture_header: false, seq_level_idx_0: 12, seq_tier_0: true, initial_display_delay_minus_1: Some(1), extra_operating_points: vec![(0x101, 5, false)], ..Default::default() },
+    ];
+    for c in &configs {
+        let data = stream(c);
+        let seq = SequenceHeader::find(&data).unwrap();
+        assert_eq!((c.seq_profile, c.reduced_still_picture_header, c.seq_level_idx_0, c.seq_tier_0),
+            (seq.seq_profile, seq.reduced_still_picture_header, seq.seq_level_idx_0, seq.seq_tier_0), "{:?}", c);
+        assert_eq!((c.width, c.height), (seq.max_frame_width, seq.max_frame_height));
+        assert_eq!((c.bit_depth, c.mono_chrome, c.subsampling_x, c.subsampling_y, c.chroma_sample_position),
+            (seq.bit_depth, seq.mono_chrome, seq.subsampling_x, seq.subsampling_y, seq.chroma_sample_position), "{:?}", c);
+        assert!(seq.still_picture);
+        assert_eq!((c.width, c.height), seq.frame_size(&data).unwrap());
+        assert_eq!(c.initial_display_delay_minus_1, seq.initial_display_delay_minus_1);
+    }
+
+    let mut seq = SequenceHeader::find(&stream(&configs[1])).unwrap();
+    assert_eq!(Ok(ChromaSubsampling::Cs420), seq.chroma_subsampling());
+    seq.subsampling_x = false;
+    assert_eq!(Err(BitstreamMismatch::InvalidSubsampling), seq.chroma_subsampling());
+
+    assert!(SequenceHeader::find(&test_streams::obu(6, &[1, 2, 3])).is_err());
+    assert!(SequenceHeader::find(&[0x0A, 0x05, 0]).is_err(), "truncated");
+}
+
+#[test]
+fn parse_frame_size() {
+    use test_streams::*;
+
+    let configs = [
+        (SequenceConfig { temporal_delimiter: true, ..Default::default() }, (64, 48)),
+        (SequenceConfig { enable_superres: true, superres_denom: Some(16), ..Default::default() }, (64, 48)),
+        (SequenceConfig { enable_superres: true, ..Default::default() }, (64, 48)),
+        (SequenceConfig { reduced_still_picture_header: false, temporal_delimiter: true, frame_size: Some((33, 17)), ..Default::default() }, (33, 17)),
+        (SequenceConfig { reduced_still_picture_header: false, timing_info: true, enable_superres: true, superres_denom: Some(11), frame_size: Some((50, 40)), ..Default::default() }, (50, 40)),
+    ];
+    for (c, size) in &configs {
+        let data = stream(c);
+        let seq = SequenceHeader::find(&data).unwrap();
+        assert_eq!(*size, seq.frame_size(&data).unwrap(), "{:?}", c);
+    }
+
+    let seq = SequenceHeader::find(&stream(&SequenceConfig::default())).unwrap();
+    assert!(seq.frame_size(&obu(OBU_SEQUENCE_HEADER, &[0])).is_err());
+}
+
+#[test]
+fn strip_tds() {
+    use test_streams::*;
+
+    let td = obu(OBU_TEMPORAL_DELIMITER, &[]);
+    // with obu_extension_flag and without obu_has_size_field; extension temporal_id 1, spatial_id 1
+    let td_ext_last = [OBU_TEMPORAL_DELIMITER << 3 | 0x4, 0b0010_1000];
+    let seq = obu(OBU_SEQUENCE_HEADER, &sequence_header_payload(&SequenceConfig::default()));
+    let metadata = obu(5, &[0; 100]);
+    let frame = obu(OBU_FRAME, &[1, 2, 3]);
+    let cat = |parts: &[&[u8]]| parts.concat();
+
+    let clean = cat(&[&seq, &frame]);
+    assert!(matches!(strip_temporal_delimiters(&clean).unwrap(), Cow::Borrowed(s) if s == &clean[..]));
+    let leading = cat(&[&td, &td, &seq, &frame]);
+    assert!(matches!(strip_temporal_delimiters(&leading).unwrap(), Cow::Borrowed(s) if s == &clean[..]));
+
+    let middle = cat(&[&td, &seq, &td, &metadata, &td, &frame, &td_ext_last]);
+    assert_eq!(cat(&[&seq, &metadata, &frame]), &*strip_temporal_delimiters(&middle).unwrap());
+    assert!(matches!(strip_temporal_delimiters(&middle).unwrap(), Cow::Owned(_)));
+
+    assert!(strip_temporal_delimiters(&td).unwrap().is_empty());
+    assert!(strip_temporal_delimiters(&cat(&[&seq, &frame[..3]])).is_err());
+}
+
+#[test]
+fn start_of_data() {
+    use test_streams::*;
+
+    let data = stream(&SequenceConfig::default());
+    check_start(&data).unwrap();
+    check_start(&stream(&SequenceConfig { temporal_delimiter: true, ..Default::default() })).unwrap();
+
+    let error = |data: &[u8]| *check_start(data).unwrap_err().into_inner().unwrap().downcast::<InvalidAv1Data>().unwrap();
+    let ivf = [&b"DKIF\0\0\x20\0AV01"[..], &[0; 20], &data].concat();
+    assert!(check_start(&ivf).unwrap_err().to_string().contains("IVF"));
+    assert_eq!(InvalidAv1Data::Ivf, error(&ivf));
+    assert_eq!(InvalidAv1Data::BadFirstObu(OBU_FRAME), error(&obu(OBU_FRAME, &[1, 2, 3])));
+    assert_eq!(InvalidAv1Data::ObuReservedBit, error(&[1]));
+    assert_eq!(InvalidAv1Data::ObuForbiddenBit, error(&[0x80 | OBU_SEQUENCE_HEADER << 3]));
+    assert_eq!(InvalidAv1Data::ObuTooLarge, error(&data[..2]));
+    assert_eq!(InvalidAv1Data::MissingObu, error(&[]));
+}
+
+#[test]
+fn trim_trailing() {
+    use test_streams::*;
+
+    let td = obu(OBU_TEMPORAL_DELIMITER, &[]);
+    let padding = obu(OBU_PADDING, &[0; 20]);
+    let seq = obu(OBU_SEQUENCE_HEADER, &sequence_header_payload(&SequenceConfig::default()));
+    let frame = obu(OBU_FRAME, &[1, 2, 3]);
+    let cat = |parts: &[&[u8]]| parts.concat();
+
+    let clean = cat(&[&td, &seq, &padding, &frame]);
+    assert_eq!(&clean[..], trim_trailing_obus(&clean).unwrap());
+    assert_eq!(&clean[..], trim_trailing_obus(&cat(&[&clean, &td])).unwrap());
+    assert_eq!(&clean[..], trim_trailing_obus(&cat(&[&clean, &padding, &td, &td])).unwrap());
+    assert!(trim_trailing_obus(&cat(&[&td, &padding])).unwrap().is_empty());
+    assert!(trim_trailing_obus(&cat(&[&clean, &frame[..3]])).is_err());
+}
+
+#[test]
+fn split_tile_groups() {
+    use test_streams::*;
+
+    let headers = [obu(OBU_SEQUENCE_HEADER, &sequence_header_payload(&SequenceConfig::default())), obu(OBU_FRAME_HEADER, &[1])].concat();
+    let tile_groups = [obu(OBU_TILE_GROUP, &[2, 2]), obu(OBU_TILE_GROUP, &[3]), obu(OBU_TILE_GROUP, &[4, 4, 4])];
+    let data = [&headers[..], &tile_groups[0], &tile_groups[1], &tile_groups[2]].concat();
+    let pieces = split_at_tile_groups(&data).unwrap();
+    assert_eq!(vec![&[&headers[..], &tile_groups[0]].concat()[..], &tile_groups[1], &tile_groups[2]], pieces);
+
+    // a frame OBU is one tile group
+    let single = stream(&SequenceConfig::default());
+    assert_eq!(vec![&single[..]], split_at_tile_groups(&single).unwrap());
+    assert!(split_at_tile_groups(&[0x80]).is_err());
+}
@@ -0,0 +1,106 @@
+//! Integration with the `rav1e` encoder, enabled with the `rav1e` Cargo feature.
+
+use crate::{Aviffy, BitstreamMismatch, ChromaSubsampling, Error};
+use ::rav1e::prelude::{ChromaSampling, EncoderConfig, Packet, Pixel};
+use std::convert::TryFrom;
+use std::io;
+
+impl Aviffy {
+    /// Like [`Aviffy::write`], but takes packets from `rav1e` directly, without concatenating them first.
+    ///
+    /// The width, height and bit depth are taken from the color encoder's `config`.
+    /// Its chroma sampling must match [`Aviffy::chroma_subsampling`] and [`Aviffy::monochrome`], or it's a [`BitstreamMismatch`] error.
+    /// The alpha should have been encoded with the same size and depth, and `ChromaSampling::Cs400`.
+    pub fn write_rav1e_packets<W: io::Write, T: Pixel>(&self, into_output: W, config: &EncoderConfig, color: &[Packet<T>], alpha: Option<&[Packet<T>]>) -> Result<(), Error> {
+        // None is monochrome
+        let encoded = match config.chroma_sampling {
+            ChromaSampling::Cs400 => None,
+            ChromaSampling::Cs420 => Some(ChromaSubsampling::Cs420),
+            ChromaSampling::Cs422 => Some(ChromaSubsampling::Cs422),
+            ChromaSampling::Cs444 => Some(ChromaSubsampling::Cs444),
+        };
+        let expected = if self.monochrome { None } else { Some(self.chroma_subsampling) };
+        match (expected, encoded) {
+            (Some(configured), Some(bitstream)) if configured != bitstream => {
+                return Err(BitstreamMismatch::ChromaSubsampling { configured, bitstream }.into());
+            },
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(BitstreamMismatch::Monochrome { configured: self.monochrome, bitstream: encoded.is_none() }.into());
+            },
+            _ => {},
+        }
+        let (width, height) = match (u32::try_from(config.width), u32::try_from(config.height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return Err(Error::EncoderDimensions { width: config.width, height: config.height }),
+        };
+        let depth_bits = u8::try_from(config.bit_depth).map_err(|_| Error::EncoderDepth(config.bit_depth))?;
+        self.write_packets(into_output,
+            color.iter().map(|p| &p.data[..]),
+            alpha.map(|alpha| alpha.iter().map(|p| &p.data[..])),
+            width, height, depth_bits)
+    }
+}
+
+#[cfg(test)]
+fn encode<T: Pixel>(config: &EncoderConfig) -> Vec<Packet<T>> {
+    use ::rav1e::prelude::{Config, Context, EncoderStatus};
+
+    let mut ctx: Context<T> = Config::new().with_encoder_config(config.clone()).new_context().unwrap();
+    let frame = ctx.new_frame();
+    ctx.send_frame(frame).unwrap();
+    ctx.flush();
+    let mut packets = Vec::new();
+    loop {
+        match ctx.receive_packet() {
+            Ok(packet) => packets.push(packet),
+            Err(EncoderStatus::Encoded) => {},
+            Err(EncoderStatus::LimitReached) => return packets,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+}
+
+#[test]
+fn rav1e_packets() {
+    fn check<T: Pixel>(aviffy: &Aviffy, config: &EncoderConfig, with_alpha: bool) {
+        let color = encode::<T>(config);
+        let alpha = if with_alpha { Some(encode::<T>(&EncoderConfig { chroma_sampling: ChromaSampling::Cs400, ..config.clone() })) } else { None };
+        let concat = |packets: &[Packet<T>]| packets.iter().flat_map(|p| p.data.iter().copied()).collect::<Vec<u8>>();
+
+        let mut avif = Vec::new();
+        aviffy.write_rav1e_packets(&mut avif, config, &color, alpha.as_deref()).unwrap();
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(concat(&color), ctx.primary_item.as_slice());
+        assert_eq!(alpha.as_deref().map(concat).as_deref(), ctx.alpha_item.as_deref());
+
+        let mut expected = Vec::new();
+        aviffy.write_packets(&mut expected, color.iter().map(|p| &p.data[..]), alpha.as_ref().map(|a| a.iter().map(|p| &p.data[..])), 16, 8, config.bit_depth as u8).unwrap();
+        assert_eq!(expected, avif);
+    }
+    let config = EncoderConfig { width: 16, height: 8, bit_depth: 8, chroma_sampling: ChromaSampling::Cs444, still_picture: true, ..Default::default() };
+    check::<u8>(&Aviffy::new(), &config, true);
+    check::<u8>(Aviffy::new().monochrome(true), &EncoderConfig { chroma_sampling: ChromaSampling::Cs400, ..config.clone() }, false);
+    check::<u16>(Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs420), &EncoderConfig { bit_depth: 10, chroma_sampling: ChromaSampling::Cs420, ..config.clone() }, true);
+
+    let color = encode::<u8>(&config);
+    let error = |aviffy: &Aviffy, config: &EncoderConfig| {
+        aviffy.write_rav1e_packets(&mut Vec::new(), config, &color, None).unwrap_err()
+    };
+    let mismatch = |aviffy: &Aviffy, config: &EncoderConfig| match error(aviffy, config) {
+        Error::MismatchedBitstream(mismatch) => mismatch,
+        err => panic!("{:?}", err),
+    };
+    assert_eq!(BitstreamMismatch::ChromaSubsampling { configured: ChromaSubsampling::Cs444, bitstream: ChromaSubsampling::Cs420 },
+        mismatch(&Aviffy::new(), &EncoderConfig { chroma_sampling: ChromaSampling::Cs420, ..config.clone() }));
+    assert_eq!(BitstreamMismatch::ChromaSubsampling { configured: ChromaSubsampling::Cs422, bitstream: ChromaSubsampling::Cs444 },
+        mismatch(Aviffy::new().chroma_subsampling(ChromaSubsampling::Cs422), &config));
+    assert_eq!(BitstreamMismatch::Monochrome { configured: false, bitstream: true },
+        mismatch(&Aviffy::new(), &EncoderConfig { chroma_sampling: ChromaSampling::Cs400, ..config.clone() }));
+    assert_eq!(BitstreamMismatch::Monochrome { configured: true, bitstream: false }, mismatch(Aviffy::new().monochrome(true), &config));
+
+    let invalid = |config: &EncoderConfig| error(&Aviffy::new(), config);
+    assert!(matches!(invalid(&EncoderConfig { width: usize::MAX, ..config.clone() }), Error::EncoderDimensions { width: usize::MAX, height: 8 }));
+    assert!(matches!(invalid(&EncoderConfig { height: 65537, ..config.clone() }), Error::InvalidDimensions { width: 16, height: 65537 }));
+    assert!(matches!(invalid(&EncoderConfig { bit_depth: 264, ..config.clone() }), Error::EncoderDepth(264)));
+    assert!(matches!(invalid(&EncoderConfig { bit_depth: 16, ..config.clone() }), Error::InvalidDepth(16)));
+}
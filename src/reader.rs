@@ -0,0 +1,893 @@
+//! Just enough of the HEIF syntax to read back the files this crate writes, for re-muxing them without re-encoding.
+
+use crate::boxes::{essential_by_default, padding_len, ClapBox, FourCC, Nclx, MAX_EXTENTS, MAX_MDAT, MAX_METADATA};
+use crate::constants::{ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
+use crate::{AlphaMode, AlphaUrn, Av1Config, Aviffy, ChromaSubsampling, DataOrder, Error, IlocVersion, Layout, MdatGroup};
+use arrayvec::ArrayVec;
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+use std::io;
+
+/// Settings and image data of a file read by [`Aviffy::from_existing`]
+pub struct ExistingAvif<'data> {
+    /// Settings that make the same file again from the same data. They can be changed before writing it.
+    pub aviffy: Aviffy,
+    /// Borrowed from the file, unless the data was in several pieces
+    pub color_av1_data: Cow<'data, [u8]>,
+    pub alpha_av1_data: Option<Cow<'data, [u8]>>,
+    /// Encoded size of the color image (before cropping)
+    pub width: u32,
+    pub height: u32,
+    /// Bit depth of the color image
+    pub depth_bits: u8,
+}
+
+impl ExistingAvif<'_> {
+    /// [`Aviffy::write`] with the settings and data of this image
+    pub fn write<W: io::Write>(&self, into_output: W) -> Result<(), Error> {
+        self.aviffy.write(into_output, &self.color_av1_data, self.alpha_av1_data.as_deref(), self.width, self.height, self.depth_bits)
+    }
+
+    /// [`Aviffy::try_to_vec`] with the settings and data of this image
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        self.aviffy.try_to_vec(&self.color_av1_data, self.alpha_av1_data.as_deref(), self.width, self.height, self.depth_bits)
+    }
+}
+
+impl Aviffy {
+    /// Reads back the settings and AV1 data of an AVIF file made by this crate, e.g. to change its properties without re-encoding it.
+    ///
+    /// Only the subset of HEIF that this crate writes is supported: a still image, optionally with alpha, stored in `mdat` or `idat`.
+    /// Files with anything [`Aviffy`] has no setting for (such as alternative, stereo or depth images, restricted ICC profiles, or cropping other than the top-left corner)
+    /// are rejected with [`Error::Unsupported`], rather than losing that information, and invalid files with [`Error::Malformed`].
+    ///
+    /// Writing the image with unchanged settings makes the same file, except that image data in several pieces is joined into one,
+    /// and properties that merely repeat the defaults (such as the default `nclx` `colr`) are omitted.
+    pub fn from_existing(avif: &[u8]) -> Result<ExistingAvif<'_>, Error> {
+        let mut top = Bytes(avif).boxes();
+        let (major_brand, minor_version) = match top.next().transpose()? {
+            Some(ftyp) if ftyp.typ.0 == *b"ftyp" => {
+                let mut r = Bytes(ftyp.payload);
+                let brand = r.fourcc()?;
+                // Image sequences would need a moov box, which is rejected below
+                if ![*b"avif", *b"avis"].contains(&brand.0) {
+                    return Err(Error::Unsupported("Not an AVIF file"));
+                }
+                (brand, r.u32()?)
+            },
+            _ => return Err(Error::Malformed("File doesn't start with ftyp")),
+        };
+        let mut meta = None;
+        let mut mdat_first = false;
+        // Start and end of the padding before mdat
+        let mut free = None;
+        // Payload start and header length
+        let mut mdat_start = None;
+        // Where each of the consecutive mdat boxes ends
+        let mut mdat_ends = ArrayVec::<usize, MAX_MDAT>::new();
+        let mut trailing = ArrayVec::<_, 4>::new();
+        for b in top {
+            let b = b?;
+            match &b.typ.0 {
+                b"meta" if meta.is_none() && trailing.is_empty() => {
+                    mdat_first = mdat_start.is_some();
+                    meta = Some(b.payload);
+                },
+                b"free" | b"skip" if mdat_start.is_none() && free.is_none() && trailing.is_empty() => {
+                    free = Some((b.start, b.payload_start + b.payload.len()));
+                },
+                b"mdat" if mdat_start.is_none() && trailing.is_empty() => {
+                    if matches!(free, Some((_, free_end)) if free_end != b.start) {
+                        return Err(Error::Unsupported("Unsupported free box that isn't right before mdat"));
+                    }
+                    mdat_start = Some((b.payload_start, b.payload_start - b.start));
+                    mdat_ends.push(b.payload_start + b.payload.len());
+                },
+                b"mdat" if mdat_ends.last() == Some(&b.start) => {
+                    mdat_ends.try_push(b.payload_start + b.payload.len()).map_err(|_| Error::Unsupported("Too many mdat boxes"))?;
+                },
+                b"ftyp" | b"meta" | b"mdat" => return Err(Error::Unsupported("Unsupported duplicate top-level box")),
+                // Files with the data in idat have no mdat to come after
+                _ if meta.is_some() && (mdat_start.is_some() || free.is_none()) => trailing.try_push(b).map_err(|_| Error::Unsupported("Too many boxes after meta and mdat"))?,
+                _ => return Err(Error::Unsupported("Unsupported top-level box")),
+            }
+        }
+        let meta = meta.ok_or(Error::Malformed("Missing meta box"))?;
+        let mut meta = Meta::parse(meta, avif)?;
+        let mdat_start = match (mdat_start, meta.in_idat) {
+            (Some(start), false) => Some(start),
+            (None, false) => return Err(Error::Malformed("Missing mdat box")),
+            (None, true) if free.is_none() => None,
+            (_, true) => return Err(Error::Unsupported("Unsupported mdat or free box with the image data in idat")),
+        };
+
+        let mut aviffy = Aviffy::new();
+        aviffy.major_brand(major_brand).minor_version(minor_version);
+        // The file has been made already, so it's up to date with the dimension checks it needed
+        aviffy.allow_odd_dimensions(true);
+
+        let (primary_id, primary_hidden) = meta.primary;
+        if primary_hidden {
+            return Err(Error::Unsupported("Unsupported hidden primary item"));
+        }
+        let color = meta.props_of(primary_id, &mut aviffy)?;
+        let (width, height) = color.ispe.ok_or(Error::Malformed("Missing ispe of the primary item"))?;
+        let config = color.av1c.ok_or(Error::Malformed("Missing av1C of the primary item"))?;
+        let depth_bits = config.av1c.bit_depth();
+        aviffy.monochrome(config.av1c.monochrome);
+        if let Some(subsampling) = ChromaSubsampling::from_xy((config.av1c.chroma_subsampling_x, config.av1c.chroma_subsampling_y)) {
+            if !config.av1c.monochrome {
+                aviffy.chroma_subsampling(subsampling);
+            }
+        }
+        aviffy.av1c(config);
+        match color.pixi {
+            None => {
+                aviffy.write_pixi(false);
+            },
+            Some(&[pixi_depth_bits, ..]) if pixi_depth_bits != depth_bits => {
+                aviffy.output_depth_bits(pixi_depth_bits).allow_output_depth_mismatch(true);
+            },
+            Some(_) => {},
+        }
+        if let Some(nclx) = color.nclx {
+            aviffy.nclx = nclx;
+        }
+        if let Some(profile) = color.icc_profile {
+            aviffy.icc_profile(profile);
+        }
+        if let Some(clap) = color.clap {
+            if clap != ClapBox::top_left(width, height, clap.width_n, clap.height_n) {
+                return Err(Error::Unsupported("Only cropping to the top-left corner is supported"));
+            }
+            aviffy.crop_to(clap.width_n, clap.height_n);
+        }
+        if let Some(layer_id) = color.lsel {
+            aviffy.select_layer(layer_id);
+        }
+        let color_extents = meta.take_extents_of(primary_id)?;
+
+        let alpha_extents = match meta.alpha {
+            Some((alpha_id, hidden)) => {
+                let alpha = meta.props_of(alpha_id, &mut aviffy)?;
+                match alpha.auxc {
+                    Some((urn, &[])) => {
+                        aviffy.alpha_urn([AlphaUrn::Cicp, AlphaUrn::Hevc].iter().find(|known| known.as_str() == urn).cloned()
+                            .unwrap_or_else(|| AlphaUrn::Custom(urn.into())));
+                    },
+                    Some(_) => return Err(Error::Unsupported("Unsupported auxC subtype")),
+                    None => return Err(Error::Malformed("Missing auxC of the alpha item")),
+                }
+                if alpha.lsel.is_some() {
+                    return Err(Error::Unsupported("Unsupported lsel of the alpha image"));
+                }
+                match alpha.nclx {
+                    Some(nclx) if nclx == Nclx::ALPHA && alpha.icc_profile.is_none() => {
+                        aviffy.alpha_colr(true);
+                    },
+                    None if alpha.icc_profile.is_none() => {},
+                    _ => return Err(Error::Unsupported("Unsupported colr of the alpha image")),
+                }
+                if let Some(size) = alpha.ispe {
+                    if size != (width, height) {
+                        aviffy.alpha_dimensions(size.0, size.1);
+                    }
+                }
+                if alpha.pixi.is_some() != color.pixi.is_some() {
+                    return Err(Error::Unsupported("Unsupported pixi on only one of the images"));
+                }
+                let config = alpha.av1c.ok_or(Error::Malformed("Missing av1C of the alpha item"))?;
+                let alpha_depth_bits = config.av1c.bit_depth();
+                if alpha_depth_bits != depth_bits {
+                    aviffy.alpha_depth_bits(alpha_depth_bits);
+                }
+                aviffy.alpha_av1c(config);
+                aviffy.hidden_alpha(hidden);
+                if meta.premultiplied {
+                    aviffy.alpha_mode(AlphaMode::Premultiplied);
+                }
+                let alpha_extents = meta.take_extents_of(alpha_id)?;
+                if alpha_extents.first_offset > color_extents.first_offset {
+                    aviffy.data_order(DataOrder::ColorFirst);
+                }
+                Some(alpha_extents)
+            },
+            None => None,
+        };
+
+        // Index of the mdat box the data is in
+        let mdat_of = |extents: &Extents<'_>| mdat_ends.iter().position(|&end| extents.first_offset < end as u64);
+        let mut metadata_len = 0;
+        let mut metadata_mdat = None;
+        for (id, typ, content_type) in std::mem::take(&mut meta.metadata) {
+            if let Some(&(_, url, len)) = meta.external.iter().find(|&&(external_id, ..)| external_id == id) {
+                aviffy.add_external_metadata(typ, content_type, url, len);
+                continue;
+            }
+            let extents = meta.take_extents_of(id)?;
+            metadata_len += extents.len();
+            if metadata_mdat.is_some() && metadata_mdat != Some(mdat_of(&extents)) {
+                return Err(Error::Unsupported("Unsupported metadata in different mdat boxes"));
+            }
+            metadata_mdat = Some(mdat_of(&extents));
+            aviffy.add_metadata(typ, content_type, &extents.joined());
+        }
+        if mdat_ends.len() > 1 {
+            let images_in_first = std::iter::once(&color_extents).chain(&alpha_extents).all(|e| mdat_of(e) == Some(0));
+            if !images_in_first || metadata_mdat != Some(Some(1)) || mdat_ends.len() != 2 {
+                return Err(Error::Unsupported("Unsupported data in multiple mdat boxes (only metadata can be separate)"));
+            }
+            aviffy.separate_mdat(MdatGroup::Metadata, true);
+        }
+
+        if meta.large_offsets {
+            aviffy.large_offsets(true);
+        }
+        if meta.base_offsets {
+            aviffy.iloc_base_offsets(true);
+        }
+        aviffy.iloc_version(match meta.iloc_version {
+            1 => IlocVersion::V1,
+            2 => IlocVersion::V2,
+            _ => IlocVersion::Auto,
+        });
+        if meta.in_idat {
+            aviffy.idat_threshold(color_extents.len() + alpha_extents.as_ref().map_or(0, |extents| extents.len()) + metadata_len);
+        }
+        // It's needed anyway over 4GB
+        if let Some((_, 16)) = mdat_start {
+            aviffy.large_mdat(true);
+        }
+        if mdat_first {
+            aviffy.layout(Layout::MdatFirst);
+        }
+        if let (Some((free_start, _)), Some((mdat_start, header_len))) = (free, mdat_start) {
+            // The smallest power of two is the most likely setting, but any alignment that reproduces the offset will do
+            let unaligned = free_start + header_len;
+            let pow2 = 1 << mdat_start.trailing_zeros().min(31);
+            let alignment = if unaligned + padding_len(unaligned, pow2) == mdat_start { pow2 } else { mdat_start };
+            aviffy.align_mdat(u32::try_from(alignment).map_err(|_| Error::Unsupported("The meta box is too large"))?);
+        }
+        for b in &trailing {
+            aviffy.append_box(b.typ, b.payload);
+        }
+
+        Ok(ExistingAvif {
+            aviffy,
+            color_av1_data: color_extents.joined(),
+            alpha_av1_data: alpha_extents.map(|extents| extents.joined()),
+            width,
+            height,
+            depth_bits,
+        })
+    }
+}
+
+/// Big-endian reader of box payloads
+#[derive(Copy, Clone)]
+struct Bytes<'data>(&'data [u8]);
+
+impl<'data> Bytes<'data> {
+    fn take(&mut self, len: usize) -> Result<&'data [u8], Error> {
+        if len > self.0.len() {
+            return Err(Error::Malformed("Truncated box"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Item ids are 16-bit in old versions of the boxes, and 32-bit in new ones
+    fn item_id(&mut self, wide: bool) -> Result<u32, Error> {
+        if wide { self.u32() } else { self.u16().map(u32::from) }
+    }
+
+    /// `iloc` fields of `size` bytes
+    fn sized(&mut self, size: u8) -> Result<u64, Error> {
+        match size {
+            0 => Ok(0),
+            4 => self.u32().map(u64::from),
+            8 => self.u64(),
+            _ => Err(Error::Malformed("Invalid iloc field size")),
+        }
+    }
+
+    fn fourcc(&mut self) -> Result<FourCC, Error> {
+        Ok(FourCC(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Version and flags
+    fn full_box(&mut self) -> Result<(u8, u32), Error> {
+        let val = self.u32()?;
+        Ok(((val >> 24) as u8, val & 0xFF_FFFF))
+    }
+
+    fn nul_terminated_str(&mut self) -> Result<&'data str, Error> {
+        let len = self.0.iter().position(|&b| b == 0).ok_or(Error::Malformed("String isn't nul-terminated"))?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|_| Error::Malformed("String isn't UTF-8"))?;
+        self.take(1)?;
+        Ok(s)
+    }
+
+    fn boxes(self) -> Boxes<'data> {
+        Boxes { data: self.0, pos: 0 }
+    }
+}
+
+struct BoxRef<'data> {
+    typ: FourCC,
+    payload: &'data [u8],
+    /// Offsets of the box header and the payload in the parent
+    start: usize,
+    payload_start: usize,
+}
+
+/// Splits data into boxes
+struct Boxes<'data> {
+    data: &'data [u8],
+    pos: usize,
+}
+
+impl<'data> Iterator for Boxes<'data> {
+    type Item = Result<BoxRef<'data>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let res = self.parse_box();
+        if res.is_err() {
+            self.pos = self.data.len();
+        }
+        Some(res)
+    }
+}
+
+impl<'data> Boxes<'data> {
+    fn parse_box(&mut self) -> Result<BoxRef<'data>, Error> {
+        let start = self.pos;
+        let mut r = Bytes(&self.data[start..]);
+        let size = r.u32()?;
+        let typ = r.fourcc()?;
+        let (size, header_len) = match size {
+            0 => ((self.data.len() - start) as u64, 8),
+            1 => (r.u64()?, 16),
+            size => (u64::from(size), 8),
+        };
+        let payload_len = size.checked_sub(header_len).and_then(|len| usize::try_from(len).ok()).ok_or(Error::Malformed("Invalid box size"))?;
+        let payload = r.take(payload_len)?;
+        let payload_start = start + header_len as usize;
+        self.pos = payload_start + payload_len;
+        Ok(BoxRef { typ, payload, start, payload_start })
+    }
+}
+
+/// Contents of the `meta` box
+struct Meta<'data> {
+    /// Id and hidden flag
+    primary: (u32, bool),
+    alpha: Option<(u32, bool)>,
+    /// Id, item type and content type of `cdsc` items
+    metadata: ArrayVec<(u32, FourCC, &'data str), MAX_METADATA>,
+    /// Id, URL and length of items with their data in other files
+    external: ArrayVec<(u32, &'data str, u64), MAX_METADATA>,
+    premultiplied: bool,
+    /// 64-bit `iloc` fields
+    large_offsets: bool,
+    base_offsets: bool,
+    iloc_version: u8,
+    /// The data is in `meta`'s `idat` box rather than in the file
+    in_idat: bool,
+    /// Item id and its pieces of data
+    locations: ArrayVec<(u32, Extents<'data>), { 2 + MAX_METADATA }>,
+    ipco: Vec<(FourCC, &'data [u8])>,
+    /// Item id, and indices into `ipco` with the essential flag
+    ipma: ArrayVec<(u32, Vec<(usize, bool)>), 2>,
+}
+
+/// Image data of an item
+struct Extents<'data> {
+    pieces: ArrayVec<&'data [u8], MAX_EXTENTS>,
+    first_offset: u64,
+}
+
+impl<'data> Extents<'data> {
+    fn len(&self) -> usize {
+        self.pieces.iter().map(|p| p.len()).sum()
+    }
+
+    fn joined(&self) -> Cow<'data, [u8]> {
+        match *self.pieces {
+            [data] => Cow::Borrowed(data),
+            _ => Cow::Owned(self.pieces.concat()),
+        }
+    }
+}
+
+/// Properties of an item
+#[derive(Default)]
+struct ItemProps<'data> {
+    ispe: Option<(u32, u32)>,
+    av1c: Option<Av1Config>,
+    pixi: Option<&'data [u8]>,
+    nclx: Option<Nclx>,
+    icc_profile: Option<&'data [u8]>,
+    clap: Option<ClapBox>,
+    /// URN and subtype
+    auxc: Option<(&'data str, &'data [u8])>,
+    lsel: Option<u16>,
+}
+
+impl<'data> Meta<'data> {
+    /// `avif` is the whole file, which `iloc` points into
+    fn parse(meta: &'data [u8], avif: &'data [u8]) -> Result<Self, Error> {
+        let mut r = Bytes(meta);
+        r.full_box()?;
+        let (mut hdlr, mut dinf, mut pitm, mut iloc, mut iinf, mut iref, mut iprp, mut idat) = (None, None, None, None, None, None, None, None);
+        for b in r.boxes() {
+            let b = b?;
+            let slot = match &b.typ.0 {
+                b"hdlr" => &mut hdlr,
+                b"dinf" => &mut dinf,
+                b"pitm" => &mut pitm,
+                b"iloc" => &mut iloc,
+                b"iinf" => &mut iinf,
+                b"iref" => &mut iref,
+                b"iprp" => &mut iprp,
+                b"idat" => &mut idat,
+                _ => return Err(Error::Unsupported("Unsupported box in meta")),
+            };
+            if slot.replace(Bytes(b.payload)).is_some() {
+                return Err(Error::Malformed("Duplicate box in meta"));
+            }
+        }
+
+        let mut hdlr = hdlr.ok_or(Error::Malformed("Missing hdlr box"))?;
+        hdlr.full_box()?;
+        hdlr.u32()?;
+        if hdlr.fourcc()?.0 != *b"pict" {
+            return Err(Error::Unsupported("Not an image (the handler isn't pict)"));
+        }
+
+        // `iloc`'s data_reference_index 0 means this file, and 1 the first of these
+        let mut urls = ArrayVec::<&str, MAX_METADATA>::new();
+        for b in dinf.into_iter().flat_map(Bytes::boxes) {
+            let b = b?;
+            if b.typ.0 != *b"dref" {
+                return Err(Error::Unsupported("Unsupported box in dinf"));
+            }
+            let mut dref = Bytes(b.payload);
+            dref.full_box()?;
+            let entry_count = dref.u32()?;
+            for entry in dref.boxes() {
+                let entry = entry?;
+                let mut url = Bytes(entry.payload);
+                // the flag is for entries that point to this file
+                if entry.typ.0 != *b"url " || url.full_box()?.1 != 0 {
+                    return Err(Error::Unsupported("Unsupported data reference"));
+                }
+                urls.try_push(url.nul_terminated_str()?).map_err(|_| Error::Unsupported("Too many data references"))?;
+            }
+            if urls.len() as u32 != entry_count {
+                return Err(Error::Malformed("Wrong number of entries in dref"));
+            }
+        }
+
+        let mut pitm = pitm.ok_or(Error::Malformed("Missing pitm box"))?;
+        let (version, _) = pitm.full_box()?;
+        let primary_id = pitm.item_id(version > 0)?;
+
+        let mut iinf = iinf.ok_or(Error::Malformed("Missing iinf box"))?;
+        let (version, _) = iinf.full_box()?;
+        let item_count = iinf.item_id(version > 0)?;
+        let mut items = ArrayVec::<(u32, bool), 2>::new();
+        let mut metadata = ArrayVec::<_, MAX_METADATA>::new();
+        for b in iinf.boxes() {
+            let b = b?;
+            if b.typ.0 != *b"infe" {
+                return Err(Error::Malformed("Unexpected box in iinf"));
+            }
+            let mut infe = Bytes(b.payload);
+            let (version, flags) = infe.full_box()?;
+            if version < 2 {
+                return Err(Error::Unsupported("Unsupported old infe version"));
+            }
+            let id = infe.item_id(version > 2)?;
+            if infe.u16()? != 0 {
+                return Err(Error::Unsupported("Unsupported protected item"));
+            }
+            let typ = infe.fourcc()?;
+            if typ.0 != *b"av01" {
+                if flags & 1 != 0 {
+                    return Err(Error::Unsupported("Unsupported hidden metadata item"));
+                }
+                infe.nul_terminated_str()?;
+                let content_type = if typ.0 == *b"mime" {
+                    let content_type = infe.nul_terminated_str()?;
+                    if !infe.0.is_empty() && !infe.nul_terminated_str()?.is_empty() {
+                        return Err(Error::Unsupported("Unsupported content encoding of a mime item"));
+                    }
+                    content_type
+                } else if typ.0 == *b"uri " {
+                    infe.nul_terminated_str()?
+                } else {
+                    ""
+                };
+                metadata.try_push((id, typ, content_type, false)).map_err(|_| Error::Unsupported("Too many metadata items"))?;
+                continue;
+            }
+            items.try_push((id, flags & 1 != 0)).map_err(|_| Error::Unsupported("Unsupported number of items (only color and alpha are supported)"))?;
+        }
+        if (items.len() + metadata.len()) as u32 != item_count {
+            return Err(Error::Malformed("Wrong number of items in iinf"));
+        }
+        let primary = *items.iter().find(|&&(id, _)| id == primary_id).ok_or(Error::Malformed("Missing primary item"))?;
+        let alpha = items.iter().copied().find(|&(id, _)| id != primary_id);
+
+        let mut premultiplied = false;
+        let mut auxl = false;
+        if let Some(mut iref) = iref {
+            let (version, _) = iref.full_box()?;
+            for b in iref.boxes() {
+                let b = b?;
+                let mut r = Bytes(b.payload);
+                let from_id = r.item_id(version > 0)?;
+                for _ in 0..r.u16()? {
+                    let to_id = r.item_id(version > 0)?;
+                    match (&b.typ.0, alpha) {
+                        (b"auxl", Some((alpha_id, _))) if (from_id, to_id) == (alpha_id, primary_id) => auxl = true,
+                        (b"prem", Some((alpha_id, _))) if (from_id, to_id) == (primary_id, alpha_id) => premultiplied = true,
+                        (b"cdsc", _) if to_id == primary_id => match metadata.iter_mut().find(|(id, ..)| *id == from_id) {
+                            Some((.., describes)) => *describes = true,
+                            None => return Err(Error::Unsupported("Unsupported item reference")),
+                        },
+                        _ => return Err(Error::Unsupported("Unsupported item reference")),
+                    }
+                }
+            }
+        }
+        if alpha.is_some() && !auxl {
+            return Err(Error::Unsupported("Unsupported item that isn't the alpha of the primary item"));
+        }
+        if metadata.iter().any(|&(.., describes)| !describes) {
+            return Err(Error::Unsupported("Unsupported metadata item that doesn't describe the primary item"));
+        }
+        let metadata = metadata.into_iter().map(|(id, typ, content_type, _)| (id, typ, content_type)).collect();
+
+        let mut iloc = iloc.ok_or(Error::Malformed("Missing iloc box"))?;
+        let (iloc_version, _) = iloc.full_box()?;
+        let version = iloc_version;
+        if version > 2 {
+            return Err(Error::Unsupported("Unsupported iloc version"));
+        }
+        let sizes = iloc.u8()?;
+        let (offset_size, length_size) = (sizes >> 4, sizes & 0xF);
+        let sizes = iloc.u8()?;
+        let (base_offset_size, index_size) = (sizes >> 4, if version > 0 { sizes & 0xF } else { 0 });
+        let mut locations = ArrayVec::new();
+        let mut external = ArrayVec::new();
+        let mut construction_methods = 0u8;
+        for _ in 0..iloc.item_id(version > 1)? {
+            let id = iloc.item_id(version > 1)?;
+            let construction_method = if version > 0 { iloc.u16()? & 0xF } else { 0 };
+            let data_reference_index = iloc.u16()?;
+            let base_offset = iloc.sized(base_offset_size)?;
+            if data_reference_index != 0 {
+                let url = *urls.get(usize::from(data_reference_index) - 1).ok_or(Error::Malformed("Missing data reference"))?;
+                // Only the whole start of the other file, as made by `Aviffy::add_external_metadata`
+                if construction_method != 0 || base_offset != 0 || iloc.u16()? != 1 {
+                    return Err(Error::Unsupported("Unsupported extents in another file"));
+                }
+                iloc.sized(index_size)?;
+                let (offset, len) = (iloc.sized(offset_size)?, iloc.sized(length_size)?);
+                if offset != 0 || len == 0 {
+                    return Err(Error::Unsupported("Unsupported extents in another file"));
+                }
+                external.try_push((id, url, len)).map_err(|_| Error::Unsupported("Too many items in other files"))?;
+                continue;
+            }
+            let source = match construction_method {
+                0 => {
+                    construction_methods |= 1;
+                    avif
+                },
+                1 => {
+                    construction_methods |= 2;
+                    idat.as_ref().ok_or(Error::Malformed("Missing idat box"))?.0
+                },
+                _ => return Err(Error::Unsupported("Unsupported image data in other items")),
+            };
+            let mut extents = Extents { pieces: ArrayVec::new(), first_offset: u64::MAX };
+            for _ in 0..iloc.u16()? {
+                iloc.sized(index_size)?;
+                let offset = base_offset.checked_add(iloc.sized(offset_size)?).ok_or(Error::Malformed("Invalid iloc offset"))?;
+                let len = iloc.sized(length_size)?;
+                if len == 0 {
+                    return Err(Error::Unsupported("Unsupported extent covering the rest of the file"));
+                }
+                let piece = usize::try_from(offset).ok()
+                    .and_then(|start| Some(start..start.checked_add(usize::try_from(len).ok()?)?))
+                    .and_then(|range| source.get(range))
+                    .ok_or(Error::Malformed("iloc extent is outside of the file"))?;
+                extents.first_offset = extents.first_offset.min(offset);
+                extents.pieces.try_push(piece).map_err(|_| Error::Unsupported("Too many extents"))?;
+            }
+            locations.try_push((id, extents)).map_err(|_| Error::Unsupported("Too many items in iloc"))?;
+        }
+        if construction_methods == 3 {
+            return Err(Error::Unsupported("Unsupported mix of image data in idat and mdat"));
+        }
+
+        let iprp = iprp.ok_or(Error::Malformed("Missing iprp box"))?;
+        let mut ipco = Vec::new();
+        let mut ipma = ArrayVec::new();
+        for b in iprp.boxes() {
+            let b = b?;
+            match &b.typ.0 {
+                b"ipco" => {
+                    for prop in Bytes(b.payload).boxes() {
+                        let prop = prop?;
+                        ipco.push((prop.typ, prop.payload));
+                    }
+                },
+                b"ipma" => {
+                    let mut r = Bytes(b.payload);
+                    let (version, flags) = r.full_box()?;
+                    for _ in 0..r.u32()? {
+                        let id = r.item_id(version > 0)?;
+                        let mut assoc = Vec::new();
+                        for _ in 0..r.u8()? {
+                            let (index, essential) = if flags & 1 != 0 {
+                                let val = r.u16()?;
+                                (usize::from(val & 0x7FFF), val & 0x8000 != 0)
+                            } else {
+                                let val = r.u8()?;
+                                (usize::from(val & 0x7F), val & 0x80 != 0)
+                            };
+                            assoc.push((index, essential));
+                        }
+                        ipma.try_push((id, assoc)).map_err(|_| Error::Unsupported("Too many items in ipma"))?;
+                    }
+                },
+                _ => return Err(Error::Unsupported("Unsupported box in iprp")),
+            }
+        }
+
+        Ok(Self { primary, alpha, metadata, external, premultiplied, large_offsets: offset_size == 8 && length_size == 8, base_offsets: base_offset_size > 0, iloc_version, in_idat: construction_methods == 2, locations, ipco, ipma })
+    }
+
+    fn take_extents_of(&mut self, item_id: u32) -> Result<Extents<'data>, Error> {
+        if self.external.iter().any(|&(id, ..)| id == item_id) {
+            return Err(Error::Unsupported("Unsupported image data in another file"));
+        }
+        let pos = self.locations.iter().position(|&(id, _)| id == item_id).ok_or(Error::Malformed("Missing iloc of an image"))?;
+        let (_, extents) = self.locations.remove(pos);
+        if extents.pieces.is_empty() {
+            return Err(Error::Malformed("Image has no data"));
+        }
+        Ok(extents)
+    }
+
+    /// Parses the item's properties, and records their essential flags in `aviffy`
+    fn props_of(&self, item_id: u32, aviffy: &mut Aviffy) -> Result<ItemProps<'data>, Error> {
+        let mut props = ItemProps::default();
+        let assoc = self.ipma.iter().find(|&&(id, _)| id == item_id).map(|(_, assoc)| &assoc[..]).unwrap_or_default();
+        for &(index, essential) in assoc {
+            // 0 means no property
+            if index == 0 {
+                continue;
+            }
+            let (typ, payload) = *self.ipco.get(index - 1).ok_or(Error::Malformed("ipma refers to a missing property"))?;
+            if essential != essential_by_default(typ) {
+                aviffy.property_essential(typ, essential);
+            }
+            let mut r = Bytes(payload);
+            match &typ.0 {
+                b"ispe" => {
+                    r.full_box()?;
+                    props.ispe = Some((r.u32()?, r.u32()?));
+                },
+                b"av1C" => props.av1c = Some(Av1Config::from_record(payload)?),
+                b"pixi" => {
+                    r.full_box()?;
+                    let channels = r.u8()?;
+                    props.pixi = Some(r.take(channels.into())?);
+                },
+                b"colr" => match &r.fourcc()?.0 {
+                    b"nclx" => {
+                        let unknown = || Error::Unsupported("Unsupported color code");
+                        props.nclx = Some(Nclx {
+                            color_primaries: ColorPrimaries::from_code(r.u16()?).ok_or_else(unknown)?,
+                            transfer_characteristics: TransferCharacteristics::from_code(r.u16()?).ok_or_else(unknown)?,
+                            matrix_coefficients: MatrixCoefficients::from_code(r.u16()?).ok_or_else(unknown)?,
+                            full_range_flag: r.u8()? & 0x80 != 0,
+                        });
+                    },
+                    b"prof" => props.icc_profile = Some(r.0),
+                    _ => return Err(Error::Unsupported("Unsupported colr type")),
+                },
+                b"clap" => {
+                    props.clap = Some(ClapBox {
+                        width_n: r.u32()?,
+                        width_d: r.u32()?,
+                        height_n: r.u32()?,
+                        height_d: r.u32()?,
+                        horiz_off_n: r.u32()? as i32,
+                        horiz_off_d: r.u32()?,
+                        vert_off_n: r.u32()? as i32,
+                        vert_off_d: r.u32()?,
+                    });
+                },
+                b"auxC" => {
+                    r.full_box()?;
+                    props.auxc = Some((r.nul_terminated_str()?, r.0));
+                },
+                b"lsel" => props.lsel = Some(r.u16()?),
+                _ => return Err(Error::Unsupported("Unsupported item property")),
+            }
+        }
+        Ok(props)
+    }
+}
+
+#[test]
+fn existing_roundtrip() {
+    let file = Aviffy::new().try_to_vec(b"color", Some(b"alpha"), 10, 20, 8).unwrap();
+    let existing = Aviffy::from_existing(&file).unwrap();
+    assert_eq!((10, 20, 8), (existing.width, existing.height, existing.depth_bits));
+    assert_eq!(&b"color"[..], &*existing.color_av1_data);
+    assert_eq!(Some(&b"alpha"[..]), existing.alpha_av1_data.as_deref());
+    assert!(matches!(existing.color_av1_data, Cow::Borrowed(_)));
+    assert_eq!(file, existing.to_vec().unwrap());
+
+    let file = Aviffy::new().try_to_vec(b"color", None, 1, 1, 10).unwrap();
+    let existing = Aviffy::from_existing(&file).unwrap();
+    assert!(existing.alpha_av1_data.is_none());
+    assert_eq!(file, existing.to_vec().unwrap());
+}
+
+#[test]
+fn existing_settings_roundtrip() {
+    let mut aviffy = Aviffy::new();
+    aviffy.premultiplied_alpha(true)
+        .hidden_alpha(true)
+        .alpha_colr(true)
+        .alpha_depth_bits(8)
+        .output_depth_bits(8)
+        .allow_output_depth_mismatch(true)
+        .data_order(DataOrder::ColorFirst)
+        .alpha_urn(AlphaUrn::Hevc)
+        .chroma_subsampling(ChromaSubsampling::Cs420)
+        .chroma_sample_position(crate::ChromaSamplePosition::Colocated)
+        .seq_level_idx(12)
+        .initial_presentation_delay(3)
+        .color_primaries(ColorPrimaries::Bt2020)
+        .transfer_characteristics(TransferCharacteristics::Smpte2084)
+        .matrix_coefficients(MatrixCoefficients::Bt2020Ncl)
+        .full_color_range(false)
+        .icc_profile(b"fake ICC profile")
+        .encoded_size(64, 32)
+        .align_mdat(256)
+        .large_offsets(true)
+        .iloc_base_offsets(true)
+        .iloc_version(IlocVersion::V2)
+        .minor_version(7)
+        .select_layer(1)
+        .large_mdat(true)
+        .add_metadata(FourCC(*b"mime"), "application/rdf+xml", b"<x/>")
+        .property_essential(FourCC(*b"ispe"), true)
+        .append_box(FourCC(*b"skip"), b"trailing");
+    let file = aviffy.try_to_vec(b"color", Some(b"alpha"), 63, 31, 10).unwrap();
+    let mut existing = Aviffy::from_existing(&file).unwrap();
+    assert_eq!((64, 32, 10), (existing.width, existing.height, existing.depth_bits));
+    assert_eq!(file, existing.to_vec().unwrap());
+
+    // re-muxed with a change
+    existing.aviffy.color_primaries(ColorPrimaries::Smpte432).align_mdat(1);
+    let remuxed = existing.to_vec().unwrap();
+    assert_ne!(file, remuxed);
+    let existing = Aviffy::from_existing(&remuxed).unwrap();
+    assert_eq!(ColorPrimaries::Smpte432, existing.aviffy.nclx.color_primaries);
+    assert_eq!(1, existing.aviffy.mdat_alignment);
+    assert_eq!(AlphaUrn::Hevc, existing.aviffy.alpha_urn);
+    assert_eq!(remuxed, existing.to_vec().unwrap());
+
+    let mut aviffy = Aviffy::new();
+    aviffy.strict_av1c(false).write_pixi(false).monochrome(true).alpha_dimensions(5, 5).align_mdat(3000);
+    let file = aviffy.try_to_vec(b"color", Some(b"alpha"), 10, 10, 12).unwrap();
+    let existing = Aviffy::from_existing(&file).unwrap();
+    assert_eq!(12, existing.depth_bits);
+    assert_eq!(Some((5, 5)), existing.aviffy.alpha_dimensions);
+    assert_eq!(file, existing.to_vec().unwrap());
+}
+
+#[test]
+fn existing_multiple_extents() {
+    let aviffy = Aviffy::new();
+    let mut file = Vec::new();
+    aviffy.write_with_extents(&mut file, &[b"co", b"lor"], Some(&[b"al", b"p", b"ha"]), 1, 1, 8).unwrap();
+    let existing = Aviffy::from_existing(&file).unwrap();
+    assert_eq!(&b"color"[..], &*existing.color_av1_data);
+    assert_eq!(Some(&b"alpha"[..]), existing.alpha_av1_data.as_deref());
+    assert_eq!(aviffy.try_to_vec(b"color", Some(b"alpha"), 1, 1, 8).unwrap(), existing.to_vec().unwrap());
+}
+
+#[test]
+fn existing_idat() {
+    let mut aviffy = Aviffy::new();
+    aviffy.idat_threshold(100).add_metadata(FourCC(*b"Exif"), "", b"\0\0\0\0MM").append_box(FourCC(*b"uuid"), b"after meta");
+    let file = aviffy.try_to_vec(b"color", Some(b"alpha"), 1, 1, 8).unwrap();
+    let existing = Aviffy::from_existing(&file).unwrap();
+    assert_eq!(&b"color"[..], &*existing.color_av1_data);
+    assert_eq!(Some(&b"alpha"[..]), existing.alpha_av1_data.as_deref());
+    assert_eq!(file, existing.to_vec().unwrap());
+
+    // data in idat can't be kept together with an mdat
+    let mut extra = file.clone();
+    extra.extend_from_slice(b"\0\0\0\x08mdat");
+    assert!(matches!(Aviffy::from_existing(&extra), Err(Error::Unsupported(_))));
+}
+
+#[test]
+fn existing_layouts() {
+    let mut extracted = Vec::new();
+    for &(layout, separate) in &[(Layout::MetaFirst, false), (Layout::MdatFirst, false), (Layout::MetaFirst, true), (Layout::MdatFirst, true)] {
+        let mut aviffy = Aviffy::new();
+        aviffy.layout(layout).align_mdat(32).separate_mdat(MdatGroup::Metadata, separate).add_metadata(FourCC(*b"Exif"), "", b"\0\0\0\0MM");
+        let file = aviffy.try_to_vec(b"color", Some(b"alpha"), 1, 1, 8).unwrap();
+        let existing = Aviffy::from_existing(&file).unwrap();
+        assert_eq!(layout, existing.aviffy.layout);
+        assert_eq!(separate, existing.aviffy.separate_metadata);
+        assert_eq!(file, existing.to_vec().unwrap());
+        let (_, _, metadata) = &existing.aviffy.metadata[0];
+        extracted.push((existing.color_av1_data.to_vec(), existing.alpha_av1_data.as_deref().map(<[u8]>::to_vec), metadata.clone()));
+    }
+    assert!(extracted.iter().all(|e| *e == extracted[0]));
+    assert_eq!(crate::ItemData::Embedded(b"\0\0\0\0MM".to_vec()), extracted[0].2);
+
+
+    // the padding must be right before mdat
+    let file = Aviffy::new().align_mdat(4096).try_to_vec(b"color", None, 1, 1, 8).unwrap();
+    let meta = file.windows(4).position(|w| w == b"meta").unwrap() - 4;
+    let free = file.windows(4).position(|w| w == b"free").unwrap() - 4;
+    let mdat = file.windows(4).position(|w| w == b"mdat").unwrap() - 4;
+    let mut moved = file[..meta].to_vec();
+    moved.extend_from_slice(&file[free..mdat]);
+    moved.extend_from_slice(&file[meta..free]);
+    moved.extend_from_slice(&file[mdat..]);
+    assert_eq!(file.len(), moved.len());
+    assert!(matches!(Aviffy::from_existing(&moved), Err(Error::Unsupported(_))), "free is before meta");
+}
+
+#[test]
+fn existing_unsupported() {
+    assert!(matches!(Aviffy::from_existing(b""), Err(Error::Malformed("File doesn't start with ftyp"))));
+    assert!(matches!(Aviffy::from_existing(b"\0\0\0\x08ftyp"), Err(Error::Malformed("Truncated box"))));
+
+    let file = Aviffy::new().try_to_vec(b"color", None, 1, 1, 8).unwrap();
+    for len in 0..file.len() {
+        assert!(Aviffy::from_existing(&file[..len]).is_err());
+    }
+    // an extra top-level box before mdat
+    let meta_end = file.windows(4).position(|w| w == b"mdat").unwrap() - 4;
+    let mut extra = file[..meta_end].to_vec();
+    extra.extend_from_slice(b"\0\0\0\x08uuid");
+    extra.extend_from_slice(&file[meta_end..]);
+    assert!(matches!(Aviffy::from_existing(&extra), Err(Error::Unsupported(_))));
+}
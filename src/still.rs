@@ -0,0 +1,115 @@
+//! Encoding of still images in one call, with any AV1 encoder plugged in through the [`Av1Encoder`] trait.
+//!
+//! This crate doesn't compress pixels itself. Implement [`Av1Encoder`] for a wrapper around
+//! [rav1e](//lib.rs/rav1e) or [libaom](//lib.rs/libaom-sys), and [`encode_still`] will make a complete AVIF file.
+
+use crate::constants::MatrixCoefficients;
+use crate::obu::SequenceHeader;
+use crate::{Aviffy, Error};
+use std::io;
+
+/// An AV1 encoder that compresses a single frame.
+pub trait Av1Encoder {
+    /// Compress RGBA `pixels` (`width * height` of them, row by row) into AV1 data.
+    ///
+    /// The alpha channel should be encoded as a separate monochrome image, and can be omitted if all pixels are opaque.
+    /// Both must start with a sequence header, which is where the bit depth and chroma subsampling are taken from.
+    ///
+    /// `quality` is 1-100 (higher is better), and `speed` is 1-10 (higher is faster). Map them to the encoder's own settings.
+    fn encode(&mut self, pixels: &[[u8; 4]], width: u32, height: u32, quality: u8, speed: u8) -> io::Result<EncodedImage>;
+}
+
+/// What [`Av1Encoder::encode`] returns
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub color_av1_data: Vec<u8>,
+    pub alpha_av1_data: Option<Vec<u8>>,
+    /// How the encoder has converted RGB to YUV
+    pub matrix_coefficients: MatrixCoefficients,
+}
+
+/// Settings for [`encode_still`]
+pub struct EncoderOptions<'a> {
+    /// Makes the AV1 data
+    pub encoder: &'a mut dyn Av1Encoder,
+    /// 1-100, passed to the encoder
+    pub quality: u8,
+    /// 1-10, passed to the encoder
+    pub speed: u8,
+}
+
+/// Compress the pixels with the encoder from `options`, and make an AVIF file with the result.
+///
+/// The file's `av1C` is copied from the encoder's sequence headers, and the size of its frames is checked
+/// (see [`Aviffy::validate_bitstream`]), so the encoder's output doesn't need to be described separately.
+/// For more control, encode the image yourself, and use [`Aviffy`].
+pub fn encode_still(pixels: &[[u8; 4]], width: u32, height: u32, options: EncoderOptions<'_>) -> Result<Vec<u8>, Error> {
+    if pixels.len() as u64 != u64::from(width) * u64::from(height) {
+        return Err(Error::PixelCount { width, height, pixels: pixels.len() });
+    }
+    let encoded = options.encoder.encode(pixels, width, height, options.quality, options.speed)?;
+    let color = SequenceHeader::find(&encoded.color_av1_data)?;
+    let chroma_subsampling = color.chroma_subsampling()?;
+
+    let mut aviffy = Aviffy::new();
+    aviffy.derive_av1c_from_bitstream(true).validate_bitstream(true)
+        .matrix_coefficients(encoded.matrix_coefficients)
+        .chroma_subsampling(chroma_subsampling)
+        .monochrome(color.mono_chrome);
+    if let Some(alpha_av1_data) = &encoded.alpha_av1_data {
+        aviffy.alpha_depth_bits(SequenceHeader::find(alpha_av1_data)?.bit_depth);
+    }
+    aviffy.try_to_vec(&encoded.color_av1_data, encoded.alpha_av1_data.as_deref(), width, height, color.bit_depth)
+}
+
+/// Returns canned AV1 data for any pixels
+#[cfg(test)]
+struct StubEncoder {
+    with_alpha: bool,
+    calls: Vec<(usize, u32, u32, u8, u8)>,
+}
+
+#[cfg(test)]
+impl Av1Encoder for StubEncoder {
+    fn encode(&mut self, pixels: &[[u8; 4]], width: u32, height: u32, quality: u8, speed: u8) -> io::Result<EncodedImage> {
+        use crate::obu::test_streams::*;
+
+        self.calls.push((pixels.len(), width, height, quality, speed));
+        let color = SequenceConfig { seq_profile: 0, bit_depth: 10, subsampling_x: true, subsampling_y: true, width, height, ..Default::default() };
+        let alpha = SequenceConfig { seq_profile: 0, mono_chrome: true, subsampling_x: true, subsampling_y: true, width, height, ..Default::default() };
+        Ok(EncodedImage {
+            color_av1_data: stream(&color),
+            alpha_av1_data: if self.with_alpha { Some(stream(&alpha)) } else { None },
+            matrix_coefficients: MatrixCoefficients::Bt709,
+        })
+    }
+}
+
+#[test]
+fn encode_with_stub() {
+    let pixels = vec![[0, 0, 0, 255]; 64 * 48];
+    for &with_alpha in &[false, true] {
+        let mut encoder = StubEncoder { with_alpha, calls: Vec::new() };
+        let avif = encode_still(&pixels, 64, 48, EncoderOptions { encoder: &mut encoder, quality: 80, speed: 6 }).unwrap();
+        assert_eq!(vec![(64 * 48, 64, 48, 80, 6)], encoder.calls);
+
+        let expected = encoder.encode(&pixels, 64, 48, 80, 6).unwrap();
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(expected.color_av1_data, ctx.primary_item.as_slice());
+        assert_eq!(expected.alpha_av1_data.as_deref(), ctx.alpha_item.as_deref());
+
+        let existing = Aviffy::from_existing(&avif).unwrap();
+        assert_eq!((64, 48, 10), (existing.width, existing.height, existing.depth_bits));
+        assert_eq!(crate::ChromaSubsampling::Cs420, existing.aviffy.chroma_subsampling);
+        assert_eq!(MatrixCoefficients::Bt709, existing.aviffy.nclx.matrix_coefficients);
+        assert_eq!(with_alpha, existing.alpha_av1_data.is_some());
+    }
+}
+
+#[test]
+fn encode_wrong_pixel_count() {
+    let mut encoder = StubEncoder { with_alpha: false, calls: Vec::new() };
+    let err = encode_still(&[[0; 4]; 3], 2, 2, EncoderOptions { encoder: &mut encoder, quality: 80, speed: 6 }).unwrap_err();
+    assert!(matches!(err, Error::PixelCount { width: 2, height: 2, pixels: 3 }));
+    assert!(encoder.calls.is_empty());
+}
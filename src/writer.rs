@@ -1,3 +1,4 @@
+use arrayvec::ArrayVec;
 use std::convert::Infallible;
 use std::convert::TryFrom;
 use std::io;
@@ -5,6 +6,18 @@ use std::io;
 pub trait WriterBackend {
     type Error;
     fn extend_from_slice(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn extend_from_slices(&mut self, data: &[&[u8]]) -> Result<(), Self::Error> {
+        for d in data {
+            self.extend_from_slice(d)?;
+        }
+        Ok(())
+    }
+
+    /// After an error boxes are left incomplete, so their sizes aren't checked
+    fn has_failed(&self) -> bool {
+        false
+    }
 }
 
 /// `io::Write` generates bloated code (with backtrace for every byte written),
@@ -18,23 +31,97 @@ impl WriterBackend for Vec<u8> {
     }
 }
 
-pub struct IO<W>(pub W);
+pub struct IO<W> {
+    out: W,
+    failed: bool,
+}
+
+impl<W: io::Write> IO<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, failed: false }
+    }
+
+    /// Writers that don't support vectored I/O will get one slice at a time
+    fn write_slices(&mut self, mut data: &[&[u8]]) -> io::Result<()> {
+        // bytes of data[0] already written
+        let mut skip = 0;
+        loop {
+            while let Some((first, rest)) = data.split_first() {
+                if first.len() > skip {
+                    break;
+                }
+                data = rest;
+                skip = 0;
+            }
+            if data.is_empty() {
+                return Ok(());
+            }
+            let slices = data.iter().take(32).enumerate()
+                .map(|(i, d)| io::IoSlice::new(if i == 0 { &d[skip..] } else { d }))
+                .collect::<ArrayVec<_, 32>>();
+            let mut written = match self.out.write_vectored(&slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some((first, rest)) = data.split_first() {
+                let remaining = first.len() - skip;
+                if written < remaining {
+                    skip += written;
+                    break;
+                }
+                written -= remaining;
+                data = rest;
+                skip = 0;
+            }
+        }
+    }
+}
 
 impl<W: io::Write> WriterBackend for IO<W> {
     type Error = io::Error;
     #[inline(always)]
     fn extend_from_slice(&mut self, data: &[u8]) -> io::Result<()> {
-        self.0.write_all(data)
+        let res = self.out.write_all(data);
+        self.failed |= res.is_err();
+        res
+    }
+
+    fn extend_from_slices(&mut self, data: &[&[u8]]) -> io::Result<()> {
+        let res = self.write_slices(data);
+        self.failed |= res.is_err();
+        res
+    }
+
+    fn has_failed(&self) -> bool {
+        self.failed
     }
 }
 
-pub struct Writer<'p, 'w, B> {
+/// Writes a box whose size isn't known until its contents have been written, by seeking back to patch its header.
+///
+/// This is for boxes that would be awkward to measure up front. They can't be 4GB or larger.
+pub fn write_patched_box<W: io::Write + io::Seek>(out: &mut W, typ: [u8; 4], contents: impl FnOnce(&mut W) -> io::Result<()>) -> io::Result<()> {
+    let start = out.stream_position()?;
+    out.write_all(&[0; 4])?;
+    out.write_all(&typ)?;
+    contents(out)?;
+    let end = out.stream_position()?;
+    let len = u32::try_from(end - start).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Box is too large for a 32-bit size"))?;
+    out.seek(io::SeekFrom::Start(start))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.seek(io::SeekFrom::Start(end))?;
+    Ok(())
+}
+
+pub struct Writer<'p, 'w, B: WriterBackend> {
     parent: Option<&'p mut usize>,
     left: Option<usize>,
     out: &'w mut B,
 }
 
-impl<'w, B> Writer<'static, 'w, B> {
+impl<'w, B: WriterBackend> Writer<'static, 'w, B> {
     #[inline]
     pub fn new(out: &'w mut B) -> Self {
         Self {
@@ -60,8 +147,15 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
 
     #[inline(always)]
     pub fn full_box(&mut self, typ: [u8; 4], version: u8) -> Result<(), B::Error> {
+        self.full_box_with_flags(typ, version, 0)
+    }
+
+    /// Only the lower 24 bits of `flags` are written
+    #[inline]
+    pub fn full_box_with_flags(&mut self, typ: [u8; 4], version: u8, flags: u32) -> Result<(), B::Error> {
         self.basic_box(typ)?;
-        self.push(&[version, 0, 0, 0])
+        let [_, f1, f2, f3] = flags.to_be_bytes();
+        self.push(&[version, f1, f2, f3])
     }
 
     #[inline]
@@ -71,21 +165,51 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
             **parent -= len;
         }
         match u32::try_from(len) {
-            Ok(len) => self.u32(len)?,
-            Err(_) => {
-                self.u32(1)?;
-                self.u64(len as u64)?;
-            }
+            Ok(len) => {
+                self.u32(len)?;
+                self.push(&typ)
+            },
+            Err(_) => self.large_box_header(typ, len),
         }
-        self.push(&typ)
+    }
+
+    /// Box header with a 64-bit size, even if the box is small, so that the size can be patched later
+    #[inline]
+    pub fn large_box(&mut self, typ: [u8; 4]) -> Result<(), B::Error> {
+        let len = self.left.unwrap();
+        if let Some(parent) = &mut self.parent {
+            **parent -= len;
+        }
+        self.large_box_header(typ, len)
+    }
+
+    fn large_box_header(&mut self, typ: [u8; 4], len: usize) -> Result<(), B::Error> {
+        // largesize comes after the type
+        self.u32(1)?;
+        self.push(&typ)?;
+        self.u64(len as u64)
     }
 
     #[inline(always)]
     pub fn push(&mut self, data: &[u8]) -> Result<(), B::Error> {
-        *self.left.as_mut().unwrap() -= data.len();
+        self.consume(data.len());
         self.out.extend_from_slice(data)
     }
 
+    #[inline]
+    pub fn push_slices(&mut self, data: &[&[u8]]) -> Result<(), B::Error> {
+        self.consume(data.iter().map(|d| d.len()).sum::<usize>());
+        self.out.extend_from_slices(data)
+    }
+
+    /// Box sizes are computed separately from writing, so they're checked in debug builds
+    #[inline(always)]
+    fn consume(&mut self, len: usize) {
+        let left = self.left.as_mut().unwrap();
+        debug_assert!(len <= *left, "box is larger than its declared size ({} more bytes than {} left)", len, *left);
+        *left = left.wrapping_sub(len);
+    }
+
     #[inline(always)]
     pub fn u8(&mut self, val: u8) -> Result<(), B::Error> {
         self.push(std::slice::from_ref(&val))
@@ -108,10 +232,46 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
 }
 
 #[cfg(debug_assertions)]
-impl<B> Drop for Writer<'_, '_, B> {
+impl<B: WriterBackend> Drop for Writer<'_, '_, B> {
     fn drop(&mut self) {
         if let Some(unwritten_bytes) = self.left {
-            assert_eq!(0, unwritten_bytes);
+            if !self.out.has_failed() && !std::thread::panicking() {
+                assert_eq!(0, unwritten_bytes, "box is smaller than its declared size");
+            }
         }
     }
 }
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "box is smaller than its declared size")]
+fn box_too_small() {
+    let mut out = Vec::new();
+    let mut w = Writer::new(&mut out);
+    let mut b = w.new_box(13);
+    b.basic_box(*b"test").unwrap();
+    b.u32(0).unwrap();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "box is larger than its declared size")]
+fn box_too_large() {
+    let mut out = Vec::new();
+    let mut w = Writer::new(&mut out);
+    let mut b = w.new_box(11);
+    b.basic_box(*b"test").unwrap();
+    b.u32(0).unwrap();
+}
+
+#[test]
+fn patched_box_size() {
+    let mut out = io::Cursor::new(b"xx".to_vec());
+    out.set_position(2);
+    write_patched_box(&mut out, *b"test", |out| {
+        io::Write::write_all(out, &[1, 2, 3])?;
+        write_patched_box(out, *b"kids", |_| Ok(()))
+    }).unwrap();
+    assert_eq!(&b"xx\0\0\0\x13test\x01\x02\x03\0\0\0\x08kids"[..], &out.get_ref()[..]);
+    assert_eq!(21, out.position());
+}